@@ -0,0 +1,55 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::routing::Alias;
+
+#[derive(Deserialize)]
+struct AliasRequest {
+    targets: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AliasResponse {
+    address: String,
+    #[serde(flatten)]
+    alias: Alias,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/aliases")
+            .wrap(from_fn(auth::admin))
+            .route("", web::get().to(list))
+            .route("/{address}", web::put().to(set))
+            .route("/{address}", web::delete().to(remove)),
+    );
+}
+
+/// Lists every alias with its targets
+async fn list(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let aliases = state
+        .router
+        .aliases()
+        .await?
+        .into_iter()
+        .map(|(address, alias)| AliasResponse { address, alias })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(aliases))
+}
+
+/// Creates an alias or replaces its targets
+async fn set(
+    state: web::Data<AppState>,
+    address: web::Path<String>,
+    request: web::Json<AliasRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let targets = request.into_inner().targets;
+    let (address, alias) = state.router.set_alias(&address, targets).await?;
+    Ok(HttpResponse::Ok().json(AliasResponse { address, alias }))
+}
+
+async fn remove(state: web::Data<AppState>, address: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.router.remove_alias(&address).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,105 @@
+use actix_web::{
+    body::BoxBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, Error, HttpMessage, HttpRequest,
+};
+
+use super::{ApiError, AppState};
+use crate::auth::{Identity, Scope};
+
+/// The token of an `Authorization: Bearer` header
+pub(super) fn bearer_token(request: &HttpRequest) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// Rejects requests without an API key or session token carrying the given scope.
+/// The verified identity is left in the request extensions for the handler.
+pub(super) async fn authorize(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+    scope: Scope,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let token = bearer_token(request.request()).ok_or(ApiError::Unauthorized)?;
+    let state = request
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .ok_or(ApiError::Unauthorized)?;
+
+    let identity = match state.keys.verify(&token).await.map_err(ApiError::from)? {
+        Some((_, key)) => Identity::Key(key),
+        None => match state.users.session(&token).await.map_err(ApiError::from)? {
+            Some(username) => Identity::User(username),
+            None => return Err(ApiError::Unauthorized.into()),
+        },
+    };
+    if !identity.allows(scope) {
+        return Err(ApiError::Forbidden(scope).into());
+    }
+    request.extensions_mut().insert(identity);
+    next.call(request).await
+}
+
+/// Reading needs the read scope, deleting the delete scope
+pub(super) async fn read_or_delete(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let scope = match *request.method() {
+        Method::DELETE => Scope::Delete,
+        _ => Scope::Read,
+    };
+    authorize(request, next, scope).await
+}
+
+/// Reading needs the read scope, deleting the delete scope and any other change the write scope
+pub(super) async fn read_delete_or_write(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let scope = match *request.method() {
+        Method::GET | Method::HEAD => Scope::Read,
+        Method::DELETE => Scope::Delete,
+        _ => Scope::Write,
+    };
+    authorize(request, next, scope).await
+}
+
+/// Reading needs the read scope, any change the write scope
+pub(super) async fn read_or_write(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let scope = match *request.method() {
+        Method::GET | Method::HEAD => Scope::Read,
+        _ => Scope::Write,
+    };
+    authorize(request, next, scope).await
+}
+
+pub(super) async fn read(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    authorize(request, next, Scope::Read).await
+}
+
+pub(super) async fn admin(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    authorize(request, next, Scope::Admin).await
+}
+
+pub(super) async fn send(
+    request: ServiceRequest,
+    next: Next<BoxBody>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    authorize(request, next, Scope::Send).await
+}
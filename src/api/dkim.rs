@@ -0,0 +1,77 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::dkim::{Algorithm, DkimKey, DnsRecord};
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    selector: Option<String>,
+    algorithm: Option<Algorithm>,
+    /// RSA key size, 2048 when left out
+    bits: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct KeyResponse {
+    selector: String,
+    algorithm: Algorithm,
+    created_at: i64,
+    dns: DnsRecord,
+}
+
+impl KeyResponse {
+    fn new(domain: &str, selector: String, key: DkimKey) -> Self {
+        KeyResponse {
+            dns: key.dns_record(domain, &selector),
+            selector,
+            algorithm: key.algorithm,
+            created_at: key.created_at,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/domains/{name}/dkim")
+            .wrap(from_fn(auth::admin))
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{selector}", web::delete().to(remove)),
+    );
+}
+
+/// Lists the signing keys of a domain with the TXT records to publish
+async fn list(state: web::Data<AppState>, name: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner().to_lowercase();
+    let keys = state
+        .dkim
+        .list(&name)
+        .await?
+        .into_iter()
+        .map(|(selector, key)| KeyResponse::new(&name, selector, key))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+/// Generates a signing key, outbound mail of the domain is signed with it right away
+async fn create(
+    state: web::Data<AppState>,
+    name: web::Path<String>,
+    request: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner().to_lowercase();
+    let request = request.into_inner();
+    let algorithm = request.algorithm.unwrap_or(Algorithm::Rsa);
+    let (selector, key) = state
+        .dkim
+        .generate(&name, request.selector, algorithm, request.bits)
+        .await?;
+    Ok(HttpResponse::Created().json(KeyResponse::new(&name, selector, key)))
+}
+
+async fn remove(state: web::Data<AppState>, path: web::Path<(String, String)>) -> Result<HttpResponse, ApiError> {
+    let (name, selector) = path.into_inner();
+    state.dkim.remove(&name, &selector).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,71 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::domain::Domain;
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    catch_all: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DomainResponse {
+    name: String,
+    #[serde(flatten)]
+    domain: Domain,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/domains")
+            .wrap(from_fn(auth::admin))
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{name}", web::patch().to(update))
+            .route("/{name}", web::delete().to(remove)),
+    );
+}
+
+/// Lists the domains mail is accepted for
+async fn list(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let domains = state
+        .domains
+        .list()
+        .await?
+        .into_iter()
+        .map(|(name, domain)| DomainResponse { name, domain })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(domains))
+}
+
+/// Starts accepting mail for a domain
+async fn create(
+    state: web::Data<AppState>,
+    request: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let (name, domain) = state.domains.add(&request.name).await?;
+    Ok(HttpResponse::Created().json(DomainResponse { name, domain }))
+}
+
+/// Sets the catch-all address of a domain, `null` removes it
+async fn update(
+    state: web::Data<AppState>,
+    name: web::Path<String>,
+    request: web::Json<UpdateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner().to_lowercase();
+    let domain = state.domains.set_catch_all(&name, request.into_inner().catch_all).await?;
+    Ok(HttpResponse::Ok().json(DomainResponse { name, domain }))
+}
+
+/// Stops accepting mail for a domain, stored messages are kept
+async fn remove(state: web::Data<AppState>, name: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.domains.remove(&name).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,152 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::{
+    auth::Identity,
+    db::DatabaseError,
+    folder::{system_folder, Folder, FolderError},
+    message::{FlagChanges, FolderCounts},
+};
+
+#[derive(Deserialize)]
+struct MailboxParams {
+    mailbox: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FolderResponse {
+    #[serde(flatten)]
+    folder: Folder,
+    #[serde(flatten)]
+    counts: FolderCounts,
+}
+
+#[derive(Serialize)]
+struct ReadResponse {
+    /// Messages that were unread before
+    updated: usize,
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+    mailbox: Option<String>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/folders")
+            .wrap(from_fn(auth::read_or_write))
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{name}", web::delete().to(remove))
+            .route("/{name}/read", web::post().to(read)),
+    );
+}
+
+/// The mailbox a request is about. Users only ever get their own, API keys name an existing user.
+pub(super) async fn mailbox(
+    state: &AppState,
+    identity: &Identity,
+    requested: Option<String>,
+) -> Result<Option<String>, ApiError> {
+    match (identity, requested) {
+        (Identity::User(username), None) => Ok(Some(username.clone())),
+        (Identity::User(username), Some(requested)) if requested == *username => Ok(Some(requested)),
+        (Identity::User(_), Some(_)) => Err(DatabaseError::NotFound.into()),
+        (Identity::Key(_), Some(requested)) => {
+            state.users.get(&requested).await?;
+            Ok(Some(requested))
+        }
+        (Identity::Key(_), None) => Ok(None),
+    }
+}
+
+pub(super) async fn required_mailbox(state: &AppState, identity: &Identity, requested: Option<String>) -> Result<String, ApiError> {
+    mailbox(state, identity, requested)
+        .await?
+        .ok_or_else(|| ApiError::BadRequest("API keys have to name a mailbox".to_string()))
+}
+
+/// Lists the system folders and the custom folders of a mailbox with how many messages they hold
+async fn list(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    params: web::Query<MailboxParams>,
+) -> Result<HttpResponse, ApiError> {
+    let mailbox = required_mailbox(&state, &identity, params.into_inner().mailbox).await?;
+    let folders = state
+        .folders
+        .list(&mailbox)
+        .await?
+        .into_iter()
+        .map(|folder| {
+            Ok(FolderResponse {
+                counts: state.messages.folder_counts(&mailbox, &folder.name)?,
+                folder,
+            })
+        })
+        .collect::<Result<Vec<_>, ApiError>>()?;
+    Ok(HttpResponse::Ok().json(folders))
+}
+
+/// Marks every message in a folder as seen
+async fn read(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    name: web::Path<String>,
+    params: web::Query<MailboxParams>,
+) -> Result<HttpResponse, ApiError> {
+    let mailbox = required_mailbox(&state, &identity, params.into_inner().mailbox).await?;
+    let name = state.folders.resolve(&mailbox, &name).await?;
+    let changes = FlagChanges {
+        seen: Some(true),
+        ..FlagChanges::default()
+    };
+    let mut updated = 0;
+    for id in state.messages.folder_ids(&mailbox, &name)? {
+        let message = match state.messages.get(&id).await {
+            Ok(message) => message,
+            // Deleted since the index was read
+            Err(DatabaseError::NotFound) => continue,
+            Err(error) => return Err(error.into()),
+        };
+        if !message.flags.seen {
+            state.messages.set_flags(&id, &changes).await?;
+            updated += 1;
+        }
+    }
+    Ok(HttpResponse::Ok().json(ReadResponse { updated }))
+}
+
+/// Creates a custom folder
+async fn create(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    request: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    let mailbox = required_mailbox(&state, &identity, request.mailbox).await?;
+    let folder = state.folders.create(&mailbox, &request.name).await?;
+    Ok(HttpResponse::Created().json(folder))
+}
+
+/// Removes an empty custom folder
+async fn remove(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    name: web::Path<String>,
+    params: web::Query<MailboxParams>,
+) -> Result<HttpResponse, ApiError> {
+    let mailbox = required_mailbox(&state, &identity, params.into_inner().mailbox).await?;
+    if let Some(system) = system_folder(&name) {
+        return Err(FolderError::System(system.to_string()).into());
+    }
+    let name = state.folders.resolve(&mailbox, &name).await?;
+    if !state.messages.folder_is_empty(&mailbox, &name)? {
+        return Err(FolderError::NotEmpty(name).into());
+    }
+    state.folders.remove(&mailbox, &name).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
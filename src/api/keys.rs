@@ -0,0 +1,82 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::auth::{ApiKey, Scope};
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct KeyResponse {
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    created_at: i64,
+}
+
+impl KeyResponse {
+    fn new(id: String, key: ApiKey) -> Self {
+        KeyResponse {
+            id,
+            name: key.name,
+            scopes: key.scopes,
+            created_at: key.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CreatedResponse {
+    #[serde(flatten)]
+    key: KeyResponse,
+    /// Only ever shown in this response
+    token: String,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/keys")
+            .wrap(from_fn(auth::admin))
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{id}", web::delete().to(revoke)),
+    );
+}
+
+/// Lists every key without its secret
+async fn list(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let keys = state
+        .keys
+        .list()
+        .await?
+        .into_iter()
+        .map(|(id, key)| KeyResponse::new(id, key))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+/// Creates a key, the response holds the only copy of its token
+async fn create(
+    state: web::Data<AppState>,
+    request: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let CreateRequest { name, scopes } = request.into_inner();
+    if scopes.is_empty() {
+        return Err(ApiError::BadRequest("A key needs at least one scope".to_string()));
+    }
+    let (id, key, token) = state.keys.create(&name, scopes).await?;
+    Ok(HttpResponse::Created().json(CreatedResponse {
+        key: KeyResponse::new(id, key),
+        token,
+    }))
+}
+
+/// Revokes a key, requests using it fail from now on
+async fn revoke(state: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.keys.revoke(&id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
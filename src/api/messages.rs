@@ -0,0 +1,393 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use actix_web::{
+    http::header::{self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue},
+    middleware::from_fn,
+    web, HttpResponse,
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::{auth, folders, ApiError, AppState};
+use crate::{
+    auth::Identity,
+    db::DatabaseError,
+    folder::{normalize_name, system_folder},
+    message::{FlagChanges, Flags, Listing, Message, Query, Sort, Summary},
+};
+
+/// Page size when the request does not ask for one
+pub(super) const DEFAULT_LIMIT: usize = 50;
+pub(super) const MAX_LIMIT: usize = 500;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    sort: Option<Sort>,
+    order: Option<Order>,
+    from: Option<String>,
+    /// Lets API keys list a single mailbox
+    mailbox: Option<String>,
+    folder: Option<String>,
+    label: Option<String>,
+    /// Only messages not seen yet
+    unread: Option<bool>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FolderRequest {
+    folder: String,
+}
+
+#[derive(Deserialize)]
+struct BulkFlagsRequest {
+    ids: Vec<String>,
+    #[serde(flatten)]
+    changes: FlagChanges,
+}
+
+#[derive(Serialize)]
+struct FlagsResponse {
+    id: String,
+    flags: Flags,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    messages: Vec<SummaryResponse>,
+    /// Pass as `cursor` to get the next page, `None` on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(super) struct SummaryResponse {
+    pub(super) id: String,
+    #[serde(flatten)]
+    pub(super) summary: Summary,
+}
+
+#[derive(Serialize)]
+struct MessageResponse {
+    id: String,
+    #[serde(flatten)]
+    message: Message,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // Registered ahead of /messages, where removing a label would need the delete scope
+    cfg.service(
+        web::scope("/messages/{id}/labels")
+            .wrap(from_fn(auth::read_or_write))
+            .route("/{label}", web::put().to(label))
+            .route("/{label}", web::delete().to(unlabel)),
+    );
+    cfg.service(
+        web::scope("/messages")
+            .wrap(from_fn(auth::read_delete_or_write))
+            .route("", web::get().to(list))
+            .route("", web::patch().to(flag_many))
+            .route("/search", web::get().to(search))
+            .route("/{id}", web::get().to(fetch))
+            .route("/{id}", web::patch().to(flag))
+            .route("/{id}", web::delete().to(remove))
+            .route("/{id}/move", web::post().to(move_to))
+            .route("/{id}/copy", web::post().to(copy_to))
+            .route("/{id}/raw", web::get().to(raw))
+            .route("/{id}/attachments/{index}", web::get().to(attachment)),
+    );
+}
+
+/// Fetches a message, pretending it does not exist when it belongs to another mailbox
+async fn accessible(state: &AppState, identity: &Identity, id: &str) -> Result<Message, ApiError> {
+    let message = state.messages.get(id).await?;
+    match identity.can_access(message.mailbox.as_deref()) {
+        true => Ok(message),
+        false => Err(DatabaseError::NotFound.into()),
+    }
+}
+
+/// Lists one page of summaries of the messages the caller may see, newest first unless sorted
+/// otherwise
+async fn list(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    params: web::Query<ListParams>,
+) -> Result<HttpResponse, ApiError> {
+    let params = params.into_inner();
+    let sort = params.sort.unwrap_or(Sort::Date);
+    // Dates read best newest first, senders alphabetically
+    let order = params.order.unwrap_or(match sort {
+        Sort::Date => Order::Desc,
+        Sort::Sender => Order::Asc,
+    });
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    let cursor = params
+        .cursor
+        .map(|cursor| URL_SAFE_NO_PAD.decode(cursor))
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid cursor".to_string()))?;
+    let mailbox = folders::mailbox(&state, &identity, params.mailbox).await?;
+    let folder = match params.folder {
+        Some(folder) => Some(match &mailbox {
+            Some(mailbox) => state.folders.resolve(mailbox, &folder).await?,
+            // Across mailboxes only system folders have a name known to be spelled one way
+            None => system_folder(&folder).map_or(folder, str::to_string),
+        }),
+        None => None,
+    };
+    let listing = Listing {
+        sort,
+        descending: matches!(order, Order::Desc),
+        from: params.from,
+        mailbox,
+        folder,
+        label: params.label.map(|label| normalize_name(&label)).transpose()?,
+        unread: params.unread.unwrap_or_default(),
+        limit,
+        cursor,
+    };
+
+    // Users only ever get a listing of their own mailbox, so every page is full
+    let (messages, next_cursor) = state.messages.page(&listing).await?;
+    let messages = messages
+        .into_iter()
+        .map(|(id, message)| SummaryResponse {
+            summary: message.summary(),
+            id,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(ListResponse {
+        messages,
+        next_cursor: next_cursor.map(|cursor| URL_SAFE_NO_PAD.encode(cursor)),
+    }))
+}
+
+/// Lists one page of summaries of the messages matching `q`, newest first
+async fn search(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    params: web::Query<SearchParams>,
+) -> Result<HttpResponse, ApiError> {
+    let params = params.into_inner();
+    let mut query = Query::parse(&params.q).map_err(ApiError::BadRequest)?;
+    // Restricted before paging, so pages of users are not thinned out by other mailboxes
+    if let Identity::User(username) = &*identity {
+        query.restrict_to(username);
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    let cursor = params
+        .cursor
+        .map(|cursor| URL_SAFE_NO_PAD.decode(cursor))
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid cursor".to_string()))?;
+
+    let (messages, next_cursor) = state.messages.search(&query, limit, cursor.as_deref()).await?;
+    let messages = messages
+        .into_iter()
+        .map(|(id, message)| SummaryResponse {
+            summary: message.summary(),
+            id,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(ListResponse {
+        messages,
+        next_cursor: next_cursor.map(|cursor| URL_SAFE_NO_PAD.encode(cursor)),
+    }))
+}
+
+/// Fetches a single parsed message by id
+async fn fetch(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let message = accessible(&state, &identity, &id).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse { id, message }))
+}
+
+/// Downloads the RFC 5322 source of a message
+async fn raw(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    accessible(&state, &identity, &id).await?;
+    let source = state.messages.raw(&id).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("message/rfc822")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.eml\"", id),
+        ))
+        .body(source))
+}
+
+/// Streams a single attachment of a message, counted from zero
+async fn attachment(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    path: web::Path<(String, usize)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, index) = path.into_inner();
+    let message = accessible(&state, &identity, &id).await?;
+    let attachment = message
+        .attachments
+        .get(index)
+        .ok_or(DatabaseError::NotFound)?;
+
+    // Non ASCII names need the RFC 6266 extended parameter
+    let filename = attachment.filename.clone().unwrap_or_else(|| format!("attachment-{}", index));
+    let parameter = match filename.is_ascii() {
+        true => DispositionParam::Filename(filename),
+        false => DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.into_bytes(),
+        }),
+    };
+
+    let contents = state.messages.attachment(attachment).map_err(ApiError::from);
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.content_type.as_str())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![parameter],
+        })
+        .no_chunking(attachment.size as u64)
+        .streaming(contents))
+}
+
+/// The folder a request names, as spelled when it was created in the message's mailbox
+async fn target_folder(state: &AppState, message: &Message, folder: &str) -> Result<String, ApiError> {
+    Ok(state
+        .folders
+        .resolve(message.mailbox.as_deref().unwrap_or_default(), folder)
+        .await?)
+}
+
+/// Files a message into another folder
+async fn move_to(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+    request: web::Json<FolderRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let message = accessible(&state, &identity, &id).await?;
+    let folder = target_folder(&state, &message, &request.folder).await?;
+    let message = state.messages.move_to(&id, &folder).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse { id, message }))
+}
+
+/// Files a copy of a message into a folder, answered with the copy and its new id
+async fn copy_to(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+    request: web::Json<FolderRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let message = accessible(&state, &identity, &id).await?;
+    let folder = target_folder(&state, &message, &request.folder).await?;
+    let (id, message) = state.messages.copy_to(&id, &folder).await?;
+    Ok(HttpResponse::Created().json(MessageResponse { id, message }))
+}
+
+async fn label(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, label) = path.into_inner();
+    accessible(&state, &identity, &id).await?;
+    let message = state.messages.label(&id, &normalize_name(&label)?).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse { id, message }))
+}
+
+async fn unlabel(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, label) = path.into_inner();
+    accessible(&state, &identity, &id).await?;
+    let message = state.messages.unlabel(&id, &normalize_name(&label)?).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse { id, message }))
+}
+
+fn require_changes(changes: &FlagChanges) -> Result<(), ApiError> {
+    match changes.is_empty() {
+        true => Err(ApiError::BadRequest("No flags to change".to_string())),
+        false => Ok(()),
+    }
+}
+
+/// Changes the flags of a message, the ones left out of the request keep their value
+async fn flag(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+    changes: web::Json<FlagChanges>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    require_changes(&changes)?;
+    accessible(&state, &identity, &id).await?;
+    let message = state.messages.set_flags(&id, &changes).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse { id, message }))
+}
+
+/// Changes the flags of several messages at once, none change unless all of them are accessible
+async fn flag_many(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    request: web::Json<BulkFlagsRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let request = request.into_inner();
+    require_changes(&request.changes)?;
+    if !(1..=MAX_LIMIT).contains(&request.ids.len()) {
+        return Err(ApiError::BadRequest(format!("ids must list between 1 and {} messages", MAX_LIMIT)));
+    }
+    for id in &request.ids {
+        accessible(&state, &identity, id).await?;
+    }
+    let mut updated = Vec::with_capacity(request.ids.len());
+    for id in request.ids {
+        let message = state.messages.set_flags(&id, &request.changes).await?;
+        updated.push(FlagsResponse {
+            id,
+            flags: message.flags,
+        });
+    }
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+/// Deletes a single message by id
+async fn remove(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    accessible(&state, &identity, &id).await?;
+    state.messages.delete(&id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
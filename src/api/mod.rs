@@ -0,0 +1,170 @@
+mod aliases;
+mod auth;
+mod dkim;
+mod domains;
+mod folders;
+mod keys;
+mod messages;
+mod outbound;
+mod sessions;
+mod threads;
+mod users;
+mod webhooks;
+
+use std::sync::Arc;
+
+use actix_cors::Cors;
+use actix_web::{
+    dev::Server,
+    http::{header, StatusCode},
+    web, App, HttpResponse, HttpServer, ResponseError,
+};
+use rustls::ServerConfig;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    auth::{ApiKeys, AuthError, Scope, Users},
+    db::DatabaseError,
+    dkim::{DkimError, DkimKeys},
+    domain::{DomainError, Domains},
+    folder::{FolderError, Folders},
+    message::MessageStore,
+    outbound::{Outbound, OutboundError},
+    routing::{Router, RoutingError},
+    webhook::Webhook,
+};
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+    #[error("{0}")]
+    Domain(#[from] DomainError),
+    #[error("{0}")]
+    Routing(#[from] RoutingError),
+    #[error("{0}")]
+    Outbound(#[from] OutboundError),
+    #[error("{0}")]
+    Dkim(#[from] DkimError),
+    #[error("{0}")]
+    Folder(#[from] FolderError),
+    #[error("Missing or invalid API key or session token")]
+    Unauthorized,
+    #[error("The {0} scope is required")]
+    Forbidden(Scope),
+    #[error("{0}")]
+    BadRequest(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn database_status(error: &DatabaseError) -> StatusCode {
+    match error {
+        DatabaseError::NotFound => StatusCode::NOT_FOUND,
+        DatabaseError::Communicate => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Database(error)
+            | ApiError::Auth(AuthError::Database(error))
+            | ApiError::Domain(DomainError::Database(error))
+            | ApiError::Routing(RoutingError::Database(error))
+            | ApiError::Outbound(OutboundError::Database(error))
+            | ApiError::Dkim(DkimError::Database(error))
+            | ApiError::Folder(FolderError::Database(error))
+            | ApiError::Outbound(OutboundError::Dkim(DkimError::Database(error))) => database_status(error),
+            ApiError::Auth(AuthError::InvalidCredentials) => StatusCode::UNAUTHORIZED,
+            ApiError::Auth(AuthError::UsernameTaken(_) | AuthError::AddressTaken(_)) => StatusCode::CONFLICT,
+            ApiError::Auth(AuthError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Auth(AuthError::Hash(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Domain(DomainError::Invalid(_) | DomainError::InvalidAddress(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Domain(DomainError::Exists(_)) => StatusCode::CONFLICT,
+            ApiError::Routing(RoutingError::Invalid(_) | RoutingError::UnknownDomain(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Routing(RoutingError::AddressTaken(_)) => StatusCode::CONFLICT,
+            ApiError::Outbound(OutboundError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Outbound(OutboundError::Sender(_)) => StatusCode::FORBIDDEN,
+            ApiError::Outbound(OutboundError::Build(_) | OutboundError::Dkim(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Dkim(DkimError::Invalid(_) | DkimError::UnknownDomain(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Dkim(DkimError::Exists(_)) => StatusCode::CONFLICT,
+            ApiError::Dkim(DkimError::Generate(_) | DkimError::Sign { .. }) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Folder(FolderError::Invalid(_) | FolderError::System(_)) => StatusCode::BAD_REQUEST,
+            ApiError::Folder(FolderError::NotFound(_)) => StatusCode::NOT_FOUND,
+            ApiError::Folder(FolderError::Exists(_) | FolderError::NotEmpty(_)) => StatusCode::CONFLICT,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::Unauthorized = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody {
+            error: self.to_string(),
+        })
+    }
+}
+
+/// Shared handles available to every request handler
+pub struct AppState {
+    pub messages: MessageStore,
+    pub keys: ApiKeys,
+    pub users: Users,
+    pub domains: Domains,
+    pub folders: Folders,
+    pub router: Router,
+    pub webhook: Webhook,
+    pub outbound: Outbound,
+    pub dkim: DkimKeys,
+}
+
+/// Builds the REST API server, it starts serving once the returned future is polled.
+/// With a TLS configuration the API is only reachable over HTTPS.
+pub fn serve(
+    host: &str,
+    port: u16,
+    state: AppState,
+    tls: Option<Arc<ServerConfig>>,
+) -> std::io::Result<Server> {
+    let state = web::Data::new(state);
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(Cors::permissive())
+            .app_data(state.clone())
+            .configure(aliases::configure)
+            // Registered ahead of /domains, whose scope would otherwise swallow these paths
+            .configure(dkim::configure)
+            .configure(domains::configure)
+            .configure(folders::configure)
+            .configure(keys::configure)
+            .configure(messages::configure)
+            .configure(outbound::configure)
+            .configure(sessions::configure)
+            .configure(threads::configure)
+            .configure(users::configure)
+            .configure(webhooks::configure)
+    });
+    let server = match tls {
+        Some(tls) => {
+            log::info!("REST API listening on https://{}:{}", host, port);
+            server.bind_rustls_0_23((host, port), ServerConfig::clone(&tls))?
+        }
+        None => {
+            log::info!("REST API listening on http://{}:{}", host, port);
+            server.bind((host, port))?
+        }
+    };
+    Ok(server.run())
+}
@@ -0,0 +1,95 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::Serialize;
+
+use super::{auth, ApiError, AppState};
+use crate::{
+    auth::Identity,
+    db::DatabaseError,
+    outbound::{Bounce, Draft, OutboundMessage},
+};
+
+#[derive(Serialize)]
+struct OutboundResponse {
+    id: String,
+    #[serde(flatten)]
+    message: OutboundMessage,
+}
+
+#[derive(Serialize)]
+struct BounceResponse {
+    id: String,
+    #[serde(flatten)]
+    bounce: Bounce,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/send")
+            .wrap(from_fn(auth::send))
+            .route(web::post().to(send)),
+    )
+    .service(
+        web::scope("/outbound")
+            .wrap(from_fn(auth::read_or_delete))
+            .route("", web::get().to(list))
+            .route("/bounces", web::get().to(bounces))
+            .route("/{id}", web::get().to(fetch)),
+    );
+}
+
+/// Sending user, `None` for API keys
+fn owner(identity: &Identity) -> Option<String> {
+    match identity {
+        Identity::Key(_) => None,
+        Identity::User(username) => Some(username.clone()),
+    }
+}
+
+/// Builds a message from the draft and queues it for the relay
+async fn send(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    draft: web::Json<Draft>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, message) = state.outbound.submit(owner(&identity), draft.into_inner()).await?;
+    Ok(HttpResponse::Accepted().json(OutboundResponse { id, message }))
+}
+
+/// Lists sent messages with the delivery state of each recipient
+async fn list(state: web::Data<AppState>, identity: web::ReqData<Identity>) -> Result<HttpResponse, ApiError> {
+    let messages = state
+        .outbound
+        .list()
+        .await?
+        .into_iter()
+        .filter(|(_, message)| identity.can_access(message.owner.as_deref()))
+        .map(|(id, message)| OutboundResponse { id, message })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(messages))
+}
+
+async fn fetch(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let message = state.outbound.get(&id).await?;
+    if !identity.can_access(message.owner.as_deref()) {
+        return Err(DatabaseError::NotFound.into());
+    }
+    Ok(HttpResponse::Ok().json(OutboundResponse { id, message }))
+}
+
+/// Lists recipients the relay refused or that were given up on
+async fn bounces(state: web::Data<AppState>, identity: web::ReqData<Identity>) -> Result<HttpResponse, ApiError> {
+    let bounces = state
+        .outbound
+        .bounces()
+        .await?
+        .into_iter()
+        .filter(|(_, bounce)| identity.can_access(bounce.owner.as_deref()))
+        .map(|(id, bounce)| BounceResponse { id, bounce })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(bounces))
+}
@@ -0,0 +1,38 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    expires_at: i64,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/login", web::post().to(login))
+        .route("/logout", web::post().to(logout));
+}
+
+/// Exchanges a username and password for a session token
+async fn login(
+    state: web::Data<AppState>,
+    request: web::Json<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let LoginRequest { username, password } = request.into_inner();
+    let (token, expires_at) = state.users.login(&username, password).await?;
+    Ok(HttpResponse::Ok().json(LoginResponse { token, expires_at }))
+}
+
+/// Ends the session the request is authenticated with
+async fn logout(state: web::Data<AppState>, request: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let token = auth::bearer_token(&request).ok_or(ApiError::Unauthorized)?;
+    state.users.logout(&token).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
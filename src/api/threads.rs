@@ -0,0 +1,156 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    auth, folders,
+    messages::{SummaryResponse, DEFAULT_LIMIT, MAX_LIMIT},
+    ApiError, AppState,
+};
+use crate::{
+    auth::Identity,
+    db::DatabaseError,
+    message::{Message, Thread},
+};
+
+#[derive(Deserialize)]
+struct ListParams {
+    /// Lets API keys list the threads of a mailbox
+    mailbox: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+/// A thread as listed, with what a client needs to render a conversation row
+#[derive(Serialize)]
+struct ThreadSummary {
+    id: String,
+    subject: Option<String>,
+    /// Number of messages in the thread
+    messages: usize,
+    unread: usize,
+    /// Sender addresses in the order they first wrote
+    participants: Vec<String>,
+    created_at: i64,
+    updated_at: i64,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    threads: Vec<ThreadSummary>,
+    /// Pass as `cursor` to get the next page, `None` on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThreadResponse {
+    id: String,
+    mailbox: Option<String>,
+    subject: Option<String>,
+    created_at: i64,
+    updated_at: i64,
+    /// Oldest first
+    messages: Vec<SummaryResponse>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/threads")
+            .wrap(from_fn(auth::read))
+            .route("", web::get().to(list))
+            .route("/{id}", web::get().to(fetch)),
+    );
+}
+
+/// The messages of a thread in the order they were stored, skipping any deleted meanwhile.
+/// Copies filed in other folders share the Message-ID of their source and are listed once.
+async fn messages(state: &AppState, thread: &Thread) -> Result<Vec<(String, Message)>, ApiError> {
+    let mut messages: Vec<(String, Message)> = Vec::with_capacity(thread.messages.len());
+    for id in &thread.messages {
+        match state.messages.get(id).await {
+            Ok(message)
+                if message.message_id.is_some()
+                    && messages.iter().any(|(_, known)| known.message_id == message.message_id) => {}
+            Ok(message) => messages.push((id.clone(), message)),
+            Err(DatabaseError::NotFound) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(messages)
+}
+
+/// Lists one page of the threads of a mailbox, latest activity first
+async fn list(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    params: web::Query<ListParams>,
+) -> Result<HttpResponse, ApiError> {
+    let params = params.into_inner();
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    let cursor = params
+        .cursor
+        .map(|cursor| URL_SAFE_NO_PAD.decode(cursor))
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid cursor".to_string()))?;
+    let mailbox = folders::required_mailbox(&state, &identity, params.mailbox).await?;
+
+    let (threads, next_cursor) = state.messages.threads(&mailbox, limit, cursor.as_deref()).await?;
+    let mut summaries = Vec::with_capacity(threads.len());
+    for (id, thread) in threads {
+        let messages = messages(&state, &thread).await?;
+        let mut participants = Vec::<String>::new();
+        for (_, message) in &messages {
+            for address in message.from.iter().filter_map(|address| address.address.as_ref()) {
+                if !participants.iter().any(|known| known.eq_ignore_ascii_case(address)) {
+                    participants.push(address.clone());
+                }
+            }
+        }
+        summaries.push(ThreadSummary {
+            id,
+            subject: thread.subject,
+            messages: messages.len(),
+            unread: messages.iter().filter(|(_, message)| !message.flags.seen).count(),
+            participants,
+            created_at: thread.created_at,
+            updated_at: thread.updated_at,
+        });
+    }
+    Ok(HttpResponse::Ok().json(ListResponse {
+        threads: summaries,
+        next_cursor: next_cursor.map(|cursor| URL_SAFE_NO_PAD.encode(cursor)),
+    }))
+}
+
+/// Fetches a thread with summaries of its messages, pretending it does not exist when it belongs
+/// to another mailbox
+async fn fetch(
+    state: web::Data<AppState>,
+    identity: web::ReqData<Identity>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let thread = state.messages.thread(&id).await?;
+    if !identity.can_access(thread.mailbox.as_deref()) {
+        return Err(DatabaseError::NotFound.into());
+    }
+    let messages = messages(&state, &thread)
+        .await?
+        .into_iter()
+        .map(|(id, message)| SummaryResponse {
+            summary: message.summary(),
+            id,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(ThreadResponse {
+        id,
+        mailbox: thread.mailbox,
+        subject: thread.subject,
+        created_at: thread.created_at,
+        updated_at: thread.updated_at,
+        messages,
+    }))
+}
@@ -0,0 +1,97 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{auth, ApiError, AppState};
+use crate::auth::User;
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    username: String,
+    password: String,
+    addresses: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdateRequest {
+    password: Option<String>,
+    addresses: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct UserResponse {
+    username: String,
+    addresses: Vec<String>,
+    created_at: i64,
+}
+
+impl UserResponse {
+    fn new(username: String, user: User) -> Self {
+        UserResponse {
+            username,
+            addresses: user.addresses,
+            created_at: user.created_at,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/users")
+            .wrap(from_fn(auth::admin))
+            .route("", web::get().to(list))
+            .route("", web::post().to(create))
+            .route("/{username}", web::get().to(fetch))
+            .route("/{username}", web::patch().to(update))
+            .route("/{username}", web::delete().to(remove)),
+    );
+}
+
+/// Lists every user without their password hash
+async fn list(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let users = state
+        .users
+        .list()
+        .await?
+        .into_iter()
+        .map(|(username, user)| UserResponse::new(username, user))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(users))
+}
+
+async fn fetch(state: web::Data<AppState>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let user = state.users.get(&username).await?;
+    Ok(HttpResponse::Ok().json(UserResponse::new(username, user)))
+}
+
+/// Creates a user owning the given addresses
+async fn create(
+    state: web::Data<AppState>,
+    request: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let CreateRequest {
+        username,
+        password,
+        addresses,
+    } = request.into_inner();
+    let (username, user) = state.users.create(&username, password, addresses).await?;
+    Ok(HttpResponse::Created().json(UserResponse::new(username, user)))
+}
+
+/// Changes the password and/or addresses, a new password logs the user out everywhere
+async fn update(
+    state: web::Data<AppState>,
+    username: web::Path<String>,
+    request: web::Json<UpdateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let username = username.into_inner();
+    let UpdateRequest { password, addresses } = request.into_inner();
+    let user = state.users.update(&username, password, addresses).await?;
+    Ok(HttpResponse::Ok().json(UserResponse::new(username, user)))
+}
+
+/// Removes a user, messages already delivered to them stay in the store
+async fn remove(state: web::Data<AppState>, username: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.users.delete(&username).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
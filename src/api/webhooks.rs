@@ -0,0 +1,53 @@
+use actix_web::{middleware::from_fn, web, HttpResponse};
+use serde::Serialize;
+
+use super::{auth, ApiError, AppState};
+use crate::webhook::Delivery;
+
+#[derive(Serialize)]
+struct DeliveryResponse {
+    id: String,
+    #[serde(flatten)]
+    delivery: Delivery,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/webhooks")
+            .wrap(from_fn(auth::admin))
+            .route("/queue", web::get().to(queue))
+            .route("/dead-letters", web::get().to(dead_letters))
+            .route("/dead-letters/{id}", web::delete().to(discard))
+            .route("/dead-letters/{id}/retry", web::post().to(retry)),
+    );
+}
+
+fn respond(deliveries: Vec<(String, Delivery)>) -> HttpResponse {
+    let deliveries = deliveries
+        .into_iter()
+        .map(|(id, delivery)| DeliveryResponse { id, delivery })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(deliveries)
+}
+
+/// Lists notifications still waiting to be delivered
+async fn queue(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(respond(state.webhook.pending().await?))
+}
+
+/// Lists notifications that exhausted their attempts
+async fn dead_letters(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(respond(state.webhook.dead_letters().await?))
+}
+
+/// Queues a dead letter for delivery again
+async fn retry(state: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.webhook.retry(&id).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Drops a dead letter for good
+async fn discard(state: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    state.webhook.discard(&id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
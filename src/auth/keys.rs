@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use super::{hash, random_token, Scope};
+use crate::{
+    db::{self, DatabaseError, Store},
+    time,
+};
+
+/// Prefix of every API key, makes leaked keys easy to recognise
+const TOKEN_PREFIX: &str = "fm";
+
+/// A stored API key, only the hash of its secret is kept
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+    /// Hex encoded SHA-256 of the secret part of the token
+    hash: String,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// API keys of the REST API, handed out as `fm_<id>_<secret>`
+#[derive(Clone)]
+pub struct ApiKeys {
+    database: Db,
+    keys: Store<ApiKey>,
+}
+
+impl ApiKeys {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(ApiKeys {
+            database: database.clone(),
+            keys: Store::open(database, "api_keys")?,
+        })
+    }
+
+    /// Creates a key and returns it with its id and token, the token cannot be recovered later
+    pub async fn create(&self, name: &str, scopes: Vec<Scope>) -> Result<(String, ApiKey, String), DatabaseError> {
+        let id = db::generate_key(&self.database)?;
+        let secret = random_token();
+
+        let key = ApiKey {
+            name: name.to_string(),
+            scopes,
+            created_at: time::now(),
+            hash: hash(&secret),
+        };
+        self.keys.set(&id, &key).await?;
+        let token = format!("{}_{}_{}", TOKEN_PREFIX, id, secret);
+        Ok((id, key, token))
+    }
+
+    /// Creates an admin key when none exist yet, so a fresh install can be managed at all
+    pub async fn bootstrap(&self) -> Result<(), DatabaseError> {
+        if !self.keys.tree().is_empty() {
+            return Ok(());
+        }
+        let (_, _, token) = self.create("bootstrap", vec![Scope::Admin]).await?;
+        log::warn!("Created an initial admin API key, it is printed once to stderr and not logged");
+        // Written past the logger, so the token does not end up in log files and collectors
+        eprintln!("Initial admin API key, store it now as it will not be shown again: {}", token);
+        Ok(())
+    }
+
+    /// Resolves a token to its key, `None` for unknown or revoked tokens
+    pub async fn verify(&self, token: &str) -> Result<Option<(String, ApiKey)>, DatabaseError> {
+        let mut parts = token.splitn(3, '_');
+        let (Some(TOKEN_PREFIX), Some(id), Some(secret)) = (parts.next(), parts.next(), parts.next()) else {
+            return Ok(None);
+        };
+        let key = match self.keys.get(id).await {
+            Ok(key) => key,
+            Err(DatabaseError::NotFound) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        // Comparing digests leaks nothing useful about the secret through timing
+        Ok((key.hash == hash(secret)).then(|| (id.to_string(), key)))
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, ApiKey)>, DatabaseError> {
+        self.keys.list().await
+    }
+
+    pub async fn revoke(&self, id: &str) -> Result<(), DatabaseError> {
+        self.keys.delete(id).await
+    }
+}
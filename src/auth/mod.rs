@@ -0,0 +1,96 @@
+mod keys;
+mod users;
+
+use std::fmt;
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::db::DatabaseError;
+pub use keys::{ApiKey, ApiKeys};
+pub use users::{User, Users};
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("User {0} already exists")]
+    UsernameTaken(String),
+    #[error("Address {0} already belongs to another user")]
+    AddressTaken(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Could not hash password: {0}")]
+    Hash(String),
+}
+
+/// What an API key is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// List and fetch messages
+    Read,
+    /// Delete messages
+    Delete,
+    /// Send outbound mail
+    Send,
+    /// Manage keys, users and webhooks, implies every other scope
+    Admin,
+    /// Organize messages into folders and labels
+    Write,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Delete => "delete",
+            Scope::Send => "send",
+            Scope::Admin => "admin",
+            Scope::Write => "write",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Whoever a request was authenticated as
+#[derive(Debug, Clone)]
+pub enum Identity {
+    /// An API key, it sees every mailbox
+    Key(ApiKey),
+    /// A logged in user, confined to their own mailbox
+    User(String),
+}
+
+impl Identity {
+    pub fn allows(&self, scope: Scope) -> bool {
+        match self {
+            Identity::Key(key) => key.allows(scope),
+            Identity::User(_) => scope != Scope::Admin,
+        }
+    }
+
+    /// Whether a message delivered to the given mailbox may be accessed
+    pub fn can_access(&self, mailbox: Option<&str>) -> bool {
+        match self {
+            Identity::Key(_) => true,
+            Identity::User(username) => mailbox == Some(username.as_str()),
+        }
+    }
+}
+
+/// Hex encoded SHA-256, good enough for random tokens which cannot be guessed anyway
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// 256 random bits, hex encoded
+pub(crate) fn random_token() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,281 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use super::{hash, random_token, AuthError};
+use crate::{
+    db::{DatabaseError, Store},
+    routing::normalize_address,
+    time,
+};
+
+/// Prefix of session tokens, keeps them apart from API keys
+const TOKEN_PREFIX: &str = "fms";
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A person with a mailbox, reachable under one or more addresses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub addresses: Vec<String>,
+    pub created_at: i64,
+    /// Argon2id hash in PHC string format
+    password_hash: String,
+}
+
+/// A login, stored under the hash of its token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Session {
+    username: String,
+    expires_at: i64,
+}
+
+fn normalize_username(username: &str) -> Result<String, AuthError> {
+    let username = username.trim().to_lowercase();
+    let valid = !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    match valid {
+        true => Ok(username),
+        false => Err(AuthError::Invalid(format!(
+            "Invalid username '{}', use letters, digits, '.', '_' and '-'",
+            username
+        ))),
+    }
+}
+
+/// Lowercases and deduplicates addresses, rejecting anything without a local part and a domain
+fn normalize_addresses(addresses: Vec<String>) -> Result<Vec<String>, AuthError> {
+    let mut normalized = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let address = normalize_address(&address)
+            .ok_or_else(|| AuthError::Invalid(format!("Invalid address '{}'", address)))?;
+        if !normalized.contains(&address) {
+            normalized.push(address);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Argon2 is deliberately slow, so it runs off the async workers
+async fn hash_password(password: String) -> Result<String, AuthError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::Invalid(format!(
+            "Passwords need at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|error| AuthError::Hash(error.to_string()))
+    })
+    .await
+    .map_err(|error| AuthError::Hash(error.to_string()))?
+}
+
+async fn verify_password(password: String, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Users, the addresses they own and their sessions
+#[derive(Clone)]
+pub struct Users {
+    users: Store<User>,
+    /// Address to username, every address belongs to at most one user
+    addresses: Store<String>,
+    sessions: Store<Session>,
+    /// Seconds a session stays valid after login
+    session_lifetime: i64,
+}
+
+impl Users {
+    pub fn open(database: &Db, session_lifetime: i64) -> Result<Self, DatabaseError> {
+        Ok(Users {
+            users: Store::open(database, "users")?,
+            addresses: Store::open(database, "user_addresses")?,
+            sessions: Store::open(database, "sessions")?,
+            session_lifetime,
+        })
+    }
+
+    pub async fn get(&self, username: &str) -> Result<User, DatabaseError> {
+        self.users.get(username).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, User)>, DatabaseError> {
+        self.users.list().await
+    }
+
+    /// Fails when an address is already owned by someone other than `username`
+    async fn claim(&self, username: &str, addresses: &[String]) -> Result<(), AuthError> {
+        for address in addresses {
+            match self.addresses.get(address).await {
+                Ok(owner) if owner != username => return Err(AuthError::AddressTaken(address.clone())),
+                Ok(_) | Err(DatabaseError::NotFound) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn create(
+        &self,
+        username: &str,
+        password: String,
+        addresses: Vec<String>,
+    ) -> Result<(String, User), AuthError> {
+        let username = normalize_username(username)?;
+        let addresses = normalize_addresses(addresses)?;
+        if self.users.tree().contains_key(&username).map_err(DatabaseError::from)? {
+            return Err(AuthError::UsernameTaken(username));
+        }
+        self.claim(&username, &addresses).await?;
+
+        let user = User {
+            addresses,
+            created_at: time::now(),
+            password_hash: hash_password(password).await?,
+        };
+        self.users.set(&username, &user).await?;
+        for address in &user.addresses {
+            self.addresses.set(address, &username).await?;
+        }
+        Ok((username, user))
+    }
+
+    /// Changes the password and/or the addresses of a user, a new password ends every session
+    pub async fn update(
+        &self,
+        username: &str,
+        password: Option<String>,
+        addresses: Option<Vec<String>>,
+    ) -> Result<User, AuthError> {
+        let mut user = self.users.get(username).await?;
+        if let Some(addresses) = addresses {
+            let addresses = normalize_addresses(addresses)?;
+            self.claim(username, &addresses).await?;
+            for address in user.addresses.iter().filter(|address| !addresses.contains(address)) {
+                self.addresses.delete(address).await?;
+            }
+            for address in &addresses {
+                self.addresses.set(address, &username.to_string()).await?;
+            }
+            user.addresses = addresses;
+        }
+        if let Some(password) = password {
+            user.password_hash = hash_password(password).await?;
+            self.end_sessions(username).await?;
+        }
+        self.users.set(username, &user).await?;
+        Ok(user)
+    }
+
+    /// Removes a user, their addresses and sessions. Their messages are kept.
+    pub async fn delete(&self, username: &str) -> Result<(), DatabaseError> {
+        let user = self.users.get(username).await?;
+        for address in &user.addresses {
+            self.addresses.delete(address).await?;
+        }
+        self.end_sessions(username).await?;
+        self.users.delete(username).await
+    }
+
+    /// Username owning an address, if any
+    pub async fn owner(&self, address: &str) -> Result<Option<String>, DatabaseError> {
+        match self.addresses.get(&address.to_lowercase()).await {
+            Ok(owner) => Ok(Some(owner)),
+            Err(DatabaseError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Checks a user's password without opening a session, returning the normalized username
+    pub async fn authenticate(&self, username: &str, password: String) -> Result<String, AuthError> {
+        let username = username.trim().to_lowercase();
+        let user = match self.users.get(&username).await {
+            Ok(user) => user,
+            Err(DatabaseError::NotFound) => {
+                // Spend the same time as a real check, so unknown names cannot be told apart
+                let _ = hash_password(password).await;
+                return Err(AuthError::InvalidCredentials);
+            }
+            Err(error) => return Err(error.into()),
+        };
+        match verify_password(password, user.password_hash).await {
+            true => Ok(username),
+            false => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    /// Checks the password and opens a session, returning its token and expiry
+    pub async fn login(&self, username: &str, password: String) -> Result<(String, i64), AuthError> {
+        let username = self.authenticate(username, password).await?;
+        self.purge_expired().await?;
+        let token = format!("{}_{}", TOKEN_PREFIX, random_token());
+        let session = Session {
+            username,
+            expires_at: time::now() + self.session_lifetime,
+        };
+        self.sessions.set(&hash(&token), &session).await?;
+        Ok((token, session.expires_at))
+    }
+
+    /// Resolves a session token to its username, `None` once it expired or was ended
+    pub async fn session(&self, token: &str) -> Result<Option<String>, DatabaseError> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let key = hash(token);
+        let session = match self.sessions.get(&key).await {
+            Ok(session) => session,
+            Err(DatabaseError::NotFound) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        if session.expires_at <= time::now() {
+            self.sessions.delete(&key).await?;
+            return Ok(None);
+        }
+        let exists = self.users.tree().contains_key(&session.username)?;
+        Ok(exists.then_some(session.username))
+    }
+
+    pub async fn logout(&self, token: &str) -> Result<(), DatabaseError> {
+        match self.sessions.delete(&hash(token)).await {
+            Ok(()) | Err(DatabaseError::NotFound) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn end_sessions(&self, username: &str) -> Result<(), DatabaseError> {
+        for (key, session) in self.sessions.list().await? {
+            if session.username == username {
+                self.sessions.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> Result<(), DatabaseError> {
+        let now = time::now();
+        for (key, session) in self.sessions.list().await? {
+            if session.expires_at <= now {
+                self.sessions.delete(&key).await?;
+            }
+        }
+        Ok(())
+    }
+}
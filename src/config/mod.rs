@@ -0,0 +1,491 @@
+use std::{
+    env,
+    fmt::Display,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use thiserror::Error;
+use toml::{Table, Value};
+
+/// Config file read when `FEATHERMAIL_CONFIG` is not set, it is optional
+const DEFAULT_PATH: &str = "feathermail.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Could not read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Could not parse {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("Invalid configuration:\n{}", .0.iter().map(|error| format!("  - {}", error)).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Port for implicit TLS, only used when TLS is configured
+    pub tls_port: Option<u16>,
+    /// Domain announced in the greeting
+    pub domain: String,
+    /// Largest message accepted in bytes
+    pub max_message_size: usize,
+    /// Recipients accepted per message
+    pub max_recipients: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImapConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Port for implicit TLS, only used when TLS is configured
+    pub tls_port: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Seconds a user session stays valid after login
+    pub session_lifetime: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub fullchain: PathBuf,
+    pub privkey: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WebhookConfig {
+    pub url: Option<String>,
+    pub secret: Option<String>,
+}
+
+/// How the connection to the relay is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayTls {
+    /// Upgrade a plain connection, failing if the relay does not offer it
+    StartTls,
+    /// TLS from the first byte, usually on port 465
+    Tls,
+    /// Plain text, only sensible for a relay on the same host
+    None,
+}
+
+impl FromStr for RelayTls {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "starttls" => Ok(RelayTls::StartTls),
+            "tls" => Ok(RelayTls::Tls),
+            "none" => Ok(RelayTls::None),
+            _ => Err(format!("'{}' is not one of starttls, tls or none", value)),
+        }
+    }
+}
+
+/// Smarthost outbound mail is handed to
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: RelayTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfig {
+    /// Queried instead of the system resolver, e.g. a local stub in tests
+    pub nameserver: Option<SocketAddr>,
+    /// TOML file answering every query instead of the network, see `dns::Fixtures`
+    pub fixtures: Option<PathBuf>,
+}
+
+/// Validated settings, read from the config file and overridden by environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_path: PathBuf,
+    pub smtp: SmtpConfig,
+    pub imap: ImapConfig,
+    pub api: ApiConfig,
+    pub tls: Option<TlsConfig>,
+    pub webhook: WebhookConfig,
+    /// Without a relay outbound mail goes straight to the recipients' mail exchangers
+    pub relay: Option<RelayConfig>,
+    pub dns: DnsConfig,
+}
+
+/// Values of the config file and the environment, collecting every invalid one
+struct Source {
+    table: Table,
+    errors: Vec<String>,
+}
+
+impl Source {
+    /// Takes a value from the environment, or else from the file under its dotted key
+    fn value<T>(&mut self, key: &str, variables: &[&str]) -> Option<T>
+    where
+        T: FromStr + DeserializeOwned,
+        T::Err: Display,
+    {
+        let from_file = self.take(key);
+        let from_env = variables.iter().find_map(|variable| {
+            let value = env::var(variable).ok().filter(|value| !value.is_empty())?;
+            Some((variable, value))
+        });
+
+        if let Some((variable, value)) = from_env {
+            return match value.parse() {
+                Ok(value) => Some(value),
+                Err(error) => {
+                    self.errors.push(format!("{} (from {}): {}", key, variable, error));
+                    None
+                }
+            };
+        }
+        match from_file?.try_into() {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.errors.push(format!("{}: {}", key, error.to_string().trim_end()));
+                None
+            }
+        }
+    }
+
+    /// Removes a value from the file, so whatever is left over is unknown
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let (section, name) = match key.split_once('.') {
+            Some((section, name)) => (Some(section), name),
+            None => (None, key),
+        };
+        let table = match section {
+            Some(section) => match self.table.get_mut(section) {
+                Some(Value::Table(table)) => table,
+                Some(_) => {
+                    self.errors.push(format!("{}: expected a table", section));
+                    self.table.remove(section);
+                    return None;
+                }
+                None => return None,
+            },
+            None => &mut self.table,
+        };
+        table.remove(name)
+    }
+
+    /// Reports every key that no setting consumed, most likely a typo
+    fn unknown(&mut self) {
+        for (key, value) in std::mem::take(&mut self.table) {
+            match value {
+                Value::Table(table) => self
+                    .errors
+                    .extend(table.keys().map(|name| format!("{}.{}: unknown field", key, name))),
+                _ => self.errors.push(format!("{}: unknown field", key)),
+            }
+        }
+    }
+}
+
+/// Reads the file at `path`, a missing default file simply means there is none
+fn read_table(path: &Path, explicit: bool) -> Result<Table, ConfigError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if !explicit && error.kind() == io::ErrorKind::NotFound => return Ok(Table::new()),
+        Err(source) => {
+            return Err(ConfigError::Read {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+    contents.parse().map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+impl Config {
+    /// Loads the file named by `FEATHERMAIL_CONFIG`, or `feathermail.toml` when present,
+    /// and applies the environment on top of it
+    pub fn load() -> Result<Self, ConfigError> {
+        let explicit = env::var("FEATHERMAIL_CONFIG").ok().filter(|path| !path.is_empty());
+        let path = PathBuf::from(explicit.as_deref().unwrap_or(DEFAULT_PATH));
+        let table = read_table(&path, explicit.is_some())?;
+        if !table.is_empty() {
+            log::info!("Loaded configuration from {}", path.display());
+        }
+        Config::from_table(table)
+    }
+
+    /// Applies the environment on top of the values of a config file and validates the result
+    fn from_table(table: Table) -> Result<Self, ConfigError> {
+        let mut source = Source {
+            table,
+            errors: Vec::new(),
+        };
+        let config = Config::from_source(&mut source);
+        source.unknown();
+        config.validate(&mut source.errors);
+
+        match source.errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError::Invalid(source.errors)),
+        }
+    }
+
+    fn from_source(source: &mut Source) -> Self {
+        let database_path = source
+            .value("database_path", &["DATABASE_PATH"])
+            .unwrap_or_else(|| PathBuf::from("feathermail.db"));
+
+        let smtp = SmtpConfig {
+            bind_address: source
+                .value("smtp.bind_address", &["SMTP_BIND_ADDRESS", "BIND_ADDRESS"])
+                .unwrap_or_else(|| "localhost".to_string()),
+            port: source.value("smtp.port", &["SMTP_PORT"]).unwrap_or(25),
+            tls_port: source.value("smtp.tls_port", &["SMTPS_PORT"]),
+            domain: source
+                .value("smtp.domain", &["DOMAIN"])
+                .unwrap_or_else(|| "localhost".to_string()),
+            max_message_size: source
+                .value("smtp.max_message_size", &["SMTP_MAX_MESSAGE_SIZE"])
+                .unwrap_or(25 * 1024 * 1024),
+            max_recipients: source
+                .value("smtp.max_recipients", &["SMTP_MAX_RECIPIENTS"])
+                .unwrap_or(100),
+        };
+
+        let imap = ImapConfig {
+            bind_address: source
+                .value("imap.bind_address", &["IMAP_BIND_ADDRESS", "BIND_ADDRESS"])
+                .unwrap_or_else(|| "localhost".to_string()),
+            port: source.value("imap.port", &["IMAP_PORT"]).unwrap_or(143),
+            tls_port: source.value("imap.tls_port", &["IMAPS_PORT"]),
+        };
+
+        let api = ApiConfig {
+            bind_address: source
+                .value("api.bind_address", &["API_BIND_ADDRESS", "BIND_ADDRESS"])
+                .unwrap_or_else(|| "localhost".to_string()),
+            port: source.value("api.port", &["API_PORT"]).unwrap_or(8080),
+            session_lifetime: source
+                .value("api.session_lifetime", &["SESSION_LIFETIME"])
+                .unwrap_or(24 * 60 * 60),
+        };
+
+        let fullchain = source.value::<PathBuf>("tls.fullchain", &["SSL_FULLCHAIN"]);
+        let privkey = source.value::<PathBuf>("tls.privkey", &["SSL_PRIVKEY"]);
+        let tls = match (fullchain, privkey) {
+            (Some(fullchain), Some(privkey)) => Some(TlsConfig { fullchain, privkey }),
+            (None, None) => None,
+            // A broken certificate setup is refused instead of silently running without TLS
+            _ => {
+                source
+                    .errors
+                    .push("tls.fullchain and tls.privkey must be set together".to_string());
+                None
+            }
+        };
+
+        let webhook = WebhookConfig {
+            url: source.value("webhook.url", &["WEBHOOK_URL"]),
+            secret: source.value("webhook.secret", &["WEBHOOK_SECRET"]),
+        };
+
+        let host = source.value::<String>("relay.host", &["RELAY_HOST"]);
+        let port = source.value("relay.port", &["RELAY_PORT"]);
+        let security = source.value("relay.tls", &["RELAY_TLS"]);
+        let username = source.value("relay.username", &["RELAY_USERNAME"]);
+        let password = source.value("relay.password", &["RELAY_PASSWORD"]);
+        if username.is_some() != password.is_some() {
+            source
+                .errors
+                .push("relay.username and relay.password must be set together".to_string());
+        }
+        let relay = match host {
+            Some(host) => Some(RelayConfig {
+                host,
+                port: port.unwrap_or(587),
+                tls: security.unwrap_or(RelayTls::StartTls),
+                username,
+                password,
+            }),
+            None => {
+                if port.is_some() || security.is_some() || username.is_some() {
+                    source
+                        .errors
+                        .push("relay.host is required when the relay is configured".to_string());
+                }
+                None
+            }
+        };
+
+        let dns = DnsConfig {
+            nameserver: source.value("dns.nameserver", &["DNS_NAMESERVER"]),
+            fixtures: source.value("dns.fixtures", &["DNS_FIXTURES"]),
+        };
+
+        Config {
+            database_path,
+            smtp,
+            imap,
+            api,
+            tls,
+            webhook,
+            relay,
+            dns,
+        }
+    }
+
+    /// Checks the values that parsed but make no sense
+    fn validate(&self, errors: &mut Vec<String>) {
+        let ports = [
+            ("smtp.port", &self.smtp.bind_address, Some(self.smtp.port)),
+            ("smtp.tls_port", &self.smtp.bind_address, self.smtp.tls_port),
+            ("imap.port", &self.imap.bind_address, Some(self.imap.port)),
+            ("imap.tls_port", &self.imap.bind_address, self.imap.tls_port),
+            ("api.port", &self.api.bind_address, Some(self.api.port)),
+        ];
+        for (index, (key, address, port)) in ports.iter().enumerate() {
+            let Some(port) = port else {
+                continue;
+            };
+            if *port == 0 {
+                errors.push(format!("{}: must not be 0", key));
+                continue;
+            }
+            let taken = ports[..index]
+                .iter()
+                .find(|(_, other_address, other_port)| other_address == address && *other_port == Some(*port));
+            if let Some((other, _, _)) = taken {
+                errors.push(format!("{}: already used by {}", key, other));
+            }
+        }
+
+        if self.smtp.domain.is_empty() || self.smtp.domain.contains(char::is_whitespace) {
+            errors.push(format!("smtp.domain: '{}' is not a domain name", self.smtp.domain));
+        }
+        if self.smtp.max_message_size == 0 {
+            errors.push("smtp.max_message_size: must be greater than 0".to_string());
+        }
+        if self.api.session_lifetime <= 0 {
+            errors.push("api.session_lifetime: must be greater than 0".to_string());
+        }
+        if self.smtp.max_recipients == 0 {
+            errors.push("smtp.max_recipients: must be greater than 0".to_string());
+        }
+
+        if self.relay.as_ref().is_some_and(|relay| relay.port == 0) {
+            errors.push("relay.port: must not be 0".to_string());
+        }
+        if self.dns.nameserver.is_some() && self.dns.fixtures.is_some() {
+            errors.push("dns.fixtures: cannot be used together with dns.nameserver".to_string());
+        }
+
+        if let Some(url) = &self.webhook.url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                errors.push(format!("webhook.url: '{}' is not an http or https url", url));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(contents: &str) -> Vec<String> {
+        match Config::from_table(contents.parse().unwrap()) {
+            Ok(config) => panic!("accepted {:?}", config),
+            Err(ConfigError::Invalid(errors)) => errors,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    #[test]
+    fn lists_every_invalid_field() {
+        let errors = errors(
+            r#"
+            [smtp]
+            port = "twenty-five"
+            colour = "blue"
+
+            [imap]
+            port = 25
+
+            [api]
+            session_lifetime = 0
+
+            [tls]
+            fullchain = "/etc/feathermail/fullchain.pem"
+
+            [webhook]
+            url = "ftp://example.com/hook"
+
+            [relay]
+            username = "feathermail"
+            "#,
+        );
+        let expected = [
+            "smtp.port: ",
+            "tls.fullchain and tls.privkey must be set together",
+            "relay.username and relay.password must be set together",
+            "relay.host is required when the relay is configured",
+            "smtp.colour: unknown field",
+            // The invalid SMTP port leaves the default of 25 in place
+            "imap.port: already used by smtp.port",
+            "api.session_lifetime: must be greater than 0",
+            "webhook.url: 'ftp://example.com/hook' is not an http or https url",
+        ];
+        assert_eq!(errors.len(), expected.len(), "{:?}", errors);
+        for (error, expected) in errors.iter().zip(expected) {
+            assert!(error.starts_with(expected), "{} is not {}", error, expected);
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        // Variables no other test reads, the environment is shared by every test
+        env::set_var("DATABASE_PATH", "/var/lib/feathermail.db");
+        env::set_var("WEBHOOK_SECRET", "");
+        let config = Config::from_table(
+            r#"
+            database_path = "feathermail.db"
+
+            [webhook]
+            secret = "from the file"
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(config.database_path, PathBuf::from("/var/lib/feathermail.db"));
+        // Empty variables count as unset
+        assert_eq!(config.webhook.secret.as_deref(), Some("from the file"));
+
+        env::set_var("SMTP_MAX_RECIPIENTS", "many");
+        let errors = errors("[smtp]\nmax_recipients = 10\nmystery = 1\n");
+        env::remove_var("DATABASE_PATH");
+        env::remove_var("WEBHOOK_SECRET");
+        env::remove_var("SMTP_MAX_RECIPIENTS");
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("smtp.max_recipients (from SMTP_MAX_RECIPIENTS): "));
+        assert_eq!(errors[1], "smtp.mystery: unknown field");
+    }
+}
@@ -1,9 +1,11 @@
-use std::io;
+mod store;
 
-use serde::de::DeserializeOwned;
-use sled::Tree;
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{transaction::TransactionError, Db, Tree};
 use thiserror::Error;
 
+pub use store::Store;
+
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("No matches found")]
@@ -13,17 +15,28 @@ pub enum DatabaseError {
     #[error("Could not set to database")]
     Set,
     #[error("Could not communicate with database")]
+    #[allow(dead_code)]
     Communicate,
     #[error("Could not deserialize binary data")]
     Deserialize,
     #[error("Could not serialize binary data")]
     Serialize,
     #[error("Could not delete from database")]
+    #[allow(dead_code)]
     NoDelete,
     #[error("Database internal error: {0}")]
     SledError(#[from] sled::Error),
 }
 
+impl From<TransactionError> for DatabaseError {
+    fn from(error: TransactionError) -> Self {
+        match error {
+            TransactionError::Abort(error) | TransactionError::Storage(error) => {
+                DatabaseError::SledError(error)
+            }
+        }
+    }
+}
 
 /// Retrieve a value by key from a tree.
 async fn get_from_tree(db: &Tree, key: &str) -> Result<Vec<u8>, DatabaseError> {
@@ -67,7 +80,7 @@ pub async fn get_last<T>(tree: &sled::Tree) -> Result<(String, T), DatabaseError
 }
 
 /// Wrapper for retrieving all key value pairs from a tree
-pub async fn get_all<T>(tree: &sled::Tree) -> Result<Vec<(Vec<u8>, T)>, DatabaseError> where T: DeserializeOwned {
+pub async fn get_all<T>(tree: &sled::Tree) -> Result<Vec<(String, T)>, DatabaseError> where T: DeserializeOwned {
     let binary_data = get_all_from_tree(tree).await?;
     let mut all = Vec::with_capacity(binary_data.len());
     for (binary_key, binary_value) in binary_data {
@@ -109,12 +122,17 @@ async fn set_to_tree(db: &Tree, key: &str, bin: Vec<u8>) -> Result<(), DatabaseE
 }
 
 /// Wrapper for setting a value to a tree
-pub async fn set(tree: &Tree, key: &str, data: &Invoice) -> Result<(), Box<Error>> {
-    let binary_data = bincode::serialize::<Invoice>(data)?;
-    set_to_tree(tree, key, binary_data)
-        .await
-        .map_err(|_| DatabaseError::Communicate)?;
-    Ok(())
+pub async fn set<T>(tree: &Tree, key: &str, data: &T) -> Result<(), DatabaseError> where T: Serialize {
+    let binary_data = bincode::serialize::<T>(data).map_err(|error| {
+        log::error!("Db Interaction Error: {}", error);
+        DatabaseError::Serialize
+    })?;
+    set_to_tree(tree, key, binary_data).await
+}
+
+/// Generates a unique key that sorts in insertion order
+pub fn generate_key(db: &Db) -> Result<String, DatabaseError> {
+    Ok(format!("{:020}", db.generate_id()?))
 }
 
 /// Used to delete from a tree
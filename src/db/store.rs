@@ -0,0 +1,97 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{Db, Tree};
+
+use super::DatabaseError;
+
+/// Typed repository over a named sled tree
+pub struct Store<T> {
+    tree: Tree,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Store<T> {
+    fn clone(&self) -> Self {
+        Store {
+            tree: self.tree.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Store<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Opens the tree with the given name, creating it if needed
+    pub fn open(db: &Db, name: &str) -> Result<Self, DatabaseError> {
+        Ok(Store {
+            tree: db.open_tree(name)?,
+            marker: PhantomData,
+        })
+    }
+
+    /// Retrieve a value by key
+    pub async fn get(&self, key: &str) -> Result<T, DatabaseError> {
+        super::get(&self.tree, key).await
+    }
+
+    /// Set a value under a key, replacing any previous value
+    pub async fn set(&self, key: &str, value: &T) -> Result<(), DatabaseError> {
+        super::set(&self.tree, key, value).await
+    }
+
+    /// Delete a value by key
+    pub async fn delete(&self, key: &str) -> Result<(), DatabaseError> {
+        super::delete(&self.tree, key).await
+    }
+
+    /// Retrieve all key value pairs in key order
+    pub async fn list(&self) -> Result<Vec<(String, T)>, DatabaseError> {
+        super::get_all(&self.tree).await
+    }
+
+    /// Retrieve the entry with the greatest key
+    #[allow(dead_code)]
+    pub async fn last(&self) -> Result<(String, T), DatabaseError> {
+        super::get_last(&self.tree).await
+    }
+
+    /// The underlying tree, for operations the store does not cover
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store<u32> {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        Store::open(&database, "numbers").unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_typed_values() {
+        let store = store();
+        store.set("b", &2).await.unwrap();
+        store.set("a", &1).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), 1);
+        assert_eq!(store.list().await.unwrap(), [("a".to_string(), 1), ("b".to_string(), 2)]);
+        store.delete("a").await.unwrap();
+        assert!(matches!(store.get("a").await, Err(DatabaseError::NotFound)));
+        assert!(matches!(store.delete("a").await, Err(DatabaseError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn last_is_the_greatest_key() {
+        let store = store();
+        assert!(matches!(store.last().await, Err(DatabaseError::NotFound)));
+        for (key, value) in [("00000000000000000002", 2), ("00000000000000000010", 10), ("00000000000000000001", 1)] {
+            store.set(key, &value).await.unwrap();
+        }
+        assert_eq!(store.last().await.unwrap(), ("00000000000000000010".to_string(), 10));
+    }
+}
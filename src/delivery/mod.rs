@@ -0,0 +1,100 @@
+use crate::{
+    db::DatabaseError,
+    message::{Message, MessageStore},
+    routing::{Resolution, Route, Router},
+    webhook::Webhook,
+};
+
+/// Stores mail for the hosted domains, whether it came in over SMTP or was generated here
+#[derive(Clone)]
+pub struct LocalDelivery {
+    messages: MessageStore,
+    router: Router,
+    webhook: Webhook,
+}
+
+impl LocalDelivery {
+    pub fn new(messages: MessageStore, router: Router, webhook: Webhook) -> Self {
+        LocalDelivery {
+            messages,
+            router,
+            webhook,
+        }
+    }
+
+    /// Persists a parsed message, one copy per mailbox the envelope recipients lead to.
+    /// Returns the id of the first copy, `None` when not every copy could be stored.
+    pub async fn deliver(&self, message: Message, raw: &[u8], attachments: &[Vec<u8>]) -> Option<String> {
+        // Each mailbox with the envelope recipients that lead to it
+        let mut routes: Vec<(Route, Vec<String>)> = Vec::new();
+        for recipient in &message.envelope.rcpt_to {
+            match self.router.resolve(recipient).await {
+                Ok(Resolution::Deliver(resolved)) => {
+                    for route in resolved {
+                        // One copy per mailbox, keeping a tag if any recipient carried one
+                        match routes.iter_mut().find(|(known, _)| known.mailbox == route.mailbox) {
+                            Some((known, recipients)) => {
+                                known.tag = known.tag.take().or(route.tag);
+                                if !recipients.contains(recipient) {
+                                    recipients.push(recipient.clone());
+                                }
+                            }
+                            None => routes.push((route, vec![recipient.clone()])),
+                        }
+                    }
+                }
+                // Routing changed since RCPT, the remaining recipients still get their copy
+                Ok(_) => log::warn!("Recipient {} no longer resolves, skipping it", recipient),
+                Err(error) => {
+                    log::error!("Could not look up recipient {}: {}", recipient, error);
+                    return None;
+                }
+            }
+        }
+
+        // Every copy is stored before any is announced, so a failure can take them all back
+        let mut stored: Vec<(String, Message)> = Vec::new();
+        for (route, recipients) in routes {
+            // A copy only names its own recipients, the others may have been Bcc
+            let mut envelope = message.envelope.clone();
+            envelope.rcpt_to = recipients;
+            let message = Message {
+                mailbox: route.mailbox,
+                tag: route.tag,
+                envelope,
+                ..message.clone()
+            };
+            match self.messages.insert(&message, raw, attachments).await {
+                Ok(id) => stored.push((id, message)),
+                Err(error) => {
+                    log::error!(
+                        "Could not store message from {}: {}",
+                        message.envelope.mail_from,
+                        error
+                    );
+                    // The sender retries after a temporary failure, which must not duplicate the copies kept so far
+                    for (id, _) in stored {
+                        if let Err(error) = self.messages.delete(&id).await {
+                            log::error!("Could not remove partially delivered message {}: {}", id, error);
+                        }
+                    }
+                    return None;
+                }
+            }
+        }
+
+        for (id, message) in &stored {
+            log::info!("Stored message {} from {}", id, message.envelope.mail_from);
+            // The message is safe at this point, a lost notification must not bounce it
+            if let Err(error) = self.webhook.enqueue(id, message).await {
+                log::error!("Could not queue webhook for message {}: {}", id, error);
+            }
+        }
+        stored.into_iter().next().map(|(id, _)| id)
+    }
+
+    /// Stores a message in the mailbox and folder it names, without routing or announcing it
+    pub async fn file(&self, message: &Message, raw: &[u8], attachments: &[Vec<u8>]) -> Result<String, DatabaseError> {
+        self.messages.insert(message, raw, attachments).await
+    }
+}
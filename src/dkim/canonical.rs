@@ -0,0 +1,177 @@
+/// A header field as it appears in the message, continuation lines included
+pub struct Field<'a> {
+    pub name: String,
+    pub raw: &'a [u8],
+}
+
+fn strip_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Replaces every run of spaces and tabs with a single space
+fn collapse_whitespace(bytes: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b' ' | b'\t' if collapsed.last() == Some(&b' ') => {}
+            b' ' | b'\t' => collapsed.push(b' '),
+            _ => collapsed.push(byte),
+        }
+    }
+    collapsed
+}
+
+/// Splits a message into its header fields and its body, accepting CRLF and bare LF line endings
+pub fn split(message: &[u8]) -> (Vec<Field<'_>>, &[u8]) {
+    // Start and end offset of every field
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut offset = 0;
+    for line in message.split_inclusive(|&byte| byte == b'\n') {
+        let start = offset;
+        offset += line.len();
+        if strip_newline(line).is_empty() {
+            break;
+        }
+        match (line.first(), spans.last_mut()) {
+            // A continuation line extends the previous field
+            (Some(b' ' | b'\t'), Some(span)) => span.1 = offset,
+            _ => spans.push((start, offset)),
+        }
+    }
+
+    let fields = spans
+        .into_iter()
+        .map(|(start, end)| {
+            let raw = &message[start..end];
+            let colon = raw.iter().position(|&byte| byte == b':').unwrap_or(raw.len());
+            Field {
+                name: String::from_utf8_lossy(&raw[..colon]).trim().to_ascii_lowercase(),
+                raw,
+            }
+        })
+        .collect();
+    (fields, &message[offset..])
+}
+
+/// The relaxed header canonicalization of RFC 6376 section 3.4.2, ending in CRLF
+pub fn relaxed_header(raw: &[u8]) -> Vec<u8> {
+    let colon = raw.iter().position(|&byte| byte == b':').unwrap_or(raw.len());
+    let name = String::from_utf8_lossy(&raw[..colon]).trim().to_ascii_lowercase();
+    let value = raw
+        .get(colon + 1..)
+        .unwrap_or_default()
+        .iter()
+        .copied()
+        .filter(|&byte| byte != b'\r' && byte != b'\n')
+        .collect::<Vec<_>>();
+    let value = collapse_whitespace(&value);
+    let value = value.trim_ascii();
+
+    let mut canonical = Vec::with_capacity(name.len() + value.len() + 3);
+    canonical.extend_from_slice(name.as_bytes());
+    canonical.push(b':');
+    canonical.extend_from_slice(value);
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+/// The relaxed body canonicalization of RFC 6376 section 3.4.4
+pub fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut lines = body
+        .split_inclusive(|&byte| byte == b'\n')
+        .map(|line| {
+            let mut line = collapse_whitespace(strip_newline(line));
+            if line.last() == Some(&b' ') {
+                line.pop();
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    let mut canonical = Vec::with_capacity(body.len());
+    for line in lines {
+        canonical.extend_from_slice(&line);
+        canonical.extend_from_slice(b"\r\n");
+    }
+    canonical
+}
+
+/// Line endings as sent over SMTP, bare LF becomes CRLF
+fn crlf(line: &[u8]) -> Vec<u8> {
+    let mut line = strip_newline(line).to_vec();
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+/// The simple header canonicalization of RFC 6376 section 3.4.1, the field as it is
+pub fn simple_header(raw: &[u8]) -> Vec<u8> {
+    raw.split_inclusive(|&byte| byte == b'\n').flat_map(crlf).collect()
+}
+
+/// The simple body canonicalization of RFC 6376 section 3.4.3, an empty body is a single CRLF
+pub fn simple_body(body: &[u8]) -> Vec<u8> {
+    let mut lines = body.split_inclusive(|&byte| byte == b'\n').map(crlf).collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line == b"\r\n") {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return b"\r\n".to_vec();
+    }
+    lines.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_folded_fields_from_the_body() {
+        let message = b"From: ann@example.org\r\nSubject: long\r\n\tsubject\r\nTo: bob@example.com\r\n\r\nBody\r\n";
+        let (fields, body) = split(message);
+        let names = fields.iter().map(|field| field.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["from", "subject", "to"]);
+        assert_eq!(fields[1].raw, b"Subject: long\r\n\tsubject\r\n");
+        assert_eq!(body, b"Body\r\n");
+
+        let (fields, body) = split(b"Subject: bare\n\nBody\n");
+        assert_eq!(fields[0].raw, b"Subject: bare\n");
+        assert_eq!(body, b"Body\n");
+    }
+
+    // The examples of RFC 6376 section 3.4.5
+    const HEADER: &[&[u8]] = &[b"A: X\r\n", b"B : Y\t\r\n\tZ  \r\n"];
+    const BODY: &[u8] = b" C \r\nD \t E\r\n\r\n\r\n";
+
+    #[test]
+    fn relaxed_matches_the_rfc_example() {
+        let header = HEADER.iter().flat_map(|field| relaxed_header(field)).collect::<Vec<_>>();
+        assert_eq!(header, b"a:X\r\nb:Y Z\r\n");
+        assert_eq!(relaxed_body(BODY), b" C\r\nD E\r\n");
+    }
+
+    #[test]
+    fn simple_matches_the_rfc_example() {
+        let header = HEADER.iter().flat_map(|field| simple_header(field)).collect::<Vec<_>>();
+        assert_eq!(header, b"A: X\r\nB : Y\t\r\n\tZ  \r\n");
+        assert_eq!(simple_body(BODY), b" C \r\nD \t E\r\n");
+    }
+
+    #[test]
+    fn canonicalizes_empty_bodies() {
+        assert_eq!(simple_body(b""), b"\r\n");
+        assert_eq!(simple_body(b"\r\n\r\n"), b"\r\n");
+        assert_eq!(relaxed_body(b""), b"");
+        assert_eq!(relaxed_body(b" \r\n\r\n"), b"");
+    }
+
+    #[test]
+    fn turns_bare_newlines_into_crlf() {
+        assert_eq!(simple_header(b"Subject: a\n b\n"), b"Subject: a\r\n b\r\n");
+        assert_eq!(simple_body(b"one\ntwo\n"), b"one\r\ntwo\r\n");
+        assert_eq!(relaxed_body(b"one  \ntwo\n"), b"one\r\ntwo\r\n");
+    }
+}
@@ -0,0 +1,338 @@
+pub mod canonical;
+mod verify;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents, UnparsedPublicKey, ED25519,
+        RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, RSA_PKCS1_SHA256,
+    },
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use thiserror::Error;
+
+use crate::{
+    db::{DatabaseError, Store},
+    domain::Domains,
+    time,
+};
+pub use verify::verify;
+
+/// Headers signed whenever the message has them
+const SIGNED_HEADERS: &[&str] = &[
+    "from",
+    "reply-to",
+    "subject",
+    "date",
+    "to",
+    "cc",
+    "message-id",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+const DEFAULT_RSA_BITS: usize = 2048;
+/// Smaller keys are refused by receivers, larger ones rarely fit into a TXT record
+const RSA_BITS: std::ops::RangeInclusive<usize> = 2048..=4096;
+
+#[derive(Error, Debug)]
+pub enum DkimError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Domain {0} is not hosted")]
+    UnknownDomain(String),
+    #[error("Selector {0} already exists")]
+    Exists(String),
+    #[error("Could not generate key: {0}")]
+    Generate(String),
+    #[error("Could not sign with selector {selector} of {domain}: {reason}")]
+    Sign {
+        domain: String,
+        selector: String,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Rsa,
+    Ed25519,
+}
+
+impl Algorithm {
+    /// Value of the `a=` tag
+    fn signature_tag(self) -> &'static str {
+        match self {
+            Algorithm::Rsa => "rsa-sha256",
+            Algorithm::Ed25519 => "ed25519-sha256",
+        }
+    }
+
+    /// Value of the `k=` tag in DNS
+    fn key_type(self) -> &'static str {
+        match self {
+            Algorithm::Rsa => "rsa",
+            Algorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    /// Checks a signature over canonicalized data, fails when the published key is unusable
+    fn verify(self, public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, String> {
+        match self {
+            Algorithm::Rsa => {
+                // Keys are published as SubjectPublicKeyInfo, a few senders use bare PKCS#1
+                let key = RsaPublicKey::from_public_key_der(public_key)
+                    .or_else(|_| RsaPublicKey::from_pkcs1_der(public_key))
+                    .map_err(|_| "invalid RSA key".to_string())?;
+                let key = RsaPublicKeyComponents {
+                    n: key.n().to_bytes_be(),
+                    e: key.e().to_bytes_be(),
+                };
+                // RFC 8301 still has verifiers accept 1024 bit keys
+                Ok(key
+                    .verify(&RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, data, signature)
+                    .is_ok())
+            }
+            Algorithm::Ed25519 => {
+                if public_key.len() != 32 {
+                    return Err("invalid Ed25519 key".to_string());
+                }
+                Ok(UnparsedPublicKey::new(&ED25519, public_key)
+                    .verify(&Sha256::digest(data), signature)
+                    .is_ok())
+            }
+        }
+    }
+}
+
+/// A signing key of a domain, the private half never leaves the database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DkimKey {
+    pub algorithm: Algorithm,
+    pub created_at: i64,
+    /// PKCS#8 DER
+    private_key: Vec<u8>,
+    /// As published in DNS: SubjectPublicKeyInfo DER for RSA, the bare key for Ed25519
+    public_key: Vec<u8>,
+}
+
+/// The TXT record receivers look the public key up in
+#[derive(Serialize, Debug, Clone)]
+pub struct DnsRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub value: String,
+}
+
+impl DkimKey {
+    pub fn dns_record(&self, domain: &str, selector: &str) -> DnsRecord {
+        DnsRecord {
+            name: format!("{}._domainkey.{}", selector, domain),
+            kind: "TXT",
+            value: format!(
+                "v=DKIM1; k={}; p={}",
+                self.algorithm.key_type(),
+                STANDARD.encode(&self.public_key)
+            ),
+        }
+    }
+
+    /// Signs already canonicalized data, returning the raw signature
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self.algorithm {
+            Algorithm::Rsa => {
+                let key = RsaKeyPair::from_pkcs8(&self.private_key).map_err(|error| error.to_string())?;
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), data, &mut signature)
+                    .map_err(|error| error.to_string())?;
+                Ok(signature)
+            }
+            // RFC 8463 signs the SHA-256 hash rather than the data itself
+            Algorithm::Ed25519 => {
+                let key = Ed25519KeyPair::from_pkcs8(&self.private_key).map_err(|error| error.to_string())?;
+                Ok(key.sign(&Sha256::digest(data)).as_ref().to_vec())
+            }
+        }
+    }
+}
+
+/// Selectors end up as a DNS label
+fn validate_selector(selector: &str) -> Result<String, DkimError> {
+    let selector = selector.trim().to_lowercase();
+    let valid = !selector.is_empty()
+        && selector.len() <= 63
+        && !selector.starts_with('-')
+        && !selector.ends_with('-')
+        && selector.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    match valid {
+        true => Ok(selector),
+        false => Err(DkimError::Invalid(format!("Invalid selector '{}'", selector))),
+    }
+}
+
+/// RSA key generation takes a while, so it runs off the async workers
+async fn generate(algorithm: Algorithm, bits: usize) -> Result<(Vec<u8>, Vec<u8>), DkimError> {
+    tokio::task::spawn_blocking(move || match algorithm {
+        Algorithm::Rsa => {
+            let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, bits).map_err(|error| error.to_string())?;
+            let private_key = key.to_pkcs8_der().map_err(|error| error.to_string())?;
+            let public_key = key
+                .to_public_key()
+                .to_public_key_der()
+                .map_err(|error| error.to_string())?;
+            Ok((private_key.as_bytes().to_vec(), public_key.as_bytes().to_vec()))
+        }
+        Algorithm::Ed25519 => {
+            let private_key = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|error| error.to_string())?;
+            let key = Ed25519KeyPair::from_pkcs8(private_key.as_ref()).map_err(|error| error.to_string())?;
+            Ok((private_key.as_ref().to_vec(), key.public_key().as_ref().to_vec()))
+        }
+    })
+    .await
+    .map_err(|error| DkimError::Generate(error.to_string()))?
+    .map_err(DkimError::Generate)
+}
+
+/// DKIM keys of the hosted domains, stored under `domain/selector`
+#[derive(Clone)]
+pub struct DkimKeys {
+    keys: Store<DkimKey>,
+    domains: Domains,
+}
+
+impl DkimKeys {
+    pub fn open(database: &Db, domains: Domains) -> Result<Self, DatabaseError> {
+        Ok(DkimKeys {
+            keys: Store::open(database, "dkim_keys")?,
+            domains,
+        })
+    }
+
+    /// Keys of a domain with their selectors
+    pub async fn list(&self, domain: &str) -> Result<Vec<(String, DkimKey)>, DatabaseError> {
+        let prefix = format!("{}/", domain.to_lowercase());
+        Ok(self
+            .keys
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|(key, dkim)| Some((key.strip_prefix(&prefix)?.to_string(), dkim)))
+            .collect())
+    }
+
+    /// Creates a key for a hosted domain. Without a selector one is derived from the algorithm and time.
+    pub async fn generate(
+        &self,
+        domain: &str,
+        selector: Option<String>,
+        algorithm: Algorithm,
+        bits: Option<usize>,
+    ) -> Result<(String, DkimKey), DkimError> {
+        let domain = domain.to_lowercase();
+        if !self.domains.contains(&domain).await? {
+            return Err(DkimError::UnknownDomain(domain));
+        }
+        let selector = match selector {
+            Some(selector) => validate_selector(&selector)?,
+            None => format!("{}-{}", algorithm.key_type(), time::now()),
+        };
+        let bits = bits.unwrap_or(DEFAULT_RSA_BITS);
+        if algorithm == Algorithm::Rsa && !RSA_BITS.contains(&bits) {
+            return Err(DkimError::Invalid(format!(
+                "RSA keys need between {} and {} bits",
+                RSA_BITS.start(),
+                RSA_BITS.end()
+            )));
+        }
+        let id = format!("{}/{}", domain, selector);
+        if self.keys.tree().contains_key(&id).map_err(DatabaseError::from)? {
+            return Err(DkimError::Exists(selector));
+        }
+
+        let (private_key, public_key) = generate(algorithm, bits).await?;
+        let key = DkimKey {
+            algorithm,
+            created_at: time::now(),
+            private_key,
+            public_key,
+        };
+        self.keys.set(&id, &key).await?;
+        Ok((selector, key))
+    }
+
+    pub async fn remove(&self, domain: &str, selector: &str) -> Result<(), DatabaseError> {
+        self.keys
+            .delete(&format!("{}/{}", domain.to_lowercase(), selector.to_lowercase()))
+            .await
+    }
+
+    /// Prepends a `DKIM-Signature` for every key of the domain, unchanged without keys
+    pub async fn sign(&self, domain: &str, message: &[u8]) -> Result<Vec<u8>, DkimError> {
+        let keys = self.list(domain).await?;
+        if keys.is_empty() {
+            return Ok(message.to_vec());
+        }
+
+        let (fields, body) = canonical::split(message);
+        let body_hash = STANDARD.encode(Sha256::digest(canonical::relaxed_body(body)));
+        // Fields are picked from the bottom up when a header occurs more than once
+        let mut names = Vec::new();
+        let mut headers = Vec::new();
+        for name in SIGNED_HEADERS {
+            for field in fields.iter().rev().filter(|field| field.name == *name) {
+                names.push(*name);
+                headers.extend(canonical::relaxed_header(field.raw));
+            }
+        }
+
+        let mut signed = Vec::with_capacity(message.len() + keys.len() * 512);
+        for (selector, key) in keys {
+            let mut header = format!(
+                "DKIM-Signature: v=1; a={}; c=relaxed/relaxed; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+                key.algorithm.signature_tag(),
+                domain,
+                selector,
+                time::now(),
+                names.join(":"),
+                body_hash
+            );
+            // The signature covers its own header with an empty b= and no line ending
+            let mut data = headers.clone();
+            let mut own = canonical::relaxed_header(header.as_bytes());
+            own.truncate(own.len() - 2);
+            data.extend(own);
+
+            let signature = key.sign(&data).map_err(|reason| DkimError::Sign {
+                domain: domain.to_string(),
+                selector: selector.clone(),
+                reason,
+            })?;
+            let signature = STANDARD.encode(signature);
+            let lines = signature
+                .as_bytes()
+                .chunks(72)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>();
+            header.push_str(&lines.join("\r\n\t"));
+            header.push_str("\r\n");
+            signed.extend_from_slice(header.as_bytes());
+        }
+        signed.extend_from_slice(message);
+        Ok(signed)
+    }
+}
@@ -0,0 +1,307 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+use super::{canonical, Algorithm};
+use crate::{
+    dns::Resolver,
+    time,
+    verify::{tag_list, DkimResult, Verdict},
+};
+
+/// Signatures checked per message, each one costs a DNS query
+const MAX_SIGNATURES: usize = 5;
+
+/// Why a signature did not pass
+struct Failure {
+    result: Verdict,
+    reason: String,
+}
+
+fn fail(reason: impl Into<String>) -> Failure {
+    Failure {
+        result: Verdict::Fail,
+        reason: reason.into(),
+    }
+}
+
+fn permerror(reason: impl Into<String>) -> Failure {
+    Failure {
+        result: Verdict::PermError,
+        reason: reason.into(),
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Failure> {
+    let value = value.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    STANDARD
+        .decode(value)
+        .map_err(|error| permerror(format!("invalid base64: {}", error)))
+}
+
+/// The signature field with the value of its b= tag removed, as it was when it got signed
+fn without_signature(raw: &[u8]) -> Vec<u8> {
+    let start = raw.iter().position(|&byte| byte == b':').map_or(raw.len(), |colon| colon + 1);
+    let mut stripped = raw[..start].to_vec();
+    for tag in raw[start..].split_inclusive(|&byte| byte == b';') {
+        match tag.iter().position(|&byte| byte == b'=') {
+            Some(equals) if tag[..equals].trim_ascii() == b"b" => {
+                stripped.extend_from_slice(&tag[..=equals]);
+                if tag.ends_with(b";") {
+                    stripped.push(b';');
+                }
+            }
+            _ => stripped.extend_from_slice(tag),
+        }
+    }
+    stripped
+}
+
+/// Checks every DKIM signature of a message (RFC 6376 section 6)
+pub async fn verify(resolver: &Resolver, message: &[u8]) -> Vec<DkimResult> {
+    let (fields, body) = canonical::split(message);
+    let mut results = Vec::new();
+    for field in fields
+        .iter()
+        .filter(|field| field.name == "dkim-signature")
+        .take(MAX_SIGNATURES)
+    {
+        let text = String::from_utf8_lossy(field.raw);
+        let value = text.split_once(':').map(|(_, value)| value).unwrap_or_default();
+        let tags = tag_list(value);
+        let tag = |name: &str| {
+            tags.as_deref()
+                .unwrap_or_default()
+                .iter()
+                .find(|(known, _)| known == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let domain = tag("d").unwrap_or_default().to_ascii_lowercase();
+        let selector = tag("s").unwrap_or_default().to_string();
+        let signature = tag("b")
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .take(8)
+            .collect();
+
+        let outcome = match &tags {
+            Ok(tags) => check(resolver, &fields, body, field.raw, tags).await,
+            Err(reason) => Err(permerror(reason.as_str())),
+        };
+        let (result, reason) = match outcome {
+            Ok(()) => (Verdict::Pass, None),
+            Err(failure) => (failure.result, Some(failure.reason)),
+        };
+        results.push(DkimResult {
+            result,
+            domain,
+            selector,
+            signature,
+            reason,
+        });
+    }
+    results
+}
+
+async fn check(
+    resolver: &Resolver,
+    fields: &[canonical::Field<'_>],
+    body: &[u8],
+    raw: &[u8],
+    tags: &[(String, String)],
+) -> Result<(), Failure> {
+    let tag = |name: &str| tags.iter().find(|(known, _)| known == name).map(|(_, value)| value.as_str());
+    let required = |name: &str| tag(name).ok_or_else(|| permerror(format!("missing {}= tag", name)));
+
+    if required("v")? != "1" {
+        return Err(permerror("unsupported version"));
+    }
+    let algorithm = match required("a")?.to_ascii_lowercase().as_str() {
+        "rsa-sha256" => Algorithm::Rsa,
+        "ed25519-sha256" => Algorithm::Ed25519,
+        // RFC 8301 section 3.1
+        "rsa-sha1" => return Err(permerror("rsa-sha1 is no longer accepted")),
+        other => return Err(permerror(format!("unknown algorithm {}", other))),
+    };
+    let canonicalization = tag("c").unwrap_or("simple").to_ascii_lowercase();
+    let (header_method, body_method) = canonicalization
+        .split_once('/')
+        .unwrap_or((&canonicalization, "simple"));
+    let relaxed = |method: &str| match method {
+        "simple" => Ok(false),
+        "relaxed" => Ok(true),
+        other => Err(permerror(format!("unknown canonicalization {}", other))),
+    };
+    let (relaxed_header, relaxed_body) = (relaxed(header_method)?, relaxed(body_method)?);
+
+    let domain = required("d")?.to_ascii_lowercase();
+    let selector = required("s")?;
+    let signed = required("h")?
+        .split(':')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if !signed.iter().any(|name| name == "from") {
+        return Err(permerror("From is not signed"));
+    }
+    if let Some(identity) = tag("i") {
+        let identity = identity.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+        if identity != domain && !identity.ends_with(&format!(".{}", domain)) {
+            return Err(permerror("i= is not within d="));
+        }
+    }
+    if let Some(expires) = tag("x") {
+        let expires = expires.parse::<i64>().map_err(|_| permerror("invalid x= tag"))?;
+        if expires < time::now() {
+            return Err(fail("signature expired"));
+        }
+    }
+    let body_hash = decode(required("bh")?)?;
+    let signature = decode(required("b")?)?;
+
+    let mut canonical_body = match relaxed_body {
+        true => canonical::relaxed_body(body),
+        false => canonical::simple_body(body),
+    };
+    if let Some(length) = tag("l") {
+        let length = length.parse::<usize>().map_err(|_| permerror("invalid l= tag"))?;
+        if length > canonical_body.len() {
+            return Err(permerror("l= is longer than the body"));
+        }
+        canonical_body.truncate(length);
+    }
+    if Sha256::digest(&canonical_body).as_slice() != body_hash {
+        return Err(fail("body hash did not verify"));
+    }
+
+    let key = public_key(resolver, &domain, selector, algorithm).await?;
+
+    let header = |raw: &[u8]| match relaxed_header {
+        true => canonical::relaxed_header(raw),
+        false => canonical::simple_header(raw),
+    };
+    // Fields are taken from the bottom up, a name listed more often than present signs nothing
+    let mut used = vec![false; fields.len()];
+    let mut data = Vec::new();
+    for name in &signed {
+        let field = (0..fields.len())
+            .rev()
+            .find(|&index| !used[index] && fields[index].name == *name);
+        if let Some(index) = field {
+            used[index] = true;
+            data.extend(header(fields[index].raw));
+        }
+    }
+    let own = header(&without_signature(raw));
+    data.extend_from_slice(own.strip_suffix(b"\r\n").unwrap_or(&own));
+
+    match algorithm.verify(&key, &data, &signature) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(fail("signature did not verify")),
+        Err(reason) => Err(permerror(reason)),
+    }
+}
+
+/// Fetches the key a signature refers to, as published under `selector._domainkey.domain`
+async fn public_key(resolver: &Resolver, domain: &str, selector: &str, algorithm: Algorithm) -> Result<Vec<u8>, Failure> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = resolver.txt(&name).await.map_err(|error| Failure {
+        result: Verdict::TempError,
+        reason: error.to_string(),
+    })?;
+    let record = records
+        .first()
+        .ok_or_else(|| permerror(format!("no key published at {}", name)))?;
+    let tags = tag_list(record).map_err(|reason| permerror(format!("invalid key record: {}", reason)))?;
+    let tag = |name: &str| tags.iter().find(|(known, _)| known == name).map(|(_, value)| value.as_str());
+
+    if tag("v").is_some_and(|version| version != "DKIM1") {
+        return Err(permerror("unsupported key record version"));
+    }
+    if !tag("k").unwrap_or("rsa").eq_ignore_ascii_case(algorithm.key_type()) {
+        return Err(permerror("key type does not match the signature"));
+    }
+    if tag("h").is_some_and(|hashes| !hashes.split(':').any(|hash| hash.trim() == "sha256")) {
+        return Err(permerror("key does not allow sha256"));
+    }
+    match tag("p") {
+        None => Err(permerror("key record has no p= tag")),
+        Some(key) if key.trim().is_empty() => Err(permerror("key revoked")),
+        Some(key) => decode(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dkim::DkimKeys,
+        dns::Fixtures,
+        domain::Domains,
+    };
+
+    const MESSAGE: &[u8] = b"From: Ann <ann@example.com>\r\nTo: bob@example.org\r\nSubject: Lunch\r\n\r\nSee you  at noon\r\n";
+
+    /// Keys of example.com and a resolver publishing them
+    async fn signer(algorithms: &[Algorithm]) -> (DkimKeys, Resolver) {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let domains = Domains::open(&database).unwrap();
+        domains.add("example.com").await.unwrap();
+        let keys = DkimKeys::open(&database, domains).unwrap();
+        let mut zones = String::new();
+        for (number, algorithm) in algorithms.iter().enumerate() {
+            let selector = format!("s{}", number);
+            let (_, key) = keys
+                .generate("example.com", Some(selector.clone()), *algorithm, None)
+                .await
+                .unwrap();
+            let record = key.dns_record("example.com", &selector);
+            zones.push_str(&format!("[\"{}\"]\ntxt = [\"{}\"]\n", record.name, record.value));
+        }
+        (keys, Resolver::new(Fixtures::parse(&zones).unwrap()))
+    }
+
+    fn verdicts(results: &[DkimResult]) -> Vec<Verdict> {
+        results.iter().map(|result| result.result).collect()
+    }
+
+    #[tokio::test]
+    async fn verifies_its_own_signatures() {
+        let (keys, resolver) = signer(&[Algorithm::Ed25519, Algorithm::Rsa]).await;
+        let signed = keys.sign("example.com", MESSAGE).await.unwrap();
+        let results = verify(&resolver, &signed).await;
+        assert_eq!(verdicts(&results), [Verdict::Pass, Verdict::Pass]);
+        assert_eq!(results[0].domain, "example.com");
+
+        // Relaxed canonicalization tolerates whitespace changes in transit
+        let rewrapped = String::from_utf8(signed).unwrap().replace("Subject: Lunch", "Subject:  Lunch");
+        let rewrapped = rewrapped.replace("See you  at noon", "See you at noon \t");
+        assert_eq!(verdicts(&verify(&resolver, rewrapped.as_bytes()).await), [Verdict::Pass, Verdict::Pass]);
+    }
+
+    #[tokio::test]
+    async fn detects_changes() {
+        let (keys, resolver) = signer(&[Algorithm::Ed25519]).await;
+        let signed = String::from_utf8(keys.sign("example.com", MESSAGE).await.unwrap()).unwrap();
+
+        let body = signed.replace("at noon", "at one");
+        let results = verify(&resolver, body.as_bytes()).await;
+        assert_eq!(verdicts(&results), [Verdict::Fail]);
+        assert_eq!(results[0].reason.as_deref(), Some("body hash did not verify"));
+
+        let header = signed.replace("Subject: Lunch", "Subject: Dinner");
+        let results = verify(&resolver, header.as_bytes()).await;
+        assert_eq!(verdicts(&results), [Verdict::Fail]);
+        assert_eq!(results[0].reason.as_deref(), Some("signature did not verify"));
+    }
+
+    #[tokio::test]
+    async fn needs_a_published_key() {
+        let (keys, _) = signer(&[Algorithm::Ed25519]).await;
+        let signed = keys.sign("example.com", MESSAGE).await.unwrap();
+        let resolver = Resolver::new(Fixtures::parse("").unwrap());
+        let results = verify(&resolver, &signed).await;
+        assert_eq!(verdicts(&results), [Verdict::PermError]);
+        assert_eq!(results[0].reason.as_deref(), Some("no key published at s0._domainkey.example.com"));
+        assert!(verify(&resolver, MESSAGE).await.is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use futures::future::{self, BoxFuture};
+use serde::Deserialize;
+
+use super::{DnsError, Lookup, Record, RecordType};
+
+/// Records of one name as written in the fixtures file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Zone {
+    #[serde(default)]
+    a: Vec<Ipv4Addr>,
+    #[serde(default)]
+    aaaa: Vec<Ipv6Addr>,
+    /// `"<preference> <exchange>"`, as in a zone file
+    #[serde(default)]
+    mx: Vec<String>,
+    #[serde(default)]
+    txt: Vec<String>,
+    /// Every query for the name fails, to see how temporary errors are handled
+    #[serde(default)]
+    servfail: bool,
+}
+
+/// Canned answers read from a TOML file, so mail can be delivered and verified without a
+/// network. Every table is a name, names missing from the file do not exist:
+///
+/// ```toml
+/// ["example.org"]
+/// a = ["192.0.2.1"]
+/// mx = ["10 mx.example.org"]
+/// txt = ["v=spf1 ip4:192.0.2.0/24 -all"]
+/// ```
+pub struct Fixtures {
+    names: HashMap<String, Option<Vec<(RecordType, Record)>>>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Fixtures {
+    pub fn load(path: &Path) -> Result<Self, DnsError> {
+        let error = |reason: String| DnsError::Fixtures {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let contents = fs::read_to_string(path).map_err(|reason| error(reason.to_string()))?;
+        Fixtures::parse(&contents).map_err(error)
+    }
+
+    /// Reads fixtures from the contents of such a file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let zones: HashMap<String, Zone> =
+            toml::from_str(contents).map_err(|reason| reason.to_string().trim_end().to_string())?;
+
+        let mut names = HashMap::new();
+        for (name, zone) in zones {
+            if zone.servfail {
+                names.insert(normalize(&name), None);
+                continue;
+            }
+            let mut records = Vec::new();
+            records.extend(zone.a.into_iter().map(|ip| (RecordType::A, Record::Address(ip.into()))));
+            records.extend(zone.aaaa.into_iter().map(|ip| (RecordType::Aaaa, Record::Address(ip.into()))));
+            for mx in zone.mx {
+                let parsed = mx
+                    .split_once(' ')
+                    .and_then(|(preference, exchange)| Some((preference.parse().ok()?, exchange.trim())));
+                let Some((preference, exchange)) = parsed else {
+                    return Err(format!("{}: '{}' is not '<preference> <exchange>'", name, mx));
+                };
+                let exchange = exchange.trim_end_matches('.').to_string();
+                records.push((RecordType::Mx, Record::Mx { preference, exchange }));
+            }
+            records.extend(zone.txt.into_iter().map(|text| (RecordType::Txt, Record::Txt(text))));
+            names.insert(normalize(&name), Some(records));
+        }
+        Ok(Fixtures { names })
+    }
+}
+
+impl Lookup for Fixtures {
+    fn query<'a>(&'a self, name: &'a str, kind: RecordType) -> BoxFuture<'a, Result<Vec<Record>, DnsError>> {
+        let answer = match self.names.get(&normalize(name)) {
+            Some(Some(records)) => Ok(records
+                .iter()
+                .filter(|(record_type, _)| *record_type == kind)
+                .map(|(_, record)| record.clone())
+                .collect()),
+            Some(None) => Err(DnsError::Lookup {
+                name: name.to_string(),
+                reason: "server failure".to_string(),
+            }),
+            None => Err(DnsError::NotFound(name.to_string())),
+        };
+        Box::pin(future::ready(answer))
+    }
+}
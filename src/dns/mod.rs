@@ -0,0 +1,207 @@
+mod fixtures;
+
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use futures::future::{self, BoxFuture};
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::{op::ResponseCode, rr},
+    TokioAsyncResolver,
+};
+use thiserror::Error;
+
+use crate::config::DnsConfig;
+pub use fixtures::Fixtures;
+
+#[derive(Error, Debug)]
+pub enum DnsError {
+    #[error("Could not read the system DNS configuration: {0}, set dns.nameserver instead")]
+    System(#[source] ResolveError),
+    #[error("Could not load DNS fixtures from {path}: {reason}")]
+    Fixtures { path: PathBuf, reason: String },
+    #[error("Domain {0} does not exist")]
+    NotFound(String),
+    #[error("DNS lookup for {name} failed: {reason}")]
+    Lookup { name: String, reason: String },
+}
+
+/// The record types mail handling asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Mx,
+    Txt,
+}
+
+#[derive(Debug, Clone)]
+pub enum Record {
+    Address(IpAddr),
+    Mx { preference: u16, exchange: String },
+    /// The character strings of the record joined together
+    Txt(String),
+}
+
+/// Answers queries, from the network or from fixtures
+pub trait Lookup: Send + Sync {
+    /// Records of one type at a name. An existing name without such records yields none,
+    /// a name that does not exist at all `DnsError::NotFound`.
+    fn query<'a>(&'a self, name: &'a str, kind: RecordType) -> BoxFuture<'a, Result<Vec<Record>, DnsError>>;
+}
+
+/// Whether a lookup failed because the name has no records of the requested type
+fn is_empty(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { response_code, .. } if *response_code == ResponseCode::NoError)
+}
+
+fn is_nxdomain(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { response_code, .. } if *response_code == ResponseCode::NXDomain)
+}
+
+impl Lookup for TokioAsyncResolver {
+    fn query<'a>(&'a self, name: &'a str, kind: RecordType) -> BoxFuture<'a, Result<Vec<Record>, DnsError>> {
+        Box::pin(async move {
+            let fqdn = format!("{}.", name.trim_end_matches('.'));
+            let record_type = match kind {
+                RecordType::A => rr::RecordType::A,
+                RecordType::Aaaa => rr::RecordType::AAAA,
+                RecordType::Mx => rr::RecordType::MX,
+                RecordType::Txt => rr::RecordType::TXT,
+            };
+            let lookup = match self.lookup(fqdn.as_str(), record_type).await {
+                Ok(lookup) => lookup,
+                Err(error) if is_empty(&error) => return Ok(Vec::new()),
+                Err(error) if is_nxdomain(&error) => return Err(DnsError::NotFound(name.to_string())),
+                Err(error) => {
+                    return Err(DnsError::Lookup {
+                        name: name.to_string(),
+                        reason: error.to_string(),
+                    })
+                }
+            };
+            // Answers may carry the CNAME chain that led to the records
+            Ok(lookup
+                .iter()
+                .filter_map(|data| match data {
+                    rr::RData::A(address) if kind == RecordType::A => Some(Record::Address(address.0.into())),
+                    rr::RData::AAAA(address) if kind == RecordType::Aaaa => Some(Record::Address(address.0.into())),
+                    rr::RData::MX(mx) if kind == RecordType::Mx => Some(Record::Mx {
+                        preference: mx.preference(),
+                        exchange: mx.exchange().to_utf8().trim_end_matches('.').to_string(),
+                    }),
+                    rr::RData::TXT(txt) if kind == RecordType::Txt => Some(Record::Txt(
+                        txt.txt_data()
+                            .iter()
+                            .map(|part| String::from_utf8_lossy(part))
+                            .collect(),
+                    )),
+                    _ => None,
+                })
+                .collect())
+        })
+    }
+}
+
+/// Looks up the records needed to deliver and verify mail
+#[derive(Clone)]
+pub struct Resolver {
+    lookup: Arc<dyn Lookup>,
+}
+
+impl Resolver {
+    /// Answers from the fixtures file or the given nameserver when configured, or else from
+    /// whatever the system is set up to query
+    pub fn open(config: &DnsConfig) -> Result<Self, DnsError> {
+        if let Some(path) = &config.fixtures {
+            log::warn!("Answering DNS queries from the fixtures in {}", path.display());
+            return Ok(Resolver::new(Fixtures::load(path)?));
+        }
+        let resolver = match config.nameserver {
+            Some(nameserver) => {
+                let servers = NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true);
+                TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, Vec::new(), servers), ResolverOpts::default())
+            }
+            None => TokioAsyncResolver::tokio_from_system_conf().map_err(DnsError::System)?,
+        };
+        Ok(Resolver::new(resolver))
+    }
+
+    pub fn new(lookup: impl Lookup + 'static) -> Self {
+        Resolver {
+            lookup: Arc::new(lookup),
+        }
+    }
+
+    pub async fn query(&self, name: &str, kind: RecordType) -> Result<Vec<Record>, DnsError> {
+        self.lookup.query(name, kind).await
+    }
+
+    /// Hosts accepting mail for a domain in order of preference. Without MX records the domain
+    /// itself is used (RFC 5321 section 5.1), a null MX (RFC 7505) yields no hosts at all.
+    pub async fn mail_exchangers(&self, domain: &str) -> Result<Vec<String>, DnsError> {
+        let records = self.query(domain, RecordType::Mx).await?;
+        if records.is_empty() {
+            return Ok(vec![domain.to_string()]);
+        }
+
+        let mut exchangers = records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Mx { preference, exchange } => Some((preference, exchange)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        exchangers.sort_by_key(|(preference, _)| *preference);
+        Ok(exchangers
+            .into_iter()
+            .map(|(_, host)| host)
+            .filter(|host| !host.is_empty())
+            .collect())
+    }
+
+    /// IPv4 and IPv6 addresses of a host, empty when it has none
+    pub async fn addresses(&self, host: &str) -> Result<Vec<IpAddr>, DnsError> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        let (v4, v6) = future::join(self.query(host, RecordType::A), self.query(host, RecordType::Aaaa)).await;
+        let mut addresses = Vec::new();
+        let mut failure = None;
+        for records in [v4, v6] {
+            match records {
+                Ok(records) => addresses.extend(records.into_iter().filter_map(|record| match record {
+                    Record::Address(ip) => Some(ip),
+                    _ => None,
+                })),
+                Err(DnsError::NotFound(_)) => {}
+                Err(error) => failure = Some(error),
+            }
+        }
+        // One family failing does not matter as long as the other one answered
+        match failure {
+            Some(error) if addresses.is_empty() => Err(error),
+            _ => Ok(addresses),
+        }
+    }
+
+    /// TXT records at a name, none when the name does not exist
+    pub async fn txt(&self, name: &str) -> Result<Vec<String>, DnsError> {
+        match self.query(name, RecordType::Txt).await {
+            Ok(records) => Ok(records
+                .into_iter()
+                .filter_map(|record| match record {
+                    Record::Txt(text) => Some(text),
+                    _ => None,
+                })
+                .collect()),
+            Err(DnsError::NotFound(_)) => Ok(Vec::new()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
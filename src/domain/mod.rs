@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use thiserror::Error;
+
+use crate::{
+    db::{DatabaseError, Store},
+    routing::normalize_address,
+    time,
+};
+
+#[derive(Error, Debug)]
+pub enum DomainError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("Invalid domain name '{0}'")]
+    Invalid(String),
+    #[error("Domain {0} is already hosted")]
+    Exists(String),
+    #[error("Invalid address '{0}'")]
+    InvalidAddress(String),
+}
+
+/// A domain mail is accepted for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Domain {
+    pub created_at: i64,
+    /// Receives mail for every address of the domain that nothing else answers to
+    pub catch_all: Option<String>,
+}
+
+/// Lowercases a domain and checks it consists of valid DNS labels
+fn normalize(name: &str) -> Result<String, DomainError> {
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    match valid {
+        true => Ok(name),
+        false => Err(DomainError::Invalid(name)),
+    }
+}
+
+/// Domains hosted by this server, kept in their own tree
+#[derive(Clone)]
+pub struct Domains {
+    domains: Store<Domain>,
+}
+
+impl Domains {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        let domains = Domains {
+            domains: Store::open(database, "domains")?,
+        };
+        if domains.domains.tree().is_empty() {
+            log::warn!("No domains configured, every recipient will be rejected until one is added");
+        }
+        Ok(domains)
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, Domain)>, DatabaseError> {
+        self.domains.list().await
+    }
+
+    pub async fn add(&self, name: &str) -> Result<(String, Domain), DomainError> {
+        let name = normalize(name)?;
+        if self.domains.tree().contains_key(&name).map_err(DatabaseError::from)? {
+            return Err(DomainError::Exists(name));
+        }
+        let domain = Domain {
+            created_at: time::now(),
+            catch_all: None,
+        };
+        self.domains.set(&name, &domain).await?;
+        Ok((name, domain))
+    }
+
+    /// Stops accepting mail for a domain, users keep their addresses in it
+    pub async fn remove(&self, name: &str) -> Result<(), DatabaseError> {
+        self.domains.delete(&name.to_lowercase()).await
+    }
+
+    /// Points the catch-all at an address, or removes it with `None`
+    pub async fn set_catch_all(&self, name: &str, catch_all: Option<String>) -> Result<Domain, DomainError> {
+        let name = name.to_lowercase();
+        let mut domain = self.domains.get(&name).await?;
+        domain.catch_all = match catch_all {
+            Some(address) => Some(normalize_address(&address).ok_or(DomainError::InvalidAddress(address))?),
+            None => None,
+        };
+        self.domains.set(&name, &domain).await?;
+        Ok(domain)
+    }
+
+    pub async fn catch_all(&self, name: &str) -> Result<Option<String>, DatabaseError> {
+        match self.domains.get(&name.to_lowercase()).await {
+            Ok(domain) => Ok(domain.catch_all),
+            Err(DatabaseError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub async fn contains(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.domains.tree().contains_key(name.to_lowercase())?)
+    }
+}
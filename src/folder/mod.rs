@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use thiserror::Error;
+
+use crate::{
+    db::{DatabaseError, Store},
+    time,
+};
+
+pub const INBOX: &str = "Inbox";
+pub const ARCHIVE: &str = "Archive";
+pub const SENT: &str = "Sent";
+pub const SPAM: &str = "Spam";
+pub const TRASH: &str = "Trash";
+
+/// Folders every mailbox has, they cannot be created or removed
+pub const SYSTEM_FOLDERS: [&str; 5] = [INBOX, ARCHIVE, SENT, SPAM, TRASH];
+
+/// Longest folder or label name accepted
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum FolderError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("Invalid name '{0}'")]
+    Invalid(String),
+    #[error("Folder {0} already exists")]
+    Exists(String),
+    #[error("No folder named {0}")]
+    NotFound(String),
+    #[error("{0} is a system folder")]
+    System(String),
+    #[error("Folder {0} still holds messages")]
+    NotEmpty(String),
+}
+
+/// A folder of a mailbox
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Folder {
+    pub name: String,
+    /// One of the folders every mailbox has
+    pub system: bool,
+    /// `None` for system folders
+    pub created_at: Option<i64>,
+}
+
+/// Trims a folder or label name and checks it is usable
+pub fn normalize_name(name: &str) -> Result<String, FolderError> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LENGTH
+        && !name.chars().any(|c| c.is_control());
+    match valid {
+        true => Ok(name.to_string()),
+        false => Err(FolderError::Invalid(name.to_string())),
+    }
+}
+
+/// The system folder a name refers to, matched case-insensitively
+pub fn system_folder(name: &str) -> Option<&'static str> {
+    SYSTEM_FOLDERS
+        .into_iter()
+        .find(|system| system.eq_ignore_ascii_case(name))
+}
+
+/// Custom folders are kept per mailbox under `<mailbox>\0<lowercased name>`
+fn key(mailbox: &str, name: &str) -> String {
+    format!("{}\0{}", mailbox, name.to_lowercase())
+}
+
+/// Folders users created on top of the system folders, kept in their own tree
+#[derive(Clone)]
+pub struct Folders {
+    folders: Store<Folder>,
+}
+
+impl Folders {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(Folders {
+            folders: Store::open(database, "folders")?,
+        })
+    }
+
+    /// The system folders followed by the custom folders of a mailbox
+    pub async fn list(&self, mailbox: &str) -> Result<Vec<Folder>, DatabaseError> {
+        let mut folders = SYSTEM_FOLDERS
+            .into_iter()
+            .map(|name| Folder {
+                name: name.to_string(),
+                system: true,
+                created_at: None,
+            })
+            .collect::<Vec<_>>();
+        for entry in self.folders.tree().scan_prefix(format!("{}\0", mailbox)).values() {
+            let folder = bincode::deserialize::<Folder>(&entry?).map_err(|error| {
+                log::error!("Db Interaction Error: {}", error);
+                DatabaseError::Deserialize
+            })?;
+            folders.push(folder);
+        }
+        Ok(folders)
+    }
+
+    pub async fn create(&self, mailbox: &str, name: &str) -> Result<Folder, FolderError> {
+        let name = normalize_name(name)?;
+        if let Some(system) = system_folder(&name) {
+            return Err(FolderError::Exists(system.to_string()));
+        }
+        let key = key(mailbox, &name);
+        if self.folders.tree().contains_key(&key).map_err(DatabaseError::from)? {
+            return Err(FolderError::Exists(name));
+        }
+        let folder = Folder {
+            name,
+            system: false,
+            created_at: Some(time::now()),
+        };
+        self.folders.set(&key, &folder).await?;
+        Ok(folder)
+    }
+
+    /// Removes a custom folder, the caller makes sure it is empty
+    pub async fn remove(&self, mailbox: &str, name: &str) -> Result<(), FolderError> {
+        if let Some(system) = system_folder(name) {
+            return Err(FolderError::System(system.to_string()));
+        }
+        match self.folders.delete(&key(mailbox, name)).await {
+            Err(DatabaseError::NotFound) => Err(FolderError::NotFound(name.to_string())),
+            result => Ok(result?),
+        }
+    }
+
+    /// The name of an existing folder as it was created, matched case-insensitively
+    pub async fn resolve(&self, mailbox: &str, name: &str) -> Result<String, FolderError> {
+        if let Some(system) = system_folder(name) {
+            return Ok(system.to_string());
+        }
+        match self.folders.get(&key(mailbox, name)).await {
+            Ok(folder) => Ok(folder.name),
+            Err(DatabaseError::NotFound) => Err(FolderError::NotFound(name.to_string())),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
@@ -0,0 +1,988 @@
+use crate::time;
+
+/// How deeply NOT, OR and parentheses may nest search keys, well within what the stack allows
+const MAX_SEARCH_DEPTH: usize = 64;
+/// Commands a client may send before logging in
+const UNAUTHENTICATED: [&str; 5] = ["CAPABILITY", "NOOP", "LOGOUT", "STARTTLS", "LOGIN"];
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Numbers or ranges of sequence numbers or UIDs, `None` standing for `*`, the largest one in use
+#[derive(Debug, Clone)]
+pub(super) struct SequenceSet(Vec<(Option<u32>, Option<u32>)>);
+
+impl SequenceSet {
+    /// Whether the set includes a number, given the largest one in use
+    pub(super) fn contains(&self, number: u32, largest: u32) -> bool {
+        self.0.iter().any(|&(start, end)| {
+            let (start, end) = (start.unwrap_or(largest), end.unwrap_or(largest));
+            (start.min(end)..=start.max(end)).contains(&number)
+        })
+    }
+}
+
+/// The system flags of RFC 3501 section 2.3.2 that are kept per message, `\Recent` is not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Deleted,
+    Draft,
+}
+
+impl Flag {
+    pub(super) const ALL: [Flag; 5] = [Flag::Seen, Flag::Answered, Flag::Flagged, Flag::Deleted, Flag::Draft];
+
+    fn parse(name: &str) -> Option<Self> {
+        Flag::ALL
+            .into_iter()
+            .find(|flag| flag.name().eq_ignore_ascii_case(name))
+    }
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Flag::Seen => "\\Seen",
+            Flag::Answered => "\\Answered",
+            Flag::Flagged => "\\Flagged",
+            Flag::Deleted => "\\Deleted",
+            Flag::Draft => "\\Draft",
+        }
+    }
+}
+
+/// What a section of a body part refers to
+#[derive(Debug, Clone)]
+pub(super) enum SectionText {
+    Header,
+    /// Only the named fields, or every field but them
+    HeaderFields { not: bool, fields: Vec<String> },
+    Text,
+    /// The MIME header of a part
+    Mime,
+}
+
+/// A `BODY[...]` section, an empty part path being the whole message
+#[derive(Debug, Clone)]
+pub(super) struct Section {
+    pub(super) part: Vec<u32>,
+    pub(super) text: Option<SectionText>,
+}
+
+impl Section {
+    /// The section as echoed in the response
+    pub(super) fn name(&self) -> String {
+        let mut name = self.part.iter().map(u32::to_string).collect::<Vec<_>>().join(".");
+        let text = match &self.text {
+            None => return name,
+            Some(SectionText::Header) => "HEADER".to_string(),
+            Some(SectionText::Text) => "TEXT".to_string(),
+            Some(SectionText::Mime) => "MIME".to_string(),
+            Some(SectionText::HeaderFields { not, fields }) => format!(
+                "HEADER.FIELDS{} ({})",
+                if *not { ".NOT" } else { "" },
+                fields.join(" ")
+            ),
+        };
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name + &text
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum FetchItem {
+    Uid,
+    Flags,
+    InternalDate,
+    Size,
+    Envelope,
+    /// `BODY` without a section, the structure without extension data
+    Body,
+    BodyStructure,
+    Rfc822,
+    Rfc822Header,
+    Rfc822Text,
+    Section {
+        section: Section,
+        /// `BODY.PEEK`, which leaves `\Seen` alone
+        peek: bool,
+        /// Offset and length of a partial fetch
+        partial: Option<(u32, u32)>,
+    },
+}
+
+impl FetchItem {
+    /// Whether fetching it marks the message as seen
+    pub(super) fn sets_seen(&self) -> bool {
+        matches!(
+            self,
+            FetchItem::Rfc822 | FetchItem::Rfc822Text | FetchItem::Section { peek: false, .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum StatusItem {
+    Messages,
+    Recent,
+    UidNext,
+    UidValidity,
+    Unseen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum StoreMode {
+    Replace,
+    Add,
+    Remove,
+}
+
+/// A search key of RFC 3501 section 6.4.4, dates as unix time at the start of the day
+#[derive(Debug, Clone)]
+pub(super) enum SearchKey {
+    All,
+    /// Whether a flag is set, or cleared when `false`
+    Flag(Flag, bool),
+    /// Keywords are not kept, so no message has one
+    Keyword(bool),
+    New,
+    Old,
+    Recent,
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Before(i64),
+    On(i64),
+    Since(i64),
+    SentBefore(i64),
+    SentOn(i64),
+    SentSince(i64),
+    Larger(u32),
+    Smaller(u32),
+    Uid(SequenceSet),
+    Sequence(SequenceSet),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    And(Vec<SearchKey>),
+}
+
+#[derive(Debug)]
+pub(super) enum Command {
+    Capability,
+    Noop,
+    Logout,
+    StartTls,
+    Login { username: String, password: String },
+    Select { mailbox: String, read_only: bool },
+    Create { mailbox: String },
+    Delete { mailbox: String },
+    Rename,
+    /// Every folder counts as subscribed, so subscribing changes nothing
+    Subscribe,
+    List { reference: String, pattern: String, subscribed: bool },
+    Status { mailbox: String, items: Vec<StatusItem> },
+    Append { mailbox: String, flags: Vec<Flag>, date: Option<i64>, message: Vec<u8> },
+    Check,
+    Close,
+    Unselect,
+    Expunge,
+    Search { uid: bool, key: SearchKey },
+    Fetch { uid: bool, set: SequenceSet, items: Vec<FetchItem> },
+    Store { uid: bool, set: SequenceSet, mode: StoreMode, silent: bool, flags: Vec<Flag> },
+    Copy { uid: bool, set: SequenceSet, mailbox: String },
+    Move { uid: bool, set: SequenceSet, mailbox: String },
+    Idle,
+}
+
+/// A complete command line, with any literals inline after their `{n}` announcement
+pub(super) struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+    /// Search keys currently being parsed within each other
+    depth: usize,
+}
+
+type Parsed<T> = Result<T, String>;
+
+impl<'a> Parser<'a> {
+    pub(super) fn new(input: &'a [u8]) -> Self {
+        Parser {
+            input,
+            position: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    fn expect(&mut self, byte: u8) -> Parsed<()> {
+        match self.peek() {
+            Some(found) if found == byte => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(format!("Expected '{}'", byte as char)),
+        }
+    }
+
+    fn space(&mut self) -> Parsed<()> {
+        self.expect(b' ')
+    }
+
+    fn end(&self) -> Parsed<()> {
+        match self.at_end() {
+            true => Ok(()),
+            false => Err("Unexpected arguments".to_string()),
+        }
+    }
+
+    /// Characters up to the next space, parenthesis or bracket, or any other character not allowed
+    fn take_while(&mut self, allowed: impl Fn(u8) -> bool) -> &'a [u8] {
+        let start = self.position;
+        while self.peek().is_some_and(&allowed) {
+            self.position += 1;
+        }
+        &self.input[start..self.position]
+    }
+
+    fn atom(&mut self) -> Parsed<String> {
+        let atom = self.take_while(is_atom_char);
+        match atom.is_empty() {
+            true => Err("Expected an atom".to_string()),
+            false => Ok(String::from_utf8_lossy(atom).into_owned()),
+        }
+    }
+
+    fn number(&mut self) -> Parsed<u32> {
+        let digits = self.take_while(|byte| byte.is_ascii_digit());
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| "Expected a number".to_string())
+    }
+
+    fn quoted(&mut self) -> Parsed<Vec<u8>> {
+        self.expect(b'"')?;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(value);
+                }
+                Some(b'\\') => {
+                    self.position += 1;
+                    value.push(self.peek().ok_or("Unterminated string")?);
+                }
+                Some(b'\r' | b'\n') | None => return Err("Unterminated string".to_string()),
+                Some(byte) => value.push(byte),
+            }
+            self.position += 1;
+        }
+    }
+
+    fn literal(&mut self) -> Parsed<Vec<u8>> {
+        self.expect(b'{')?;
+        let length = self.number()? as usize;
+        self.expect(b'}')?;
+        self.expect(b'\r')?;
+        self.expect(b'\n')?;
+        let value = self
+            .input
+            .get(self.position..self.position + length)
+            .ok_or("Literal is shorter than announced")?;
+        self.position += length;
+        Ok(value.to_vec())
+    }
+
+    fn string(&mut self) -> Parsed<Vec<u8>> {
+        match self.peek() {
+            Some(b'"') => self.quoted(),
+            Some(b'{') => self.literal(),
+            _ => Err("Expected a string".to_string()),
+        }
+    }
+
+    /// An atom, which may contain `]`, or a string
+    fn astring(&mut self) -> Parsed<String> {
+        let value = match self.peek() {
+            Some(b'"' | b'{') => self.string()?,
+            _ => {
+                let atom = self.take_while(|byte| is_atom_char(byte) || byte == b']');
+                if atom.is_empty() {
+                    return Err("Expected a string".to_string());
+                }
+                atom.to_vec()
+            }
+        };
+        String::from_utf8(value).map_err(|_| "Strings must be UTF-8".to_string())
+    }
+
+    /// A mailbox pattern of LIST, which may contain the `%` and `*` wildcards
+    fn list_mailbox(&mut self) -> Parsed<String> {
+        match self.peek() {
+            Some(b'"' | b'{') => self.astring(),
+            _ => {
+                let pattern = self.take_while(|byte| is_atom_char(byte) || matches!(byte, b']' | b'%' | b'*'));
+                match pattern.is_empty() {
+                    true => Err("Expected a mailbox pattern".to_string()),
+                    false => Ok(String::from_utf8_lossy(pattern).into_owned()),
+                }
+            }
+        }
+    }
+
+    fn sequence_number(&mut self) -> Parsed<Option<u32>> {
+        if self.peek() == Some(b'*') {
+            self.position += 1;
+            return Ok(None);
+        }
+        match self.number()? {
+            0 => Err("Sequence numbers start at 1".to_string()),
+            number => Ok(Some(number)),
+        }
+    }
+
+    fn sequence_set(&mut self) -> Parsed<SequenceSet> {
+        let mut ranges = Vec::new();
+        loop {
+            let start = self.sequence_number()?;
+            let end = match self.peek() {
+                Some(b':') => {
+                    self.position += 1;
+                    self.sequence_number()?
+                }
+                _ => start,
+            };
+            ranges.push((start, end));
+            if self.peek() != Some(b',') {
+                return Ok(SequenceSet(ranges));
+            }
+            self.position += 1;
+        }
+    }
+
+    /// A flag, `None` for keywords and `\Recent`, which are not kept
+    fn flag(&mut self) -> Parsed<Option<Flag>> {
+        let backslash = self.peek() == Some(b'\\');
+        if backslash {
+            self.position += 1;
+        }
+        let name = self.atom()?;
+        match backslash {
+            true if name.eq_ignore_ascii_case("Recent") => Ok(None),
+            true => Flag::parse(&format!("\\{}", name))
+                .map(Some)
+                .ok_or_else(|| format!("Unknown flag \\{}", name)),
+            false => Ok(None),
+        }
+    }
+
+    /// A parenthesized list of flags, or a bare one where the syntax allows it
+    fn flags(&mut self, bare: bool) -> Parsed<Vec<Flag>> {
+        let mut flags = Vec::new();
+        if self.peek() != Some(b'(') {
+            if !bare {
+                return Err("Expected a flag list".to_string());
+            }
+            loop {
+                flags.extend(self.flag()?);
+                if self.peek() != Some(b' ') {
+                    return Ok(flags);
+                }
+                self.space()?;
+            }
+        }
+        self.expect(b'(')?;
+        let mut first = true;
+        while self.peek() != Some(b')') {
+            if !first {
+                self.space()?;
+            }
+            flags.extend(self.flag()?);
+            first = false;
+        }
+        self.expect(b')')?;
+        Ok(flags)
+    }
+
+    /// `date-text` of RFC 3501, e.g. `1-Feb-1994`, optionally quoted
+    fn date(&mut self) -> Parsed<i64> {
+        let quoted = self.peek() == Some(b'"');
+        let text = match quoted {
+            true => String::from_utf8_lossy(&self.quoted()?).into_owned(),
+            false => self.atom()?,
+        };
+        parse_date(&text).ok_or_else(|| format!("Invalid date {}", text))
+    }
+
+    fn header_fields(&mut self) -> Parsed<Vec<String>> {
+        self.space()?;
+        self.expect(b'(')?;
+        let mut fields = vec![self.astring()?];
+        while self.peek() == Some(b' ') {
+            self.space()?;
+            fields.push(self.astring()?);
+        }
+        self.expect(b')')?;
+        Ok(fields)
+    }
+
+    /// The part of `BODY[...]` between the brackets
+    fn section(&mut self) -> Parsed<Section> {
+        self.expect(b'[')?;
+        let mut section = Section {
+            part: Vec::new(),
+            text: None,
+        };
+        while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            section.part.push(self.number()?);
+            if self.peek() != Some(b'.') {
+                break;
+            }
+            self.position += 1;
+        }
+        if self.peek() != Some(b']') {
+            let name = self.take_while(|byte| byte.is_ascii_alphanumeric() || byte == b'.');
+            section.text = Some(match name.to_ascii_uppercase().as_slice() {
+                b"HEADER" => SectionText::Header,
+                b"TEXT" => SectionText::Text,
+                b"MIME" if !section.part.is_empty() => SectionText::Mime,
+                b"HEADER.FIELDS" => SectionText::HeaderFields {
+                    not: false,
+                    fields: self.header_fields()?,
+                },
+                b"HEADER.FIELDS.NOT" => SectionText::HeaderFields {
+                    not: true,
+                    fields: self.header_fields()?,
+                },
+                _ => return Err("Unknown section".to_string()),
+            });
+        }
+        self.expect(b']')?;
+        Ok(section)
+    }
+
+    fn fetch_item(&mut self) -> Parsed<FetchItem> {
+        let name = self.take_while(|byte| byte.is_ascii_alphanumeric() || byte == b'.');
+        let item = match name.to_ascii_uppercase().as_slice() {
+            b"UID" => FetchItem::Uid,
+            b"FLAGS" => FetchItem::Flags,
+            b"INTERNALDATE" => FetchItem::InternalDate,
+            b"RFC822.SIZE" => FetchItem::Size,
+            b"ENVELOPE" => FetchItem::Envelope,
+            b"BODYSTRUCTURE" => FetchItem::BodyStructure,
+            b"RFC822" => FetchItem::Rfc822,
+            b"RFC822.HEADER" => FetchItem::Rfc822Header,
+            b"RFC822.TEXT" => FetchItem::Rfc822Text,
+            b"BODY" if self.peek() != Some(b'[') => FetchItem::Body,
+            name @ (b"BODY" | b"BODY.PEEK") => {
+                let peek = name.len() > 4;
+                let section = self.section()?;
+                let partial = match self.peek() {
+                    Some(b'<') => {
+                        self.position += 1;
+                        let offset = self.number()?;
+                        self.expect(b'.')?;
+                        let length = self.number()?;
+                        self.expect(b'>')?;
+                        Some((offset, length))
+                    }
+                    _ => None,
+                };
+                FetchItem::Section { section, peek, partial }
+            }
+            _ => return Err(format!("Unknown fetch item {}", String::from_utf8_lossy(name))),
+        };
+        Ok(item)
+    }
+
+    fn fetch_items(&mut self) -> Parsed<Vec<FetchItem>> {
+        // Macros of RFC 3501 section 6.4.5
+        let fast = vec![FetchItem::Flags, FetchItem::InternalDate, FetchItem::Size];
+        let start = self.position;
+        let name = self.take_while(|byte| byte.is_ascii_alphabetic());
+        match name.to_ascii_uppercase().as_slice() {
+            b"ALL" => return Ok([fast, vec![FetchItem::Envelope]].concat()),
+            b"FAST" => return Ok(fast),
+            b"FULL" => return Ok([fast, vec![FetchItem::Envelope, FetchItem::Body]].concat()),
+            _ => self.position = start,
+        }
+        if self.peek() != Some(b'(') {
+            return Ok(vec![self.fetch_item()?]);
+        }
+        self.expect(b'(')?;
+        let mut items = vec![self.fetch_item()?];
+        while self.peek() == Some(b' ') {
+            self.space()?;
+            items.push(self.fetch_item()?);
+        }
+        self.expect(b')')?;
+        Ok(items)
+    }
+
+    fn status_items(&mut self) -> Parsed<Vec<StatusItem>> {
+        self.expect(b'(')?;
+        let mut items = Vec::new();
+        loop {
+            items.push(match self.atom()?.to_ascii_uppercase().as_str() {
+                "MESSAGES" => StatusItem::Messages,
+                "RECENT" => StatusItem::Recent,
+                "UIDNEXT" => StatusItem::UidNext,
+                "UIDVALIDITY" => StatusItem::UidValidity,
+                "UNSEEN" => StatusItem::Unseen,
+                item => return Err(format!("Unknown status item {}", item)),
+            });
+            if self.peek() != Some(b' ') {
+                break;
+            }
+            self.space()?;
+        }
+        self.expect(b')')?;
+        Ok(items)
+    }
+
+    fn search_string(&mut self) -> Parsed<String> {
+        self.space()?;
+        self.astring()
+    }
+
+    fn search_key(&mut self) -> Parsed<SearchKey> {
+        if self.depth == MAX_SEARCH_DEPTH {
+            return Err("Search keys are nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let key = self.nested_search_key();
+        self.depth -= 1;
+        key
+    }
+
+    fn nested_search_key(&mut self) -> Parsed<SearchKey> {
+        if self.peek() == Some(b'(') {
+            self.position += 1;
+            let keys = self.search_keys()?;
+            self.expect(b')')?;
+            return Ok(SearchKey::And(keys));
+        }
+        if self.peek().is_some_and(|byte| byte.is_ascii_digit() || byte == b'*') {
+            return Ok(SearchKey::Sequence(self.sequence_set()?));
+        }
+        let key = match self.atom()?.to_ascii_uppercase().as_str() {
+            "ALL" => SearchKey::All,
+            "ANSWERED" => SearchKey::Flag(Flag::Answered, true),
+            "DELETED" => SearchKey::Flag(Flag::Deleted, true),
+            "DRAFT" => SearchKey::Flag(Flag::Draft, true),
+            "FLAGGED" => SearchKey::Flag(Flag::Flagged, true),
+            "SEEN" => SearchKey::Flag(Flag::Seen, true),
+            "UNANSWERED" => SearchKey::Flag(Flag::Answered, false),
+            "UNDELETED" => SearchKey::Flag(Flag::Deleted, false),
+            "UNDRAFT" => SearchKey::Flag(Flag::Draft, false),
+            "UNFLAGGED" => SearchKey::Flag(Flag::Flagged, false),
+            "UNSEEN" => SearchKey::Flag(Flag::Seen, false),
+            "NEW" => SearchKey::New,
+            "OLD" => SearchKey::Old,
+            "RECENT" => SearchKey::Recent,
+            "KEYWORD" => {
+                self.space()?;
+                self.atom()?;
+                SearchKey::Keyword(true)
+            }
+            "UNKEYWORD" => {
+                self.space()?;
+                self.atom()?;
+                SearchKey::Keyword(false)
+            }
+            name @ ("FROM" | "TO" | "CC" | "BCC" | "SUBJECT") => SearchKey::Header(name.to_string(), self.search_string()?),
+            "HEADER" => {
+                let name = self.search_string()?;
+                SearchKey::Header(name, self.search_string()?)
+            }
+            "BODY" => SearchKey::Body(self.search_string()?),
+            "TEXT" => SearchKey::Text(self.search_string()?),
+            "BEFORE" => SearchKey::Before(self.space().and_then(|_| self.date())?),
+            "ON" => SearchKey::On(self.space().and_then(|_| self.date())?),
+            "SINCE" => SearchKey::Since(self.space().and_then(|_| self.date())?),
+            "SENTBEFORE" => SearchKey::SentBefore(self.space().and_then(|_| self.date())?),
+            "SENTON" => SearchKey::SentOn(self.space().and_then(|_| self.date())?),
+            "SENTSINCE" => SearchKey::SentSince(self.space().and_then(|_| self.date())?),
+            "LARGER" => SearchKey::Larger(self.space().and_then(|_| self.number())?),
+            "SMALLER" => SearchKey::Smaller(self.space().and_then(|_| self.number())?),
+            "UID" => SearchKey::Uid(self.space().and_then(|_| self.sequence_set())?),
+            "NOT" => {
+                self.space()?;
+                SearchKey::Not(Box::new(self.search_key()?))
+            }
+            "OR" => {
+                self.space()?;
+                let left = self.search_key()?;
+                self.space()?;
+                SearchKey::Or(Box::new(left), Box::new(self.search_key()?))
+            }
+            key => return Err(format!("Unknown search key {}", key)),
+        };
+        Ok(key)
+    }
+
+    fn search_keys(&mut self) -> Parsed<Vec<SearchKey>> {
+        let mut keys = vec![self.search_key()?];
+        while self.peek() == Some(b' ') {
+            self.space()?;
+            keys.push(self.search_key()?);
+        }
+        Ok(keys)
+    }
+
+    fn search(&mut self) -> Parsed<SearchKey> {
+        // The first key may be a list or a sequence set rather than an atom
+        let start = self.position;
+        let has_charset = self.take_while(is_atom_char).eq_ignore_ascii_case(b"CHARSET") && self.peek() == Some(b' ');
+        if has_charset {
+            self.space()?;
+            let charset = self.astring()?;
+            if !["UTF-8", "US-ASCII"].iter().any(|known| known.eq_ignore_ascii_case(&charset)) {
+                return Err(format!("[BADCHARSET (UTF-8 US-ASCII)] Unsupported charset {}", charset));
+            }
+            self.space()?;
+        } else {
+            self.position = start;
+        }
+        Ok(SearchKey::And(self.search_keys()?))
+    }
+
+    /// Reads the tag, `None` when the line does not even have one
+    pub(super) fn tag(&mut self) -> Option<String> {
+        let tag = self.take_while(|byte| (is_atom_char(byte) && byte != b'+') || byte == b']');
+        (!tag.is_empty()).then(|| String::from_utf8_lossy(tag).into_owned())
+    }
+
+    /// Parses the command following the tag, refusing anything a client may not send before logging
+    /// in without reading its arguments
+    pub(super) fn command(&mut self, authenticated: bool) -> Parsed<Command> {
+        self.space()?;
+        let mut name = self.atom()?.to_ascii_uppercase();
+        if !authenticated && !UNAUTHENTICATED.contains(&name.as_str()) {
+            return Err("Log in first".to_string());
+        }
+        let uid = name == "UID";
+        if uid {
+            self.space()?;
+            name = self.atom()?.to_ascii_uppercase();
+            if !["FETCH", "SEARCH", "STORE", "COPY", "MOVE"].contains(&name.as_str()) {
+                return Err(format!("UID {} is not a command", name));
+            }
+        }
+        let command = match name.as_str() {
+            "CAPABILITY" => Command::Capability,
+            "NOOP" => Command::Noop,
+            "LOGOUT" => Command::Logout,
+            "STARTTLS" => Command::StartTls,
+            "CHECK" => Command::Check,
+            "CLOSE" => Command::Close,
+            "UNSELECT" => Command::Unselect,
+            "EXPUNGE" => Command::Expunge,
+            "IDLE" => Command::Idle,
+            "LOGIN" => {
+                self.space()?;
+                let username = self.astring()?;
+                self.space()?;
+                Command::Login {
+                    username,
+                    password: self.astring()?,
+                }
+            }
+            "SELECT" | "EXAMINE" => Command::Select {
+                mailbox: self.space().and_then(|_| self.astring())?,
+                read_only: name == "EXAMINE",
+            },
+            "CREATE" => Command::Create {
+                mailbox: self.space().and_then(|_| self.astring())?,
+            },
+            "DELETE" => Command::Delete {
+                mailbox: self.space().and_then(|_| self.astring())?,
+            },
+            "RENAME" => {
+                self.space()?;
+                self.astring()?;
+                self.space()?;
+                self.astring()?;
+                Command::Rename
+            }
+            "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                self.space()?;
+                self.astring()?;
+                Command::Subscribe
+            }
+            "LIST" | "LSUB" => {
+                self.space()?;
+                let reference = self.astring()?;
+                self.space()?;
+                Command::List {
+                    reference,
+                    pattern: self.list_mailbox()?,
+                    subscribed: name == "LSUB",
+                }
+            }
+            "STATUS" => {
+                self.space()?;
+                let mailbox = self.astring()?;
+                self.space()?;
+                Command::Status {
+                    mailbox,
+                    items: self.status_items()?,
+                }
+            }
+            "APPEND" => {
+                self.space()?;
+                let mailbox = self.astring()?;
+                self.space()?;
+                let flags = match self.peek() {
+                    Some(b'(') => {
+                        let flags = self.flags(false)?;
+                        self.space()?;
+                        flags
+                    }
+                    _ => Vec::new(),
+                };
+                let date = match self.peek() {
+                    Some(b'"') => {
+                        let text = String::from_utf8_lossy(&self.quoted()?).into_owned();
+                        self.space()?;
+                        Some(parse_date_time(&text).ok_or_else(|| format!("Invalid date-time {}", text))?)
+                    }
+                    _ => None,
+                };
+                Command::Append {
+                    mailbox,
+                    flags,
+                    date,
+                    message: self.literal()?,
+                }
+            }
+            "SEARCH" => Command::Search {
+                uid,
+                key: self.space().and_then(|_| self.search())?,
+            },
+            "FETCH" => {
+                self.space()?;
+                let set = self.sequence_set()?;
+                self.space()?;
+                Command::Fetch {
+                    uid,
+                    set,
+                    items: self.fetch_items()?,
+                }
+            }
+            "STORE" => {
+                self.space()?;
+                let set = self.sequence_set()?;
+                self.space()?;
+                let item = self.take_while(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'+' | b'-'));
+                let item = String::from_utf8_lossy(item).to_ascii_uppercase();
+                let (mode, rest) = match item.as_bytes().first() {
+                    Some(b'+') => (StoreMode::Add, &item[1..]),
+                    Some(b'-') => (StoreMode::Remove, &item[1..]),
+                    _ => (StoreMode::Replace, item.as_str()),
+                };
+                let silent = match rest {
+                    "FLAGS" => false,
+                    "FLAGS.SILENT" => true,
+                    _ => return Err(format!("Unknown store item {}", item)),
+                };
+                self.space()?;
+                Command::Store {
+                    uid,
+                    set,
+                    mode,
+                    silent,
+                    flags: self.flags(true)?,
+                }
+            }
+            "COPY" | "MOVE" => {
+                self.space()?;
+                let set = self.sequence_set()?;
+                self.space()?;
+                let mailbox = self.astring()?;
+                match name.as_str() {
+                    "COPY" => Command::Copy { uid, set, mailbox },
+                    _ => Command::Move { uid, set, mailbox },
+                }
+            }
+            _ => return Err(format!("Unknown command {}", name)),
+        };
+        self.end()?;
+        Ok(command)
+    }
+}
+
+/// `ATOM-CHAR` of RFC 3501, anything printable but the list, quoting and wildcard specials
+fn is_atom_char(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !matches!(byte, b'(' | b')' | b'{' | b'%' | b'*' | b'"' | b'\\' | b']')
+}
+
+/// `1-Feb-1994` as unix time at the start of the day in UTC
+pub(super) fn parse_date(text: &str) -> Option<i64> {
+    let mut parts = text.splitn(3, '-');
+    let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+    let month = MONTHS.iter().position(|known| known.eq_ignore_ascii_case(month))? + 1;
+    let day = day.trim().parse::<u32>().ok()?;
+    time::parse_date(&format!("{}-{:02}-{:02}", year, month, day))
+}
+
+/// `17-Jul-1996 02:44:25 -0700` as unix time
+fn parse_date_time(text: &str) -> Option<i64> {
+    let mut parts = text.split_whitespace();
+    let (date, clock, zone) = (parts.next()?, parts.next()?, parts.next()?);
+    let mut clock = clock.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+    let sign = match zone.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let zone = zone.get(1..)?.parse::<i64>().ok()?;
+    let offset = sign * (zone / 100 * 3600 + zone % 100 * 60);
+    Some(parse_date(date)? + hours * 3600 + minutes * 60 + seconds - offset)
+}
+
+/// Unix time as an IMAP `date-time`, e.g. `17-Jul-1996 02:44:25 +0000`
+pub(super) fn format_date_time(timestamp: i64) -> String {
+    let (year, month, day, hours, minutes, seconds) = time::civil(timestamp);
+    format!(
+        "{:>2}-{}-{} {:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hours,
+        minutes,
+        seconds
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &[u8]) -> Parsed<Command> {
+        let mut parser = Parser::new(line);
+        parser.tag().ok_or("Missing tag")?;
+        parser.command(true)
+    }
+
+    fn search(line: &str) -> Parsed<SearchKey> {
+        match parse(line.as_bytes())? {
+            Command::Search { key, .. } => Ok(key),
+            other => panic!("{} parsed as {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn parses_login_with_quoted_and_literal_strings() {
+        let command = parse(b"a1 LOGIN \"ann\" {6}\r\nsecret").unwrap();
+        assert!(matches!(command, Command::Login { username, password } if username == "ann" && password == "secret"));
+        let command = parse(b"a2 login ann \"with \\\"quote\\\"\"").unwrap();
+        assert!(matches!(command, Command::Login { password, .. } if password == "with \"quote\""));
+    }
+
+    #[test]
+    fn refuses_commands_before_login() {
+        let mut parser = Parser::new(b"a1 SELECT INBOX");
+        parser.tag().unwrap();
+        assert_eq!(parser.command(false).unwrap_err(), "Log in first");
+        // Arguments are not even looked at
+        let mut parser = Parser::new(b"a1 UID SEARCH (((");
+        parser.tag().unwrap();
+        assert_eq!(parser.command(false).unwrap_err(), "Log in first");
+        let mut parser = Parser::new(b"a1 CAPABILITY");
+        parser.tag().unwrap();
+        assert!(matches!(parser.command(false), Ok(Command::Capability)));
+    }
+
+    #[test]
+    fn parses_uid_commands_and_sequence_sets() {
+        let Command::Fetch { uid, set, items } = parse(b"a UID FETCH 1:3,7,9:* (FLAGS BODY.PEEK[HEADER])").unwrap() else {
+            panic!("not a fetch");
+        };
+        assert!(uid);
+        assert_eq!(items.len(), 2);
+        assert!(!items.iter().any(FetchItem::sets_seen));
+        for (number, expected) in [(1, true), (3, true), (5, false), (7, true), (9, true), (12, true)] {
+            assert_eq!(set.contains(number, 12), expected, "{}", number);
+        }
+        assert!(parse(b"a UID EXPUNGE").is_err());
+    }
+
+    #[test]
+    fn parses_store() {
+        let command = parse(b"a STORE 2 -FLAGS.SILENT (\\Seen \\Deleted)").unwrap();
+        assert!(matches!(
+            command,
+            Command::Store { mode: StoreMode::Remove, silent: true, flags, .. } if flags == [Flag::Seen, Flag::Deleted]
+        ));
+        // Keywords and \Recent are not kept, other system flags do not exist
+        assert!(matches!(parse(b"a STORE 2 +FLAGS (\\Recent $Label)"), Ok(Command::Store { flags, .. }) if flags.is_empty()));
+        assert!(parse(b"a STORE 2 FLAGS (\\Unknown)").is_err());
+    }
+
+    #[test]
+    fn parses_append_with_date() {
+        let command = parse(b"a APPEND Sent (\\Seen) \"17-Jul-1996 02:44:25 -0700\" {5}\r\nHello").unwrap();
+        let Command::Append { mailbox, flags, date, message } = command else {
+            panic!("not an append");
+        };
+        assert_eq!(mailbox, "Sent");
+        assert_eq!(flags, [Flag::Seen]);
+        assert_eq!(date, Some(837_596_665));
+        assert_eq!(message, b"Hello");
+    }
+
+    #[test]
+    fn parses_search_keys() {
+        let key = search("a SEARCH OR UNSEEN FROM \"ann\" NOT SINCE 1-Feb-1994").unwrap();
+        let SearchKey::And(keys) = key else { panic!("not a list") };
+        assert!(matches!(&keys[..], [SearchKey::Or(..), SearchKey::Not(_)]));
+        assert!(search("a SEARCH CHARSET UTF-8 SUBJECT lunch").is_ok());
+        assert!(search("a SEARCH CHARSET KOI8-R SUBJECT lunch").unwrap_err().starts_with("[BADCHARSET"));
+    }
+
+    #[test]
+    fn search_may_start_with_a_list_or_sequence_set() {
+        let SearchKey::And(keys) = search("a SEARCH (SEEN)").unwrap() else { panic!("not a list") };
+        assert!(matches!(&keys[..], [SearchKey::And(inner)] if matches!(inner[..], [SearchKey::Flag(Flag::Seen, true)])));
+        let SearchKey::And(keys) = search("a SEARCH *").unwrap() else { panic!("not a list") };
+        assert!(matches!(&keys[..], [SearchKey::Sequence(_)]));
+    }
+
+    #[test]
+    fn limits_search_nesting() {
+        let nested = format!("a SEARCH {}ALL{}", "(".repeat(4000), ")".repeat(4000));
+        assert_eq!(search(&nested).unwrap_err(), "Search keys are nested too deeply");
+        let negated = format!("a SEARCH {}ALL", "NOT ".repeat(MAX_SEARCH_DEPTH));
+        assert!(search(&negated).is_err());
+        let negated = format!("a SEARCH {}ALL", "NOT ".repeat(MAX_SEARCH_DEPTH - 1));
+        assert!(search(&negated).is_ok());
+    }
+
+    #[test]
+    fn rejects_trailing_arguments() {
+        assert_eq!(parse(b"a NOOP now").unwrap_err(), "Unexpected arguments");
+        assert_eq!(parse(b"a FROBNICATE").unwrap_err(), "Unknown command FROBNICATE");
+    }
+
+    #[test]
+    fn parses_and_formats_dates() {
+        assert_eq!(parse_date("1-Feb-1994"), Some(760_060_800));
+        assert_eq!(parse_date("31-Feb-1994"), None);
+        assert_eq!(parse_date("1-Foo-1994"), None);
+        assert_eq!(parse_date_time("17-Jul-1996 02:44:25 -0700"), Some(837_596_665));
+        assert_eq!(format_date_time(837_596_665), "17-Jul-1996 09:44:25 +0000");
+        assert_eq!(format_date_time(760_060_800), " 1-Feb-1994 00:00:00 +0000");
+    }
+}
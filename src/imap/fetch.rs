@@ -0,0 +1,404 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mail_parser::{Address, HeaderValue, MessageParser, MessagePart, MimeHeaders, PartType};
+
+use super::command::{format_date_time, FetchItem, Section, SectionText};
+use crate::{dkim::canonical, message::Flags, message::Message};
+
+/// Longest string sent quoted, anything longer goes out as a literal
+const MAX_QUOTED_LENGTH: usize = 1024;
+
+/// Writes an IMAP string, quoted when it is printable ASCII and a literal otherwise
+pub(super) fn string(out: &mut Vec<u8>, value: &[u8]) {
+    let quotable = value.len() <= MAX_QUOTED_LENGTH && value.iter().all(|&byte| (b' '..=b'~').contains(&byte));
+    if !quotable {
+        out.extend_from_slice(format!("{{{}}}\r\n", value.len()).as_bytes());
+        out.extend_from_slice(value);
+        return;
+    }
+    out.push(b'"');
+    for &byte in value {
+        if matches!(byte, b'"' | b'\\') {
+            out.push(b'\\');
+        }
+        out.push(byte);
+    }
+    out.push(b'"');
+}
+
+/// A string or `NIL`
+fn nstring(out: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => string(out, value),
+        None => out.extend_from_slice(b"NIL"),
+    }
+}
+
+/// A quoted string within a response line, for mailbox names and other short values
+pub(super) fn quote(value: &str) -> String {
+    let mut out = Vec::with_capacity(value.len() + 2);
+    string(&mut out, value.as_bytes());
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The flags of a message as a parenthesized list
+pub(super) fn flag_list(flags: Flags) -> String {
+    let names = [
+        (flags.seen, "\\Seen"),
+        (flags.answered, "\\Answered"),
+        (flags.flagged, "\\Flagged"),
+        (flags.deleted, "\\Deleted"),
+        (flags.draft, "\\Draft"),
+    ];
+    let set = names.iter().filter(|(set, _)| *set).map(|(_, name)| *name).collect::<Vec<_>>();
+    format!("({})", set.join(" "))
+}
+
+/// The raw value of a header field without the surrounding whitespace
+fn header<'a>(message: &'a mail_parser::Message, name: &'a str) -> Option<&'a [u8]> {
+    message.header_raw(name).map(|value| value.trim().as_bytes())
+}
+
+/// A display name as RFC 2047 requires it in a header, encoded when it is not ASCII
+fn encoded_name(name: &str) -> Vec<u8> {
+    match name.is_ascii() {
+        true => name.as_bytes().to_vec(),
+        false => format!("=?utf-8?b?{}?=", STANDARD.encode(name)).into_bytes(),
+    }
+}
+
+fn address(out: &mut Vec<u8>, name: Option<&str>, address: Option<&str>) {
+    out.push(b'(');
+    nstring(out, name.map(encoded_name).as_deref());
+    out.extend_from_slice(b" NIL ");
+    let (mailbox, host) = match address.and_then(|address| address.rsplit_once('@')) {
+        Some((mailbox, host)) => (Some(mailbox), Some(host)),
+        None => (address, None),
+    };
+    nstring(out, mailbox.map(str::as_bytes));
+    out.push(b' ');
+    nstring(out, host.map(str::as_bytes));
+    out.push(b')');
+}
+
+/// An address list of the envelope, groups marked the way RFC 3501 section 7.4.2 describes
+fn address_list(out: &mut Vec<u8>, addresses: Option<&Address>) {
+    let start = out.len();
+    out.push(b'(');
+    match addresses {
+        Some(Address::List(list)) => {
+            for entry in list {
+                address(out, entry.name(), entry.address());
+            }
+        }
+        Some(Address::Group(groups)) => {
+            for group in groups {
+                if let Some(name) = &group.name {
+                    address(out, None, Some(name));
+                }
+                for entry in &group.addresses {
+                    address(out, entry.name(), entry.address());
+                }
+                if group.name.is_some() {
+                    address(out, None, None);
+                }
+            }
+        }
+        None => {}
+    }
+    match out.len() - start {
+        // Nothing was written
+        1 => {
+            out.truncate(start);
+            out.extend_from_slice(b"NIL");
+        }
+        _ => out.push(b')'),
+    }
+}
+
+/// The ENVELOPE of a message, sender and reply-to default to the author as RFC 3501 requires
+fn envelope(out: &mut Vec<u8>, message: &mail_parser::Message) {
+    out.push(b'(');
+    nstring(out, header(message, "Date"));
+    out.push(b' ');
+    nstring(out, header(message, "Subject"));
+    for addresses in [
+        message.from(),
+        message.sender().or(message.from()),
+        message.reply_to().or(message.from()),
+        message.to(),
+        message.cc(),
+        message.bcc(),
+    ] {
+        out.push(b' ');
+        address_list(out, addresses);
+    }
+    out.push(b' ');
+    nstring(out, header(message, "In-Reply-To"));
+    out.push(b' ');
+    nstring(out, header(message, "Message-ID"));
+    out.push(b')');
+}
+
+/// Attribute pairs as a parenthesized list, `NIL` without any
+fn parameters(out: &mut Vec<u8>, attributes: Option<&[(std::borrow::Cow<str>, std::borrow::Cow<str>)]>) {
+    match attributes {
+        Some(attributes) if !attributes.is_empty() => {
+            out.push(b'(');
+            for (index, (name, value)) in attributes.iter().enumerate() {
+                if index > 0 {
+                    out.push(b' ');
+                }
+                string(out, name.to_ascii_uppercase().as_bytes());
+                out.push(b' ');
+                string(out, value.as_bytes());
+            }
+            out.push(b')');
+        }
+        _ => out.extend_from_slice(b"NIL"),
+    }
+}
+
+/// Disposition, language and location, the extension data every part ends with
+fn extension(out: &mut Vec<u8>, part: &MessagePart) {
+    out.push(b' ');
+    match part.content_disposition() {
+        Some(disposition) => {
+            out.push(b'(');
+            string(out, disposition.ctype().to_ascii_uppercase().as_bytes());
+            out.push(b' ');
+            parameters(out, disposition.attributes());
+            out.push(b')');
+        }
+        None => out.extend_from_slice(b"NIL"),
+    }
+    out.push(b' ');
+    match part.content_language() {
+        HeaderValue::Text(language) => string(out, language.as_bytes()),
+        HeaderValue::TextList(languages) => {
+            out.push(b'(');
+            for (index, language) in languages.iter().enumerate() {
+                if index > 0 {
+                    out.push(b' ');
+                }
+                string(out, language.as_bytes());
+            }
+            out.push(b')');
+        }
+        _ => out.extend_from_slice(b"NIL"),
+    }
+    out.push(b' ');
+    nstring(out, part.content_location().map(str::as_bytes));
+}
+
+/// The BODY or, with extension data, the BODYSTRUCTURE of a part and everything below it
+fn structure(out: &mut Vec<u8>, message: &mail_parser::Message, index: usize, extended: bool) {
+    let Some(part) = message.parts.get(index) else {
+        return out.extend_from_slice(b"NIL");
+    };
+    let content_type = part.content_type();
+    out.push(b'(');
+    if let PartType::Multipart(children) = &part.body {
+        for &child in children {
+            structure(out, message, child, extended);
+        }
+        out.push(b' ');
+        let subtype = content_type.and_then(|content_type| content_type.subtype()).unwrap_or("mixed");
+        string(out, subtype.to_ascii_uppercase().as_bytes());
+        if extended {
+            out.push(b' ');
+            parameters(out, content_type.and_then(|content_type| content_type.attributes()));
+            extension(out, part);
+        }
+        out.push(b')');
+        return;
+    }
+
+    // Parts without a Content-Type are plain ASCII text, or a message where one is expected
+    let (ctype, subtype) = match (content_type, &part.body) {
+        (Some(content_type), _) => (
+            content_type.ctype().to_string(),
+            content_type.subtype().unwrap_or_default().to_string(),
+        ),
+        (None, PartType::Message(_)) => ("message".to_string(), "rfc822".to_string()),
+        (None, _) => ("text".to_string(), "plain".to_string()),
+    };
+    string(out, ctype.to_ascii_uppercase().as_bytes());
+    out.push(b' ');
+    string(out, subtype.to_ascii_uppercase().as_bytes());
+    out.push(b' ');
+    match content_type.and_then(|content_type| content_type.attributes()) {
+        Some(attributes) => parameters(out, Some(attributes)),
+        None if ctype == "text" => out.extend_from_slice(b"(\"CHARSET\" \"US-ASCII\")"),
+        None => out.extend_from_slice(b"NIL"),
+    }
+    out.push(b' ');
+    nstring(out, part.content_id().map(|id| format!("<{}>", id).into_bytes()).as_deref());
+    out.push(b' ');
+    nstring(out, part.content_description().map(str::as_bytes));
+    out.push(b' ');
+    let encoding = part.content_transfer_encoding().unwrap_or("7bit");
+    string(out, encoding.to_ascii_uppercase().as_bytes());
+    let body = message
+        .raw_message
+        .get(part.offset_body..part.offset_end)
+        .unwrap_or_default();
+    let lines = body.iter().filter(|&&byte| byte == b'\n').count();
+    out.extend_from_slice(format!(" {}", body.len()).as_bytes());
+    match &part.body {
+        PartType::Message(nested) => {
+            out.push(b' ');
+            envelope(out, nested);
+            out.push(b' ');
+            structure(out, nested, 0, extended);
+            out.extend_from_slice(format!(" {}", lines).as_bytes());
+        }
+        _ if ctype.eq_ignore_ascii_case("text") => out.extend_from_slice(format!(" {}", lines).as_bytes()),
+        _ => {}
+    }
+    if extended {
+        // No MD5 is ever computed
+        out.extend_from_slice(b" NIL");
+        extension(out, part);
+    }
+    out.push(b')');
+}
+
+/// The message and index of the part a section path leads to, `1` being the body of a
+/// message that is not multipart
+fn locate<'a, 'x>(message: &'a mail_parser::Message<'x>, path: &[u32]) -> Option<(&'a mail_parser::Message<'x>, usize)> {
+    let (mut message, mut index) = (message, 0);
+    for (depth, &number) in path.iter().enumerate() {
+        // Numbers after an encapsulated message continue within it
+        if depth > 0 {
+            if let PartType::Message(nested) = &message.parts.get(index)?.body {
+                message = nested;
+                index = 0;
+            }
+        }
+        match &message.parts.get(index)?.body {
+            PartType::Multipart(children) => index = *children.get((number as usize).checked_sub(1)?)?,
+            _ if number == 1 => {}
+            _ => return None,
+        }
+    }
+    Some((message, index))
+}
+
+/// The header of a message with the blank line ending it, or only some of its fields
+fn header_section(raw: &[u8], fields: Option<(bool, &[String])>) -> Vec<u8> {
+    let (all, body) = canonical::split(raw);
+    let Some((not, names)) = fields else {
+        return raw[..raw.len() - body.len()].to_vec();
+    };
+    let mut out = Vec::new();
+    for field in all {
+        if names.iter().any(|name| name.eq_ignore_ascii_case(&field.name)) != not {
+            out.extend_from_slice(field.raw);
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// The HEADER, HEADER.FIELDS or TEXT of a whole message
+fn message_section(raw: &[u8], text: &SectionText) -> Vec<u8> {
+    match text {
+        SectionText::Header | SectionText::Mime => header_section(raw, None),
+        SectionText::HeaderFields { not, fields } => header_section(raw, Some((*not, fields))),
+        SectionText::Text => canonical::split(raw).1.to_vec(),
+    }
+}
+
+/// The contents of a section, empty when the message has no such part
+fn section(raw: &[u8], parsed: Option<&mail_parser::Message>, section: &Section) -> Vec<u8> {
+    if section.part.is_empty() {
+        return match &section.text {
+            None => raw.to_vec(),
+            Some(text) => message_section(raw, text),
+        };
+    }
+    let Some((message, index)) = parsed.and_then(|parsed| locate(parsed, &section.part)) else {
+        return Vec::new();
+    };
+    let part = &message.parts[index];
+    let source = &message.raw_message;
+    match (&section.text, &part.body) {
+        (None, _) => source.get(part.offset_body..part.offset_end).unwrap_or_default().to_vec(),
+        (Some(SectionText::Mime), _) => source.get(part.offset_header..part.offset_body).unwrap_or_default().to_vec(),
+        // The encapsulated message is the body of its part
+        (Some(text), PartType::Message(_)) => {
+            message_section(source.get(part.offset_body..part.offset_end).unwrap_or_default(), text)
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Renders the data items of a FETCH response, without the surrounding parentheses
+pub(super) fn render(items: &[FetchItem], uid: u32, message: &Message, raw: Option<&[u8]>) -> Vec<u8> {
+    let raw = raw.unwrap_or_default();
+    let needs_parsing = items.iter().any(|item| match item {
+        FetchItem::Envelope | FetchItem::Body | FetchItem::BodyStructure => true,
+        FetchItem::Section { section, .. } => !section.part.is_empty(),
+        _ => false,
+    });
+    let parsed = needs_parsing.then(|| MessageParser::default().parse(raw)).flatten();
+
+    let mut out = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            out.push(b' ');
+        }
+        match item {
+            FetchItem::Uid => out.extend_from_slice(format!("UID {}", uid).as_bytes()),
+            FetchItem::Flags => out.extend_from_slice(format!("FLAGS {}", flag_list(message.flags)).as_bytes()),
+            FetchItem::InternalDate => {
+                out.extend_from_slice(format!("INTERNALDATE \"{}\"", format_date_time(message.received_at)).as_bytes())
+            }
+            FetchItem::Size => out.extend_from_slice(format!("RFC822.SIZE {}", message.size).as_bytes()),
+            FetchItem::Envelope => {
+                out.extend_from_slice(b"ENVELOPE ");
+                match &parsed {
+                    Some(parsed) => envelope(&mut out, parsed),
+                    None => out.extend_from_slice(b"(NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL)"),
+                }
+            }
+            FetchItem::Body | FetchItem::BodyStructure => {
+                let extended = matches!(item, FetchItem::BodyStructure);
+                out.extend_from_slice(if extended { b"BODYSTRUCTURE " } else { b"BODY " });
+                match &parsed {
+                    Some(parsed) => structure(&mut out, parsed, 0, extended),
+                    None => out.extend_from_slice(b"(\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 0 0)"),
+                }
+            }
+            FetchItem::Rfc822 => {
+                out.extend_from_slice(b"RFC822 ");
+                string(&mut out, raw);
+            }
+            FetchItem::Rfc822Header => {
+                out.extend_from_slice(b"RFC822.HEADER ");
+                string(&mut out, &header_section(raw, None));
+            }
+            FetchItem::Rfc822Text => {
+                out.extend_from_slice(b"RFC822.TEXT ");
+                string(&mut out, canonical::split(raw).1);
+            }
+            FetchItem::Section { section: requested, partial, .. } => {
+                let contents = section(raw, parsed.as_ref(), requested);
+                out.extend_from_slice(format!("BODY[{}]", requested.name()).as_bytes());
+                match partial {
+                    Some((offset, length)) => {
+                        let start = (*offset as usize).min(contents.len());
+                        let end = start.saturating_add(*length as usize).min(contents.len());
+                        out.extend_from_slice(format!("<{}> ", offset).as_bytes());
+                        string(&mut out, &contents[start..end]);
+                    }
+                    None => {
+                        out.push(b' ');
+                        string(&mut out, &contents);
+                    }
+                }
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,128 @@
+mod command;
+mod fetch;
+mod search;
+mod session;
+mod utf7;
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use futures::future;
+use rustls::ServerConfig;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{auth::Users, config::ImapConfig, folder::Folders, message::MessageStore};
+use session::Session;
+
+#[derive(Error, Debug)]
+pub enum ImapError {
+    #[error("Could not bind to {host}:{port} because {source}")]
+    Bind {
+        host: String,
+        port: u16,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// State shared by every session of the listener
+struct Context {
+    /// Domain announced in the greeting
+    domain: String,
+    /// Largest message accepted by APPEND, the same as over SMTP
+    max_message_size: usize,
+    tls: Option<TlsAcceptor>,
+    users: Users,
+    folders: Folders,
+    messages: MessageStore,
+}
+
+async fn bind(host: &str, port: u16) -> Result<TcpListener, ImapError> {
+    let listener = TcpListener::bind((host, port))
+        .await
+        .map_err(|source| ImapError::Bind {
+            host: host.to_string(),
+            port,
+            source,
+        })?;
+    log::info!("IMAP listening on {}:{}", host, port);
+    Ok(listener)
+}
+
+/// Starts the IMAP listeners, giving users access to the folders of their mailbox
+pub async fn listen(
+    config: ImapConfig,
+    domain: String,
+    max_message_size: usize,
+    tls: Option<Arc<ServerConfig>>,
+    users: Users,
+    folders: Folders,
+    messages: MessageStore,
+) -> Result<(), ImapError> {
+    let plain = bind(&config.bind_address, config.port).await?;
+    let implicit = match (&tls, config.tls_port) {
+        (Some(_), Some(port)) => Some(bind(&config.bind_address, port).await?),
+        (None, Some(port)) => {
+            log::warn!("Not listening for IMAPS on port {} since TLS is not configured", port);
+            None
+        }
+        _ => None,
+    };
+    if tls.is_none() {
+        log::warn!("No certificate configured, IMAP passwords will be sent in the clear");
+    }
+
+    let context = Arc::new(Context {
+        domain,
+        max_message_size,
+        tls: tls.map(TlsAcceptor::from),
+        users,
+        folders,
+        messages,
+    });
+
+    match implicit {
+        Some(implicit) => {
+            future::join(accept(plain, context.clone(), false), accept(implicit, context, true)).await;
+        }
+        None => accept(plain, context, false).await,
+    }
+    Ok(())
+}
+
+/// Accepts connections until the process exits, spawning a session for each of them
+async fn accept(listener: TcpListener, context: Arc<Context>, implicit_tls: bool) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::error!("Could not accept IMAP connection: {}", error);
+                continue;
+            }
+        };
+        log::info!("New IMAP connection from {}", peer);
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve(context, stream, peer, implicit_tls).await {
+                log::warn!("IMAP session with {} ended: {}", peer, error);
+            }
+        });
+    }
+}
+
+/// Runs a session, completing the TLS handshake first on the IMAPS port
+async fn serve(
+    context: Arc<Context>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    implicit_tls: bool,
+) -> io::Result<()> {
+    match (implicit_tls, context.tls.clone()) {
+        (true, Some(acceptor)) => {
+            let stream = acceptor.accept(stream).await?;
+            Session::new(context, Box::new(stream), peer, true).run().await
+        }
+        _ => Session::new(context, Box::new(stream), peer, false).run().await,
+    }
+}
@@ -0,0 +1,96 @@
+use super::command::{Flag, SearchKey};
+use crate::{
+    message::{Address, Message},
+    time,
+};
+
+/// A message of the selected folder as SEARCH looks at it
+pub(super) struct Candidate<'a> {
+    pub(super) sequence: u32,
+    pub(super) uid: u32,
+    /// Sequence number and UID `*` stands for
+    pub(super) largest_sequence: u32,
+    pub(super) largest_uid: u32,
+    pub(super) message: &'a Message,
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+fn addresses_contain(addresses: &[Address], needle: &str) -> bool {
+    addresses.iter().any(|address| {
+        address.name.as_deref().is_some_and(|name| contains(name, needle))
+            || address.address.as_deref().is_some_and(|address| contains(address, needle))
+    })
+}
+
+/// Whether any header field of that name contains the value, any such field at all for ``
+fn header_contains(message: &Message, name: &str, needle: &str) -> bool {
+    message
+        .headers
+        .iter()
+        .any(|header| header.name.eq_ignore_ascii_case(name) && contains(&header.value, needle))
+}
+
+fn body_contains(message: &Message, needle: &str) -> bool {
+    [&message.text, &message.html]
+        .into_iter()
+        .any(|body| body.as_deref().is_some_and(|body| contains(body, needle)))
+}
+
+/// Start of the day the message was written on, as its Date header tells it
+fn sent_day(message: &Message) -> Option<i64> {
+    time::parse_date(message.date.as_deref()?.get(..10)?)
+}
+
+/// Whether a message matches a search key
+pub(super) fn matches(key: &SearchKey, candidate: &Candidate) -> bool {
+    let message = candidate.message;
+    let received_day = message.received_at.div_euclid(86400) * 86400;
+    match key {
+        SearchKey::All | SearchKey::Old => true,
+        // Nothing is recent, no other session has seen new messages first
+        SearchKey::New | SearchKey::Recent => false,
+        SearchKey::Keyword(set) => !set,
+        SearchKey::Flag(flag, set) => {
+            let flags = message.flags;
+            let value = match flag {
+                Flag::Seen => flags.seen,
+                Flag::Answered => flags.answered,
+                Flag::Flagged => flags.flagged,
+                Flag::Deleted => flags.deleted,
+                Flag::Draft => flags.draft,
+            };
+            value == *set
+        }
+        SearchKey::Header(name, needle) => match name.to_ascii_uppercase().as_str() {
+            "FROM" => addresses_contain(&message.from, needle),
+            "TO" => addresses_contain(&message.to, needle),
+            "CC" => addresses_contain(&message.cc, needle),
+            "SUBJECT" => message.subject.as_deref().is_some_and(|subject| contains(subject, needle)),
+            _ => header_contains(message, name, needle),
+        },
+        SearchKey::Body(needle) => body_contains(message, needle),
+        SearchKey::Text(needle) => {
+            body_contains(message, needle)
+                || message
+                    .headers
+                    .iter()
+                    .any(|header| contains(&header.value, needle))
+        }
+        SearchKey::Before(day) => received_day < *day,
+        SearchKey::On(day) => received_day == *day,
+        SearchKey::Since(day) => received_day >= *day,
+        SearchKey::SentBefore(day) => sent_day(message).is_some_and(|sent| sent < *day),
+        SearchKey::SentOn(day) => sent_day(message).is_some_and(|sent| sent == *day),
+        SearchKey::SentSince(day) => sent_day(message).is_some_and(|sent| sent >= *day),
+        SearchKey::Larger(size) => message.size > *size as usize,
+        SearchKey::Smaller(size) => message.size < *size as usize,
+        SearchKey::Uid(set) => set.contains(candidate.uid, candidate.largest_uid),
+        SearchKey::Sequence(set) => set.contains(candidate.sequence, candidate.largest_sequence),
+        SearchKey::Not(key) => !matches(key, candidate),
+        SearchKey::Or(left, right) => matches(left, candidate) || matches(right, candidate),
+        SearchKey::And(keys) => keys.iter().all(|key| matches(key, candidate)),
+    }
+}
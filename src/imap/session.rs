@@ -0,0 +1,929 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::broadcast::{
+        self,
+        error::{RecvError, TryRecvError},
+    },
+    time::timeout,
+};
+
+use super::{
+    command::{Command, FetchItem, Flag, Parser, SearchKey, SequenceSet, StatusItem, StoreMode},
+    fetch,
+    search::{self, Candidate},
+    utf7, Context,
+};
+use crate::{
+    auth::AuthError,
+    db::DatabaseError,
+    folder::{self, FolderError, ARCHIVE, INBOX, SENT, SPAM, TRASH},
+    message::{Envelope, Filed, FlagChanges, Flags, Message},
+};
+
+/// Longest line accepted, literals are read separately
+const MAX_LINE_LENGTH: u64 = 8192;
+/// Largest literal accepted before logging in, enough for a username or password
+const MAX_UNAUTHENTICATED_LITERAL: usize = 1024;
+/// How long a client may stay silent, RFC 3501 section 5.4 asks for at least 30 minutes
+const READ_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Extensions offered to every client, STARTTLS only until the connection is encrypted
+const CAPABILITIES: &str = "IMAP4rev1 IDLE MOVE SPECIAL-USE UNSELECT";
+const FLAGS: &str = "(\\Seen \\Answered \\Flagged \\Deleted \\Draft)";
+
+/// Sessions are shared across awaits by reference, so the stream has to be `Sync` as well
+pub(super) trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+/// The folder a session has open
+struct Selected {
+    folder: String,
+    /// Opened with EXAMINE, flags stay as they are
+    read_only: bool,
+    /// Messages in sequence number order, as the client last heard of them
+    messages: Vec<Filed>,
+}
+
+/// Why a command could not complete
+enum Failure {
+    Io(io::Error),
+    Database(DatabaseError),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Failure::Io(error)
+    }
+}
+
+impl From<DatabaseError> for Failure {
+    fn from(error: DatabaseError) -> Self {
+        Failure::Database(error)
+    }
+}
+
+/// Text of the tagged response completing a command
+type Outcome = Result<String, Failure>;
+
+/// Flags to change so a message ends up with the listed ones, added to or removed from its own
+fn flag_changes(flags: &[Flag], mode: StoreMode) -> FlagChanges {
+    let value = |flag| match (mode, flags.contains(&flag)) {
+        (StoreMode::Replace, listed) => Some(listed),
+        (StoreMode::Add, true) => Some(true),
+        (StoreMode::Remove, true) => Some(false),
+        _ => None,
+    };
+    FlagChanges {
+        seen: value(Flag::Seen),
+        flagged: value(Flag::Flagged),
+        answered: value(Flag::Answered),
+        draft: value(Flag::Draft),
+        deleted: value(Flag::Deleted),
+    }
+}
+
+/// A folder as IMAP clients see it, `INBOX` in capitals as RFC 3501 requires
+fn imap_name(folder: &str) -> String {
+    match folder == INBOX {
+        true => "INBOX".to_string(),
+        false => utf7::encode(folder),
+    }
+}
+
+/// The RFC 6154 attribute telling clients what a system folder is for
+fn special_use(folder: &str) -> Option<&'static str> {
+    match folder {
+        ARCHIVE => Some("\\Archive"),
+        SENT => Some("\\Sent"),
+        SPAM => Some("\\Junk"),
+        TRASH => Some("\\Trash"),
+        _ => None,
+    }
+}
+
+/// Pattern positions a wildcard at a reachable position lets the match skip ahead to
+fn skip_wildcards(pattern: &[u8], states: &mut [bool]) {
+    for index in 0..pattern.len() {
+        if states[index] && matches!(pattern[index], b'*' | b'%') {
+            states[index + 1] = true;
+        }
+    }
+}
+
+/// Matches a LIST pattern, `*` standing for anything and `%` for anything but the delimiter.
+/// Every position of the pattern the name could have reached is tracked at once, so patterns full
+/// of wildcards take time proportional to pattern times name length rather than backtracking.
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    let mut states = vec![false; pattern.len() + 1];
+    states[0] = true;
+    skip_wildcards(pattern, &mut states);
+    for &byte in name {
+        let mut next = vec![false; pattern.len() + 1];
+        for (index, &expected) in pattern.iter().enumerate() {
+            if !states[index] {
+                continue;
+            }
+            match expected {
+                b'*' => next[index] = true,
+                b'%' => next[index] = byte != b'/',
+                expected => next[index + 1] |= expected.eq_ignore_ascii_case(&byte),
+            }
+        }
+        skip_wildcards(pattern, &mut next);
+        states = next;
+    }
+    states[pattern.len()]
+}
+
+/// Length of the literal announced at the end of a line, and whether the client waits for a
+/// continuation before sending it
+fn literal_length(line: &[u8]) -> Option<(usize, bool)> {
+    let inner = line.strip_suffix(b"}")?;
+    let start = inner.iter().rposition(|&byte| byte == b'{')?;
+    let digits = &inner[start + 1..];
+    let (digits, synchronizing) = match digits.strip_suffix(b"+") {
+        Some(digits) => (digits, false),
+        None => (digits, true),
+    };
+    let length = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    Some((length, synchronizing))
+}
+
+/// A single IMAP connection
+pub(super) struct Session {
+    context: Arc<Context>,
+    stream: BufReader<Box<dyn Stream>>,
+    peer: SocketAddr,
+    encrypted: bool,
+    /// Mailbox of the user who logged in
+    user: Option<String>,
+    selected: Option<Selected>,
+    /// Wakes up IDLE when a mailbox changes
+    changes: broadcast::Receiver<String>,
+}
+
+impl Session {
+    pub(super) fn new(
+        context: Arc<Context>,
+        stream: Box<dyn Stream>,
+        peer: SocketAddr,
+        encrypted: bool,
+    ) -> Self {
+        let changes = context.messages.subscribe();
+        Session {
+            context,
+            stream: BufReader::new(stream),
+            peer,
+            encrypted,
+            user: None,
+            selected: None,
+            changes,
+        }
+    }
+
+    /// Drives the session until the client logs out or disconnects
+    pub(super) async fn run(mut self) -> io::Result<()> {
+        let greeting = format!(
+            "* OK [CAPABILITY {}] {} feathermail IMAP4rev1 ready",
+            self.capabilities(),
+            self.context.domain
+        );
+        self.send(greeting.as_bytes()).await?;
+
+        loop {
+            let Some(line) = self.read_command().await? else {
+                return Ok(());
+            };
+            let mut parser = Parser::new(&line);
+            let Some(tag) = parser.tag() else {
+                self.send(b"* BAD Missing tag").await?;
+                continue;
+            };
+            let command = match parser.command(self.user.is_some()) {
+                Ok(command) => command,
+                Err(error) => {
+                    let status = if error.starts_with("[BADCHARSET") { "NO" } else { "BAD" };
+                    self.respond(&tag, &format!("{} {}", status, error)).await?;
+                    continue;
+                }
+            };
+
+            match command {
+                Command::Logout => {
+                    self.send(b"* BYE Logging out").await?;
+                    return self.respond(&tag, "OK LOGOUT completed").await;
+                }
+                Command::StartTls => {
+                    self.starttls(&tag).await?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            // RFC 3501 section 7.4.1 forbids EXPUNGE responses while these run
+            let expunge = !matches!(
+                command,
+                Command::Fetch { .. } | Command::Store { .. } | Command::Search { .. }
+            );
+            let mut outcome = self.handle(command).await;
+            // Tell the client what changed meanwhile before the command completes
+            if outcome.is_ok() {
+                if let Err(failure) = self.refresh(expunge).await {
+                    outcome = Err(failure);
+                }
+            }
+            let reply = match outcome {
+                Ok(reply) => reply,
+                Err(Failure::Io(error)) => return Err(error),
+                Err(Failure::Database(error)) => {
+                    log::error!("Db Interaction Error: {}", error);
+                    "NO [SERVERBUG] Internal error".to_string()
+                }
+            };
+            self.respond(&tag, &reply).await?;
+        }
+    }
+
+    fn capabilities(&self) -> String {
+        match !self.encrypted && self.context.tls.is_some() {
+            true => format!("{} STARTTLS LOGINDISABLED", CAPABILITIES),
+            false => CAPABILITIES.to_string(),
+        }
+    }
+
+    /// Mailbox of the logged in user, only called once there is one
+    fn user(&self) -> String {
+        self.user.clone().unwrap_or_default()
+    }
+
+    async fn handle(&mut self, command: Command) -> Outcome {
+        let authenticated = matches!(
+            command,
+            Command::Select { .. }
+                | Command::Create { .. }
+                | Command::Delete { .. }
+                | Command::Rename
+                | Command::Subscribe
+                | Command::List { .. }
+                | Command::Status { .. }
+                | Command::Append { .. }
+                | Command::Idle
+        );
+        let selected = matches!(
+            command,
+            Command::Check
+                | Command::Close
+                | Command::Unselect
+                | Command::Expunge
+                | Command::Search { .. }
+                | Command::Fetch { .. }
+                | Command::Store { .. }
+                | Command::Copy { .. }
+                | Command::Move { .. }
+        );
+        if (authenticated || selected) && self.user.is_none() {
+            return Ok("BAD Log in first".to_string());
+        }
+        if selected && self.selected.is_none() {
+            return Ok("BAD Select a mailbox first".to_string());
+        }
+
+        match command {
+            Command::Capability => {
+                let capabilities = format!("* CAPABILITY {}", self.capabilities());
+                self.send(capabilities.as_bytes()).await?;
+                Ok("OK CAPABILITY completed".to_string())
+            }
+            Command::Noop => Ok("OK NOOP completed".to_string()),
+            Command::Check => Ok("OK CHECK completed".to_string()),
+            Command::Login { username, password } => self.login(&username, password).await,
+            Command::Select { mailbox, read_only } => self.select(&mailbox, read_only).await,
+            Command::Create { mailbox } => self.create(&mailbox).await,
+            Command::Delete { mailbox } => self.delete(&mailbox).await,
+            Command::Rename => Ok("NO [CANNOT] Folders cannot be renamed".to_string()),
+            Command::Subscribe => Ok("OK Every folder is subscribed".to_string()),
+            Command::List {
+                reference,
+                pattern,
+                subscribed,
+            } => self.list(&reference, &pattern, subscribed).await,
+            Command::Status { mailbox, items } => self.status(&mailbox, &items).await,
+            Command::Append {
+                mailbox,
+                flags,
+                date,
+                message,
+            } => self.append(&mailbox, &flags, date, &message).await,
+            Command::Close => {
+                if !self.selected.as_ref().is_some_and(|selected| selected.read_only) {
+                    self.expunge().await?;
+                }
+                self.selected = None;
+                Ok("OK CLOSE completed".to_string())
+            }
+            Command::Unselect => {
+                self.selected = None;
+                Ok("OK UNSELECT completed".to_string())
+            }
+            Command::Expunge => {
+                if self.read_only() {
+                    return Ok("NO [READ-ONLY] Mailbox is read-only".to_string());
+                }
+                self.expunge().await?;
+                Ok("OK EXPUNGE completed".to_string())
+            }
+            Command::Search { uid, key } => self.search(uid, &key).await,
+            Command::Fetch { uid, set, items } => self.fetch(uid, &set, items).await,
+            Command::Store {
+                uid,
+                set,
+                mode,
+                silent,
+                flags,
+            } => self.store(uid, &set, flag_changes(&flags, mode), silent).await,
+            Command::Copy { uid, set, mailbox } => self.copy(uid, &set, &mailbox, false).await,
+            Command::Move { uid, set, mailbox } => self.copy(uid, &set, &mailbox, true).await,
+            Command::Idle => self.idle().await,
+            // Handled before the session state matters
+            Command::Logout | Command::StartTls => Ok("BAD Unexpected command".to_string()),
+        }
+    }
+
+    async fn starttls(&mut self, tag: &str) -> io::Result<()> {
+        if self.encrypted {
+            return self.respond(tag, "BAD Already running TLS").await;
+        }
+        if self.user.is_some() {
+            return self.respond(tag, "BAD Already logged in").await;
+        }
+        let Some(acceptor) = self.context.tls.clone() else {
+            return self.respond(tag, "NO TLS not available").await;
+        };
+        self.respond(tag, "OK Begin TLS negotiation now").await?;
+
+        // Anything the client pipelined before the handshake is discarded, as RFC 3501 requires
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Box::new(tokio::io::empty())))
+            .into_inner();
+        let stream = acceptor.accept(stream).await?;
+        self.stream = BufReader::new(Box::new(stream));
+        self.encrypted = true;
+        Ok(())
+    }
+
+    async fn login(&mut self, username: &str, password: String) -> Outcome {
+        if self.user.is_some() {
+            return Ok("BAD Already logged in".to_string());
+        }
+        if !self.encrypted && self.context.tls.is_some() {
+            return Ok("NO [PRIVACYREQUIRED] Run STARTTLS first".to_string());
+        }
+        match self.context.users.authenticate(username, password).await {
+            Ok(user) => {
+                log::info!("{} logged in over IMAP from {}", user, self.peer);
+                self.user = Some(user);
+                Ok(format!("OK [CAPABILITY {}] Logged in", self.capabilities()))
+            }
+            Err(AuthError::InvalidCredentials) => {
+                log::warn!("Failed IMAP login for {} from {}", username, self.peer);
+                Ok("NO [AUTHENTICATIONFAILED] Invalid username or password".to_string())
+            }
+            Err(AuthError::Database(error)) => Err(error.into()),
+            Err(error) => {
+                log::error!("Could not check the password of {}: {}", username, error);
+                Ok("NO [UNAVAILABLE] Could not check password".to_string())
+            }
+        }
+    }
+
+    /// The folder a mailbox name refers to, `None` when there is no such folder
+    async fn resolve(&self, name: &str) -> Result<Option<String>, DatabaseError> {
+        let Some(name) = utf7::decode(name) else {
+            return Ok(None);
+        };
+        let name = name.trim_end_matches('/');
+        // Only INBOX is case-insensitive in IMAP, but every folder name is here
+        match self.context.folders.resolve(&self.user(), name).await {
+            Ok(folder) => Ok(Some(folder)),
+            Err(FolderError::Database(error)) => Err(error),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.selected.as_ref().is_some_and(|selected| selected.read_only)
+    }
+
+    async fn select(&mut self, mailbox: &str, read_only: bool) -> Outcome {
+        // A failed SELECT leaves no folder selected
+        self.selected = None;
+        let Some(folder) = self.resolve(mailbox).await? else {
+            return Ok("NO [NONEXISTENT] No such mailbox".to_string());
+        };
+        let user = self.user();
+        let messages = self.context.messages.filed(&user, &folder)?;
+        let uids = self.context.messages.uids(&user, &folder)?;
+
+        let mut responses = vec![
+            format!("* FLAGS {}", FLAGS),
+            format!("* {} EXISTS", messages.len()),
+            "* 0 RECENT".to_string(),
+        ];
+        if let Some(unseen) = messages.iter().position(|filed| !filed.flags.seen) {
+            responses.push(format!("* OK [UNSEEN {}] First unseen", unseen + 1));
+        }
+        let permanent = if read_only { "()" } else { FLAGS };
+        responses.push(format!("* OK [PERMANENTFLAGS {}] Flags kept", permanent));
+        responses.push(format!("* OK [UIDVALIDITY {}] UIDs valid", uids.validity));
+        responses.push(format!("* OK [UIDNEXT {}] Predicted next UID", uids.next));
+        for response in responses {
+            self.send(response.as_bytes()).await?;
+        }
+
+        self.selected = Some(Selected {
+            folder,
+            read_only,
+            messages,
+        });
+        Ok(match read_only {
+            true => "OK [READ-ONLY] EXAMINE completed".to_string(),
+            false => "OK [READ-WRITE] SELECT completed".to_string(),
+        })
+    }
+
+    async fn create(&mut self, mailbox: &str) -> Outcome {
+        let Some(name) = utf7::decode(mailbox) else {
+            return Ok("NO Invalid mailbox name".to_string());
+        };
+        match self.context.folders.create(&self.user(), name.trim_end_matches('/')).await {
+            Ok(_) => Ok("OK CREATE completed".to_string()),
+            Err(FolderError::Database(error)) => Err(error.into()),
+            Err(error @ FolderError::Exists(_)) => Ok(format!("NO [ALREADYEXISTS] {}", error)),
+            Err(error) => Ok(format!("NO {}", error)),
+        }
+    }
+
+    /// Removes an empty custom folder, the REST API refuses to delete messages with a folder too
+    async fn delete(&mut self, mailbox: &str) -> Outcome {
+        let Some(folder) = self.resolve(mailbox).await? else {
+            return Ok("NO [NONEXISTENT] No such mailbox".to_string());
+        };
+        if folder::system_folder(&folder).is_some() {
+            return Ok(format!("NO [CANNOT] {}", FolderError::System(folder)));
+        }
+        let user = self.user();
+        if !self.context.messages.folder_is_empty(&user, &folder)? {
+            return Ok(format!("NO [CANNOT] {}", FolderError::NotEmpty(folder)));
+        }
+        match self.context.folders.remove(&user, &folder).await {
+            Ok(()) => {}
+            Err(FolderError::Database(error)) => return Err(error.into()),
+            Err(error) => return Ok(format!("NO {}", error)),
+        }
+        if self.selected.as_ref().is_some_and(|selected| selected.folder == folder) {
+            self.selected = None;
+        }
+        Ok("OK DELETE completed".to_string())
+    }
+
+    async fn list(&mut self, reference: &str, pattern: &str, subscribed: bool) -> Outcome {
+        let name = if subscribed { "LSUB" } else { "LIST" };
+        // An empty pattern asks for the hierarchy delimiter
+        if pattern.is_empty() {
+            let response = format!("* {} (\\Noselect) \"/\" \"\"", name);
+            self.send(response.as_bytes()).await?;
+            return Ok(format!("OK {} completed", name));
+        }
+        let pattern = format!("{}{}", reference, pattern);
+        let folders = self
+            .context
+            .folders
+            .list(&self.user())
+            .await?
+            .into_iter()
+            .map(|folder| (imap_name(&folder.name), special_use(&folder.name)))
+            .collect::<Vec<_>>();
+
+        for (folder, special) in &folders {
+            if !wildcard(pattern.as_bytes(), folder.as_bytes()) {
+                continue;
+            }
+            let parent = format!("{}/", folder);
+            let children = folders.iter().any(|(other, _)| other.starts_with(&parent));
+            let mut attributes = vec![if children { "\\HasChildren" } else { "\\HasNoChildren" }];
+            // RFC 6154 only adds special-use attributes to LIST
+            attributes.extend(special.filter(|_| !subscribed));
+            let response = format!("* {} ({}) \"/\" {}", name, attributes.join(" "), fetch::quote(folder));
+            self.send(response.as_bytes()).await?;
+        }
+        Ok(format!("OK {} completed", name))
+    }
+
+    async fn status(&mut self, mailbox: &str, items: &[StatusItem]) -> Outcome {
+        let Some(folder) = self.resolve(mailbox).await? else {
+            return Ok("NO [NONEXISTENT] No such mailbox".to_string());
+        };
+        let user = self.user();
+        let counts = self.context.messages.folder_counts(&user, &folder)?;
+        let uids = self.context.messages.uids(&user, &folder)?;
+        let values = items
+            .iter()
+            .map(|item| match item {
+                StatusItem::Messages => format!("MESSAGES {}", counts.messages),
+                StatusItem::Recent => "RECENT 0".to_string(),
+                StatusItem::UidNext => format!("UIDNEXT {}", uids.next),
+                StatusItem::UidValidity => format!("UIDVALIDITY {}", uids.validity),
+                StatusItem::Unseen => format!("UNSEEN {}", counts.unread),
+            })
+            .collect::<Vec<_>>();
+        let response = format!("* STATUS {} ({})", fetch::quote(&imap_name(&folder)), values.join(" "));
+        self.send(response.as_bytes()).await?;
+        Ok("OK STATUS completed".to_string())
+    }
+
+    /// Files a message the client uploads, e.g. a draft or a copy of sent mail
+    async fn append(&mut self, mailbox: &str, flags: &[Flag], date: Option<i64>, raw: &[u8]) -> Outcome {
+        let Some(folder) = self.resolve(mailbox).await? else {
+            return Ok("NO [TRYCREATE] No such mailbox".to_string());
+        };
+        let envelope = Envelope {
+            client_ip: Some(self.peer.ip()),
+            helo: String::new(),
+            mail_from: String::new(),
+            rcpt_to: Vec::new(),
+        };
+        let (mut message, attachments) = Message::parse(envelope, raw);
+        message.envelope.mail_from = message
+            .from
+            .iter()
+            .find_map(|address| address.address.clone())
+            .unwrap_or_default();
+        message.mailbox = Some(self.user());
+        message.folder = folder;
+        message.flags = flag_changes(flags, StoreMode::Replace).apply(Flags::default());
+        if let Some(date) = date {
+            message.received_at = date;
+        }
+        self.context.messages.insert(&message, raw, &attachments).await?;
+        Ok("OK APPEND completed".to_string())
+    }
+
+    /// Deletes the messages of the selected folder flagged `\Deleted`
+    async fn expunge(&mut self) -> Result<(), Failure> {
+        let Some(selected) = &self.selected else {
+            return Ok(());
+        };
+        for filed in self.context.messages.filed(&self.user(), &selected.folder)? {
+            if !filed.flags.deleted {
+                continue;
+            }
+            match self.context.messages.delete(&filed.id).await {
+                // Someone else was faster
+                Ok(()) | Err(DatabaseError::NotFound) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Positions in the selected folder a set of sequence numbers or UIDs refers to
+    fn targets(&self, uid: bool, set: &SequenceSet) -> Vec<usize> {
+        let Some(selected) = &self.selected else {
+            return Vec::new();
+        };
+        let messages = &selected.messages;
+        match uid {
+            true => {
+                let largest = messages.last().map_or(0, |filed| filed.uid);
+                (0..messages.len())
+                    .filter(|&index| set.contains(messages[index].uid, largest))
+                    .collect()
+            }
+            false => (0..messages.len())
+                .filter(|&index| set.contains(index as u32 + 1, messages.len() as u32))
+                .collect(),
+        }
+    }
+
+    async fn search(&mut self, uid: bool, key: &SearchKey) -> Outcome {
+        let Some(selected) = &self.selected else {
+            return Ok("BAD Select a mailbox first".to_string());
+        };
+        let largest_sequence = selected.messages.len() as u32;
+        let largest_uid = selected.messages.last().map_or(0, |filed| filed.uid);
+        let mut found = Vec::new();
+        for (index, filed) in selected.messages.iter().enumerate() {
+            let message = match self.context.messages.get(&filed.id).await {
+                Ok(message) => message,
+                Err(DatabaseError::NotFound) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let candidate = Candidate {
+                sequence: index as u32 + 1,
+                uid: filed.uid,
+                largest_sequence,
+                largest_uid,
+                message: &message,
+            };
+            if search::matches(key, &candidate) {
+                found.push(if uid { filed.uid } else { index as u32 + 1 });
+            }
+        }
+        let response = found.iter().fold("* SEARCH".to_string(), |response, number| {
+            format!("{} {}", response, number)
+        });
+        self.send(response.as_bytes()).await?;
+        Ok("OK SEARCH completed".to_string())
+    }
+
+    async fn fetch(&mut self, uid: bool, set: &SequenceSet, mut items: Vec<FetchItem>) -> Outcome {
+        // UID FETCH always reports the UID
+        if uid && !items.iter().any(|item| matches!(item, FetchItem::Uid)) {
+            items.insert(0, FetchItem::Uid);
+        }
+        let sets_seen = !self.read_only() && items.iter().any(FetchItem::sets_seen);
+        let needs_raw = items
+            .iter()
+            .any(|item| !matches!(item, FetchItem::Uid | FetchItem::Flags | FetchItem::InternalDate | FetchItem::Size));
+
+        for index in self.targets(uid, set) {
+            let Some(filed) = self.selected.as_ref().map(|selected| selected.messages[index].clone()) else {
+                break;
+            };
+            let mut message = match self.context.messages.get(&filed.id).await {
+                Ok(message) => message,
+                // Expunged meanwhile, the client hears about it once it may
+                Err(DatabaseError::NotFound) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let raw = match needs_raw {
+                true => match self.context.messages.raw(&filed.id).await {
+                    Ok(raw) => Some(raw),
+                    Err(DatabaseError::NotFound) => continue,
+                    Err(error) => return Err(error.into()),
+                },
+                false => None,
+            };
+            let mut response_items = items.clone();
+            if sets_seen && !message.flags.seen {
+                let changes = FlagChanges {
+                    seen: Some(true),
+                    ..FlagChanges::default()
+                };
+                message = self.context.messages.set_flags(&filed.id, &changes).await?;
+                // RFC 3501 section 6.4.5 has the server report flags it changed
+                if !items.iter().any(|item| matches!(item, FetchItem::Flags)) {
+                    response_items.push(FetchItem::Flags);
+                }
+            }
+            if let Some(selected) = &mut self.selected {
+                selected.messages[index].flags = message.flags;
+            }
+
+            let mut response = format!("* {} FETCH (", index + 1).into_bytes();
+            response.extend(fetch::render(&response_items, filed.uid, &message, raw.as_deref()));
+            response.push(b')');
+            self.send(&response).await?;
+        }
+        Ok("OK FETCH completed".to_string())
+    }
+
+    async fn store(&mut self, uid: bool, set: &SequenceSet, changes: FlagChanges, silent: bool) -> Outcome {
+        if self.read_only() {
+            return Ok("NO [READ-ONLY] Mailbox is read-only".to_string());
+        }
+        for index in self.targets(uid, set) {
+            let Some(filed) = self.selected.as_ref().map(|selected| selected.messages[index].clone()) else {
+                break;
+            };
+            let message = match self.context.messages.set_flags(&filed.id, &changes).await {
+                Ok(message) => message,
+                Err(DatabaseError::NotFound) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            if let Some(selected) = &mut self.selected {
+                selected.messages[index].flags = message.flags;
+            }
+            if !silent {
+                let uid = match uid {
+                    true => format!("UID {} ", filed.uid),
+                    false => String::new(),
+                };
+                let response = format!("* {} FETCH ({}FLAGS {})", index + 1, uid, fetch::flag_list(message.flags));
+                self.send(response.as_bytes()).await?;
+            }
+        }
+        Ok("OK STORE completed".to_string())
+    }
+
+    /// Copies messages to another folder, or moves them there when `moving`
+    async fn copy(&mut self, uid: bool, set: &SequenceSet, mailbox: &str, moving: bool) -> Outcome {
+        let Some(folder) = self.resolve(mailbox).await? else {
+            return Ok("NO [TRYCREATE] No such mailbox".to_string());
+        };
+        if moving && self.read_only() {
+            return Ok("NO [READ-ONLY] Mailbox is read-only".to_string());
+        }
+        for index in self.targets(uid, set) {
+            let Some(id) = self.selected.as_ref().map(|selected| selected.messages[index].id.clone()) else {
+                break;
+            };
+            let result = match moving {
+                true => self.context.messages.move_to(&id, &folder).await.map(|_| ()),
+                false => self.context.messages.copy_to(&id, &folder).await.map(|_| ()),
+            };
+            match result {
+                Ok(()) | Err(DatabaseError::NotFound) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(format!("OK {} completed", if moving { "MOVE" } else { "COPY" }))
+    }
+
+    /// Reports changes to the selected folder as they happen until the client sends DONE
+    async fn idle(&mut self) -> Outcome {
+        self.send(b"+ idling").await?;
+        // Changes up to now were reported when the previous command completed
+        while let Ok(_) | Err(TryRecvError::Lagged(_)) = self.changes.try_recv() {}
+
+        let user = self.user();
+        let mut line = Vec::new();
+        let mut listening = true;
+        loop {
+            tokio::select! {
+                // The line is kept outside the future, so nothing read is lost when a change wins
+                read = timeout(READ_TIMEOUT, async {
+                    (&mut self.stream).take(MAX_LINE_LENGTH).read_until(b'\n', &mut line).await
+                }) => {
+                    let read = read.map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                    if read == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    if line.ends_with(b"\n") {
+                        break;
+                    }
+                }
+                change = self.changes.recv(), if listening => match change {
+                    Ok(mailbox) if mailbox != user => {}
+                    Ok(_) | Err(RecvError::Lagged(_)) => self.refresh(true).await?,
+                    Err(RecvError::Closed) => listening = false,
+                },
+            }
+        }
+        match String::from_utf8_lossy(&line).trim().eq_ignore_ascii_case("DONE") {
+            true => Ok("OK IDLE terminated".to_string()),
+            false => Ok("BAD Expected DONE".to_string()),
+        }
+    }
+
+    /// Brings the client's view of the selected folder up to date with what is stored, leaving
+    /// messages deleted meanwhile in place unless it may hear about them
+    async fn refresh(&mut self, expunge: bool) -> Result<(), Failure> {
+        let user = self.user();
+        let Some(selected) = &mut self.selected else {
+            return Ok(());
+        };
+        // Both lists are ordered by UID
+        let current = self.context.messages.filed(&user, &selected.folder)?;
+        let mut responses = Vec::new();
+        if expunge {
+            // Highest first, so every number is still right when the client applies it
+            for index in (0..selected.messages.len()).rev() {
+                let uid = selected.messages[index].uid;
+                if current.binary_search_by_key(&uid, |filed| filed.uid).is_err() {
+                    selected.messages.remove(index);
+                    responses.push(format!("* {} EXPUNGE", index + 1));
+                }
+            }
+        }
+        for (index, known) in selected.messages.iter_mut().enumerate() {
+            let Ok(position) = current.binary_search_by_key(&known.uid, |filed| filed.uid) else {
+                continue;
+            };
+            if current[position].flags != known.flags {
+                known.flags = current[position].flags;
+                responses.push(format!("* {} FETCH (FLAGS {})", index + 1, fetch::flag_list(known.flags)));
+            }
+        }
+        let last = selected.messages.last().map_or(0, |filed| filed.uid);
+        let before = selected.messages.len();
+        selected.messages.extend(current.into_iter().filter(|filed| filed.uid > last));
+        if selected.messages.len() != before {
+            responses.push(format!("* {} EXISTS", selected.messages.len()));
+        }
+        for response in responses {
+            self.send(response.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads a command with the literals it carries, `None` once the client disconnected
+    async fn read_command(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // A whole message may only be appended once logged in, before that a short line and credentials will do
+        let limit = match self.user {
+            Some(_) => self.context.max_message_size + MAX_LINE_LENGTH as usize,
+            None => MAX_LINE_LENGTH as usize,
+        };
+        let mut command = Vec::new();
+        loop {
+            let Some(line) = self.read_line().await? else {
+                return Ok(None);
+            };
+            if !line.ends_with(b"\n") {
+                self.send(b"* BYE Line too long").await?;
+                return Ok(None);
+            }
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            command.extend_from_slice(line);
+            if command.len() > limit {
+                self.send(b"* BYE Command too long").await?;
+                return Ok(None);
+            }
+            let Some((length, synchronizing)) = literal_length(line) else {
+                return Ok(Some(command));
+            };
+
+            if command.len() + length > limit || (self.user.is_none() && length > MAX_UNAUTHENTICATED_LITERAL) {
+                if !synchronizing {
+                    // The literal is on its way already, there is no telling where it ends
+                    self.send(b"* BYE Literal too large").await?;
+                    return Ok(None);
+                }
+                let tag = Parser::new(&command).tag().unwrap_or_else(|| "*".to_string());
+                self.respond(&tag, "NO [TOOBIG] Literal too large").await?;
+                command.clear();
+                continue;
+            }
+            command.extend_from_slice(b"\r\n");
+            if synchronizing {
+                self.send(b"+ Ready for literal data").await?;
+            }
+            let start = command.len();
+            command.resize(start + length, 0);
+            timeout(READ_TIMEOUT, self.stream.read_exact(&mut command[start..]))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        }
+    }
+
+    /// Reads one line including its terminator, `None` once the client disconnected
+    async fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let read = timeout(
+            READ_TIMEOUT,
+            (&mut self.stream).take(MAX_LINE_LENGTH).read_until(b'\n', &mut line),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        Ok((read > 0).then_some(line))
+    }
+
+    async fn respond(&mut self, tag: &str, response: &str) -> io::Result<()> {
+        self.send(format!("{} {}", tag, response).as_bytes()).await
+    }
+
+    async fn send(&mut self, response: &[u8]) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(response).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_list_patterns() {
+        assert!(wildcard(b"*", b"Archive/2024"));
+        assert!(wildcard(b"%", b"Archive"));
+        assert!(!wildcard(b"%", b"Archive/2024"));
+        assert!(wildcard(b"Archive/%", b"archive/2024"));
+        assert!(wildcard(b"A*4", b"Archive/2024"));
+        assert!(wildcard(b"%/%", b"Archive/2024"));
+        assert!(!wildcard(b"%/%", b"Archive/2024/Q1"));
+        assert!(wildcard(b"*%", b"Archive/2024"));
+        assert!(wildcard(b"", b""));
+        assert!(!wildcard(b"", b"Inbox"));
+        assert!(!wildcard(b"Inbox", b"Inbox/Old"));
+    }
+
+    #[test]
+    fn wildcards_do_not_backtrack() {
+        let pattern = [b"*".repeat(4000), b"x".to_vec()].concat();
+        let name = b"a".repeat(200);
+        assert!(!wildcard(&pattern, &name));
+        let pattern = [b"%*".repeat(2000), b"a".to_vec()].concat();
+        assert!(wildcard(&pattern, &name));
+    }
+
+    #[test]
+    fn reads_literal_lengths() {
+        assert_eq!(literal_length(b"a LOGIN {3}"), Some((3, true)));
+        assert_eq!(literal_length(b"a APPEND Inbox {120+}"), Some((120, false)));
+        assert_eq!(literal_length(b"a LOGIN ann {x}"), None);
+        assert_eq!(literal_length(b"a NOOP"), None);
+    }
+}
@@ -0,0 +1,73 @@
+use base64::{
+    alphabet::IMAP_MUTF7,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+
+/// Base64 with `,` for `/` and without padding, as modified UTF-7 uses it
+const ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &IMAP_MUTF7,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::RequireNone)
+        .with_decode_allow_trailing_bits(true),
+);
+
+fn flush(encoded: &mut String, pending: &mut Vec<u16>) {
+    if pending.is_empty() {
+        return;
+    }
+    let bytes = pending.drain(..).flat_map(u16::to_be_bytes).collect::<Vec<_>>();
+    encoded.push('&');
+    encoded.push_str(&ENGINE.encode(bytes));
+    encoded.push('-');
+}
+
+/// Writes a mailbox name in the modified UTF-7 of RFC 3501 section 5.1.3
+pub(super) fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    let mut pending = Vec::new();
+    for c in name.chars() {
+        match c {
+            '&' => {
+                flush(&mut encoded, &mut pending);
+                encoded.push_str("&-");
+            }
+            ' '..='~' => {
+                flush(&mut encoded, &mut pending);
+                encoded.push(c);
+            }
+            _ => pending.extend(c.encode_utf16(&mut [0; 2]).iter()),
+        }
+    }
+    flush(&mut encoded, &mut pending);
+    encoded
+}
+
+/// Reads a mailbox name sent in modified UTF-7, `None` when it is not valid
+pub(super) fn decode(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let shifted = &rest[start + 1..];
+        let end = shifted.find('-')?;
+        match end {
+            0 => decoded.push('&'),
+            _ => {
+                let bytes = ENGINE.decode(&shifted[..end]).ok()?;
+                if bytes.len() % 2 != 0 {
+                    return None;
+                }
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect::<Vec<_>>();
+                decoded.push_str(&String::from_utf16(&units).ok()?);
+            }
+        }
+        rest = &shifted[end + 1..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
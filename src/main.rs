@@ -1,14 +1,127 @@
-use std::{env, io};
+use std::io;
+mod api;
+mod auth;
+mod config;
 mod db;
+mod delivery;
+mod dkim;
+mod dns;
+mod domain;
+mod folder;
+mod imap;
+mod message;
+mod outbound;
+mod routing;
+mod smtp;
+mod time;
+mod tls;
+mod verify;
+mod webhook;
 
-#[actix_web]
+use api::AppState;
+use auth::{ApiKeys, Users};
+use config::Config;
+use delivery::LocalDelivery;
+use dkim::DkimKeys;
+use dns::Resolver;
+use domain::Domains;
+use folder::Folders;
+use futures::TryFutureExt;
+use message::MessageStore;
+use outbound::Outbound;
+use routing::Router;
+use tls::Tls;
+use verify::Verifier;
+use webhook::Webhook;
+
+/// Logs a fatal startup error in readable form before handing it back to `main`
+fn startup_error<E>(error: E) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    log::error!("Could not start feathermail: {}", error);
+    io::Error::other(error)
+}
+
+#[actix_web::main]
 async fn main() -> io::Result<()> {
     env_logger::init();
 
-    let SSL_FULLCHAIN=env::var("SSL_FULLCHAIN").unwrap_or_default();
-    let SSL_PRIVKEY=env::var("SSL_PRIVKEY").unwrap_or_default();
-    let BIND_ADDRESS=env::var("BIND_ADDRESS").unwrap_or("localhost");
-    let WEBHOOK_URL=env::var("WEBHOOK_URL").unwrap_or_default();
+    // rustls ships with more than one crypto provider in this tree, settle on ring
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let config = Config::load().map_err(startup_error)?;
+
+    let database = sled::open(&config.database_path).map_err(startup_error)?;
+    let messages = MessageStore::open(&database).map_err(startup_error)?;
+    messages.index_existing().await.map_err(startup_error)?;
+    let keys = ApiKeys::open(&database).map_err(startup_error)?;
+    keys.bootstrap().await.map_err(startup_error)?;
+    let users = Users::open(&database, config.api.session_lifetime).map_err(startup_error)?;
+    let domains = Domains::open(&database).map_err(startup_error)?;
+    let folders = Folders::open(&database).map_err(startup_error)?;
+    let router = Router::open(&database, users.clone(), domains.clone()).map_err(startup_error)?;
+    let webhook = Webhook::open(&database, config.webhook.url, config.webhook.secret).map_err(startup_error)?;
+    actix_web::rt::spawn(webhook.clone().run());
+    let dkim = DkimKeys::open(&database, domains.clone()).map_err(startup_error)?;
+    let resolver = Resolver::open(&config.dns).map_err(startup_error)?;
+    let delivery = LocalDelivery::new(messages.clone(), router.clone(), webhook.clone());
+    let outbound = Outbound::open(
+        &database,
+        config.relay,
+        resolver.clone(),
+        config.smtp.domain.clone(),
+        users.clone(),
+        domains.clone(),
+        dkim.clone(),
+        delivery.clone(),
+    )
+    .map_err(startup_error)?;
+    actix_web::rt::spawn(outbound.clone().run());
+
+    let tls = match &config.tls {
+        Some(paths) => Some(Tls::load(&paths.fullchain, &paths.privkey).map_err(startup_error)?),
+        None => None,
+    };
+    if let Some(tls) = &tls {
+        actix_web::rt::spawn(tls.clone().watch());
+    }
+    let tls = tls.map(|tls| tls.config());
+
+    let imap = imap::listen(
+        config.imap,
+        config.smtp.domain.clone(),
+        config.smtp.max_message_size,
+        tls.clone(),
+        users.clone(),
+        folders.clone(),
+        messages.clone(),
+    )
+    .map_err(startup_error);
 
+    let api = api::serve(
+        &config.api.bind_address,
+        config.api.port,
+        AppState {
+            messages,
+            keys,
+            users,
+            domains,
+            folders,
+            router: router.clone(),
+            webhook,
+            outbound: outbound.clone(),
+            dkim,
+        },
+        tls.clone(),
+    )?;
 
-}
\ No newline at end of file
+    // Whichever service stops first, on error or on a shutdown signal, ends the process
+    let verifier = Verifier::new(resolver, config.smtp.domain.clone());
+    let smtp = smtp::listen(config.smtp, tls, router, delivery, verifier, outbound).map_err(startup_error);
+    tokio::select! {
+        result = smtp => result,
+        result = imap => result,
+        result = api => result,
+    }
+}
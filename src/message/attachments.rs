@@ -0,0 +1,91 @@
+use actix_web::web::Bytes;
+use futures::{stream, Stream};
+use sled::{Db, Transactional, Tree};
+
+use crate::db::DatabaseError;
+
+/// Attachment contents are split into chunks of this size so they can be streamed
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Attachment contents addressed by their SHA-256 hash and shared between messages.
+/// This keeps large contents out of the message records, the raw sources still hold every
+/// attachment in its transfer encoding.
+#[derive(Clone)]
+pub struct AttachmentStore {
+    chunks: Tree,
+    references: Tree,
+}
+
+fn chunk_key(hash: &str, index: usize) -> String {
+    format!("{}/{:08}", hash, index)
+}
+
+fn decode_count(value: Option<sled::IVec>) -> u64 {
+    value
+        .and_then(|value| value.as_ref().try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or_default()
+}
+
+impl AttachmentStore {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(AttachmentStore {
+            chunks: database.open_tree("attachment_chunks")?,
+            references: database.open_tree("attachment_references")?,
+        })
+    }
+
+    /// Adds a reference to the contents, storing them if they are not known yet
+    pub async fn insert(&self, hash: &str, contents: &[u8]) -> Result<(), DatabaseError> {
+        (&self.chunks, &self.references).transaction(|(chunks, references)| {
+            let count = decode_count(references.get(hash)?);
+            if count == 0 {
+                for (index, chunk) in contents.chunks(CHUNK_SIZE).enumerate() {
+                    chunks.insert(chunk_key(hash, index).as_str(), chunk)?;
+                }
+            }
+            references.insert(hash, &(count + 1).to_be_bytes())?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Adds a reference to contents already stored, for another copy of a message
+    pub async fn retain(&self, hash: &str) -> Result<(), DatabaseError> {
+        self.references.update_and_fetch(hash, |count| {
+            let count = decode_count(count.map(Into::into));
+            Some((count + 1).to_be_bytes().to_vec())
+        })?;
+        Ok(())
+    }
+
+    /// Drops a reference to the contents, removing them once nothing refers to them
+    pub async fn release(&self, hash: &str, size: usize) -> Result<(), DatabaseError> {
+        (&self.chunks, &self.references).transaction(|(chunks, references)| {
+            let count = decode_count(references.get(hash)?);
+            if count > 1 {
+                references.insert(hash, &(count - 1).to_be_bytes())?;
+                return Ok(());
+            }
+            references.remove(hash)?;
+            for index in 0..size.div_ceil(CHUNK_SIZE) {
+                chunks.remove(chunk_key(hash, index).as_str())?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Streams the contents chunk by chunk without loading them into memory at once
+    pub fn stream(&self, hash: &str) -> impl Stream<Item = Result<Bytes, DatabaseError>> {
+        let chunks = self.chunks.scan_prefix(format!("{}/", hash));
+        stream::iter(chunks.map(|chunk| {
+            chunk
+                .map(|(_, value)| Bytes::copy_from_slice(&value))
+                .map_err(|error| {
+                    log::error!("Db Interaction Error: {}", error);
+                    DatabaseError::Get
+                })
+        }))
+    }
+}
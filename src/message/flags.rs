@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// State a client keeps per message, named after the IMAP system flags (RFC 3501 section 2.3.2)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// Read by the user, new mail starts out unseen
+    pub seen: bool,
+    /// Marked for attention
+    pub flagged: bool,
+    /// Replied to
+    pub answered: bool,
+    /// Not finished composing
+    pub draft: bool,
+    /// Marked for removal, the message stays until it is deleted
+    pub deleted: bool,
+}
+
+/// Flags to change, the ones left out keep their value
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct FlagChanges {
+    pub seen: Option<bool>,
+    pub flagged: Option<bool>,
+    pub answered: Option<bool>,
+    pub draft: Option<bool>,
+    pub deleted: Option<bool>,
+}
+
+impl FlagChanges {
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_none()
+            && self.flagged.is_none()
+            && self.answered.is_none()
+            && self.draft.is_none()
+            && self.deleted.is_none()
+    }
+
+    pub fn apply(&self, flags: Flags) -> Flags {
+        Flags {
+            seen: self.seen.unwrap_or(flags.seen),
+            flagged: self.flagged.unwrap_or(flags.flagged),
+            answered: self.answered.unwrap_or(flags.answered),
+            draft: self.draft.unwrap_or(flags.draft),
+            deleted: self.deleted.unwrap_or(flags.deleted),
+        }
+    }
+}
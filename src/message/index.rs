@@ -0,0 +1,442 @@
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use sled::{Db, IVec, Tree};
+
+use super::{Flags, Message};
+use crate::{db::DatabaseError, time};
+
+/// Order of a listing
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// By the time the message was received
+    Date,
+    /// By sender address, then by date
+    Sender,
+}
+
+/// Which page of the messages to list
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub sort: Sort,
+    pub descending: bool,
+    /// Only messages from this sender address
+    pub from: Option<String>,
+    /// Only messages in this user's mailbox, `None` for all of them
+    pub mailbox: Option<String>,
+    pub folder: Option<String>,
+    pub label: Option<String>,
+    /// Only messages not seen yet
+    pub unread: bool,
+    pub limit: usize,
+    /// Position after which the page starts, as returned with the previous page
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// Ids of one page of messages, with the cursor of the next page if there is one
+pub struct Page {
+    pub ids: Vec<String>,
+    pub next_cursor: Option<Vec<u8>>,
+}
+
+/// What every index entry holds about its message, enough to filter without loading it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    mailbox: Option<String>,
+    sender: String,
+    folder: String,
+    labels: Vec<String>,
+    flags: Flags,
+    uid: u32,
+}
+
+/// UIDs of a folder as IMAP clients see them (RFC 3501 section 2.3.1.1)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Uids {
+    /// Changes only if UIDs were ever reused, which they are not
+    pub validity: u32,
+    /// The UID the next message filed in the folder gets
+    pub next: u32,
+}
+
+/// A message of a folder as listed by UID
+#[derive(Debug, Clone)]
+pub struct Filed {
+    pub uid: u32,
+    pub id: String,
+    pub flags: Flags,
+}
+
+/// How many messages a folder holds
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct FolderCounts {
+    pub messages: usize,
+    pub unread: usize,
+}
+
+impl Entry {
+    fn new(message: &Message) -> Self {
+        Entry {
+            mailbox: message.mailbox.clone(),
+            sender: sender(message),
+            folder: message.folder.clone(),
+            labels: message.labels.clone(),
+            flags: message.flags,
+            uid: message.uid,
+        }
+    }
+
+    fn matches(&self, listing: &Listing) -> bool {
+        listing.mailbox.as_ref().is_none_or(|mailbox| self.mailbox.as_ref() == Some(mailbox))
+            && listing.from.as_ref().is_none_or(|from| from.eq_ignore_ascii_case(&self.sender))
+            && listing.folder.as_ref().is_none_or(|folder| *folder == self.folder)
+            && listing.label.as_ref().is_none_or(|label| self.labels.contains(label))
+            && !(listing.unread && self.flags.seen)
+    }
+}
+
+/// Address a message is listed under, the author if there is one and the envelope sender otherwise
+fn sender(message: &Message) -> String {
+    message
+        .from
+        .iter()
+        .find_map(|address| address.address.as_deref())
+        .unwrap_or(&message.envelope.mail_from)
+        .to_lowercase()
+}
+
+/// Received time and id, fixed width so keys sort by time first
+fn position(message: &Message, id: &str) -> String {
+    format!("{:020}{}", message.received_at.max(0), id)
+}
+
+/// Secondary indexes over stored messages, ordered by date within each
+#[derive(Clone)]
+pub struct MessageIndex {
+    by_date: Tree,
+    by_sender: Tree,
+    by_mailbox: Tree,
+    by_folder: Tree,
+    by_label: Tree,
+    /// `mailbox\0folder\0uid id` of every message, in the order IMAP clients number them
+    by_uid: Tree,
+    /// `mailbox\0folder` to its `Uids`
+    uids: Tree,
+}
+
+impl MessageIndex {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(MessageIndex {
+            by_date: database.open_tree("messages_by_date")?,
+            by_sender: database.open_tree("messages_by_sender")?,
+            by_mailbox: database.open_tree("messages_by_mailbox")?,
+            by_folder: database.open_tree("messages_by_folder")?,
+            by_label: database.open_tree("messages_by_label")?,
+            by_uid: database.open_tree("messages_by_uid")?,
+            uids: database.open_tree("folder_uids")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_date.is_empty()
+    }
+
+    /// Every tree and key a message is listed under
+    fn keys(&self, message: &Message, id: &str) -> Vec<(&Tree, String)> {
+        let position = position(message, id);
+        let mailbox = message.mailbox.as_deref().unwrap_or_default();
+        let mut keys = vec![
+            (&self.by_date, position.clone()),
+            (&self.by_sender, format!("{}\0{}", sender(message), position)),
+            (&self.by_mailbox, format!("{}\0{}", mailbox, position)),
+            (&self.by_folder, format!("{}\0{}\0{}", mailbox, message.folder, position)),
+            (&self.by_uid, format!("{}\0{}\0{:010}{}", mailbox, message.folder, message.uid, id)),
+        ];
+        for label in &message.labels {
+            keys.push((&self.by_label, format!("{}\0{}\0{}", mailbox, label, position)));
+        }
+        keys
+    }
+
+    pub fn add(&self, id: &str, message: &Message) -> Result<(), DatabaseError> {
+        let entry = bincode::serialize(&Entry::new(message)).map_err(|error| {
+            log::error!("Db Interaction Error: {}", error);
+            DatabaseError::Serialize
+        })?;
+        for (tree, key) in self.keys(message, id) {
+            tree.insert(key, entry.as_slice())?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str, message: &Message) -> Result<(), DatabaseError> {
+        for (tree, key) in self.keys(message, id) {
+            tree.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Picks the tree whose keys narrow the listing down the most
+    fn source(&self, listing: &Listing) -> (&Tree, String) {
+        if listing.sort == Sort::Sender {
+            return match &listing.from {
+                Some(from) => (&self.by_sender, format!("{}\0", from.to_lowercase())),
+                None => (&self.by_sender, String::new()),
+            };
+        }
+        match (&listing.mailbox, &listing.label, &listing.folder, &listing.from) {
+            (Some(mailbox), Some(label), _, _) => (&self.by_label, format!("{}\0{}\0", mailbox, label)),
+            (Some(mailbox), None, Some(folder), _) => (&self.by_folder, format!("{}\0{}\0", mailbox, folder)),
+            (_, _, _, Some(from)) => (&self.by_sender, format!("{}\0", from.to_lowercase())),
+            (Some(mailbox), None, None, None) => (&self.by_mailbox, format!("{}\0", mailbox)),
+            (None, _, _, None) => (&self.by_date, String::new()),
+        }
+    }
+
+    /// Walks the index fitting the listing with a range scan, starting after the cursor
+    pub fn page(&self, listing: &Listing) -> Result<Page, DatabaseError> {
+        let (tree, prefix) = self.source(listing);
+        let prefix = prefix.into_bytes();
+        // Positions are digits, anything sorting after them ends the prefix
+        let mut end = prefix.clone();
+        end.push(0xff);
+        let start = listing.cursor.as_ref().map(|cursor| [prefix.as_slice(), cursor].concat());
+        let entries: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = match (listing.descending, start) {
+            (false, None) => Box::new(tree.range(prefix.clone()..end)),
+            (false, Some(start)) => Box::new(tree.range::<Vec<u8>, _>((Bound::Excluded(start), Bound::Excluded(end)))),
+            (true, None) => Box::new(tree.range(prefix.clone()..end).rev()),
+            (true, Some(start)) => Box::new(tree.range(prefix.clone()..start).rev()),
+        };
+
+        let mut page = Page {
+            ids: Vec::new(),
+            next_cursor: None,
+        };
+        let mut last = None;
+        for item in entries {
+            let (key, value) = item?;
+            let entry = bincode::deserialize::<Entry>(&value).map_err(|error| {
+                log::error!("Db Interaction Error: {}", error);
+                DatabaseError::Deserialize
+            })?;
+            if !entry.matches(listing) {
+                continue;
+            }
+            // One entry past the page tells whether there is a next one
+            if page.ids.len() == listing.limit {
+                page.next_cursor = last;
+                break;
+            }
+            // Ids are the last 20 characters of every key
+            let id = key.get(key.len().saturating_sub(20)..).unwrap_or_default();
+            page.ids.push(String::from_utf8_lossy(id).into_owned());
+            last = Some(key[prefix.len()..].to_vec());
+        }
+        Ok(page)
+    }
+
+    /// Ids and entries of the messages of a mailbox filed in a folder, oldest first
+    fn folder(&self, mailbox: &str, folder: &str) -> impl Iterator<Item = Result<(String, Entry), DatabaseError>> {
+        self.by_folder
+            .scan_prefix(format!("{}\0{}\0", mailbox, folder))
+            .map(|item| {
+                let (key, value) = item?;
+                let entry = bincode::deserialize::<Entry>(&value).map_err(|error| {
+                    log::error!("Db Interaction Error: {}", error);
+                    DatabaseError::Deserialize
+                })?;
+                let id = key.get(key.len().saturating_sub(20)..).unwrap_or_default();
+                Ok((String::from_utf8_lossy(id).into_owned(), entry))
+            })
+    }
+
+    pub fn folder_ids(&self, mailbox: &str, folder: &str) -> Result<Vec<String>, DatabaseError> {
+        self.folder(mailbox, folder).map(|item| Ok(item?.0)).collect()
+    }
+
+    pub fn folder_counts(&self, mailbox: &str, folder: &str) -> Result<FolderCounts, DatabaseError> {
+        let mut counts = FolderCounts::default();
+        for item in self.folder(mailbox, folder) {
+            let (_, entry) = item?;
+            counts.messages += 1;
+            if !entry.flags.seen {
+                counts.unread += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Applies a change to the UIDs of a folder, starting them when the folder has none yet
+    fn update_uids(&self, mailbox: &str, folder: &str, change: fn(Uids) -> Uids) -> Result<Uids, DatabaseError> {
+        let deserialize = |bytes: &[u8]| bincode::deserialize::<Uids>(bytes).ok();
+        let updated = self
+            .uids
+            .update_and_fetch(format!("{}\0{}", mailbox, folder), |state| {
+                let uids = state.and_then(deserialize).unwrap_or(Uids {
+                    validity: time::now().max(1) as u32,
+                    next: 1,
+                });
+                bincode::serialize(&change(uids)).ok()
+            })?
+            .as_deref()
+            .and_then(deserialize);
+        updated.ok_or_else(|| {
+            log::error!("Db Interaction Error: unreadable UIDs of {}", folder);
+            DatabaseError::Deserialize
+        })
+    }
+
+    pub fn uids(&self, mailbox: &str, folder: &str) -> Result<Uids, DatabaseError> {
+        self.update_uids(mailbox, folder, |uids| uids)
+    }
+
+    /// Takes the UID for a message about to be filed in a folder
+    pub fn next_uid(&self, mailbox: &str, folder: &str) -> Result<u32, DatabaseError> {
+        let uids = self.update_uids(mailbox, folder, |uids| Uids {
+            next: uids.next + 1,
+            ..uids
+        })?;
+        Ok(uids.next - 1)
+    }
+
+    /// The messages of a mailbox filed in a folder, by ascending UID
+    pub fn filed(&self, mailbox: &str, folder: &str) -> Result<Vec<Filed>, DatabaseError> {
+        self.by_uid
+            .scan_prefix(format!("{}\0{}\0", mailbox, folder))
+            .map(|item| {
+                let (key, value) = item?;
+                let entry = bincode::deserialize::<Entry>(&value).map_err(|error| {
+                    log::error!("Db Interaction Error: {}", error);
+                    DatabaseError::Deserialize
+                })?;
+                // Ids are the last 20 characters of every key
+                let id = key.get(key.len().saturating_sub(20)..).unwrap_or_default();
+                Ok(Filed {
+                    uid: entry.uid,
+                    id: String::from_utf8_lossy(id).into_owned(),
+                    flags: entry.flags,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether any message of a mailbox is filed in the folder
+    pub fn folder_is_empty(&self, mailbox: &str, folder: &str) -> Result<bool, DatabaseError> {
+        Ok(self
+            .by_folder
+            .scan_prefix(format!("{}\0{}\0", mailbox, folder))
+            .next()
+            .transpose()?
+            .is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Envelope;
+
+    /// A message from `sender` to ann, received at `received_at`
+    fn message(sender: &str, received_at: i64) -> Message {
+        let envelope = Envelope {
+            client_ip: None,
+            helo: String::new(),
+            mail_from: sender.to_string(),
+            rcpt_to: vec!["ann@example.com".to_string()],
+        };
+        let (mut message, _) = Message::parse(envelope, b"Subject: Hello\r\n\r\nHi\r\n");
+        message.mailbox = Some("ann".to_string());
+        message.received_at = received_at;
+        message
+    }
+
+    fn listing(sort: Sort, descending: bool, limit: usize) -> Listing {
+        Listing {
+            sort,
+            descending,
+            from: None,
+            mailbox: None,
+            folder: None,
+            label: None,
+            unread: false,
+            limit,
+            cursor: None,
+        }
+    }
+
+    /// Every id of a listing, following cursors page by page
+    fn walk(index: &MessageIndex, mut listing: Listing) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        loop {
+            let page = index.page(&listing).unwrap();
+            pages.push(page.ids);
+            match page.next_cursor {
+                Some(cursor) => listing.cursor = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    /// An index holding five messages with ids `…01` to `…05`, received in that order
+    fn index() -> MessageIndex {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let index = MessageIndex::open(&database).unwrap();
+        let senders = ["zoe@example.org", "ann@example.org", "zoe@example.org", "bob@example.org", "ann@example.org"];
+        for (number, sender) in senders.iter().enumerate() {
+            let id = format!("{:020}", number + 1);
+            index.add(&id, &message(sender, 1_700_000_000 + number as i64)).unwrap();
+        }
+        index
+    }
+
+    fn ids(numbers: &[u64]) -> Vec<String> {
+        numbers.iter().map(|number| format!("{:020}", number)).collect()
+    }
+
+    #[test]
+    fn pages_by_date_in_both_directions() {
+        let index = index();
+        assert_eq!(
+            walk(&index, listing(Sort::Date, true, 2)),
+            vec![ids(&[5, 4]), ids(&[3, 2]), ids(&[1])]
+        );
+        assert_eq!(
+            walk(&index, listing(Sort::Date, false, 2)),
+            vec![ids(&[1, 2]), ids(&[3, 4]), ids(&[5])]
+        );
+    }
+
+    #[test]
+    fn last_full_page_has_no_cursor() {
+        let index = index();
+        assert_eq!(walk(&index, listing(Sort::Date, true, 5)), vec![ids(&[5, 4, 3, 2, 1])]);
+    }
+
+    #[test]
+    fn pages_by_sender() {
+        let index = index();
+        assert_eq!(
+            walk(&index, listing(Sort::Sender, false, 2)),
+            vec![ids(&[2, 5]), ids(&[4, 1]), ids(&[3])]
+        );
+        let from = Listing {
+            from: Some("Zoe@example.org".to_string()),
+            ..listing(Sort::Date, true, 1)
+        };
+        assert_eq!(walk(&index, from), vec![ids(&[3]), ids(&[1])]);
+    }
+
+    #[test]
+    fn cursor_survives_removed_messages() {
+        let index = index();
+        let page = index.page(&listing(Sort::Date, true, 2)).unwrap();
+        // The message the cursor points at is gone before the next page is read
+        index.remove(&ids(&[4])[0], &message("bob@example.org", 1_700_000_003)).unwrap();
+        let next = Listing {
+            cursor: page.next_cursor,
+            ..listing(Sort::Date, true, 2)
+        };
+        assert_eq!(index.page(&next).unwrap().ids, ids(&[3, 2]));
+    }
+}
@@ -0,0 +1,563 @@
+mod attachments;
+mod flags;
+mod index;
+mod parse;
+mod search;
+mod thread;
+
+use std::{net::IpAddr, sync::Arc};
+
+use actix_web::web::Bytes;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    db::{self, DatabaseError, Store},
+    verify::Authentication,
+};
+pub use attachments::AttachmentStore;
+pub use flags::{FlagChanges, Flags};
+pub use index::{Filed, FolderCounts, Listing, MessageIndex, Sort, Uids};
+pub use search::{Query, SearchIndex};
+pub use thread::{Thread, ThreadIndex};
+
+/// SMTP envelope a message was delivered with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub client_ip: Option<IpAddr>,
+    pub helo: String,
+    pub mail_from: String,
+    pub rcpt_to: Vec<String>,
+}
+
+/// A mailbox as written in an address header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Address {
+    pub name: Option<String>,
+    pub address: Option<String>,
+}
+
+/// A header field with its unparsed value
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+/// Metadata of a single MIME attachment
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub size: usize,
+    /// SHA-256 of the decoded contents, the key into the attachment store
+    pub hash: String,
+}
+
+/// A node of the MIME tree of a message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MimePart {
+    pub content_type: String,
+    pub size: usize,
+    pub filename: Option<String>,
+    pub parts: Vec<MimePart>,
+}
+
+/// A received message with its envelope and parsed contents
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub envelope: Envelope,
+    /// User this copy was delivered to, `None` when no recipient belongs to a user
+    pub mailbox: Option<String>,
+    /// Subaddress the copy was delivered through, e.g. `shop` for `alice+shop@example.com`
+    pub tag: Option<String>,
+    /// Folder the copy is filed in, `Inbox` for new mail
+    pub folder: String,
+    pub labels: Vec<String>,
+    pub flags: Flags,
+    /// Number of the message within its folder for IMAP clients, a new one whenever it is filed
+    pub uid: u32,
+    /// Conversation the message belongs to, assigned when it is stored
+    pub thread: Option<String>,
+    pub received_at: i64,
+    pub size: usize,
+    pub message_id: Option<String>,
+    /// Message-IDs of the messages this one answers
+    pub in_reply_to: Vec<String>,
+    /// Message-IDs of the earlier messages of the conversation, oldest first
+    pub references: Vec<String>,
+    pub subject: Option<String>,
+    pub date: Option<String>,
+    pub from: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub reply_to: Vec<Address>,
+    pub headers: Vec<Header>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+    pub structure: Option<MimePart>,
+    /// SPF, DKIM and DMARC results, also found in the Authentication-Results header
+    pub authentication: Option<Authentication>,
+}
+
+/// The fields a client needs to render an inbox row
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Summary {
+    pub mailbox: Option<String>,
+    pub tag: Option<String>,
+    pub folder: String,
+    pub labels: Vec<String>,
+    pub flags: Flags,
+    pub thread: Option<String>,
+    pub received_at: i64,
+    pub size: usize,
+    pub subject: Option<String>,
+    pub date: Option<String>,
+    pub from: Vec<Address>,
+    pub to: Vec<Address>,
+    pub has_attachments: bool,
+}
+
+impl Message {
+    pub fn summary(&self) -> Summary {
+        Summary {
+            mailbox: self.mailbox.clone(),
+            tag: self.tag.clone(),
+            folder: self.folder.clone(),
+            labels: self.labels.clone(),
+            flags: self.flags,
+            thread: self.thread.clone(),
+            received_at: self.received_at,
+            size: self.size,
+            subject: self.subject.clone(),
+            date: self.date.clone(),
+            from: self.from.clone(),
+            to: self.to.clone(),
+            has_attachments: !self.attachments.is_empty(),
+        }
+    }
+}
+
+/// How many changes a slow subscriber may fall behind before it has to look at everything again
+const CHANGES_CAPACITY: usize = 64;
+
+/// Mailbox a message is indexed under, `""` when it has none
+fn mailbox(message: &Message) -> &str {
+    message.mailbox.as_deref().unwrap_or_default()
+}
+
+/// Parsed messages alongside their raw RFC 5322 source
+#[derive(Clone)]
+pub struct MessageStore {
+    database: Db,
+    messages: Store<Message>,
+    raw: Store<Vec<u8>>,
+    attachments: AttachmentStore,
+    search: SearchIndex,
+    index: MessageIndex,
+    threads: ThreadIndex,
+    /// Mailboxes whose messages changed, `""` for messages without one
+    changes: broadcast::Sender<String>,
+    /// Stored messages are read and rewritten as a whole, one change at a time
+    lock: Arc<Mutex<()>>,
+}
+
+impl MessageStore {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(MessageStore {
+            database: database.clone(),
+            messages: Store::open(database, "messages")?,
+            raw: Store::open(database, "raw_messages")?,
+            attachments: AttachmentStore::open(database)?,
+            search: SearchIndex::open(database)?,
+            index: MessageIndex::open(database)?,
+            threads: ThreadIndex::open(database)?,
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Stores a message, its source and its attachment contents under a freshly generated id
+    pub async fn insert(
+        &self,
+        message: &Message,
+        raw: &[u8],
+        attachments: &[Vec<u8>],
+    ) -> Result<String, DatabaseError> {
+        let id = db::generate_key(&self.database)?;
+        for (attachment, contents) in message.attachments.iter().zip(attachments) {
+            self.attachments.insert(&attachment.hash, contents).await?;
+        }
+        let message = Message {
+            uid: self.index.next_uid(mailbox(message), &message.folder)?,
+            thread: Some(self.threads.add(&id, message).await?),
+            ..message.clone()
+        };
+        self.raw.set(&id, &raw.to_vec()).await?;
+        self.messages.set(&id, &message).await?;
+        self.search.add(&id, &message).await?;
+        self.index.add(&id, &message)?;
+        self.changed(&message);
+        Ok(id)
+    }
+
+    /// Builds the indexes from the stored messages when they are empty. Fails on messages stored
+    /// in a format this version cannot read, there is no migration from those.
+    pub async fn index_existing(&self) -> Result<(), DatabaseError> {
+        let (search, listing, threads) = (self.search.is_empty(), self.index.is_empty(), self.threads.is_empty());
+        if !search && !listing && !threads {
+            return Ok(());
+        }
+        let mut indexed = 0;
+        for key in self.messages.tree().iter().keys() {
+            let id = String::from_utf8_lossy(&key?).into_owned();
+            let mut message = match self.messages.get(&id).await {
+                Ok(message) => message,
+                Err(DatabaseError::Deserialize) => {
+                    log::error!("Message {} was stored by an older version and cannot be read, use a new database", id);
+                    return Err(DatabaseError::Deserialize);
+                }
+                Err(error) => return Err(error),
+            };
+            if threads {
+                message.thread = Some(self.threads.add(&id, &message).await?);
+                self.messages.set(&id, &message).await?;
+            }
+            if search {
+                self.search.add(&id, &message).await?;
+            }
+            if listing {
+                self.index.add(&id, &message)?;
+            }
+            indexed += 1;
+        }
+        if indexed > 0 {
+            log::info!("Indexed {} stored messages", indexed);
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Message, DatabaseError> {
+        self.messages.get(id).await
+    }
+
+    /// One page of messages in the order the listing asks for, with the cursor of the next page.
+    /// Messages deleted since the index was read are made up for from further on.
+    pub async fn page(&self, listing: &Listing) -> Result<(Vec<(String, Message)>, Option<Vec<u8>>), DatabaseError> {
+        let limit = listing.limit;
+        let mut listing = listing.clone();
+        let mut messages = Vec::with_capacity(limit);
+        loop {
+            let page = self.index.page(&listing)?;
+            for id in page.ids {
+                match self.messages.get(&id).await {
+                    Ok(message) => messages.push((id, message)),
+                    Err(DatabaseError::NotFound) => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            match page.next_cursor {
+                Some(cursor) if messages.len() < limit => {
+                    listing.limit = limit - messages.len();
+                    listing.cursor = Some(cursor);
+                }
+                next_cursor => return Ok((messages, next_cursor)),
+            }
+        }
+    }
+
+    /// One page of messages matching a query, newest first, with the cursor of the next page.
+    /// Messages deleted since the index was read are made up for from further on.
+    pub async fn search(
+        &self,
+        query: &Query,
+        limit: usize,
+        cursor: Option<&[u8]>,
+    ) -> Result<(Vec<(String, Message)>, Option<Vec<u8>>), DatabaseError> {
+        let mut cursor = cursor.map(<[u8]>::to_vec);
+        let mut found = Vec::with_capacity(limit);
+        loop {
+            let (ids, next_cursor) = self.search.search(query, limit - found.len(), cursor.as_deref())?;
+            for id in ids {
+                match self.messages.get(&id).await {
+                    Ok(message) => found.push((id, message)),
+                    Err(DatabaseError::NotFound) => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            match next_cursor {
+                Some(next) if found.len() < limit => cursor = Some(next),
+                next_cursor => return Ok((found, next_cursor)),
+            }
+        }
+    }
+
+    /// Replaces a stored message, keeping the listing indexes in step
+    async fn update(&self, id: &str, old: &Message, new: &Message) -> Result<(), DatabaseError> {
+        self.index.remove(id, old)?;
+        self.messages.set(id, new).await?;
+        self.index.add(id, new)?;
+        self.changed(new);
+        Ok(())
+    }
+
+    /// Tells subscribers the mailbox of a message changed
+    fn changed(&self, message: &Message) {
+        // Nobody listening is fine
+        let _ = self.changes.send(mailbox(message).to_string());
+    }
+
+    /// Mailboxes whose messages change from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.changes.subscribe()
+    }
+
+    /// Files a message into another folder
+    pub async fn move_to(&self, id: &str, folder: &str) -> Result<Message, DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let old = self.messages.get(id).await?;
+        let message = Message {
+            folder: folder.to_string(),
+            uid: match old.folder == folder {
+                true => old.uid,
+                false => self.index.next_uid(mailbox(&old), folder)?,
+            },
+            ..old.clone()
+        };
+        self.update(id, &old, &message).await?;
+        Ok(message)
+    }
+
+    /// Stores another copy of a message in a folder, sharing its source's attachments
+    pub async fn copy_to(&self, id: &str, folder: &str) -> Result<(String, Message), DatabaseError> {
+        let source = self.messages.get(id).await?;
+        let mut message = Message {
+            folder: folder.to_string(),
+            uid: self.index.next_uid(mailbox(&source), folder)?,
+            ..source
+        };
+        let raw = self.raw.get(id).await?;
+        let copy = db::generate_key(&self.database)?;
+        message.thread = Some(self.threads.add(&copy, &message).await?);
+        for attachment in &message.attachments {
+            self.attachments.retain(&attachment.hash).await?;
+        }
+        self.raw.set(&copy, &raw).await?;
+        self.messages.set(&copy, &message).await?;
+        self.search.add(&copy, &message).await?;
+        self.index.add(&copy, &message)?;
+        self.changed(&message);
+        Ok((copy, message))
+    }
+
+    /// Adds a label to a message, nothing changes when it already has it
+    pub async fn label(&self, id: &str, label: &str) -> Result<Message, DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let old = self.messages.get(id).await?;
+        if old.labels.iter().any(|known| known == label) {
+            return Ok(old);
+        }
+        let mut message = old.clone();
+        message.labels.push(label.to_string());
+        self.update(id, &old, &message).await?;
+        Ok(message)
+    }
+
+    pub async fn unlabel(&self, id: &str, label: &str) -> Result<Message, DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let old = self.messages.get(id).await?;
+        let mut message = old.clone();
+        message.labels.retain(|known| known != label);
+        self.update(id, &old, &message).await?;
+        Ok(message)
+    }
+
+    /// Changes the flags of a message, nothing is written when they stay the same
+    pub async fn set_flags(&self, id: &str, changes: &FlagChanges) -> Result<Message, DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let old = self.messages.get(id).await?;
+        let flags = changes.apply(old.flags);
+        if flags == old.flags {
+            return Ok(old);
+        }
+        let message = Message { flags, ..old.clone() };
+        self.update(id, &old, &message).await?;
+        Ok(message)
+    }
+
+    /// Ids of the messages of a mailbox filed in a folder, oldest first
+    pub fn folder_ids(&self, mailbox: &str, folder: &str) -> Result<Vec<String>, DatabaseError> {
+        self.index.folder_ids(mailbox, folder)
+    }
+
+    /// Number of messages in a folder and how many of them are unseen
+    pub fn folder_counts(&self, mailbox: &str, folder: &str) -> Result<FolderCounts, DatabaseError> {
+        self.index.folder_counts(mailbox, folder)
+    }
+
+    /// One page of the threads of a mailbox, latest activity first, with the cursor of the next page
+    pub async fn threads(
+        &self,
+        mailbox: &str,
+        limit: usize,
+        cursor: Option<&[u8]>,
+    ) -> Result<(Vec<(String, Thread)>, Option<Vec<u8>>), DatabaseError> {
+        let page = self.threads.page(mailbox, limit, cursor)?;
+        let mut threads = Vec::with_capacity(page.ids.len());
+        for id in page.ids {
+            match self.threads.get(&id).await {
+                Ok(thread) => threads.push((id, thread)),
+                // Emptied since the index was read
+                Err(DatabaseError::NotFound) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok((threads, page.next_cursor))
+    }
+
+    pub async fn thread(&self, id: &str) -> Result<Thread, DatabaseError> {
+        self.threads.get(id).await
+    }
+
+    /// The messages of a mailbox filed in a folder, by ascending UID
+    pub fn filed(&self, mailbox: &str, folder: &str) -> Result<Vec<Filed>, DatabaseError> {
+        self.index.filed(mailbox, folder)
+    }
+
+    pub fn uids(&self, mailbox: &str, folder: &str) -> Result<Uids, DatabaseError> {
+        self.index.uids(mailbox, folder)
+    }
+
+    /// Whether no message of the mailbox is filed in the folder
+    pub fn folder_is_empty(&self, mailbox: &str, folder: &str) -> Result<bool, DatabaseError> {
+        self.index.folder_is_empty(mailbox, folder)
+    }
+
+    /// Retrieve the message exactly as it was received, stored in full with its attachments
+    pub async fn raw(&self, id: &str) -> Result<Vec<u8>, DatabaseError> {
+        self.raw.get(id).await
+    }
+
+    /// Streams the contents of one of the attachments of a message
+    pub fn attachment(&self, attachment: &Attachment) -> impl Stream<Item = Result<Bytes, DatabaseError>> {
+        self.attachments.stream(&attachment.hash)
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let message = self.messages.get(id).await?;
+        self.messages.delete(id).await?;
+        self.search.remove(id).await?;
+        self.index.remove(id, &message)?;
+        self.threads.remove(id, &message).await?;
+        for attachment in &message.attachments {
+            self.attachments.release(&attachment.hash, attachment.size).await?;
+        }
+        self.raw.delete(id).await?;
+        self.changed(&message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::folder::INBOX;
+
+    /// Stores a message for the mailbox, received `number` seconds into the epoch
+    async fn insert(store: &MessageStore, mailbox: &str, number: i64) -> String {
+        let envelope = Envelope {
+            client_ip: None,
+            helo: String::new(),
+            mail_from: "ann@example.org".to_string(),
+            rcpt_to: vec![format!("{}@example.com", mailbox)],
+        };
+        let raw = format!("Subject: Invoice {}\r\n\r\nSee attached\r\n", number);
+        let (mut message, attachments) = Message::parse(envelope, raw.as_bytes());
+        message.mailbox = Some(mailbox.to_string());
+        message.received_at = number;
+        store.insert(&message, raw.as_bytes(), &attachments).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_stay_full_within_a_mailbox() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let store = MessageStore::open(&database).unwrap();
+        let mut ann = Vec::new();
+        for number in 1..=8 {
+            let mailbox = if number % 2 == 0 { "ann" } else { "bob" };
+            let id = insert(&store, mailbox, number).await;
+            if mailbox == "ann" {
+                ann.push(id);
+            }
+        }
+        // Gone from the store but not yet from the indexes, as while a delete is under way
+        store.messages.delete(&ann[2]).await.unwrap();
+
+        let mut listing = Listing {
+            sort: Sort::Date,
+            descending: true,
+            from: None,
+            mailbox: Some("ann".to_string()),
+            folder: None,
+            label: None,
+            unread: false,
+            limit: 2,
+            cursor: None,
+        };
+        let ids = |page: &[(String, Message)]| page.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        let (first, cursor) = store.page(&listing).await.unwrap();
+        assert_eq!(ids(&first), vec![ann[3].clone(), ann[1].clone()]);
+        listing.cursor = cursor;
+        let (second, cursor) = store.page(&listing).await.unwrap();
+        assert_eq!(ids(&second), vec![ann[0].clone()]);
+        assert_eq!(cursor, None);
+
+        let mut query = Query::parse("invoice").unwrap();
+        query.restrict_to("ann");
+        let (first, cursor) = store.search(&query, 2, None).await.unwrap();
+        assert_eq!(ids(&first), vec![ann[3].clone(), ann[1].clone()]);
+        let (second, cursor) = store.search(&query, 2, cursor.as_deref()).await.unwrap();
+        assert_eq!(ids(&second), vec![ann[0].clone()]);
+        assert_eq!(cursor, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_in_an_older_format() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let store = MessageStore::open(&database).unwrap();
+        store.messages.tree().insert("00000000000000000001", &b"\x03ann"[..]).unwrap();
+        assert!(matches!(store.index_existing().await, Err(DatabaseError::Deserialize)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_changes_are_all_kept() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let store = MessageStore::open(&database).unwrap();
+        let envelope = Envelope {
+            client_ip: None,
+            helo: String::new(),
+            mail_from: "ann@example.org".to_string(),
+            rcpt_to: vec!["bob@example.com".to_string()],
+        };
+        let (message, attachments) = Message::parse(envelope, b"Subject: Invoice\r\n\r\nSee attached\r\n");
+        let id = store.insert(&message, b"Subject: Invoice\r\n\r\nSee attached\r\n", &attachments).await.unwrap();
+
+        let seen = FlagChanges { seen: Some(true), ..Default::default() };
+        let (moved, labelled, flagged) =
+            tokio::join!(store.move_to(&id, "Archive"), store.label(&id, "work"), store.set_flags(&id, &seen));
+        moved.unwrap();
+        labelled.unwrap();
+        flagged.unwrap();
+
+        let message = store.get(&id).await.unwrap();
+        assert_eq!(message.folder, "Archive");
+        assert_eq!(message.labels, vec!["work".to_string()]);
+        assert!(message.flags.seen);
+        assert_eq!(store.folder_ids("", "Archive").unwrap(), vec![id]);
+        assert!(store.folder_is_empty("", INBOX).unwrap());
+    }
+}
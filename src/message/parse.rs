@@ -0,0 +1,141 @@
+use mail_parser::{HeaderValue, MessageParser, MessagePart, MimeHeaders};
+use sha2::{Digest, Sha256};
+
+use super::{Address, Attachment, Envelope, Flags, Header, Message, MimePart};
+use crate::{folder::INBOX, time};
+
+impl Message {
+    /// Parses a raw RFC 5322 message that arrived with the given envelope.
+    /// The decoded attachment contents are returned in the order of `Message::attachments`.
+    pub fn parse(envelope: Envelope, raw: &[u8]) -> (Message, Vec<Vec<u8>>) {
+        let mut message = Message {
+            envelope,
+            mailbox: None,
+            tag: None,
+            folder: INBOX.to_string(),
+            labels: Vec::new(),
+            flags: Flags::default(),
+            uid: 0,
+            thread: None,
+            received_at: time::now(),
+            size: raw.len(),
+            message_id: None,
+            in_reply_to: Vec::new(),
+            references: Vec::new(),
+            subject: None,
+            date: None,
+            from: Vec::new(),
+            to: Vec::new(),
+            cc: Vec::new(),
+            reply_to: Vec::new(),
+            headers: Vec::new(),
+            text: None,
+            html: None,
+            attachments: Vec::new(),
+            structure: None,
+            authentication: None,
+        };
+
+        // Unparseable mail is still kept, only with an empty structure
+        let Some(parsed) = MessageParser::default().parse(raw) else {
+            log::warn!("Could not parse message from {}", message.envelope.mail_from);
+            return (message, Vec::new());
+        };
+
+        message.message_id = parsed.message_id().map(str::to_string);
+        message.in_reply_to = message_ids(parsed.in_reply_to());
+        message.references = message_ids(parsed.references());
+        message.subject = parsed.subject().map(str::to_string);
+        message.date = parsed.date().map(|date| date.to_rfc3339());
+        message.from = addresses(parsed.from());
+        message.to = addresses(parsed.to());
+        message.cc = addresses(parsed.cc());
+        message.reply_to = addresses(parsed.reply_to());
+        message.headers = parsed
+            .headers_raw()
+            .map(|(name, value)| Header {
+                name: name.to_string(),
+                value: value.trim().to_string(),
+            })
+            .collect();
+
+        // mail-parser converts between text and html when a body is missing,
+        // only keep the parts that were actually sent
+        message.text = parsed
+            .text_bodies()
+            .find(|part| part.is_text() && !part.is_text_html())
+            .and_then(|part| part.text_contents())
+            .map(str::to_string);
+        message.html = parsed
+            .html_bodies()
+            .find(|part| part.is_text_html())
+            .and_then(|part| part.text_contents())
+            .map(str::to_string);
+
+        let (attachments, contents) = parsed
+            .attachments()
+            .map(|part| {
+                let attachment = Attachment {
+                    filename: part.attachment_name().map(str::to_string),
+                    content_type: content_type(part),
+                    size: part.len(),
+                    hash: format!("{:x}", Sha256::digest(part.contents())),
+                };
+                (attachment, part.contents().to_vec())
+            })
+            .unzip();
+        message.attachments = attachments;
+        message.structure = Some(mime_tree(&parsed.parts, 0));
+        (message, contents)
+    }
+}
+
+/// The Message-IDs of a header, without angle brackets
+fn message_ids(value: &HeaderValue) -> Vec<String> {
+    value
+        .as_text_list()
+        .unwrap_or_default()
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+fn addresses(address: Option<&mail_parser::Address>) -> Vec<Address> {
+    address
+        .map(|address| {
+            address
+                .iter()
+                .map(|addr| Address {
+                    name: addr.name().map(str::to_string),
+                    address: addr.address().map(str::to_string),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn content_type(part: &MessagePart) -> String {
+    match part.content_type() {
+        Some(content_type) => match content_type.subtype() {
+            Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+            None => content_type.ctype().to_string(),
+        },
+        None => "text/plain".to_string(),
+    }
+    .to_ascii_lowercase()
+}
+
+fn mime_tree(parts: &[MessagePart], index: usize) -> MimePart {
+    let part = &parts[index];
+    MimePart {
+        content_type: content_type(part),
+        size: part.len(),
+        filename: part.attachment_name().map(str::to_string),
+        parts: part
+            .sub_parts()
+            .unwrap_or_default()
+            .iter()
+            .map(|&child| mime_tree(parts, child))
+            .collect(),
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::BTreeSet;
+
+use sled::{Batch, Db, Tree};
+
+use super::Message;
+use crate::{
+    db::{DatabaseError, Store},
+    time,
+};
+
+/// Longest word indexed, longer ones are hashes and encoded blobs nobody searches for
+const MAX_TERM_LENGTH: usize = 64;
+
+/// Part of a message a term is indexed under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Subject, bodies, attachment names and the people involved, searched by plain words
+    Text,
+    From,
+    /// To and Cc
+    To,
+    Subject,
+    Has,
+    Mailbox,
+}
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Text => "text",
+            Field::From => "from",
+            Field::To => "to",
+            Field::Subject => "subject",
+            Field::Has => "has",
+            Field::Mailbox => "mailbox",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Exact(String),
+    /// Written with a trailing `*`
+    Prefix(String),
+}
+
+/// A search as written in `q`, every condition has to hold
+#[derive(Debug, Default)]
+pub struct Query {
+    terms: Vec<(Field, Term)>,
+    /// Received on or after, unix time
+    after: Option<i64>,
+    /// Received before, unix time
+    before: Option<i64>,
+}
+
+/// Lowercased words of a text, split at anything that is not a letter or digit
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() <= MAX_TERM_LENGTH)
+        .map(str::to_lowercase)
+}
+
+/// The text of an HTML body with its tags left out
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Splits at whitespace outside of double quotes, which are dropped
+fn tokens(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+impl Query {
+    /// Parses words and `from:`, `to:`, `subject:`, `has:attachment`, `before:` and `after:`
+    /// filters. Dates are `YYYY-MM-DD` in UTC, `before:` excludes its day and `after:` includes it.
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = Query::default();
+        for token in tokens(query) {
+            let (name, value) = token.split_once(':').unwrap_or(("", &token));
+            let field = match name.to_ascii_lowercase().as_str() {
+                "from" => Field::From,
+                "to" => Field::To,
+                "subject" => Field::Subject,
+                "has" => match value.to_ascii_lowercase().as_str() {
+                    "attachment" | "attachments" => {
+                        parsed.terms.push((Field::Has, Term::Exact("attachment".to_string())));
+                        continue;
+                    }
+                    _ => return Err(format!("Unknown filter has:{}", value)),
+                },
+                filter @ ("before" | "after") => {
+                    let date = time::parse_date(value)
+                        .ok_or_else(|| format!("Invalid date '{}', expected YYYY-MM-DD", value))?;
+                    match filter {
+                        "before" => parsed.before = Some(parsed.before.map_or(date, |known| known.min(date))),
+                        _ => parsed.after = Some(parsed.after.map_or(date, |known| known.max(date))),
+                    }
+                    continue;
+                }
+                // Anything else with a colon, like a URL, is searched for as words
+                _ => {
+                    parsed.push_words(Field::Text, &token);
+                    continue;
+                }
+            };
+            if value.is_empty() {
+                return Err(format!("{}: needs a value", name));
+            }
+            // A whole address only matches itself, not everyone at the same domain
+            match field {
+                Field::From | Field::To if value.contains('@') && !value.ends_with('*') => {
+                    parsed.terms.push((field, Term::Exact(value.to_lowercase())))
+                }
+                _ => parsed.push_words(field, value),
+            }
+        }
+        if parsed.terms.is_empty() && parsed.after.is_none() && parsed.before.is_none() {
+            return Err("The query is empty".to_string());
+        }
+        Ok(parsed)
+    }
+
+    fn push_words(&mut self, field: Field, value: &str) {
+        let prefix = value.ends_with('*');
+        let mut words = words(value).collect::<Vec<_>>();
+        let last = words.pop();
+        for word in words {
+            self.terms.push((field, Term::Exact(word)));
+        }
+        if let Some(word) = last {
+            self.terms.push((
+                field,
+                match prefix {
+                    true => Term::Prefix(word),
+                    false => Term::Exact(word),
+                },
+            ));
+        }
+    }
+
+    /// Only matches messages delivered to the given user
+    pub fn restrict_to(&mut self, mailbox: &str) {
+        self.terms.push((Field::Mailbox, Term::Exact(mailbox.to_string())));
+    }
+}
+
+/// Every field and term a message is indexed under
+fn index_terms(message: &Message) -> BTreeSet<(&'static str, String)> {
+    let mut terms = BTreeSet::new();
+    let mut add = |field: Field, term: String| {
+        terms.insert((field.name(), term));
+    };
+
+    let subject = message.subject.as_deref().unwrap_or_default();
+    for word in words(subject) {
+        add(Field::Subject, word.clone());
+        add(Field::Text, word);
+    }
+    let body = match (&message.text, &message.html) {
+        (Some(text), _) => text.clone(),
+        (None, Some(html)) => strip_tags(html),
+        (None, None) => String::new(),
+    };
+    for word in words(&body) {
+        add(Field::Text, word);
+    }
+    for attachment in &message.attachments {
+        for word in words(attachment.filename.as_deref().unwrap_or_default()) {
+            add(Field::Text, word);
+        }
+    }
+    if !message.attachments.is_empty() {
+        add(Field::Has, "attachment".to_string());
+    }
+
+    let people = [
+        (Field::From, &message.from),
+        (Field::To, &message.to),
+        (Field::To, &message.cc),
+    ];
+    for (field, addresses) in people {
+        for address in addresses {
+            let name = address.name.as_deref().unwrap_or_default();
+            let mailbox = address.address.as_deref().unwrap_or_default();
+            if !mailbox.is_empty() {
+                add(field, mailbox.to_lowercase());
+            }
+            for word in words(name).chain(words(mailbox)) {
+                add(field, word.clone());
+                add(Field::Text, word);
+            }
+        }
+    }
+    if let Some(mailbox) = &message.mailbox {
+        add(Field::Mailbox, mailbox.clone());
+    }
+    terms
+}
+
+fn posting(field: &str, term: &str, id: &str) -> String {
+    format!("{}\0{}\0{}", field, term, id)
+}
+
+fn dated(received_at: i64, id: &str) -> String {
+    format!("date\0{:020}\0{}", received_at.max(0), id)
+}
+
+/// The id at the end of a posting key
+fn posting_id(key: &[u8]) -> Result<String, DatabaseError> {
+    let start = key.iter().rposition(|&byte| byte == 0).map_or(0, |separator| separator + 1);
+    String::from_utf8(key[start..].to_vec()).map_err(|error| {
+        log::error!("Db Interaction Error: {}", error);
+        DatabaseError::Deserialize
+    })
+}
+
+/// Inverted index over stored messages, one key per field, term and message
+#[derive(Clone)]
+pub struct SearchIndex {
+    postings: Tree,
+    /// Keys written for each message, so they can be removed with it
+    documents: Store<Vec<String>>,
+}
+
+impl SearchIndex {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(SearchIndex {
+            postings: database.open_tree("search_postings")?,
+            documents: Store::open(database, "search_documents")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.tree().is_empty()
+    }
+
+    pub async fn add(&self, id: &str, message: &Message) -> Result<(), DatabaseError> {
+        let mut keys = index_terms(message)
+            .into_iter()
+            .map(|(field, term)| posting(field, &term, id))
+            .collect::<Vec<_>>();
+        keys.push(dated(message.received_at, id));
+        let mut batch = Batch::default();
+        for key in &keys {
+            batch.insert(key.as_bytes(), &[]);
+        }
+        self.postings.apply_batch(batch)?;
+        self.documents.set(id, &keys).await
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<(), DatabaseError> {
+        let keys = match self.documents.get(id).await {
+            Ok(keys) => keys,
+            Err(DatabaseError::NotFound) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let mut batch = Batch::default();
+        for key in &keys {
+            batch.remove(key.as_bytes());
+        }
+        self.postings.apply_batch(batch)?;
+        self.documents.delete(id).await
+    }
+
+    /// Ids of the messages containing a term
+    fn matches(&self, field: Field, term: &Term) -> Result<BTreeSet<String>, DatabaseError> {
+        let prefix = match term {
+            Term::Exact(word) => format!("{}\0{}\0", field.name(), word),
+            Term::Prefix(word) => format!("{}\0{}", field.name(), word),
+        };
+        self.postings
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| posting_id(&key?))
+            .collect()
+    }
+
+    /// Ids of the messages received within a time range
+    fn received(&self, after: Option<i64>, before: Option<i64>) -> Result<BTreeSet<String>, DatabaseError> {
+        let start = format!("date\0{:020}", after.unwrap_or_default().max(0));
+        let end = match before {
+            Some(before) => format!("date\0{:020}", before.max(0)),
+            None => "date\x01".to_string(),
+        };
+        self.postings
+            .range(start..end)
+            .keys()
+            .map(|key| posting_id(&key?))
+            .collect()
+    }
+
+    /// Ids of up to `limit` messages matching the query, newest first, starting after the one the
+    /// cursor names. The cursor of the next page is the last id returned, `None` on the last page.
+    pub fn search(
+        &self,
+        query: &Query,
+        limit: usize,
+        cursor: Option<&[u8]>,
+    ) -> Result<(Vec<String>, Option<Vec<u8>>), DatabaseError> {
+        let mut found: Option<BTreeSet<String>> = None;
+        for (field, term) in &query.terms {
+            let matches = self.matches(*field, term)?;
+            found = Some(match found {
+                Some(found) => found.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+            if found.as_ref().is_some_and(BTreeSet::is_empty) {
+                return Ok((Vec::new(), None));
+            }
+        }
+        if query.after.is_some() || query.before.is_some() {
+            let matches = self.received(query.after, query.before)?;
+            found = Some(match found {
+                Some(found) => found.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+        }
+        // Ids sort in insertion order
+        let found = found.unwrap_or_default();
+        let mut ids = match cursor {
+            Some(cursor) => {
+                let cursor = String::from_utf8_lossy(cursor).into_owned();
+                found.range(..cursor).rev().take(limit + 1).cloned().collect::<Vec<_>>()
+            }
+            None => found.iter().rev().take(limit + 1).cloned().collect(),
+        };
+        let next_cursor = match ids.len() > limit {
+            true => {
+                ids.truncate(limit);
+                ids.last().map(|id| id.clone().into_bytes())
+            }
+            false => None,
+        };
+        Ok((ids, next_cursor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Envelope;
+
+    /// Field and term of every condition, prefixes written with their trailing `*`
+    fn terms(query: &str) -> Vec<(&'static str, String)> {
+        Query::parse(query)
+            .unwrap()
+            .terms
+            .into_iter()
+            .map(|(field, term)| match term {
+                Term::Exact(word) => (field.name(), word),
+                Term::Prefix(word) => (field.name(), format!("{}*", word)),
+            })
+            .collect()
+    }
+
+    fn term(field: &'static str, word: &str) -> (&'static str, String) {
+        (field, word.to_string())
+    }
+
+    #[test]
+    fn parses_words_and_prefixes() {
+        assert_eq!(terms("Invoice march"), vec![term("text", "invoice"), term("text", "march")]);
+        assert_eq!(terms("invo*"), vec![term("text", "invo*")]);
+        // A URL is not a filter
+        assert_eq!(
+            terms("https://example.com"),
+            vec![term("text", "https"), term("text", "example"), term("text", "com")]
+        );
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(terms("from:Ann@Example.com"), vec![term("from", "ann@example.com")]);
+        assert_eq!(terms("to:ann"), vec![term("to", "ann")]);
+        assert_eq!(terms("from:ann@exam*"), vec![term("from", "ann"), term("from", "exam*")]);
+        assert_eq!(
+            terms("subject:\"march invoice\" has:attachment"),
+            vec![term("subject", "march"), term("subject", "invoice"), term("has", "attachment")]
+        );
+    }
+
+    #[test]
+    fn parses_date_ranges() {
+        let query = Query::parse("after:2024-03-01 before:2024-04-01 after:2024-03-10").unwrap();
+        assert_eq!(query.after, time::parse_date("2024-03-10"));
+        assert_eq!(query.before, time::parse_date("2024-04-01"));
+        assert!(query.terms.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        assert!(Query::parse("").is_err());
+        assert!(Query::parse("  \"\" ").is_err());
+        assert!(Query::parse("from:").is_err());
+        assert!(Query::parse("has:wings").is_err());
+        assert!(Query::parse("before:yesterday").is_err());
+        assert!(Query::parse("after:2024-02-30").is_err());
+    }
+
+    #[tokio::test]
+    async fn pages_through_matches() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let index = SearchIndex::open(&database).unwrap();
+        for number in 1..=5 {
+            let envelope = Envelope {
+                client_ip: None,
+                helo: String::new(),
+                mail_from: "ann@example.org".to_string(),
+                rcpt_to: vec!["bob@example.com".to_string()],
+            };
+            let subject = if number == 3 { "Lunch" } else { "Invoice" };
+            let raw = format!("Subject: {} {}\r\n\r\nSee attached\r\n", subject, number);
+            let (message, _) = Message::parse(envelope, raw.as_bytes());
+            index.add(&format!("{:020}", number), &message).await.unwrap();
+        }
+
+        let query = Query::parse("subject:invoice").unwrap();
+        let (first, cursor) = index.search(&query, 2, None).unwrap();
+        assert_eq!(first, vec![format!("{:020}", 5), format!("{:020}", 4)]);
+        let (second, cursor) = index.search(&query, 2, cursor.as_deref()).unwrap();
+        assert_eq!(second, vec![format!("{:020}", 2), format!("{:020}", 1)]);
+        assert_eq!(cursor, None);
+
+        index.remove(&format!("{:020}", 5)).await.unwrap();
+        let (ids, _) = index.search(&Query::parse("invoice 5").unwrap(), 10, None).unwrap();
+        assert!(ids.is_empty());
+    }
+}
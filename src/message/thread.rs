@@ -0,0 +1,315 @@
+use std::{ops::Bound, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use tokio::sync::Mutex;
+
+use super::Message;
+use crate::db::{self, DatabaseError, Store};
+
+/// Prefixes mail clients put in front of the subject of replies and forwards
+const REPLY_PREFIXES: [&str; 6] = ["re", "fwd", "fw", "aw", "sv", "wg"];
+
+/// A conversation within one mailbox
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Thread {
+    pub mailbox: Option<String>,
+    /// Subject of the first message, without reply and forward prefixes
+    pub subject: Option<String>,
+    /// Ids of the messages in the order they were stored
+    pub messages: Vec<String>,
+    pub created_at: i64,
+    /// When the latest message was received
+    pub updated_at: i64,
+}
+
+/// Ids of one page of threads, with the cursor of the next page if there is one
+pub struct ThreadPage {
+    pub ids: Vec<String>,
+    pub next_cursor: Option<Vec<u8>>,
+}
+
+/// A subject without its reply and forward prefixes, mailing list tags and `(fwd)` trailers, and
+/// whether it had any prefix or trailer, much like the base subject of RFC 5256 section 2.1
+fn base_subject(subject: &str) -> (&str, bool) {
+    let mut subject = subject.trim();
+    let mut reply = false;
+    loop {
+        let trailer = subject.len().checked_sub("(fwd)".len()).filter(|&start| subject.is_char_boundary(start));
+        if let Some(start) = trailer.filter(|&start| subject[start..].eq_ignore_ascii_case("(fwd)")) {
+            subject = subject[..start].trim_end();
+            reply = true;
+            continue;
+        }
+        // A list tag such as `[users]` goes, unless nothing else is left
+        let tagged = subject.strip_prefix('[').and_then(|rest| rest.split_once(']'));
+        if let Some((_, rest)) = tagged.filter(|(tag, rest)| !tag.contains('[') && !rest.trim().is_empty()) {
+            subject = rest.trim_start();
+            continue;
+        }
+        let Some((prefix, rest)) = subject.split_once(':') else {
+            return (subject, reply);
+        };
+        // Some clients count replies, as in `Re[2]:`
+        let prefix = prefix.trim_end_matches(|c: char| c.is_ascii_digit() || c == '[' || c == ']');
+        if !REPLY_PREFIXES.iter().any(|known| known.eq_ignore_ascii_case(prefix.trim())) {
+            return (subject, reply);
+        }
+        subject = rest.trim_start();
+        reply = true;
+    }
+}
+
+/// The base subject as threads are matched on, case and runs of whitespace ignored
+fn subject_key(subject: &str) -> String {
+    subject.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn key(mailbox: &str, value: &str) -> String {
+    format!("{}\0{}", mailbox, value)
+}
+
+/// Latest activity and id, fixed width so threads sort by activity first
+fn position(mailbox: &str, thread: &Thread, id: &str) -> String {
+    format!("{}\0{:020}{}", mailbox, thread.updated_at.max(0), id)
+}
+
+/// Groups messages into threads by their Message-ID, In-Reply-To and References headers, falling
+/// back to the subject for replies that lost those headers
+#[derive(Clone)]
+pub struct ThreadIndex {
+    database: Db,
+    threads: Store<Thread>,
+    /// `mailbox\0message-id` to the thread of the message with that id or of one referring to it
+    message_ids: Tree,
+    /// `mailbox\0subject` to the thread most recently started or continued under that subject
+    subjects: Tree,
+    /// `mailbox\0position` of every thread, so threads list by latest activity
+    by_date: Tree,
+    /// Threads are read and rewritten as a whole, one message at a time
+    lock: Arc<Mutex<()>>,
+}
+
+impl ThreadIndex {
+    pub fn open(database: &Db) -> Result<Self, DatabaseError> {
+        Ok(ThreadIndex {
+            database: database.clone(),
+            threads: Store::open(database, "threads")?,
+            message_ids: database.open_tree("thread_message_ids")?,
+            subjects: database.open_tree("thread_subjects")?,
+            by_date: database.open_tree("threads_by_date")?,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.threads.tree().is_empty()
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Thread, DatabaseError> {
+        self.threads.get(id).await
+    }
+
+    /// The id of a thread still holding messages that a tree points to
+    async fn existing(&self, tree: &Tree, key: &str) -> Result<Option<String>, DatabaseError> {
+        let Some(id) = tree.get(key)? else {
+            return Ok(None);
+        };
+        let id = String::from_utf8_lossy(&id).into_owned();
+        match self.threads.get(&id).await {
+            Ok(_) => Ok(Some(id)),
+            // Every message of the thread was deleted since
+            Err(DatabaseError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Adds a stored message to the thread it belongs to, starting a new one if there is none,
+    /// and returns the thread's id
+    pub async fn add(&self, id: &str, message: &Message) -> Result<String, DatabaseError> {
+        let _guard = self.lock.lock().await;
+        let mailbox = message.mailbox.as_deref().unwrap_or_default();
+        let (subject, reply) = base_subject(message.subject.as_deref().unwrap_or_default());
+        let subject = subject_key(subject);
+
+        // Copies share the Message-ID, then the closest relatives come first
+        let related = message
+            .message_id
+            .iter()
+            .chain(&message.in_reply_to)
+            .chain(message.references.iter().rev())
+            .collect::<Vec<_>>();
+        let mut found = None;
+        for message_id in &related {
+            found = self.existing(&self.message_ids, &key(mailbox, message_id)).await?;
+            if found.is_some() {
+                break;
+            }
+        }
+        if found.is_none() && reply && !subject.is_empty() {
+            found = self.existing(&self.subjects, &key(mailbox, &subject)).await?;
+        }
+
+        let (thread_id, thread) = match found {
+            Some(thread_id) => {
+                let mut thread = self.threads.get(&thread_id).await?;
+                self.by_date.remove(position(mailbox, &thread, &thread_id))?;
+                thread.messages.push(id.to_string());
+                thread.updated_at = thread.updated_at.max(message.received_at);
+                (thread_id, thread)
+            }
+            None => {
+                let thread = Thread {
+                    mailbox: message.mailbox.clone(),
+                    subject: message
+                        .subject
+                        .as_deref()
+                        .map(|subject| base_subject(subject).0.to_string())
+                        .filter(|subject| !subject.is_empty()),
+                    messages: vec![id.to_string()],
+                    created_at: message.received_at,
+                    updated_at: message.received_at,
+                };
+                (db::generate_key(&self.database)?, thread)
+            }
+        };
+        self.threads.set(&thread_id, &thread).await?;
+        self.by_date.insert(position(mailbox, &thread, &thread_id), &[])?;
+        // Messages arriving before their parent still find the thread through the parent's id
+        for message_id in related {
+            self.message_ids.insert(key(mailbox, message_id), thread_id.as_bytes())?;
+        }
+        if !subject.is_empty() {
+            self.subjects.insert(key(mailbox, &subject), thread_id.as_bytes())?;
+        }
+        Ok(thread_id)
+    }
+
+    /// Takes a deleted message out of its thread, removing the thread with its last message
+    pub async fn remove(&self, id: &str, message: &Message) -> Result<(), DatabaseError> {
+        let Some(thread_id) = &message.thread else {
+            return Ok(());
+        };
+        let _guard = self.lock.lock().await;
+        let mailbox = message.mailbox.as_deref().unwrap_or_default();
+        let mut thread = match self.threads.get(thread_id).await {
+            Ok(thread) => thread,
+            Err(DatabaseError::NotFound) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        thread.messages.retain(|known| known != id);
+        if thread.messages.is_empty() {
+            self.by_date.remove(position(mailbox, &thread, thread_id))?;
+            // Headers and subjects still pointing here are ignored from now on
+            return self.threads.delete(thread_id).await;
+        }
+        self.threads.set(thread_id, &thread).await
+    }
+
+    /// One page of the threads of a mailbox, latest activity first
+    pub fn page(&self, mailbox: &str, limit: usize, cursor: Option<&[u8]>) -> Result<ThreadPage, DatabaseError> {
+        let prefix = format!("{}\0", mailbox).into_bytes();
+        let end = match cursor {
+            Some(cursor) => Bound::Excluded([prefix.as_slice(), cursor].concat()),
+            // Positions are digits, anything sorting after them ends the prefix
+            None => Bound::Excluded([prefix.as_slice(), &[0xff]].concat()),
+        };
+        let mut page = ThreadPage {
+            ids: Vec::new(),
+            next_cursor: None,
+        };
+        let mut last = None;
+        for key in self.by_date.range::<Vec<u8>, _>((Bound::Included(prefix.clone()), end)).rev() {
+            let (key, _) = key?;
+            // One entry past the page tells whether there is a next one
+            if page.ids.len() == limit {
+                page.next_cursor = last;
+                break;
+            }
+            // Ids are the last 20 characters of every key
+            let id = key.get(key.len().saturating_sub(20)..).unwrap_or_default();
+            page.ids.push(String::from_utf8_lossy(id).into_owned());
+            last = Some(key[prefix.len()..].to_vec());
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Envelope;
+
+    #[test]
+    fn strips_prefixes_tags_and_trailers() {
+        assert_eq!(base_subject("Lunch"), ("Lunch", false));
+        assert_eq!(base_subject("  Re: Lunch "), ("Lunch", true));
+        assert_eq!(base_subject("RE: Fwd: re:Lunch"), ("Lunch", true));
+        assert_eq!(base_subject("Re[2]: AW: Lunch"), ("Lunch", true));
+        assert_eq!(base_subject("[users] Lunch"), ("Lunch", false));
+        assert_eq!(base_subject("Re: [users] Re: Lunch"), ("Lunch", true));
+        assert_eq!(base_subject("Lunch (fwd)"), ("Lunch", true));
+        assert_eq!(base_subject("Fwd: Lunch (FWD) (fwd)"), ("Lunch", true));
+        // Neither a tag nor a prefix when nothing else is left, or it is not one we know
+        assert_eq!(base_subject("[users]"), ("[users]", false));
+        assert_eq!(base_subject("Agenda: Lunch"), ("Agenda: Lunch", false));
+        assert_eq!(subject_key("Lunch  at\tNOON"), "lunch at noon");
+    }
+
+    fn message(mailbox: &str, headers: &str) -> Message {
+        let envelope = Envelope {
+            client_ip: None,
+            helo: String::new(),
+            mail_from: "ann@example.org".to_string(),
+            rcpt_to: vec![format!("{}@example.com", mailbox)],
+        };
+        let raw = format!("{}\r\n\r\nSee you\r\n", headers.replace('\n', "\r\n"));
+        let mut message = Message::parse(envelope, raw.as_bytes()).0;
+        message.mailbox = Some(mailbox.to_string());
+        message
+    }
+
+    #[tokio::test]
+    async fn joins_replies_by_their_headers() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let threads = ThreadIndex::open(&database).unwrap();
+        let lunch = threads
+            .add("00000000000000000001", &message("ann", "Message-ID: <a@example.org>\nSubject: Lunch"))
+            .await
+            .unwrap();
+        let reply = "Message-ID: <b@example.org>\nIn-Reply-To: <a@example.org>\nSubject: Re: Lunch";
+        assert_eq!(threads.add("00000000000000000002", &message("ann", reply)).await.unwrap(), lunch);
+        // Only References left, and a subject of its own
+        let reply = "Message-ID: <c@example.org>\nReferences: <a@example.org> <b@example.org>\nSubject: Dessert?";
+        assert_eq!(threads.add("00000000000000000003", &message("ann", reply)).await.unwrap(), lunch);
+        // A reply that lost its headers still matches on the subject, a new message does not
+        let reply = "Message-ID: <d@example.org>\nSubject: RE: [team] lunch";
+        assert_eq!(threads.add("00000000000000000004", &message("ann", reply)).await.unwrap(), lunch);
+        let other = "Message-ID: <e@example.org>\nSubject: Lunch";
+        assert_ne!(threads.add("00000000000000000005", &message("ann", other)).await.unwrap(), lunch);
+        // Other mailboxes have their own threads
+        let reply = "Message-ID: <b@example.org>\nIn-Reply-To: <a@example.org>\nSubject: Re: Lunch";
+        assert_ne!(threads.add("00000000000000000006", &message("bob", reply)).await.unwrap(), lunch);
+
+        let thread = threads.get(&lunch).await.unwrap();
+        assert_eq!(thread.subject.as_deref(), Some("Lunch"));
+        assert_eq!(
+            thread.messages,
+            ["00000000000000000001", "00000000000000000002", "00000000000000000003", "00000000000000000004"]
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_arriving_first_wait_for_their_parent() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let threads = ThreadIndex::open(&database).unwrap();
+        let reply = "Message-ID: <b@example.org>\nIn-Reply-To: <a@example.org>\nSubject: Re: Lunch";
+        let thread = threads.add("00000000000000000001", &message("ann", reply)).await.unwrap();
+        let parent = "Message-ID: <a@example.org>\nSubject: Lunch";
+        assert_eq!(threads.add("00000000000000000002", &message("ann", parent)).await.unwrap(), thread);
+        // So does a grandchild only referring to the parent
+        let later = "Message-ID: <c@example.org>\nReferences: <a@example.org>\nSubject: Dessert?";
+        assert_eq!(threads.add("00000000000000000003", &message("ann", later)).await.unwrap(), thread);
+        assert_eq!(threads.get(&thread).await.unwrap().messages.len(), 3);
+    }
+}
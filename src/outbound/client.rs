@@ -0,0 +1,338 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// RFC 5321 section 4.5.3.2 suggests five minutes for most replies
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// The server may take a while to accept the message after the final dot
+const DATA_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Longest reply line read, anything longer is a broken server
+const MAX_REPLY_LENGTH: u64 = 4096;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Could not connect to {host} ({address}) because {source}")]
+    Connect {
+        host: String,
+        address: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Server did not answer in time")]
+    Timeout,
+    #[error("Unexpected reply from server: {0}")]
+    Protocol(String),
+    #[error("Server replied {0}")]
+    Rejected(Reply),
+    #[error("TLS handshake failed: {0}")]
+    Tls(#[source] io::Error),
+    #[error("Server does not offer STARTTLS")]
+    NoStartTls,
+}
+
+impl ClientError {
+    /// Permanent failures are not worth retrying
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, ClientError::Rejected(reply) if reply.is_permanent())
+    }
+}
+
+/// A complete, possibly multi-line, server reply
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+
+    /// The enhanced status code leading the text, e.g. `5.1.1` (RFC 3463)
+    pub fn enhanced_status(&self) -> Option<&str> {
+        let status = self.lines.first()?.split_whitespace().next()?;
+        let parts = status.split('.').collect::<Vec<_>>();
+        let valid = parts.len() == 3
+            && parts[0] == (self.code / 100).to_string()
+            && parts[1..].iter().all(|part| (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit()));
+        valid.then_some(status)
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Certificates are checked against the Mozilla root store
+pub fn verifying_connector() -> TlsConnector {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    });
+    TlsConnector::from(config.clone())
+}
+
+/// Encrypts without checking who is on the other end, as opportunistic TLS between mail servers
+/// does. Many MX hosts present self-signed certificates, and plain text would be the alternative.
+pub fn opportunistic_connector() -> TlsConnector {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let verifier = AnyCertificate(Arc::new(ring::default_provider()));
+        Arc::new(
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth(),
+        )
+    });
+    TlsConnector::from(config.clone())
+}
+
+/// Accepts any certificate, while still checking the handshake is signed by its key
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Minimal SMTP client, just enough to hand a message to another server
+pub struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    host: String,
+    extensions: Vec<String>,
+}
+
+impl Client {
+    /// Opens a connection and reads the greeting. With a connector the handshake happens first,
+    /// `host` is the name the certificate has to match.
+    pub async fn connect(host: &str, address: SocketAddr, tls: Option<&TlsConnector>) -> Result<Self, ClientError> {
+        let connect_error = |source| ClientError::Connect {
+            host: host.to_string(),
+            address,
+            source,
+        };
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| connect_error(io::ErrorKind::TimedOut.into()))?
+            .map_err(connect_error)?;
+        let stream: Box<dyn Stream> = match tls {
+            Some(connector) => Box::new(handshake(connector, host, stream).await?),
+            None => Box::new(stream),
+        };
+
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            host: host.to_string(),
+            extensions: Vec::new(),
+        };
+        client.expect(REPLY_TIMEOUT).await?;
+        Ok(client)
+    }
+
+    /// Greets the server and remembers the extensions it announces
+    pub async fn ehlo(&mut self, name: &str) -> Result<(), ClientError> {
+        let reply = self.command(&format!("EHLO {}", name)).await?;
+        // The first line is the greeting, every further one an extension
+        self.extensions = reply
+            .lines
+            .iter()
+            .skip(1)
+            .map(|line| line.to_ascii_uppercase())
+            .collect();
+        Ok(())
+    }
+
+    pub fn supports(&self, extension: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|line| line.split_whitespace().next() == Some(extension))
+    }
+
+    /// Upgrades the connection, the caller has to send EHLO again afterwards
+    pub async fn starttls(&mut self, connector: &TlsConnector) -> Result<(), ClientError> {
+        if !self.supports("STARTTLS") {
+            return Err(ClientError::NoStartTls);
+        }
+        self.command("STARTTLS").await?;
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Box::new(tokio::io::empty())))
+            .into_inner();
+        self.stream = BufReader::new(Box::new(handshake(connector, &self.host, stream).await?));
+        self.extensions.clear();
+        Ok(())
+    }
+
+    /// Authenticates with AUTH PLAIN, credentials travel in the clear unless the session is encrypted
+    pub async fn auth_plain(&mut self, username: &str, password: &str) -> Result<(), ClientError> {
+        let credentials = STANDARD.encode(format!("\0{}\0{}", username, password));
+        self.command(&format!("AUTH PLAIN {}", credentials)).await?;
+        Ok(())
+    }
+
+    pub async fn mail_from(&mut self, sender: &str) -> Result<(), ClientError> {
+        self.command(&format!("MAIL FROM:<{}>", sender)).await?;
+        Ok(())
+    }
+
+    /// Offers a recipient, the reply is returned so a rejection only affects that recipient
+    pub async fn rcpt_to(&mut self, recipient: &str) -> Result<Reply, ClientError> {
+        self.send(&format!("RCPT TO:<{}>", recipient)).await?;
+        self.read_reply(REPLY_TIMEOUT).await
+    }
+
+    /// Transfers the message, escaping lines that start with a dot
+    pub async fn data(&mut self, message: &[u8]) -> Result<Reply, ClientError> {
+        self.command("DATA").await?;
+
+        let mut body = Vec::with_capacity(message.len() + 64);
+        for line in message.split_inclusive(|&byte| byte == b'\n') {
+            if line.starts_with(b".") {
+                body.push(b'.');
+            }
+            body.extend_from_slice(line);
+        }
+        if !body.ends_with(b"\r\n") {
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b".\r\n");
+        let stream = self.stream.get_mut();
+        stream.write_all(&body).await?;
+        stream.flush().await?;
+
+        self.expect(DATA_TIMEOUT).await
+    }
+
+    /// Ends the session politely, errors do not matter anymore at this point
+    pub async fn quit(mut self) {
+        let _ = self.command("QUIT").await;
+    }
+
+    /// Sends a command and fails unless the reply is positive
+    async fn command(&mut self, command: &str) -> Result<Reply, ClientError> {
+        self.send(command).await?;
+        self.expect(REPLY_TIMEOUT).await
+    }
+
+    async fn send(&mut self, command: &str) -> Result<(), ClientError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn expect(&mut self, limit: Duration) -> Result<Reply, ClientError> {
+        let reply = self.read_reply(limit).await?;
+        match reply.is_positive() {
+            true => Ok(reply),
+            false => Err(ClientError::Rejected(reply)),
+        }
+    }
+
+    /// Reads reply lines until one has a space after its code
+    async fn read_reply(&mut self, limit: Duration) -> Result<Reply, ClientError> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            let read = timeout(
+                limit,
+                (&mut self.stream).take(MAX_REPLY_LENGTH).read_until(b'\n', &mut line),
+            )
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+            if read == 0 {
+                return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| ClientError::Protocol(line.to_string()))?;
+            let last = !line[3..].starts_with('-');
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if last {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+}
+
+async fn handshake<S>(connector: &TlsConnector, host: &str, stream: S) -> Result<tokio_rustls::client::TlsStream<S>, ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|error| ClientError::Protocol(format!("Invalid server name {}: {}", host, error)))?;
+    connector.connect(name, stream).await.map_err(ClientError::Tls)
+}
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use super::{
+    client::{self, Client, ClientError},
+    DeliveryError,
+};
+use crate::dns::Resolver;
+
+/// Port mail exchangers accept mail from other servers on
+const SMTP_PORT: u16 = 25;
+
+/// Opens a session with the most preferred mail exchanger of `domain` that answers
+pub(super) async fn connect(resolver: &Resolver, domain: &str, hostname: &str) -> Result<Client, DeliveryError> {
+    let hosts = resolver.mail_exchangers(domain).await?;
+    if hosts.is_empty() {
+        return Err(DeliveryError::NullMx(domain.to_string()));
+    }
+
+    let mut last_error = None;
+    for host in hosts {
+        let addresses = match resolver.addresses(&host).await {
+            Ok(addresses) => addresses,
+            Err(error) => {
+                log::warn!("Could not resolve mail exchanger {} of {}: {}", host, domain, error);
+                last_error = Some(error.into());
+                continue;
+            }
+        };
+        for ip in addresses {
+            let address = SocketAddr::new(ip, SMTP_PORT);
+            match greet(&host, address, hostname).await {
+                Ok(client) => return Ok(client),
+                Err(error) => {
+                    log::warn!("Mail exchanger {} ({}) of {} failed: {}", host, address, domain, error);
+                    last_error = Some(error.into());
+                }
+            }
+        }
+    }
+    // Every host answered, just without an address: no point in trying again
+    Err(last_error.unwrap_or_else(|| DeliveryError::Unroutable(domain.to_string())))
+}
+
+/// Says EHLO and upgrades to TLS whenever the server offers it. A failed handshake leaves the
+/// connection unusable, so it is reopened and the message goes out in plain text instead.
+async fn greet(host: &str, address: SocketAddr, hostname: &str) -> Result<Client, ClientError> {
+    let mut client = Client::connect(host, address, None).await?;
+    client.ehlo(hostname).await?;
+    if !client.supports("STARTTLS") {
+        return Ok(client);
+    }
+    match client.starttls(&client::opportunistic_connector()).await {
+        Ok(()) => {
+            client.ehlo(hostname).await?;
+            Ok(client)
+        }
+        Err(ClientError::Tls(error)) => {
+            log::warn!("TLS with {} ({}) failed, continuing without it: {}", host, address, error);
+            let mut client = Client::connect(host, address, None).await?;
+            client.ehlo(hostname).await?;
+            Ok(client)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsError, Fixtures};
+
+    const ZONES: &str = r#"
+["example.org"]
+txt = ["v=spf1 -all"]
+
+["example.net"]
+mx = ["10 mx.example.net"]
+
+["mx.example.net"]
+txt = ["no addresses here"]
+
+["example.com"]
+mx = ["10 mx.example.com"]
+
+["mx.example.com"]
+servfail = true
+"#;
+
+    async fn connect_error(domain: &str) -> DeliveryError {
+        let resolver = Resolver::new(Fixtures::parse(ZONES).unwrap());
+        match connect(&resolver, domain, "mx.example.test").await {
+            Ok(_) => panic!("connected to {}", domain),
+            Err(error) => error,
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_domains_without_addresses() {
+        // No MX and no A or AAAA record
+        let error = connect_error("example.org").await;
+        assert!(matches!(error, DeliveryError::Unroutable(_)));
+        assert!(error.is_permanent());
+        assert_eq!(error.status(), "5.4.4");
+
+        // The only mail exchanger has no address
+        let error = connect_error("example.net").await;
+        assert!(matches!(error, DeliveryError::Unroutable(_)));
+        assert!(error.is_permanent());
+
+        let error = connect_error("missing.example.org").await;
+        assert!(matches!(error, DeliveryError::Dns(DnsError::NotFound(_))));
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn retries_failed_lookups() {
+        let error = connect_error("example.com").await;
+        assert!(matches!(error, DeliveryError::Dns(DnsError::Lookup { .. })));
+        assert!(!error.is_permanent());
+    }
+}
@@ -0,0 +1,324 @@
+use std::io;
+
+use mail_builder::{
+    headers::{address::Address, content_type::ContentType, date::Date, raw::Raw},
+    mime::MimePart,
+    MessageBuilder,
+};
+use mail_parser::{MessageParser, MimeHeaders};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::random_token, dkim::canonical};
+
+/// What happened to a recipient, as given in the Action field (RFC 3464 section 2.3.3)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Failed,
+    Delayed,
+    Delivered,
+    Relayed,
+    Expanded,
+}
+
+impl Action {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "failed" => Some(Action::Failed),
+            "delayed" => Some(Action::Delayed),
+            "delivered" => Some(Action::Delivered),
+            "relayed" => Some(Action::Relayed),
+            "expanded" => Some(Action::Expanded),
+            _ => None,
+        }
+    }
+}
+
+/// A delivery status notification about one recipient, sent from here or received
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification {
+    pub action: Action,
+    /// Enhanced status code such as `5.1.1` (RFC 3463)
+    pub status: String,
+    /// Reply of the server that refused the message, when there was one
+    pub diagnostic: Option<String>,
+    /// Server that issued the notification
+    pub reporting_mta: Option<String>,
+    /// Whether the notification came from another server rather than being generated here
+    pub remote: bool,
+    /// Id of the notification in the sender's mailbox, `None` when it could not be stored there
+    pub message: Option<String>,
+    pub created_at: i64,
+}
+
+/// A recipient to list in a generated notification
+pub struct Failure<'a> {
+    pub recipient: &'a str,
+    pub status: &'a str,
+    pub diagnostic: Option<&'a str>,
+    pub reason: &'a str,
+    pub last_attempt: i64,
+}
+
+/// Builds the bounce telling the sender which recipients failed for good (RFC 3464, RFC 6522)
+pub fn build(
+    hostname: &str,
+    sender: &str,
+    arrival: i64,
+    failures: &[Failure<'_>],
+    original: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut text = format!(
+        "This is the mail system at {}.\r\n\r\nYour message could not be delivered to the following recipients:\r\n\r\n",
+        hostname
+    );
+    let mut status = format!(
+        "Reporting-MTA: dns; {}\r\nArrival-Date: {}\r\n",
+        hostname,
+        Date::new(arrival).to_rfc822()
+    );
+    for failure in failures {
+        text.push_str(&format!("  {}: {}\r\n", failure.recipient, failure.reason));
+        status.push_str(&format!(
+            "\r\nFinal-Recipient: rfc822; {}\r\nAction: failed\r\nStatus: {}\r\n",
+            failure.recipient, failure.status
+        ));
+        if let Some(diagnostic) = failure.diagnostic {
+            status.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+        }
+        status.push_str(&format!("Last-Attempt-Date: {}\r\n", Date::new(failure.last_attempt).to_rfc822()));
+    }
+
+    // Only the header of the original is returned, the sender still has the rest
+    let (fields, _) = canonical::split(original);
+    let headers = fields.iter().flat_map(|field| field.raw.iter().copied()).collect::<Vec<_>>();
+
+    let report = MimePart::new(
+        ContentType::new("multipart/report")
+            .attribute("report-type", "delivery-status"),
+        vec![
+            MimePart::new("text/plain", text),
+            MimePart::raw(format!("Content-Type: message/delivery-status\r\n\r\n{}", status)),
+            MimePart::raw(
+                [
+                    b"Content-Type: text/rfc822-headers\r\n\r\n".as_slice(),
+                    &headers,
+                ]
+                .concat(),
+            ),
+        ],
+    );
+    MessageBuilder::new()
+        .from(Address::new_address("Mail Delivery System".into(), format!("MAILER-DAEMON@{}", hostname)))
+        .to(sender)
+        .subject("Undelivered Mail Returned to Sender")
+        .message_id(format!("{}@{}", random_token(), hostname))
+        .date(Date::now())
+        // Keeps auto-responders from answering the bounce (RFC 3834)
+        .header("Auto-Submitted", Raw::new("auto-replied"))
+        .body(report)
+        .write_to_vec()
+}
+
+/// The status of one recipient in a received notification
+pub struct RecipientReport {
+    pub recipient: String,
+    pub action: Action,
+    pub status: String,
+    pub diagnostic: Option<String>,
+}
+
+/// A received delivery status notification
+pub struct Report {
+    /// Message-ID of the message it is about, without angle brackets
+    pub message_id: Option<String>,
+    pub reporting_mta: Option<String>,
+    pub recipients: Vec<RecipientReport>,
+}
+
+/// Splits the body of a delivery-status part into its groups of fields, names lowercased
+fn groups(body: &str) -> Vec<Vec<(String, String)>> {
+    let mut groups = Vec::new();
+    let mut current: Vec<(String, String)> = Vec::new();
+    for line in body.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                groups.push(std::mem::take(&mut current));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = current.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            current.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+    groups
+}
+
+/// The value of a typed field such as `rfc822; bob@example.com`, without its type
+fn untyped(value: &str) -> &str {
+    value.split_once(';').map_or(value, |(_, value)| value).trim()
+}
+
+/// Reads a delivery status notification, `None` for any other message
+pub fn parse(raw: &[u8]) -> Option<Report> {
+    let parsed = MessageParser::default().parse(raw)?;
+    let content_type = parsed.root_part().content_type()?;
+    let is_report = content_type.ctype().eq_ignore_ascii_case("multipart")
+        && content_type.subtype().is_some_and(|subtype| subtype.eq_ignore_ascii_case("report"))
+        && content_type
+            .attribute("report-type")
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("delivery-status"));
+    if !is_report {
+        return None;
+    }
+
+    let mut status = None;
+    let mut message_id = None;
+    for part in &parsed.parts {
+        let Some(content_type) = part.content_type() else {
+            continue;
+        };
+        let kind = format!(
+            "{}/{}",
+            content_type.ctype(),
+            content_type.subtype().unwrap_or_default()
+        )
+        .to_ascii_lowercase();
+        match kind.as_str() {
+            "message/delivery-status" | "message/global-delivery-status" => {
+                status = Some(String::from_utf8_lossy(part.contents()).into_owned())
+            }
+            "text/rfc822-headers" | "message/global-headers" => {
+                message_id = MessageParser::default()
+                    .parse_headers(part.contents())
+                    .and_then(|headers| headers.message_id().map(str::to_string))
+            }
+            "message/rfc822" | "message/global" => {
+                message_id = part
+                    .message()
+                    .and_then(|message| message.message_id())
+                    .map(str::to_string)
+            }
+            _ => {}
+        }
+    }
+
+    let mut groups = groups(&status?).into_iter();
+    let field = |group: &[(String, String)], name: &str| {
+        group
+            .iter()
+            .find(|(known, _)| known == name)
+            .map(|(_, value)| value.clone())
+    };
+    let reporting_mta = field(&groups.next()?, "reporting-mta").map(|value| untyped(&value).to_string());
+    let recipients = groups
+        .filter_map(|group| {
+            let recipient = field(&group, "final-recipient").or_else(|| field(&group, "original-recipient"))?;
+            let recipient = untyped(&recipient).trim_matches(['<', '>']).to_ascii_lowercase();
+            let status = field(&group, "status")?;
+            Some(RecipientReport {
+                recipient,
+                action: Action::parse(&field(&group, "action")?)?,
+                // Servers sometimes append a comment to the code
+                status: status.split_whitespace().next().unwrap_or_default().to_string(),
+                diagnostic: field(&group, "diagnostic-code").map(|value| untyped(&value).to_string()),
+            })
+        })
+        .collect();
+    Some(Report {
+        message_id,
+        reporting_mta,
+        recipients,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &[u8] =
+        b"From: ann@example.com\r\nTo: bob@example.org\r\nMessage-ID: <lunch@example.com>\r\nSubject: Lunch\r\n\r\nNoon?\r\n";
+
+    #[test]
+    fn reads_back_what_it_builds() {
+        let failures = [
+            Failure {
+                recipient: "bob@example.org",
+                status: "5.1.1",
+                diagnostic: Some("550 5.1.1 No such user"),
+                reason: "the recipient does not exist",
+                last_attempt: 1_700_000_100,
+            },
+            Failure {
+                recipient: "Carol@example.net",
+                status: "4.4.7",
+                diagnostic: None,
+                reason: "delivery kept failing",
+                last_attempt: 1_700_000_200,
+            },
+        ];
+        let bounce = build("mx.example.com", "ann@example.com", 1_700_000_000, &failures, ORIGINAL).unwrap();
+        let text = String::from_utf8_lossy(&bounce);
+        assert!(text.contains("Auto-Submitted: auto-replied"));
+        // Only the header of the original goes back
+        assert!(!text.contains("Noon?"));
+
+        let report = parse(&bounce).unwrap();
+        assert_eq!(report.message_id.as_deref(), Some("lunch@example.com"));
+        assert_eq!(report.reporting_mta.as_deref(), Some("mx.example.com"));
+        let recipients = report
+            .recipients
+            .iter()
+            .map(|recipient| {
+                (
+                    recipient.recipient.as_str(),
+                    recipient.action,
+                    recipient.status.as_str(),
+                    recipient.diagnostic.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            recipients,
+            [
+                ("bob@example.org", Action::Failed, "5.1.1", Some("550 5.1.1 No such user")),
+                ("carol@example.net", Action::Failed, "4.4.7", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_reports_of_other_servers() {
+        let raw = b"From: MAILER-DAEMON@mx.example.org\r\n\
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"b\"\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nSorry\r\n\
+--b\r\nContent-Type: message/delivery-status\r\n\r\n\
+Reporting-MTA: dns;mx.example.org\r\n\r\n\
+Original-Recipient: rfc822;<Bob@example.org>\r\nAction: delayed\r\nStatus: 4.2.2 (mailbox full)\r\n\
+Diagnostic-Code: smtp; 452 4.2.2 Mailbox\r\n  full\r\n\r\n\
+--b\r\nContent-Type: message/rfc822\r\n\r\n\
+Message-ID: <lunch@example.com>\r\nSubject: Lunch\r\n\r\nNoon?\r\n\
+--b--\r\n";
+        let report = parse(raw).unwrap();
+        assert_eq!(report.message_id.as_deref(), Some("lunch@example.com"));
+        assert_eq!(report.reporting_mta.as_deref(), Some("mx.example.org"));
+        let recipient = &report.recipients[0];
+        assert_eq!(recipient.recipient, "bob@example.org");
+        assert_eq!(recipient.action, Action::Delayed);
+        assert_eq!(recipient.status, "4.2.2");
+        assert_eq!(recipient.diagnostic.as_deref(), Some("452 4.2.2 Mailbox full"));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert!(parse(ORIGINAL).is_none());
+        let report = b"Content-Type: multipart/report; report-type=disposition-notification; boundary=b\r\n\r\n--b--\r\n";
+        assert!(parse(report).is_none());
+    }
+}
@@ -0,0 +1,1061 @@
+mod client;
+mod direct;
+mod dsn;
+mod relay;
+
+use std::{io, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mail_builder::{headers::address::Address, MessageBuilder};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::{
+    auth::{random_token, Users},
+    config::RelayConfig,
+    db::{self, DatabaseError, Store},
+    delivery::LocalDelivery,
+    dkim::{DkimError, DkimKeys},
+    dns::{DnsError, Resolver},
+    domain::Domains,
+    folder::SENT,
+    message::{Envelope, Message},
+    routing::normalize_address,
+    time,
+};
+use client::{Client, ClientError};
+pub use dsn::{Action, Notification};
+
+/// Attempts per recipient before the message bounces
+const MAX_ATTEMPTS: u32 = 12;
+/// Delay before the first retry, doubled on every further attempt
+const INITIAL_BACKOFF: i64 = 5 * 60;
+/// Upper bound for the delay between two attempts
+const MAX_BACKOFF: i64 = 4 * 60 * 60;
+/// How often the queue is checked when nothing new is submitted
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum OutboundError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Could not build message: {0}")]
+    Build(#[from] io::Error),
+    #[error("Not allowed to send as {0}")]
+    Sender(String),
+    #[error("{0}")]
+    Dkim(#[from] DkimError),
+}
+
+/// Why a message could not be handed over to the next server
+#[derive(Error, Debug)]
+pub enum DeliveryError {
+    #[error("{0}")]
+    Dns(#[from] DnsError),
+    #[error("{0}")]
+    Client(#[from] ClientError),
+    #[error("Domain {0} does not accept mail")]
+    NullMx(String),
+    #[error("No address found for {0}")]
+    NoAddress(String),
+    /// Neither the domain nor any of its mail exchangers has an address, so mail cannot be routed
+    #[error("Domain {0} has no mail exchanger with an address")]
+    Unroutable(String),
+    /// Retried, since it is our configuration that needs fixing and not the message
+    #[error("Relay refused the credentials: {0}")]
+    Authentication(#[source] ClientError),
+}
+
+impl DeliveryError {
+    fn is_permanent(&self) -> bool {
+        match self {
+            DeliveryError::Dns(error) => matches!(error, DnsError::NotFound(_)),
+            DeliveryError::Client(error) => error.is_permanent(),
+            DeliveryError::NullMx(_) | DeliveryError::Unroutable(_) => true,
+            DeliveryError::NoAddress(_) | DeliveryError::Authentication(_) => false,
+        }
+    }
+
+    /// Enhanced status code reported in a notification (RFC 3463)
+    fn status(&self) -> String {
+        match self {
+            DeliveryError::Client(ClientError::Rejected(reply))
+            | DeliveryError::Authentication(ClientError::Rejected(reply)) => reply
+                .enhanced_status()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}.0.0", reply.code / 100)),
+            DeliveryError::Dns(DnsError::NotFound(_)) => "5.1.2".to_string(),
+            DeliveryError::Dns(_) => "4.4.3".to_string(),
+            DeliveryError::NullMx(_) => "5.1.10".to_string(),
+            DeliveryError::NoAddress(_) => "4.4.4".to_string(),
+            DeliveryError::Unroutable(_) => "5.4.4".to_string(),
+            DeliveryError::Client(ClientError::Connect { .. } | ClientError::Timeout) => "4.4.1".to_string(),
+            DeliveryError::Client(_) | DeliveryError::Authentication(_) => "4.4.2".to_string(),
+        }
+    }
+
+    /// The server reply behind the error, if it got that far
+    fn reply(&self) -> Option<String> {
+        match self {
+            DeliveryError::Client(ClientError::Rejected(reply))
+            | DeliveryError::Authentication(ClientError::Rejected(reply)) => Some(reply.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// An address with an optional display name, given as `"a@b"` or `{"name": .., "address": ..}`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Mailbox {
+    Address(String),
+    Named { name: Option<String>, address: String },
+}
+
+impl Mailbox {
+    pub fn address(&self) -> &str {
+        match self {
+            Mailbox::Address(address) | Mailbox::Named { address, .. } => address,
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            Mailbox::Address(_) => None,
+            Mailbox::Named { name, .. } => name.as_deref(),
+        }
+    }
+}
+
+/// A file to attach, its content base64 encoded
+#[derive(Deserialize, Debug, Clone)]
+pub struct DraftAttachment {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub content: String,
+}
+
+/// A message to send as submitted through the API
+#[derive(Deserialize, Debug, Clone)]
+pub struct Draft {
+    pub from: Mailbox,
+    #[serde(default)]
+    pub to: Vec<Mailbox>,
+    #[serde(default)]
+    pub cc: Vec<Mailbox>,
+    /// Only ever part of the envelope, never of the headers
+    #[serde(default)]
+    pub bcc: Vec<Mailbox>,
+    pub subject: Option<String>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<DraftAttachment>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Queued,
+    Delivered,
+    /// Rejected by the relay or given up on, a bounce was recorded
+    Failed,
+}
+
+/// Delivery state of one recipient of an outbound message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recipient {
+    pub address: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    /// Last reply or error seen for this recipient
+    pub response: Option<String>,
+    /// Latest delivery status notification, generated here or reported back by another server
+    pub dsn: Option<Notification>,
+    pub updated_at: i64,
+}
+
+/// A submitted message and where its delivery stands
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboundMessage {
+    /// User who sent it, `None` when sent with an API key
+    pub owner: Option<String>,
+    pub from: String,
+    pub message_id: String,
+    pub subject: Option<String>,
+    pub created_at: i64,
+    pub recipients: Vec<Recipient>,
+}
+
+impl OutboundMessage {
+    fn next_attempt(&self) -> Option<i64> {
+        self.recipients
+            .iter()
+            .filter(|recipient| recipient.status == DeliveryStatus::Queued)
+            .map(|recipient| recipient.next_attempt)
+            .min()
+    }
+}
+
+/// A recipient that could not be delivered to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bounce {
+    pub outbound_id: String,
+    pub owner: Option<String>,
+    pub recipient: String,
+    pub reason: String,
+    pub created_at: i64,
+}
+
+/// Why an attempt did not deliver, as far as a notification reports it
+#[derive(Debug, Clone)]
+struct Failure {
+    reason: String,
+    /// Enhanced status code
+    status: String,
+    /// Reply of the server, `None` when there was none
+    diagnostic: Option<String>,
+}
+
+impl From<&DeliveryError> for Failure {
+    fn from(error: &DeliveryError) -> Self {
+        Failure {
+            reason: error.to_string(),
+            status: error.status(),
+            diagnostic: error.reply(),
+        }
+    }
+}
+
+/// What a delivery attempt meant for one recipient
+#[derive(Debug, Clone)]
+enum Outcome {
+    Delivered(String),
+    /// Temporary failure, worth another attempt
+    Deferred(Failure),
+    Failed(Failure),
+}
+
+impl Outcome {
+    fn from_error(error: impl Into<DeliveryError>) -> Self {
+        let error = error.into();
+        match error.is_permanent() {
+            true => Outcome::Failed(Failure::from(&error)),
+            false => Outcome::Deferred(Failure::from(&error)),
+        }
+    }
+}
+
+/// Backoff shared by every message for a domain whose mail exchangers could not be reached,
+/// so a dead domain is not tried again for each of its messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DomainRetry {
+    failures: u32,
+    retry_at: i64,
+}
+
+/// Where a group of recipients is handed over
+enum Target<'a> {
+    Relay(&'a RelayConfig),
+    /// The mail exchangers of a recipient domain
+    Direct(String),
+}
+
+/// Delay before the given attempt, growing exponentially
+fn backoff(attempts: u32) -> i64 {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(MAX_BACKOFF)
+}
+
+fn address(mailbox: &Mailbox) -> Result<String, OutboundError> {
+    normalize_address(mailbox.address())
+        .filter(|address| !address.chars().any(|c| c.is_whitespace() || matches!(c, '<' | '>')))
+        .ok_or_else(|| OutboundError::Invalid(format!("Invalid address '{}'", mailbox.address())))
+}
+
+fn header_addresses(mailboxes: &[Mailbox]) -> Vec<Address<'_>> {
+    mailboxes
+        .iter()
+        .map(|mailbox| Address::new_address(mailbox.name(), mailbox.address()))
+        .collect()
+}
+
+/// Persistent queue of outbound mail, handed to the configured relay or straight to the
+/// mail exchangers of each recipient domain
+#[derive(Clone)]
+pub struct Outbound {
+    database: Db,
+    relay: Option<RelayConfig>,
+    resolver: Resolver,
+    /// Name announced in EHLO
+    hostname: String,
+    users: Users,
+    domains: Domains,
+    dkim: DkimKeys,
+    /// Hands bounces to the sender's mailbox
+    delivery: LocalDelivery,
+    messages: Store<OutboundMessage>,
+    /// Message-IDs of sent messages mapped to their id, to match notifications coming back
+    message_ids: Store<String>,
+    raw: Store<Vec<u8>>,
+    /// Ids of messages with recipients left to deliver, mapped to their next attempt
+    queue: Store<i64>,
+    bounces: Store<Bounce>,
+    domain_retries: Store<DomainRetry>,
+    wake: Arc<Notify>,
+}
+
+impl Outbound {
+    #[allow(clippy::too_many_arguments)]
+    pub fn open(
+        database: &Db,
+        relay: Option<RelayConfig>,
+        resolver: Resolver,
+        hostname: String,
+        users: Users,
+        domains: Domains,
+        dkim: DkimKeys,
+        delivery: LocalDelivery,
+    ) -> Result<Self, DatabaseError> {
+        Ok(Outbound {
+            database: database.clone(),
+            relay,
+            resolver,
+            hostname,
+            users,
+            domains,
+            dkim,
+            delivery,
+            messages: Store::open(database, "outbound")?,
+            message_ids: Store::open(database, "outbound_message_ids")?,
+            raw: Store::open(database, "outbound_raw")?,
+            queue: Store::open(database, "outbound_queue")?,
+            bounces: Store::open(database, "bounces")?,
+            domain_retries: Store::open(database, "outbound_domains")?,
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Users may send from their own addresses, API keys from any hosted domain
+    async fn check_sender(&self, owner: Option<&str>, from: &str) -> Result<(), OutboundError> {
+        let allowed = match owner {
+            Some(username) => self.users.owner(from).await?.as_deref() == Some(username),
+            None => {
+                let domain = from.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+                self.domains.contains(domain).await?
+            }
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(OutboundError::Sender(from.to_string())),
+        }
+    }
+
+    /// Builds the MIME message for a draft and queues it for every recipient.
+    /// `owner` is the sending user, `None` for API keys.
+    pub async fn submit(&self, owner: Option<String>, draft: Draft) -> Result<(String, OutboundMessage), OutboundError> {
+        let from = address(&draft.from)?;
+        self.check_sender(owner.as_deref(), &from).await?;
+        let mut recipients: Vec<String> = Vec::new();
+        for mailbox in draft.to.iter().chain(&draft.cc).chain(&draft.bcc) {
+            let recipient = address(mailbox)?;
+            if !recipients.contains(&recipient) {
+                recipients.push(recipient);
+            }
+        }
+        if recipients.is_empty() {
+            return Err(OutboundError::Invalid("At least one recipient is required".to_string()));
+        }
+        if draft.text.is_none() && draft.html.is_none() && draft.attachments.is_empty() {
+            return Err(OutboundError::Invalid("The message has no content".to_string()));
+        }
+
+        let domain = from.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+        let message_id = format!("{}@{}", random_token(), domain);
+        let now = time::now();
+        let mut builder = MessageBuilder::new()
+            .from(Address::new_address(draft.from.name(), from.as_str()))
+            .message_id(message_id.as_str())
+            .date(now);
+        if !draft.to.is_empty() {
+            builder = builder.to(header_addresses(&draft.to));
+        }
+        if !draft.cc.is_empty() {
+            builder = builder.cc(header_addresses(&draft.cc));
+        }
+        if let Some(subject) = &draft.subject {
+            builder = builder.subject(subject.as_str());
+        }
+        if let Some(text) = &draft.text {
+            builder = builder.text_body(text.as_str());
+        }
+        if let Some(html) = &draft.html {
+            builder = builder.html_body(html.as_str());
+        }
+        for attachment in &draft.attachments {
+            let content = STANDARD.decode(attachment.content.trim()).map_err(|error| {
+                OutboundError::Invalid(format!("Attachment {} is not valid base64: {}", attachment.filename, error))
+            })?;
+            let content_type = attachment
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream");
+            builder = builder.attachment(content_type, attachment.filename.as_str(), content);
+        }
+        let raw = self.dkim.sign(domain, &builder.write_to_vec()?).await?;
+
+        let message = OutboundMessage {
+            owner,
+            from,
+            message_id,
+            subject: draft.subject,
+            created_at: now,
+            recipients: recipients
+                .into_iter()
+                .map(|address| Recipient {
+                    address,
+                    status: DeliveryStatus::Queued,
+                    attempts: 0,
+                    next_attempt: now,
+                    response: None,
+                    dsn: None,
+                    updated_at: now,
+                })
+                .collect(),
+        };
+        let id = db::generate_key(&self.database)?;
+        self.raw.set(&id, &raw).await?;
+        self.messages.set(&id, &message).await?;
+        self.message_ids.set(&message.message_id, &id).await?;
+        self.queue.set(&id, &now).await?;
+        self.wake.notify_one();
+        log::info!("Queued outbound message {} from {}", id, message.from);
+        self.file_sent(&message, &raw).await;
+        Ok((id, message))
+    }
+
+    /// Keeps a copy in the Sent folder of the user the sender address belongs to
+    async fn file_sent(&self, message: &OutboundMessage, raw: &[u8]) {
+        let mailbox = match self.users.owner(&message.from).await {
+            Ok(Some(mailbox)) => mailbox,
+            Ok(None) => return,
+            Err(error) => {
+                log::error!("Could not look up the owner of {}: {}", message.from, error);
+                return;
+            }
+        };
+        let envelope = Envelope {
+            client_ip: None,
+            helo: self.hostname.clone(),
+            mail_from: message.from.clone(),
+            rcpt_to: message.recipients.iter().map(|recipient| recipient.address.clone()).collect(),
+        };
+        let (mut copy, attachments) = Message::parse(envelope, raw);
+        copy.mailbox = Some(mailbox);
+        copy.folder = SENT.to_string();
+        // The sender has read what they wrote
+        copy.flags.seen = true;
+        // The message is queued either way, only the copy is missing
+        if let Err(error) = self.delivery.file(&copy, raw, &attachments).await {
+            log::error!("Could not file a sent copy of {}: {}", message.message_id, error);
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Result<OutboundMessage, DatabaseError> {
+        self.messages.get(id).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, OutboundMessage)>, DatabaseError> {
+        self.messages.list().await
+    }
+
+    pub async fn bounces(&self) -> Result<Vec<(String, Bounce)>, DatabaseError> {
+        self.bounces.list().await
+    }
+
+    /// Delivers queued messages until the process exits
+    pub async fn run(self) {
+        match &self.relay {
+            Some(relay) => log::info!("Relaying outbound mail through {}:{}", relay.host, relay.port),
+            None => log::info!("No relay configured, delivering outbound mail directly"),
+        }
+        loop {
+            if let Err(error) = self.deliver_due().await {
+                log::error!("Outbound Queue Error: {}", error);
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Attempts every message with a recipient whose retry time has come
+    async fn deliver_due(&self) -> Result<(), DatabaseError> {
+        for (id, next_attempt) in self.queue.list().await? {
+            if next_attempt > time::now() {
+                continue;
+            }
+            let mut message = match self.messages.get(&id).await {
+                Ok(message) => message,
+                Err(DatabaseError::NotFound) => {
+                    self.queue.delete(&id).await?;
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let raw = self.raw.get(&id).await?;
+
+            let now = time::now();
+            let mut failed = Vec::new();
+            let due = message
+                .recipients
+                .iter()
+                .enumerate()
+                .filter(|(_, recipient)| recipient.status == DeliveryStatus::Queued && recipient.next_attempt <= now)
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            for (target, indexes) in self.targets(&message, due) {
+                let outcomes = match &target {
+                    Target::Relay(_) => self.transfer(&target, &message, &indexes, &raw).await,
+                    Target::Direct(domain) => {
+                        // Waits for the domain without spending an attempt of the recipients
+                        if let Some(retry_at) = self.domain_retry(domain).await?.filter(|retry_at| *retry_at > now) {
+                            for &index in &indexes {
+                                message.recipients[index].next_attempt = retry_at;
+                            }
+                            continue;
+                        }
+                        let outcomes = self.transfer(&target, &message, &indexes, &raw).await;
+                        self.update_domain_retry(domain, &outcomes).await?;
+                        outcomes
+                    }
+                };
+                for (index, outcome) in indexes.into_iter().zip(outcomes) {
+                    if let Some(failure) = self.record(&id, &mut message, index, outcome).await? {
+                        failed.push((index, failure));
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                self.notify(&id, &mut message, &failed, &raw).await;
+            }
+
+            let message = self.save(&id, message).await?;
+            match message.next_attempt() {
+                Some(next_attempt) => self.queue.set(&id, &next_attempt).await?,
+                None => {
+                    self.queue.delete(&id).await?;
+                    self.raw.delete(&id).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Groups recipients by where they are handed over, one group per domain without a relay
+    fn targets(&self, message: &OutboundMessage, due: Vec<usize>) -> Vec<(Target<'_>, Vec<usize>)> {
+        if let Some(relay) = &self.relay {
+            return vec![(Target::Relay(relay), due)];
+        }
+        let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+        for index in due {
+            let address = &message.recipients[index].address;
+            let domain = address.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+            match groups.iter_mut().find(|(known, _)| known == domain) {
+                Some((_, indexes)) => indexes.push(index),
+                None => groups.push((domain.to_string(), vec![index])),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(domain, indexes)| (Target::Direct(domain), indexes))
+            .collect()
+    }
+
+    async fn domain_retry(&self, domain: &str) -> Result<Option<i64>, DatabaseError> {
+        match self.domain_retries.get(domain).await {
+            Ok(retry) => Ok(Some(retry.retry_at)),
+            Err(DatabaseError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Backs off from a domain when every recipient was deferred, forgets the backoff otherwise
+    async fn update_domain_retry(&self, domain: &str, outcomes: &[Outcome]) -> Result<(), DatabaseError> {
+        if !outcomes.iter().all(|outcome| matches!(outcome, Outcome::Deferred(_))) {
+            return match self.domain_retries.delete(domain).await {
+                Ok(()) | Err(DatabaseError::NotFound) => Ok(()),
+                Err(error) => Err(error),
+            };
+        }
+        let failures = match self.domain_retries.get(domain).await {
+            Ok(retry) => retry.failures + 1,
+            Err(DatabaseError::NotFound) => 1,
+            Err(error) => return Err(error),
+        };
+        let retry = DomainRetry {
+            failures,
+            retry_at: time::now() + backoff(failures),
+        };
+        log::warn!("Holding back mail for {} for {}s", domain, retry.retry_at - time::now());
+        self.domain_retries.set(domain, &retry).await
+    }
+
+    /// Opens a session with the relay or a mail exchanger of the domain
+    async fn connect(&self, target: &Target<'_>) -> Result<Client, DeliveryError> {
+        match target {
+            Target::Relay(relay) => relay::connect(&self.resolver, relay, &self.hostname).await,
+            Target::Direct(domain) => direct::connect(&self.resolver, domain, &self.hostname).await,
+        }
+    }
+
+    /// Hands the message over for the given recipients, one outcome per recipient
+    async fn transfer(&self, target: &Target<'_>, message: &OutboundMessage, due: &[usize], raw: &[u8]) -> Vec<Outcome> {
+        let mut client = match self.connect(target).await {
+            Ok(client) => client,
+            Err(error) => {
+                let outcome = Outcome::from_error(error);
+                return due.iter().map(|_| outcome.clone()).collect();
+            }
+        };
+        if let Err(error) = client.mail_from(&message.from).await {
+            let outcome = Outcome::from_error(error);
+            return due.iter().map(|_| outcome.clone()).collect();
+        }
+
+        let mut outcomes = Vec::with_capacity(due.len());
+        let mut accepted = Vec::new();
+        for &index in due {
+            match client.rcpt_to(&message.recipients[index].address).await {
+                Ok(reply) if reply.is_positive() => {
+                    accepted.push(outcomes.len());
+                    outcomes.push(Outcome::Delivered(reply.to_string()));
+                }
+                Ok(reply) => outcomes.push(Outcome::from_error(ClientError::Rejected(reply))),
+                Err(error) => {
+                    // The connection is gone, everyone accepted so far is retried as well
+                    let deferred = Outcome::Deferred(Failure::from(&DeliveryError::from(error)));
+                    for &position in &accepted {
+                        outcomes[position] = deferred.clone();
+                    }
+                    outcomes.resize(due.len(), deferred);
+                    return outcomes;
+                }
+            }
+        }
+        if accepted.is_empty() {
+            client.quit().await;
+            return outcomes;
+        }
+
+        let outcome = match client.data(raw).await {
+            Ok(reply) => {
+                client.quit().await;
+                Outcome::Delivered(reply.to_string())
+            }
+            Err(error) => Outcome::from_error(error),
+        };
+        for &position in &accepted {
+            outcomes[position] = outcome.clone();
+        }
+        outcomes
+    }
+
+    /// Writes back the recipients a run worked on. Reports received meanwhile only change
+    /// recipients that were already delivered, so those are taken from the stored record.
+    async fn save(&self, id: &str, mut message: OutboundMessage) -> Result<OutboundMessage, DatabaseError> {
+        let stored = match self.messages.get(id).await {
+            Ok(stored) => stored,
+            Err(DatabaseError::NotFound) => return Ok(message),
+            Err(error) => return Err(error),
+        };
+        for (recipient, stored) in message.recipients.iter_mut().zip(stored.recipients) {
+            if stored.status != DeliveryStatus::Queued {
+                *recipient = stored;
+            }
+        }
+        self.messages.set(id, &message).await?;
+        Ok(message)
+    }
+
+    /// Applies the outcome of an attempt to a recipient, bouncing it once delivery is hopeless.
+    /// Returns why it failed when it did.
+    async fn record(
+        &self,
+        id: &str,
+        message: &mut OutboundMessage,
+        index: usize,
+        outcome: Outcome,
+    ) -> Result<Option<Failure>, DatabaseError> {
+        let now = time::now();
+        let recipient = &mut message.recipients[index];
+        recipient.attempts += 1;
+        recipient.updated_at = now;
+        let failure = match outcome {
+            Outcome::Delivered(reply) => {
+                log::info!("Delivered outbound message {} to {}", id, recipient.address);
+                recipient.status = DeliveryStatus::Delivered;
+                recipient.response = Some(reply);
+                return Ok(None);
+            }
+            Outcome::Deferred(failure) if recipient.attempts < MAX_ATTEMPTS => {
+                recipient.next_attempt = now + backoff(recipient.attempts);
+                log::warn!(
+                    "Outbound message {} to {} deferred, retrying in {}s: {}",
+                    id,
+                    recipient.address,
+                    recipient.next_attempt - now,
+                    failure.reason
+                );
+                recipient.response = Some(failure.reason);
+                return Ok(None);
+            }
+            Outcome::Deferred(failure) => Failure {
+                reason: format!("Gave up after {} attempts: {}", recipient.attempts, failure.reason),
+                ..failure
+            },
+            Outcome::Failed(failure) => failure,
+        };
+
+        log::error!("Outbound message {} to {} failed: {}", id, recipient.address, failure.reason);
+        recipient.status = DeliveryStatus::Failed;
+        recipient.response = Some(failure.reason.clone());
+        let bounce = Bounce {
+            outbound_id: id.to_string(),
+            owner: message.owner.clone(),
+            recipient: recipient.address.clone(),
+            reason: failure.reason.clone(),
+            created_at: now,
+        };
+        self.bounces.set(&db::generate_key(&self.database)?, &bounce).await?;
+        Ok(Some(failure))
+    }
+
+    /// Returns a delivery status notification to the sender for the recipients that just failed
+    async fn notify(&self, id: &str, message: &mut OutboundMessage, failed: &[(usize, Failure)], raw: &[u8]) {
+        let failures = failed
+            .iter()
+            .map(|(index, failure)| dsn::Failure {
+                recipient: &message.recipients[*index].address,
+                status: &failure.status,
+                diagnostic: failure.diagnostic.as_deref(),
+                reason: &failure.reason,
+                last_attempt: message.recipients[*index].updated_at,
+            })
+            .collect::<Vec<_>>();
+        let stored = match dsn::build(&self.hostname, &message.from, message.created_at, &failures, raw) {
+            Ok(report) => {
+                let envelope = Envelope {
+                    client_ip: None,
+                    helo: self.hostname.clone(),
+                    // Notifications have a null reverse path so they never bounce themselves
+                    mail_from: String::new(),
+                    rcpt_to: vec![message.from.clone()],
+                };
+                let (notification, attachments) = Message::parse(envelope, &report);
+                self.delivery.deliver(notification, &report, &attachments).await
+            }
+            Err(error) => {
+                log::error!("Could not build delivery report for outbound message {}: {}", id, error);
+                None
+            }
+        };
+        if stored.is_none() {
+            log::warn!("No delivery report for outbound message {} was stored for {}", id, message.from);
+        }
+
+        let now = time::now();
+        for (index, failure) in failed {
+            message.recipients[*index].dsn = Some(Notification {
+                action: Action::Failed,
+                status: failure.status.clone(),
+                diagnostic: failure.diagnostic.clone(),
+                reporting_mta: Some(self.hostname.clone()),
+                remote: false,
+                message: stored.clone(),
+                created_at: now,
+            });
+        }
+    }
+
+    /// Applies a delivery status notification received over SMTP to the sent message it is
+    /// about. Anything else is ignored, and so are reports not addressed to that message's
+    /// sender, since anyone could make them up.
+    pub async fn receive_report(&self, recipients: &[String], raw: &[u8], stored_id: &str) -> Result<(), DatabaseError> {
+        let Some(report) = dsn::parse(raw) else {
+            return Ok(());
+        };
+        let Some(message_id) = report.message_id else {
+            log::warn!("Delivery report {} does not say which message it is about", stored_id);
+            return Ok(());
+        };
+        let id = match self.message_ids.get(&message_id).await {
+            Ok(id) => id,
+            Err(DatabaseError::NotFound) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let mut message = match self.messages.get(&id).await {
+            Ok(message) => message,
+            Err(DatabaseError::NotFound) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if !recipients.iter().any(|recipient| recipient.eq_ignore_ascii_case(&message.from)) {
+            log::warn!("Ignoring delivery report {} not sent to {}", stored_id, message.from);
+            return Ok(());
+        }
+
+        let now = time::now();
+        for entry in report.recipients {
+            let Some(recipient) = message
+                .recipients
+                .iter_mut()
+                .find(|recipient| recipient.address.eq_ignore_ascii_case(&entry.recipient))
+            else {
+                continue;
+            };
+            // Queued recipients belong to the delivery loop, failed ones are settled already
+            if recipient.status != DeliveryStatus::Delivered {
+                continue;
+            }
+            recipient.updated_at = now;
+            recipient.dsn = Some(Notification {
+                action: entry.action,
+                status: entry.status.clone(),
+                diagnostic: entry.diagnostic.clone(),
+                reporting_mta: report.reporting_mta.clone(),
+                remote: true,
+                message: Some(stored_id.to_string()),
+                created_at: now,
+            });
+            if entry.action != Action::Failed {
+                log::info!("Outbound message {} to {} reported {:?}", id, recipient.address, entry.action);
+                continue;
+            }
+
+            let reason = entry.diagnostic.unwrap_or_else(|| format!("Reported as failed with status {}", entry.status));
+            log::error!("Outbound message {} to {} bounced: {}", id, recipient.address, reason);
+            recipient.status = DeliveryStatus::Failed;
+            recipient.response = Some(reason.clone());
+            let bounce = Bounce {
+                outbound_id: id.clone(),
+                owner: message.owner.clone(),
+                recipient: recipient.address.clone(),
+                reason,
+                created_at: now,
+            };
+            self.bounces.set(&db::generate_key(&self.database)?, &bounce).await?;
+        }
+        self.messages.set(&id, &message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        config::RelayTls,
+        dns::Fixtures,
+        message::MessageStore,
+        routing::Router,
+        webhook::Webhook,
+    };
+
+    /// What the sink was handed in one session
+    #[derive(Debug, Default)]
+    struct Session {
+        mail_from: String,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    /// A minimal SMTP server that defers recipients whose local part is `later`, rejects `nobody`
+    /// and accepts everyone else
+    async fn sink() -> (u16, mpsc::UnboundedReceiver<Session>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sessions, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut session = Session::default();
+                stream.get_mut().write_all(b"220 sink.example.org ESMTP\r\n").await.unwrap();
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap() > 0 {
+                    let command = line.trim_end().to_string();
+                    line.clear();
+                    let reply = if command.starts_with("EHLO ") {
+                        "250 sink.example.org"
+                    } else if let Some(sender) = command.strip_prefix("MAIL FROM:") {
+                        session.mail_from = sender.trim_matches(['<', '>']).to_string();
+                        "250 2.1.0 Ok"
+                    } else if let Some(recipient) = command.strip_prefix("RCPT TO:") {
+                        let recipient = recipient.trim_matches(['<', '>']).to_string();
+                        session.rcpt_to.push(recipient.clone());
+                        match recipient.split_once('@').map(|(local, _)| local) {
+                            Some("later") => "451 4.3.0 Try again later",
+                            Some("nobody") => "550 5.1.1 No such user",
+                            _ => "250 2.1.5 Ok",
+                        }
+                    } else if command == "DATA" {
+                        stream.get_mut().write_all(b"354 Go ahead\r\n").await.unwrap();
+                        loop {
+                            stream.read_line(&mut line).await.unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            session.data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                            line.clear();
+                        }
+                        line.clear();
+                        "250 2.0.0 Queued"
+                    } else if command == "QUIT" {
+                        stream.get_mut().write_all(b"221 2.0.0 Bye\r\n").await.unwrap();
+                        break;
+                    } else {
+                        "502 5.5.2 Not implemented"
+                    };
+                    stream.get_mut().write_all(format!("{}\r\n", reply).as_bytes()).await.unwrap();
+                }
+                let _ = sessions.send(session);
+            }
+        });
+        (port, received)
+    }
+
+    /// A queue relaying through the sink, where ann@example.com is a user of the hosted example.com
+    async fn outbound(port: u16) -> (Outbound, MessageStore) {
+        // Relay connections set up TLS with the provider the server installs on startup
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let users = Users::open(&database, 3600).unwrap();
+        users
+            .create("ann", "correct horse battery".to_string(), vec!["ann@example.com".to_string()])
+            .await
+            .unwrap();
+        let domains = Domains::open(&database).unwrap();
+        domains.add("example.com").await.unwrap();
+        let messages = MessageStore::open(&database).unwrap();
+        let delivery = LocalDelivery::new(
+            messages.clone(),
+            Router::open(&database, users.clone(), domains.clone()).unwrap(),
+            Webhook::open(&database, None, None).unwrap(),
+        );
+        let relay = RelayConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: RelayTls::None,
+            username: None,
+            password: None,
+        };
+        let outbound = Outbound::open(
+            &database,
+            Some(relay),
+            Resolver::new(Fixtures::parse("").unwrap()),
+            "mx.example.com".to_string(),
+            users,
+            domains.clone(),
+            DkimKeys::open(&database, domains).unwrap(),
+            delivery,
+        )
+        .unwrap();
+        (outbound, messages)
+    }
+
+    fn draft(recipients: &[&str]) -> Draft {
+        Draft {
+            from: Mailbox::Address("ann@example.com".to_string()),
+            to: recipients.iter().map(|recipient| Mailbox::Address(recipient.to_string())).collect(),
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            subject: Some("Lunch".to_string()),
+            text: Some("Noon?\n.\nSee you".to_string()),
+            html: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_the_envelope_and_message_over() {
+        let (port, mut sessions) = sink().await;
+        let (outbound, _) = outbound(port).await;
+        let (id, submitted) = outbound.submit(Some("ann".to_string()), draft(&["bob@example.org"])).await.unwrap();
+        let raw = outbound.raw.get(&id).await.unwrap();
+
+        outbound.deliver_due().await.unwrap();
+        let session = sessions.recv().await.unwrap();
+        assert_eq!(session.mail_from, "ann@example.com");
+        assert_eq!(session.rcpt_to, vec!["bob@example.org".to_string()]);
+        // Byte for byte, the lone dot in the body included, up to the line break DATA ends with
+        assert_eq!(session.data.trim_end_matches("\r\n").as_bytes(), raw.strip_suffix(b"\r\n").unwrap_or(&raw));
+        assert!(session.data.contains("\r\n.\r\nSee you"));
+        assert!(session.data.contains(&format!("Message-ID: <{}>", submitted.message_id)));
+
+        let message = outbound.get(&id).await.unwrap();
+        assert_eq!(message.recipients[0].status, DeliveryStatus::Delivered);
+        assert_eq!(message.recipients[0].response.as_deref(), Some("250 2.0.0 Queued"));
+        // Nothing left to deliver
+        assert!(outbound.queue.list().await.unwrap().is_empty());
+        assert!(outbound.raw.get(&id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_deferred_and_bounces_rejected_recipients() {
+        let (port, mut sessions) = sink().await;
+        let (outbound, messages) = outbound(port).await;
+        let (id, _) = outbound
+            .submit(
+                Some("ann".to_string()),
+                draft(&["bob@example.org", "later@example.org", "nobody@example.org"]),
+            )
+            .await
+            .unwrap();
+
+        let before = time::now();
+        outbound.deliver_due().await.unwrap();
+        assert_eq!(sessions.recv().await.unwrap().rcpt_to.len(), 3);
+        let message = outbound.get(&id).await.unwrap();
+        let [bob, later, nobody] = &message.recipients[..] else {
+            panic!("recipients went missing");
+        };
+        assert_eq!(bob.status, DeliveryStatus::Delivered);
+
+        assert_eq!(later.status, DeliveryStatus::Queued);
+        assert_eq!(later.attempts, 1);
+        assert!(later.next_attempt >= before + INITIAL_BACKOFF);
+        assert!(later.response.as_deref().unwrap().contains("451"));
+        assert!(later.dsn.is_none());
+
+        assert_eq!(nobody.status, DeliveryStatus::Failed);
+        let dsn = nobody.dsn.as_ref().unwrap();
+        assert_eq!(dsn.status, "5.1.1");
+        let bounces = outbound.bounces().await.unwrap();
+        assert_eq!(bounces.len(), 1);
+        assert_eq!(bounces[0].1.recipient, "nobody@example.org");
+        // The notification went to the sender's mailbox
+        let report = messages.get(dsn.message.as_deref().unwrap()).await.unwrap();
+        assert_eq!(report.mailbox.as_deref(), Some("ann"));
+
+        // Once due again only the deferred recipient is tried
+        assert_eq!(outbound.queue.get(&id).await.unwrap(), later.next_attempt);
+        let mut message = message.clone();
+        message.recipients[1].next_attempt = time::now();
+        outbound.messages.set(&id, &message).await.unwrap();
+        outbound.queue.set(&id, &time::now()).await.unwrap();
+        outbound.deliver_due().await.unwrap();
+        assert_eq!(sessions.recv().await.unwrap().rcpt_to, vec!["later@example.org".to_string()]);
+        assert_eq!(outbound.get(&id).await.unwrap().recipients[1].attempts, 2);
+    }
+}
@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+
+use super::{
+    client::{self, Client, ClientError},
+    DeliveryError,
+};
+use crate::{
+    config::{RelayConfig, RelayTls},
+    dns::Resolver,
+};
+
+/// Opens an authenticated session with the smarthost, trying each of its addresses
+pub(super) async fn connect(resolver: &Resolver, relay: &RelayConfig, hostname: &str) -> Result<Client, DeliveryError> {
+    let mut last_error = DeliveryError::NoAddress(relay.host.clone());
+    for ip in resolver.addresses(&relay.host).await? {
+        let address = SocketAddr::new(ip, relay.port);
+        match greet(relay, address, hostname).await {
+            Ok(client) => return login(client, relay).await,
+            Err(error) => {
+                log::warn!("Relay {} ({}) failed: {}", relay.host, address, error);
+                last_error = error.into();
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Says EHLO, securing the connection first as configured. The certificate has to be valid.
+async fn greet(relay: &RelayConfig, address: SocketAddr, hostname: &str) -> Result<Client, ClientError> {
+    let connector = client::verifying_connector();
+    let tls = (relay.tls == RelayTls::Tls).then_some(&connector);
+    let mut client = Client::connect(&relay.host, address, tls).await?;
+    client.ehlo(hostname).await?;
+    if relay.tls == RelayTls::StartTls {
+        client.starttls(&connector).await?;
+        client.ehlo(hostname).await?;
+    }
+    Ok(client)
+}
+
+async fn login(mut client: Client, relay: &RelayConfig) -> Result<Client, DeliveryError> {
+    if let (Some(username), Some(password)) = (&relay.username, &relay.password) {
+        client
+            .auth_plain(username, password)
+            .await
+            .map_err(DeliveryError::Authentication)?;
+    }
+    Ok(client)
+}
@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use thiserror::Error;
+
+use crate::{
+    auth::Users,
+    db::{DatabaseError, Store},
+    domain::Domains,
+    time,
+};
+
+/// Separates the mailbox from the tag in `user+tag@domain`
+const TAG_SEPARATOR: char = '+';
+/// Aliases pointing at aliases are followed this deep, which also stops loops
+const MAX_DEPTH: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum RoutingError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+    #[error("Invalid address '{0}'")]
+    Invalid(String),
+    #[error("Domain {0} is not hosted")]
+    UnknownDomain(String),
+    #[error("Address {0} already belongs to a user")]
+    AddressTaken(String),
+}
+
+/// Lowercases an address, `None` unless it has a local part and a domain
+pub fn normalize_address(address: &str) -> Option<String> {
+    let address = address.trim().to_lowercase();
+    let (local, domain) = address.rsplit_once('@')?;
+    (!local.is_empty() && !domain.is_empty() && !local.contains('@')).then_some(address)
+}
+
+/// An address that forwards to one or more other addresses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alias {
+    pub targets: Vec<String>,
+    pub created_at: i64,
+}
+
+/// Where one copy of a message ends up
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Owning user, `None` for mail only API keys can see
+    pub mailbox: Option<String>,
+    /// Subaddress the message was sent to, e.g. `shop` for `alice+shop@example.com`
+    pub tag: Option<String>,
+}
+
+/// Outcome of routing a single recipient
+#[derive(Debug)]
+pub enum Resolution {
+    /// The domain is not hosted here
+    Relay,
+    /// Nothing in a hosted domain answers to the address
+    Unknown,
+    Deliver(Vec<Route>),
+}
+
+/// Resolves recipients to mailboxes through user addresses, aliases, subaddresses and catch-alls
+#[derive(Clone)]
+pub struct Router {
+    users: Users,
+    domains: Domains,
+    aliases: Store<Alias>,
+}
+
+impl Router {
+    pub fn open(database: &Db, users: Users, domains: Domains) -> Result<Self, DatabaseError> {
+        Ok(Router {
+            users,
+            domains,
+            aliases: Store::open(database, "aliases")?,
+        })
+    }
+
+    pub async fn aliases(&self) -> Result<Vec<(String, Alias)>, DatabaseError> {
+        self.aliases.list().await
+    }
+
+    /// Creates or replaces an alias in a hosted domain
+    pub async fn set_alias(&self, address: &str, targets: Vec<String>) -> Result<(String, Alias), RoutingError> {
+        let address = normalize_address(address).ok_or_else(|| RoutingError::Invalid(address.to_string()))?;
+        let domain = address.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+        if !self.domains.contains(domain).await? {
+            return Err(RoutingError::UnknownDomain(domain.to_string()));
+        }
+        // The user would always win, leaving the alias without any effect
+        if self.users.owner(&address).await?.is_some() {
+            return Err(RoutingError::AddressTaken(address));
+        }
+
+        let mut normalized = Vec::with_capacity(targets.len());
+        for target in targets {
+            let target = normalize_address(&target).ok_or(RoutingError::Invalid(target))?;
+            if !normalized.contains(&target) {
+                normalized.push(target);
+            }
+        }
+        if normalized.is_empty() {
+            return Err(RoutingError::Invalid("An alias needs at least one target".to_string()));
+        }
+
+        let alias = Alias {
+            targets: normalized,
+            created_at: time::now(),
+        };
+        self.aliases.set(&address, &alias).await?;
+        Ok((address, alias))
+    }
+
+    pub async fn remove_alias(&self, address: &str) -> Result<(), DatabaseError> {
+        self.aliases.delete(&address.to_lowercase()).await
+    }
+
+    async fn alias(&self, address: &str) -> Result<Option<Alias>, DatabaseError> {
+        match self.aliases.get(address).await {
+            Ok(alias) => Ok(Some(alias)),
+            Err(DatabaseError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Routes a recipient, following aliases, subaddresses and the catch-all of its domain
+    pub async fn resolve(&self, recipient: &str) -> Result<Resolution, DatabaseError> {
+        let Some(recipient) = normalize_address(recipient) else {
+            return Ok(Resolution::Unknown);
+        };
+        let (local, domain) = recipient.rsplit_once('@').unwrap_or_default();
+        if !self.domains.contains(domain).await? {
+            return Ok(Resolution::Relay);
+        }
+
+        let mut routes = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![(recipient.clone(), None, 0)];
+        while let Some((address, tag, depth)) = pending.pop() {
+            if depth > MAX_DEPTH || !seen.insert(address.clone()) {
+                continue;
+            }
+            if let Some(mailbox) = self.users.owner(&address).await? {
+                // One copy per mailbox, even when several recipients lead to it
+                if !routes.iter().any(|route: &Route| route.mailbox.as_ref() == Some(&mailbox)) {
+                    routes.push(Route {
+                        mailbox: Some(mailbox),
+                        tag,
+                    });
+                }
+                continue;
+            }
+            if let Some(alias) = self.alias(&address).await? {
+                pending.extend(alias.targets.into_iter().map(|target| (target, tag.clone(), depth + 1)));
+                continue;
+            }
+
+            let (local, domain) = address.rsplit_once('@').unwrap_or_default();
+            if let Some((base, subaddress)) = local.split_once(TAG_SEPARATOR) {
+                // The outermost tag is the one the sender used
+                let tag = tag.or_else(|| Some(subaddress.to_string()));
+                pending.push((format!("{}@{}", base, domain), tag, depth));
+                continue;
+            }
+            if let Some(catch_all) = self.domains.catch_all(domain).await? {
+                pending.push((catch_all, tag, depth + 1));
+            }
+        }
+
+        // RFC 5321 requires every domain to accept mail for its postmaster
+        if routes.is_empty() && local == "postmaster" {
+            routes.push(Route {
+                mailbox: None,
+                tag: None,
+            });
+        }
+        match routes.is_empty() {
+            true => Ok(Resolution::Unknown),
+            false => Ok(Resolution::Deliver(routes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A router over a throwaway database hosting example.com, where ann@example.com is a user
+    async fn router() -> Router {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let users = Users::open(&database, 3600).unwrap();
+        users
+            .create("ann", "correct horse battery".to_string(), vec!["ann@example.com".to_string()])
+            .await
+            .unwrap();
+        let domains = Domains::open(&database).unwrap();
+        domains.add("example.com").await.unwrap();
+        Router::open(&database, users, domains).unwrap()
+    }
+
+    /// Mailbox and tag of every route of a recipient, panicking unless it is delivered
+    async fn routes(router: &Router, recipient: &str) -> Vec<(Option<String>, Option<String>)> {
+        match router.resolve(recipient).await.unwrap() {
+            Resolution::Deliver(routes) => routes.into_iter().map(|route| (route.mailbox, route.tag)).collect(),
+            other => panic!("{} resolved to {:?}", recipient, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_users_and_other_domains() {
+        let router = router().await;
+        assert_eq!(routes(&router, "Ann@Example.com").await, vec![(Some("ann".to_string()), None)]);
+        assert!(matches!(router.resolve("bob@example.com").await.unwrap(), Resolution::Unknown));
+        assert!(matches!(router.resolve("bob@example.org").await.unwrap(), Resolution::Relay));
+        assert!(matches!(router.resolve("not an address").await.unwrap(), Resolution::Unknown));
+    }
+
+    #[tokio::test]
+    async fn follows_aliases() {
+        let router = router().await;
+        router.set_alias("sales@example.com", vec!["team@example.com".to_string()]).await.unwrap();
+        // Aliases may point at each other, and a loop must not deliver twice
+        router
+            .set_alias("team@example.com", vec!["ann@example.com".to_string(), "sales@example.com".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(routes(&router, "sales@example.com").await, vec![(Some("ann".to_string()), None)]);
+        assert!(router.set_alias("ann@example.com", vec!["sales@example.com".to_string()]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn strips_subaddresses() {
+        let router = router().await;
+        assert_eq!(
+            routes(&router, "ann+shop@example.com").await,
+            vec![(Some("ann".to_string()), Some("shop".to_string()))]
+        );
+        router.set_alias("sales@example.com", vec!["ann+leads@example.com".to_string()]).await.unwrap();
+        // The tag the sender used wins over one the alias adds
+        assert_eq!(
+            routes(&router, "sales+web@example.com").await,
+            vec![(Some("ann".to_string()), Some("web".to_string()))]
+        );
+        assert_eq!(
+            routes(&router, "sales@example.com").await,
+            vec![(Some("ann".to_string()), Some("leads".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_catch_all() {
+        let router = router().await;
+        assert!(matches!(router.resolve("anyone@example.com").await.unwrap(), Resolution::Unknown));
+        router
+            .domains
+            .set_catch_all("example.com", Some("ann@example.com".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(routes(&router, "anyone@example.com").await, vec![(Some("ann".to_string()), None)]);
+        assert_eq!(
+            routes(&router, "anyone+tag@example.com").await,
+            vec![(Some("ann".to_string()), Some("tag".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_postmaster() {
+        let router = router().await;
+        assert_eq!(routes(&router, "postmaster@example.com").await, vec![(None, None)]);
+    }
+}
@@ -0,0 +1,158 @@
+mod session;
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use futures::future;
+use rustls::ServerConfig;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    config::SmtpConfig,
+    delivery::LocalDelivery,
+    message::{Envelope, Message},
+    outbound::Outbound,
+    routing::{Resolution, Router},
+    verify::Verifier,
+};
+use session::Session;
+
+#[derive(Error, Debug)]
+pub enum SmtpError {
+    #[error("Could not bind to {host}:{port} because {source}")]
+    Bind {
+        host: String,
+        port: u16,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// State shared by every session of the listener
+struct Context {
+    config: SmtpConfig,
+    tls: Option<TlsAcceptor>,
+    router: Router,
+    delivery: LocalDelivery,
+    verifier: Verifier,
+    outbound: Outbound,
+}
+
+impl Context {
+    /// Decides whether mail for a recipient is accepted, returning the rejection reply otherwise
+    async fn check_recipient(&self, recipient: &str) -> Result<(), &'static str> {
+        match self.router.resolve(recipient).await {
+            Ok(Resolution::Deliver(_)) => Ok(()),
+            Ok(Resolution::Relay) => Err("550 5.7.1 Relaying denied"),
+            Ok(Resolution::Unknown) => Err("550 5.1.1 User unknown"),
+            Err(error) => {
+                log::error!("Could not look up recipient {}: {}", recipient, error);
+                Err("451 4.3.0 Temporary lookup failure")
+            }
+        }
+    }
+
+    /// Verifies, parses and persists a message accepted by a session, one copy per mailbox the
+    /// recipients lead to. Returns the id of the first copy.
+    async fn deliver(&self, envelope: Envelope, raw: Vec<u8>) -> Option<String> {
+        let (authentication, raw) = self.verifier.verify(&envelope, &raw).await;
+        let (mut message, attachments) = Message::parse(envelope, &raw);
+        message.authentication = Some(authentication);
+        let recipients = message.envelope.rcpt_to.clone();
+        let id = self.delivery.deliver(message, &raw, &attachments).await?;
+        // Reports about mail sent from here update its outbound record
+        if let Err(error) = self.outbound.receive_report(&recipients, &raw, &id).await {
+            log::error!("Could not apply delivery report {}: {}", id, error);
+        }
+        Some(id)
+    }
+}
+
+async fn bind(host: &str, port: u16) -> Result<TcpListener, SmtpError> {
+    let listener = TcpListener::bind((host, port))
+        .await
+        .map_err(|source| SmtpError::Bind {
+            host: host.to_string(),
+            port,
+            source,
+        })?;
+    log::info!("SMTP listening on {}:{}", host, port);
+    Ok(listener)
+}
+
+/// Starts the SMTP listeners and stores every accepted message
+pub async fn listen(
+    config: SmtpConfig,
+    tls: Option<Arc<ServerConfig>>,
+    router: Router,
+    delivery: LocalDelivery,
+    verifier: Verifier,
+    outbound: Outbound,
+) -> Result<(), SmtpError> {
+    let plain = bind(&config.bind_address, config.port).await?;
+    let implicit = match (&tls, config.tls_port) {
+        (Some(_), Some(port)) => Some(bind(&config.bind_address, port).await?),
+        (None, Some(port)) => {
+            log::warn!("Not listening for SMTPS on port {} since TLS is not configured", port);
+            None
+        }
+        _ => None,
+    };
+    if tls.is_none() {
+        log::warn!("No certificate configured, STARTTLS will not be available");
+    }
+
+    let context = Arc::new(Context {
+        config,
+        tls: tls.map(TlsAcceptor::from),
+        router,
+        delivery,
+        verifier,
+        outbound,
+    });
+
+    match implicit {
+        Some(implicit) => {
+            future::join(accept(plain, context.clone(), false), accept(implicit, context, true)).await;
+        }
+        None => accept(plain, context, false).await,
+    }
+    Ok(())
+}
+
+/// Accepts connections until the process exits, spawning a session for each of them
+async fn accept(listener: TcpListener, context: Arc<Context>, implicit_tls: bool) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::error!("Could not accept SMTP connection: {}", error);
+                continue;
+            }
+        };
+        log::info!("New SMTP connection from {}", peer);
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve(context, stream, peer, implicit_tls).await {
+                log::warn!("SMTP session with {} ended: {}", peer, error);
+            }
+        });
+    }
+}
+
+/// Runs a session, completing the TLS handshake first on the SMTPS port
+async fn serve(
+    context: Arc<Context>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    implicit_tls: bool,
+) -> io::Result<()> {
+    match (implicit_tls, context.tls.clone()) {
+        (true, Some(acceptor)) => {
+            let stream = acceptor.accept(stream).await?;
+            Session::new(context, Box::new(stream), peer, true).run().await
+        }
+        _ => Session::new(context, Box::new(stream), peer, false).run().await,
+    }
+}
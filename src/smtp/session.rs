@@ -0,0 +1,338 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+
+use super::Context;
+use crate::message::Envelope;
+
+/// Longest command line accepted, generous compared to the 512 octets of RFC 5321
+const MAX_COMMAND_LENGTH: u64 = 2048;
+/// Longest line accepted inside DATA
+const MAX_DATA_LINE_LENGTH: u64 = 64 * 1024;
+/// How long a client may stay silent, RFC 5321 suggests at least five minutes
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub(super) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A single SMTP connection, which may carry several mail transactions
+pub(super) struct Session {
+    context: Arc<Context>,
+    stream: BufReader<Box<dyn Stream>>,
+    peer: SocketAddr,
+    encrypted: bool,
+    helo: Option<String>,
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+}
+
+impl Session {
+    pub(super) fn new(
+        context: Arc<Context>,
+        stream: Box<dyn Stream>,
+        peer: SocketAddr,
+        encrypted: bool,
+    ) -> Self {
+        Session {
+            context,
+            stream: BufReader::new(stream),
+            peer,
+            encrypted,
+            helo: None,
+            mail_from: None,
+            rcpt_to: Vec::new(),
+        }
+    }
+
+    /// Drives the session until the client quits or disconnects
+    pub(super) async fn run(mut self) -> io::Result<()> {
+        let greeting = format!("220 {} ESMTP feathermail", self.context.config.domain);
+        self.reply(&greeting).await?;
+
+        loop {
+            let Some(line) = self.read_line(MAX_COMMAND_LENGTH).await? else {
+                return Ok(());
+            };
+            if !line.ends_with(b"\n") {
+                self.reply("500 5.5.2 Line too long").await?;
+                return Ok(());
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (verb, arguments) = line.split_once(' ').unwrap_or((line, ""));
+
+            match verb.to_ascii_uppercase().as_str() {
+                "EHLO" => self.ehlo(arguments.trim(), true).await?,
+                "HELO" => self.ehlo(arguments.trim(), false).await?,
+                "STARTTLS" => self.starttls().await?,
+                "MAIL" => self.mail(arguments).await?,
+                "RCPT" => self.rcpt(arguments).await?,
+                "DATA" => self.data().await?,
+                "RSET" => {
+                    self.reset();
+                    self.reply("250 2.0.0 OK").await?
+                }
+                "NOOP" => self.reply("250 2.0.0 OK").await?,
+                "VRFY" => self.reply("252 2.1.5 Cannot verify user").await?,
+                "QUIT" => {
+                    self.reply("221 2.0.0 Bye").await?;
+                    return Ok(());
+                }
+                _ => self.reply("500 5.5.1 Command not recognized").await?,
+            }
+        }
+    }
+
+    async fn ehlo(&mut self, name: &str, extended: bool) -> io::Result<()> {
+        if name.is_empty() {
+            return self.reply("501 5.5.4 Domain name required").await;
+        }
+        self.reset();
+        self.helo = Some(name.to_string());
+
+        if !extended {
+            let reply = format!("250 {}", self.context.config.domain);
+            return self.reply(&reply).await;
+        }
+
+        let mut lines = vec![
+            self.context.config.domain.clone(),
+            "PIPELINING".to_string(),
+            "8BITMIME".to_string(),
+            "ENHANCEDSTATUSCODES".to_string(),
+            format!("SIZE {}", self.context.config.max_message_size),
+        ];
+        if !self.encrypted && self.context.tls.is_some() {
+            lines.push("STARTTLS".to_string());
+        }
+        let last = lines.len() - 1;
+        let reply = lines
+            .iter()
+            .enumerate()
+            .map(|(index, line)| format!("250{}{}", if index == last { ' ' } else { '-' }, line))
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        self.reply(&reply).await
+    }
+
+    async fn starttls(&mut self) -> io::Result<()> {
+        if self.encrypted {
+            return self.reply("503 5.5.1 Already running TLS").await;
+        }
+        let Some(acceptor) = self.context.tls.clone() else {
+            return self.reply("454 4.7.0 TLS not available").await;
+        };
+        self.reply("220 2.0.0 Ready to start TLS").await?;
+
+        // Anything the client pipelined before the handshake is discarded
+        let stream = std::mem::replace(&mut self.stream, BufReader::new(Box::new(tokio::io::empty())))
+            .into_inner();
+        let stream = acceptor.accept(stream).await?;
+        self.stream = BufReader::new(Box::new(stream));
+        self.encrypted = true;
+
+        // RFC 3207 requires the client to start over with EHLO
+        self.reset();
+        self.helo = None;
+        Ok(())
+    }
+
+    async fn mail(&mut self, arguments: &str) -> io::Result<()> {
+        if self.helo.is_none() {
+            return self.reply("503 5.5.1 Send EHLO first").await;
+        }
+        if self.mail_from.is_some() {
+            return self.reply("503 5.5.1 Sender already specified").await;
+        }
+        let Some((sender, parameters)) = parse_path(arguments, "FROM:") else {
+            return self.reply("501 5.5.4 Syntax: MAIL FROM:<address>").await;
+        };
+
+        let declared_size = parameters
+            .split_whitespace()
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(key, _)| key.eq_ignore_ascii_case("SIZE"))
+            .and_then(|(_, size)| size.parse::<usize>().ok());
+        if declared_size.is_some_and(|size| size > self.context.config.max_message_size) {
+            return self.reply("552 5.3.4 Message size exceeds fixed limit").await;
+        }
+
+        self.mail_from = Some(sender);
+        self.reply("250 2.1.0 OK").await
+    }
+
+    async fn rcpt(&mut self, arguments: &str) -> io::Result<()> {
+        if self.mail_from.is_none() {
+            return self.reply("503 5.5.1 Need MAIL before RCPT").await;
+        }
+        let Some((recipient, _)) = parse_path(arguments, "TO:") else {
+            return self.reply("501 5.5.4 Syntax: RCPT TO:<address>").await;
+        };
+        if !recipient.contains('@') {
+            return self.reply("501 5.1.3 Bad recipient address syntax").await;
+        }
+        if self.rcpt_to.len() >= self.context.config.max_recipients {
+            return self.reply("452 4.5.3 Too many recipients").await;
+        }
+        if let Err(reply) = self.context.check_recipient(&recipient).await {
+            return self.reply(reply).await;
+        }
+
+        self.rcpt_to.push(recipient);
+        self.reply("250 2.1.5 OK").await
+    }
+
+    async fn data(&mut self) -> io::Result<()> {
+        if self.mail_from.is_none() {
+            return self.reply("503 5.5.1 Need MAIL command").await;
+        }
+        if self.rcpt_to.is_empty() {
+            return self.reply("554 5.5.1 No valid recipients").await;
+        }
+        self.reply("354 Start mail input; end with <CRLF>.<CRLF>").await?;
+
+        let mut data = Vec::new();
+        let mut oversized = false;
+        // Overlong lines arrive in chunks, only the first one starts a line
+        let mut line_start = true;
+        loop {
+            let Some(chunk) = self.read_line(MAX_DATA_LINE_LENGTH).await? else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            let Some(line) = unstuff(&chunk, line_start) else {
+                break;
+            };
+            line_start = chunk.ends_with(b"\n");
+
+            // Keep reading until the terminator so the client stays in sync
+            if oversized || data.len() + line.len() > self.context.config.max_message_size {
+                oversized = true;
+                continue;
+            }
+            data.extend_from_slice(line);
+        }
+
+        if oversized {
+            self.reset();
+            return self.reply("552 5.3.4 Message size exceeds fixed limit").await;
+        }
+
+        let envelope = Envelope {
+            client_ip: Some(self.peer.ip()),
+            helo: self.helo.clone().unwrap_or_default(),
+            mail_from: self.mail_from.take().unwrap_or_default(),
+            rcpt_to: std::mem::take(&mut self.rcpt_to),
+        };
+        match self.context.deliver(envelope, data).await {
+            Some(id) => self.reply(&format!("250 2.0.0 OK queued as {}", id)).await,
+            None => self.reply("451 4.3.0 Could not store message").await,
+        }
+    }
+
+    /// Clears the current mail transaction
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+    }
+
+    /// Reads one line including its terminator, `None` once the client disconnected
+    async fn read_line(&mut self, limit: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let read = timeout(
+            READ_TIMEOUT,
+            (&mut self.stream).take(limit).read_until(b'\n', &mut line),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        Ok((read > 0).then_some(line))
+    }
+
+    async fn reply(&mut self, reply: &str) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(reply.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await
+    }
+}
+
+/// Undoes the dot stuffing of RFC 5321 section 4.5.2 on a chunk of DATA, `None` for the line ending
+/// the message
+fn unstuff(chunk: &[u8], line_start: bool) -> Option<&[u8]> {
+    match line_start {
+        true if chunk == b".\r\n" || chunk == b".\n" => None,
+        true => Some(chunk.strip_prefix(b".").unwrap_or(chunk)),
+        false => Some(chunk),
+    }
+}
+
+/// Splits `FROM:<address> PARAMETERS` into the address and its parameters
+fn parse_path<'a>(arguments: &'a str, prefix: &str) -> Option<(String, &'a str)> {
+    let head = arguments.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = arguments[prefix.len()..].trim_start();
+    match rest.strip_prefix('<') {
+        Some(rest) => {
+            let (path, parameters) = rest.split_once('>')?;
+            // Drop any obsolete source route, e.g. <@relay.example:user@example.com>
+            let address = path.rsplit_once(':').map_or(path, |(_, address)| address);
+            Some((address.to_string(), parameters.trim()))
+        }
+        None => {
+            let (address, parameters) = rest.split_once(' ').unwrap_or((rest, ""));
+            (!address.is_empty()).then(|| (address.to_string(), parameters.trim()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unstuffs_leading_dots() {
+        assert_eq!(unstuff(b"..hidden\r\n", true), Some(&b".hidden\r\n"[..]));
+        assert_eq!(unstuff(b"plain\r\n", true), Some(&b"plain\r\n"[..]));
+        // The rest of an overlong line is not the start of one
+        assert_eq!(unstuff(b".rest\r\n", false), Some(&b".rest\r\n"[..]));
+    }
+
+    #[test]
+    fn lone_dot_ends_data() {
+        assert_eq!(unstuff(b".\r\n", true), None);
+        assert_eq!(unstuff(b".\n", true), None);
+        assert_eq!(unstuff(b".\r\n", false), Some(&b".\r\n"[..]));
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(
+            parse_path("FROM:<ann@example.com> SIZE=100", "FROM:"),
+            Some(("ann@example.com".to_string(), "SIZE=100"))
+        );
+        assert_eq!(parse_path("to: <bob@example.org>", "TO:"), Some(("bob@example.org".to_string(), "")));
+        assert_eq!(parse_path("FROM:<>", "FROM:"), Some((String::new(), "")));
+        assert_eq!(parse_path("TO:bob@example.org", "TO:"), Some(("bob@example.org".to_string(), "")));
+    }
+
+    #[test]
+    fn drops_source_routes() {
+        assert_eq!(
+            parse_path("TO:<@relay.example:bob@example.org>", "TO:"),
+            Some(("bob@example.org".to_string(), ""))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert_eq!(parse_path("FROM:<ann@example.com", "FROM:"), None);
+        assert_eq!(parse_path("TO:<bob@example.org>", "FROM:"), None);
+        assert_eq!(parse_path("TO:", "TO:"), None);
+        assert_eq!(parse_path("TO", "TO:"), None);
+    }
+}
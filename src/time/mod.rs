@@ -0,0 +1,96 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix time in seconds
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Unix time at the start of a `YYYY-MM-DD` day in UTC
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day) = (year.parse::<i64>().ok()?, month.parse::<i64>().ok()?, day.parse::<i64>().ok()?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 from the civil date, counting years from March on
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146097 + day_of_era - 719468) * 86400)
+}
+
+/// Year, month, day, hour, minute and second of a unix time in UTC
+pub fn civil(timestamp: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    // The inverse of the day count in `parse_date`
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month as u32,
+        day as u32,
+        (seconds / 3600) as u32,
+        (seconds % 3600 / 60) as u32,
+        (seconds % 60) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2024-03-01"), Some(1_709_251_200));
+        assert_eq!(parse_date("1969-12-31"), Some(-86400));
+        assert_eq!(parse_date("2000-02-29"), Some(951_782_400));
+    }
+
+    #[test]
+    fn rejects_invalid_days() {
+        for date in ["2023-02-29", "1900-02-29", "2024-13-01", "2024-04-31", "2024-00-10", "2024-3-1", "24-03-01", ""] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn splits_timestamps() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(837_596_665), (1996, 7, 17, 9, 44, 25));
+        assert_eq!(civil(951_868_799), (2000, 2, 29, 23, 59, 59));
+        assert_eq!(civil(-1), (1969, 12, 31, 23, 59, 59));
+    }
+
+    #[test]
+    fn civil_inverts_parse_date() {
+        for date in ["1970-01-01", "1999-12-31", "2000-02-29", "2024-03-01", "2100-02-28", "1901-07-04"] {
+            let (year, month, day, ..) = civil(parse_date(date).unwrap());
+            assert_eq!(format!("{:04}-{:02}-{:02}", year, month, day), date);
+        }
+    }
+}
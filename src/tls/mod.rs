@@ -0,0 +1,197 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use thiserror::Error;
+
+/// How often the certificate files are checked for modifications
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Could not read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("No PEM certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No PEM private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Certificate {certs} cannot be used with key {key}: {source}")]
+    Invalid {
+        certs: PathBuf,
+        key: PathBuf,
+        #[source]
+        source: rustls::Error,
+    },
+}
+
+/// Reads a certificate chain and its private key, making sure they belong together
+fn read_certified_key(certs_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let read_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| TlsError::Read { path, source }
+    };
+    let invalid = |source| TlsError::Invalid {
+        certs: certs_path.to_path_buf(),
+        key: key_path.to_path_buf(),
+        source,
+    };
+
+    let certs = File::open(certs_path)
+        .map(BufReader::new)
+        .and_then(|mut reader| rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>())
+        .map_err(read_error(certs_path))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(certs_path.to_path_buf()));
+    }
+
+    let key = File::open(key_path)
+        .map(BufReader::new)
+        .and_then(|mut reader| rustls_pemfile::private_key(&mut reader))
+        .map_err(read_error(key_path))?
+        .ok_or_else(|| TlsError::NoPrivateKey(key_path.to_path_buf()))?;
+    let key = ring::sign::any_supported_type(&key).map_err(invalid)?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(invalid)?;
+    Ok(certified)
+}
+
+/// Serves whichever certificate was loaded last, so it can be replaced without a restart
+#[derive(Debug)]
+struct Certificate {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+/// TLS configuration shared by every listener, reloaded when the PEM files change
+#[derive(Clone)]
+pub struct Tls {
+    certs_path: PathBuf,
+    key_path: PathBuf,
+    certificate: Arc<Certificate>,
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    /// Loads a certificate chain and its private key into a server configuration.
+    /// Fails when either file is missing or empty, or when the key does not belong to the certificate.
+    pub fn load(certs_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        let certificate = Arc::new(Certificate {
+            current: RwLock::new(Arc::new(read_certified_key(certs_path, key_path)?)),
+        });
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|source| TlsError::Invalid {
+                certs: certs_path.to_path_buf(),
+                key: key_path.to_path_buf(),
+                source,
+            })?
+            .with_no_client_auth()
+            .with_cert_resolver(certificate.clone());
+        log::info!("Loaded TLS certificate from {}", certs_path.display());
+
+        Ok(Tls {
+            certs_path: certs_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            certificate,
+            config: Arc::new(config),
+        })
+    }
+
+    /// The server configuration, it always presents the current certificate
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+
+    /// Replaces the certificate for new connections, established sessions are unaffected.
+    /// On failure the previous certificate stays in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified = read_certified_key(&self.certs_path, &self.key_path)?;
+        if let Ok(mut current) = self.certificate.current.write() {
+            *current = Arc::new(certified);
+        }
+        log::info!("Reloaded TLS certificate from {}", self.certs_path.display());
+        Ok(())
+    }
+
+    /// Modification times of the certificate and key files
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        Some((modified(&self.certs_path)?, modified(&self.key_path)?))
+    }
+
+    /// Reloads the certificate on SIGHUP or whenever one of the PEM files changes
+    pub async fn watch(self) {
+        let mut hangup = match hangup_signal() {
+            Ok(hangup) => Some(hangup),
+            Err(error) => {
+                log::warn!("Cannot listen for SIGHUP, relying on file changes only: {}", error);
+                None
+            }
+        };
+        let mut seen = self.modified();
+
+        loop {
+            let signalled = tokio::select! {
+                _ = wait_for_hangup(&mut hangup) => true,
+                _ = tokio::time::sleep(WATCH_INTERVAL) => false,
+            };
+            let modified = self.modified();
+            if !signalled && modified == seen {
+                continue;
+            }
+
+            // A failed attempt keeps the old timestamps, so a half written renewal is retried
+            match self.reload() {
+                Ok(()) => seen = modified,
+                Err(error) => log::error!("Could not reload TLS certificate: {}", error),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> io::Result<Hangup> {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> io::Result<Hangup> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Completes on SIGHUP, never when signals are unavailable
+async fn wait_for_hangup(hangup: &mut Option<Hangup>) {
+    #[cfg(unix)]
+    if let Some(hangup) = hangup {
+        if hangup.recv().await.is_some() {
+            return;
+        }
+    }
+    let _ = hangup;
+    std::future::pending::<()>().await
+}
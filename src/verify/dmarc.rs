@@ -0,0 +1,250 @@
+use mail_parser::MessageParser;
+
+use super::{tag_list, DkimResult, DmarcResult, Policy, SpfResult, Verdict};
+use crate::dns::Resolver;
+
+/// A DMARC record as far as evaluating it is concerned (RFC 7489 section 6.3)
+struct Record {
+    policy: Policy,
+    subdomain_policy: Option<Policy>,
+    strict_spf: bool,
+    strict_dkim: bool,
+}
+
+fn policy(value: &str) -> Option<Policy> {
+    match value.to_ascii_lowercase().as_str() {
+        "none" => Some(Policy::None),
+        "quarantine" => Some(Policy::Quarantine),
+        "reject" => Some(Policy::Reject),
+        _ => None,
+    }
+}
+
+fn parse(text: &str) -> Option<Record> {
+    let tags = tag_list(text).ok()?;
+    let tag = |name: &str| tags.iter().find(|(known, _)| known == name).map(|(_, value)| value.as_str());
+    // The version has to come first
+    if tags.first().map(|(name, value)| (name.as_str(), value.as_str())) != Some(("v", "DMARC1")) {
+        return None;
+    }
+    Some(Record {
+        policy: policy(tag("p")?)?,
+        subdomain_policy: tag("sp").and_then(policy),
+        strict_spf: tag("aspf").is_some_and(|mode| mode.eq_ignore_ascii_case("s")),
+        strict_dkim: tag("adkim").is_some_and(|mode| mode.eq_ignore_ascii_case("s")),
+    })
+}
+
+/// The registered domain below the public suffix, e.g. `example.co.uk` for `mail.example.co.uk`
+fn organizational_domain(domain: &str) -> String {
+    psl::domain_str(domain).unwrap_or(domain).to_string()
+}
+
+/// Domain of the single author, DMARC has nothing to check with none or several (section 6.6.1)
+fn author_domain(message: &[u8]) -> Option<String> {
+    let parsed = MessageParser::default().parse_headers(message)?;
+    if parsed.header_values("From").count() != 1 {
+        return None;
+    }
+    let [author] = parsed.from()?.as_list()? else {
+        return None;
+    };
+    let (_, domain) = author.address()?.rsplit_once('@')?;
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    (!domain.is_empty()).then_some(domain)
+}
+
+/// Looks for the record at the domain itself, then at its organizational domain. The flag
+/// tells whether the record was inherited.
+async fn find_record(resolver: &Resolver, domain: &str) -> Result<Option<(Record, bool)>, Verdict> {
+    let organizational = organizational_domain(domain);
+    let mut names = vec![(domain, false)];
+    if organizational != domain {
+        names.push((&organizational, true));
+    }
+    for (name, inherited) in names {
+        let records = resolver
+            .txt(&format!("_dmarc.{}", name))
+            .await
+            .map_err(|_| Verdict::TempError)?;
+        let mut records = records.iter().filter_map(|record| parse(record));
+        // Several records count as none at all
+        match (records.next(), records.next()) {
+            (Some(record), None) => return Ok(Some((record, inherited))),
+            (Some(_), Some(_)) => return Ok(None),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Passes when SPF or a DKIM signature passed for a domain aligned with the author's
+pub async fn evaluate(resolver: &Resolver, message: &[u8], spf: &SpfResult, dkim: &[DkimResult]) -> DmarcResult {
+    let Some(domain) = author_domain(message) else {
+        return DmarcResult {
+            result: Verdict::PermError,
+            domain: None,
+            policy: None,
+        };
+    };
+    let (record, inherited) = match find_record(resolver, &domain).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return DmarcResult {
+                result: Verdict::None,
+                domain: Some(domain),
+                policy: None,
+            }
+        }
+        Err(result) => {
+            return DmarcResult {
+                result,
+                domain: Some(domain),
+                policy: None,
+            }
+        }
+    };
+
+    let organizational = organizational_domain(&domain);
+    let aligned = |other: &str, strict: bool| match strict {
+        true => other.eq_ignore_ascii_case(&domain),
+        false => organizational_domain(&other.to_ascii_lowercase()) == organizational,
+    };
+    let spf_aligned = spf.result == Verdict::Pass && aligned(&spf.domain, record.strict_spf);
+    let dkim_aligned = dkim
+        .iter()
+        .any(|signature| signature.result == Verdict::Pass && aligned(&signature.domain, record.strict_dkim));
+
+    let policy = match inherited {
+        true => record.subdomain_policy.unwrap_or(record.policy),
+        false => record.policy,
+    };
+    DmarcResult {
+        result: match spf_aligned || dkim_aligned {
+            true => Verdict::Pass,
+            false => Verdict::Fail,
+        },
+        domain: Some(domain),
+        policy: Some(policy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Fixtures;
+    use crate::verify::SpfScope;
+
+    const ZONES: &str = r#"
+["_dmarc.example.com"]
+txt = ["v=DMARC1; p=reject; sp=quarantine"]
+
+["_dmarc.example.org"]
+txt = ["v=DMARC1; p=none; aspf=s; adkim=s"]
+
+["_dmarc.example.net"]
+txt = ["v=DMARC1; p=quarantine"]
+
+["_dmarc.twice.example"]
+txt = ["v=DMARC1; p=reject", "v=DMARC1; p=none"]
+
+["_dmarc.example.edu"]
+servfail = true
+"#;
+
+    fn spf(result: Verdict, domain: &str) -> SpfResult {
+        SpfResult {
+            result,
+            scope: SpfScope::MailFrom,
+            domain: domain.to_string(),
+        }
+    }
+
+    fn dkim(result: Verdict, domain: &str) -> DkimResult {
+        DkimResult {
+            result,
+            domain: domain.to_string(),
+            selector: "s1".to_string(),
+            signature: "abcdefgh".to_string(),
+            reason: None,
+        }
+    }
+
+    /// Evaluates a message from `author` with the given SPF and DKIM results
+    async fn dmarc(author: &str, spf: SpfResult, dkim: &[DkimResult]) -> (Verdict, Option<Policy>) {
+        let resolver = Resolver::new(Fixtures::parse(ZONES).unwrap());
+        let message = format!("From: Ann <{}>\r\nSubject: Lunch\r\n\r\nNoon?\r\n", author);
+        let result = evaluate(&resolver, message.as_bytes(), &spf, dkim).await;
+        (result.result, result.policy)
+    }
+
+    #[tokio::test]
+    async fn aligns_relaxed_by_organizational_domain() {
+        let fail = spf(Verdict::Fail, "example.net");
+        let quarantine = Some(Policy::Quarantine);
+        assert_eq!(
+            dmarc("ann@example.net", spf(Verdict::Pass, "bounces.example.net"), &[]).await,
+            (Verdict::Pass, quarantine)
+        );
+        assert_eq!(
+            dmarc("ann@news.example.net", fail.clone(), &[dkim(Verdict::Pass, "example.net")]).await,
+            (Verdict::Pass, quarantine)
+        );
+        // Passing for someone else does not count, and neither does failing for the author
+        assert_eq!(
+            dmarc("ann@example.net", spf(Verdict::Pass, "example.org"), &[dkim(Verdict::Pass, "example.org")]).await,
+            (Verdict::Fail, quarantine)
+        );
+        assert_eq!(
+            dmarc("ann@example.net", fail, &[dkim(Verdict::Fail, "example.net")]).await,
+            (Verdict::Fail, quarantine)
+        );
+    }
+
+    #[tokio::test]
+    async fn aligns_strict_by_exact_domain() {
+        let none = Some(Policy::None);
+        assert_eq!(dmarc("ann@example.org", spf(Verdict::Pass, "example.org"), &[]).await, (Verdict::Pass, none));
+        assert_eq!(
+            dmarc("ann@example.org", spf(Verdict::Pass, "bounces.example.org"), &[]).await,
+            (Verdict::Fail, none)
+        );
+        let fail = spf(Verdict::Fail, "example.org");
+        assert_eq!(
+            dmarc("ann@example.org", fail.clone(), &[dkim(Verdict::Pass, "EXAMPLE.org")]).await,
+            (Verdict::Pass, none)
+        );
+        assert_eq!(
+            dmarc("ann@example.org", fail, &[dkim(Verdict::Pass, "mail.example.org")]).await,
+            (Verdict::Fail, none)
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_the_subdomain_policy_to_subdomains() {
+        let fail = spf(Verdict::Fail, "example.com");
+        assert_eq!(dmarc("ann@example.com", fail.clone(), &[]).await, (Verdict::Fail, Some(Policy::Reject)));
+        assert_eq!(
+            dmarc("ann@news.example.com", fail.clone(), &[]).await,
+            (Verdict::Fail, Some(Policy::Quarantine))
+        );
+        // Without sp= subdomains get the policy of the domain
+        assert_eq!(
+            dmarc("ann@news.example.net", fail, &[]).await,
+            (Verdict::Fail, Some(Policy::Quarantine))
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_missing_and_unusable_records() {
+        let pass = spf(Verdict::Pass, "example.info");
+        assert_eq!(dmarc("ann@example.info", pass.clone(), &[]).await, (Verdict::None, None));
+        assert_eq!(dmarc("ann@twice.example", pass.clone(), &[]).await, (Verdict::None, None));
+        assert_eq!(dmarc("ann@example.edu", pass.clone(), &[]).await, (Verdict::TempError, None));
+
+        let resolver = Resolver::new(Fixtures::parse(ZONES).unwrap());
+        let authors = b"From: ann@example.com, bob@example.com\r\n\r\nNoon?\r\n";
+        let result = evaluate(&resolver, authors, &pass, &[]).await;
+        assert_eq!((result.result, result.domain), (Verdict::PermError, None));
+    }
+}
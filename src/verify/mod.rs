@@ -0,0 +1,299 @@
+mod dmarc;
+mod spf;
+
+use futures::future;
+use serde::{Deserialize, Serialize};
+
+use crate::{dkim, dns::Resolver, message::Envelope};
+
+/// Outcome of a single check, named as in Authentication-Results (RFC 8601)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    None,
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    TempError,
+    PermError,
+}
+
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Verdict::None => "none",
+            Verdict::Pass => "pass",
+            Verdict::Fail => "fail",
+            Verdict::SoftFail => "softfail",
+            Verdict::Neutral => "neutral",
+            Verdict::TempError => "temperror",
+            Verdict::PermError => "permerror",
+        }
+    }
+}
+
+/// The identity SPF was checked for, the HELO name stands in for bounces
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpfScope {
+    MailFrom,
+    Helo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpfResult {
+    pub result: Verdict,
+    pub scope: SpfScope,
+    pub domain: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DkimResult {
+    pub result: Verdict,
+    pub domain: String,
+    pub selector: String,
+    /// Start of the signature, tells several signatures of one domain apart
+    pub signature: String,
+    /// Why the signature did not pass
+    pub reason: Option<String>,
+}
+
+/// What the sender domain asks receivers to do with mail failing DMARC
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    fn as_str(self) -> &'static str {
+        match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DmarcResult {
+    pub result: Verdict,
+    /// Domain of the From header, `None` unless the message has exactly one author
+    pub domain: Option<String>,
+    /// Policy published for that domain, `None` without a DMARC record
+    pub policy: Option<Policy>,
+}
+
+/// Results of the sender checks run when a message arrives
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Authentication {
+    pub spf: SpfResult,
+    pub dkim: Vec<DkimResult>,
+    pub dmarc: DmarcResult,
+}
+
+/// Splits a `name=value; name=value` tag list as used by DKIM and DMARC records
+pub fn tag_list(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for tag in text.split(';') {
+        if tag.trim().is_empty() {
+            continue;
+        }
+        let Some((name, value)) = tag.split_once('=') else {
+            return Err(format!("malformed tag '{}'", tag.trim()));
+        };
+        let name = name.trim();
+        if tags.iter().any(|(known, _)| known == name) {
+            return Err(format!("duplicate tag {}=", name));
+        }
+        tags.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(tags)
+}
+
+/// A result property value, quoted unless it is a plain token
+fn property(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-./=?@^_`{|}~".contains(c));
+    match plain {
+        true => value.to_string(),
+        false => format!(
+            "\"{}\"",
+            value
+                .chars()
+                .filter(|c| !c.is_control())
+                .map(|c| match c {
+                    '"' | '\\' => format!("\\{}", c),
+                    c => c.to_string(),
+                })
+                .collect::<String>()
+        ),
+    }
+}
+
+/// Text safe to put into a header comment
+fn comment(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() && !matches!(c, '(' | ')' | '\\'))
+        .collect()
+}
+
+impl Authentication {
+    /// The Authentication-Results header field, folded and ending in CRLF
+    fn header(&self, authserv_id: &str) -> String {
+        let mut results = Vec::new();
+
+        let identity = match self.spf.scope {
+            SpfScope::MailFrom => "smtp.mailfrom",
+            SpfScope::Helo => "smtp.helo",
+        };
+        results.push(format!(
+            "spf={} {}={}",
+            self.spf.result.as_str(),
+            identity,
+            property(&self.spf.domain)
+        ));
+
+        if self.dkim.is_empty() {
+            results.push("dkim=none".to_string());
+        }
+        for dkim in &self.dkim {
+            let mut result = format!("dkim={}", dkim.result.as_str());
+            if let Some(reason) = &dkim.reason {
+                result.push_str(&format!(" ({})", comment(reason)));
+            }
+            result.push_str(&format!(
+                " header.d={} header.s={} header.b={}",
+                property(&dkim.domain),
+                property(&dkim.selector),
+                property(&dkim.signature)
+            ));
+            results.push(result);
+        }
+
+        let mut dmarc = format!("dmarc={}", self.dmarc.result.as_str());
+        if let Some(policy) = self.dmarc.policy {
+            dmarc.push_str(&format!(" (p={})", policy.as_str()));
+        }
+        if let Some(domain) = &self.dmarc.domain {
+            dmarc.push_str(&format!(" header.from={}", property(domain)));
+        }
+        results.push(dmarc);
+
+        format!("Authentication-Results: {};\r\n\t{}\r\n", authserv_id, results.join(";\r\n\t"))
+    }
+}
+
+/// Checks who sent inbound mail, querying DNS through the given resolver
+#[derive(Clone)]
+pub struct Verifier {
+    resolver: Resolver,
+    /// Names this server in the results it adds
+    authserv_id: String,
+}
+
+impl Verifier {
+    pub fn new(resolver: Resolver, authserv_id: String) -> Self {
+        Verifier {
+            resolver,
+            authserv_id,
+        }
+    }
+
+    /// Runs SPF, DKIM and DMARC on a message just received, returning the results and the
+    /// message with an Authentication-Results header on top
+    pub async fn verify(&self, envelope: &Envelope, raw: &[u8]) -> (Authentication, Vec<u8>) {
+        let (spf, dkim) = future::join(
+            spf::check(&self.resolver, envelope),
+            dkim::verify(&self.resolver, raw),
+        )
+        .await;
+        let dmarc = dmarc::evaluate(&self.resolver, raw, &spf, &dkim).await;
+        let authentication = Authentication { spf, dkim, dmarc };
+
+        let mut stamped = authentication.header(&self.authserv_id).into_bytes();
+        stamped.extend(self.strip_forged(raw));
+        (authentication, stamped)
+    }
+
+    /// Drops results claiming to come from this server, only the ones it adds itself can be
+    /// trusted (RFC 8601 section 5)
+    fn strip_forged(&self, raw: &[u8]) -> Vec<u8> {
+        let (fields, _) = dkim::canonical::split(raw);
+        // Fields are contiguous from the start, whatever follows them is the body
+        let header_length = fields.iter().map(|field| field.raw.len()).sum::<usize>();
+        let mut kept = Vec::with_capacity(raw.len());
+        for field in &fields {
+            let value = String::from_utf8_lossy(field.raw);
+            let authserv_id = value
+                .split_once(':')
+                .and_then(|(_, value)| value.split(|c: char| c == ';' || c.is_whitespace()).find(|part| !part.is_empty()));
+            let forged = field.name == "authentication-results"
+                && authserv_id.is_some_and(|id| id.eq_ignore_ascii_case(&self.authserv_id));
+            if forged {
+                log::warn!("Removing an Authentication-Results header forged for {}", self.authserv_id);
+                continue;
+            }
+            kept.extend_from_slice(field.raw);
+        }
+        kept.extend_from_slice(&raw[header_length..]);
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Fixtures;
+
+    fn verifier() -> Verifier {
+        Verifier::new(Resolver::new(Fixtures::parse("").unwrap()), "mx.example.com".to_string())
+    }
+
+    #[test]
+    fn strips_results_forged_for_this_server() {
+        let raw = concat!(
+            "Authentication-Results: mx.example.com; spf=pass smtp.mailfrom=example.org\r\n",
+            "Authentication-Results: relay.example.net; dkim=pass header.d=example.org\r\n",
+            "From: ann@example.org\r\n",
+            "Authentication-Results:\r\n\tMX.Example.COM;\r\n\tdmarc=pass header.from=example.org\r\n",
+            "Authentication-Results: mx.example.com.example.net; spf=pass\r\n",
+            "Subject: Lunch\r\n",
+            "\r\n",
+            "Authentication-Results: mx.example.com; in the body it is just text\r\n",
+        );
+        let stripped = verifier().strip_forged(raw.as_bytes());
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            concat!(
+                "Authentication-Results: relay.example.net; dkim=pass header.d=example.org\r\n",
+                "From: ann@example.org\r\n",
+                "Authentication-Results: mx.example.com.example.net; spf=pass\r\n",
+                "Subject: Lunch\r\n",
+                "\r\n",
+                "Authentication-Results: mx.example.com; in the body it is just text\r\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn stamps_its_own_results_on_top() {
+        let envelope = Envelope {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            helo: "mail.example.org".to_string(),
+            mail_from: "ann@example.org".to_string(),
+            rcpt_to: vec!["bob@example.com".to_string()],
+        };
+        let raw = b"Authentication-Results: mx.example.com; spf=pass\r\nFrom: ann@example.org\r\n\r\nNoon?\r\n";
+        let (authentication, stamped) = verifier().verify(&envelope, raw).await;
+        let stamped = String::from_utf8(stamped).unwrap();
+        assert!(stamped.starts_with(&authentication.header("mx.example.com")));
+        assert_eq!(stamped.matches("Authentication-Results:").count(), 1);
+        assert!(stamped.ends_with("From: ann@example.org\r\n\r\nNoon?\r\n"));
+    }
+}
@@ -0,0 +1,532 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use futures::future::BoxFuture;
+
+use super::{SpfResult, SpfScope, Verdict};
+use crate::{
+    dns::{DnsError, Record, RecordType, Resolver},
+    message::Envelope,
+};
+
+/// Terms causing DNS queries per check, RFC 7208 section 4.6.4
+const MAX_LOOKUPS: usize = 10;
+/// Queries answered with nothing, more is likely an abusive record
+const MAX_VOID_LOOKUPS: usize = 2;
+/// Mail exchangers looked at per mx mechanism
+const MAX_MX_HOSTS: usize = 10;
+
+/// Whether a name can be checked at all, anything else has no SPF record by definition
+fn is_domain(name: &str) -> bool {
+    name.len() <= 253
+        && name.contains('.')
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn prefix(bits: &str, max: u8) -> Result<u8, Verdict> {
+    match bits.parse::<u8>() {
+        Ok(bits) if bits <= max => Ok(bits),
+        _ => Err(Verdict::PermError),
+    }
+}
+
+/// Splits `name=value` modifiers from mechanisms, whose names end at `:`, `/` or the term's end
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let end = term.find(|c: char| !(c.is_ascii_alphanumeric() || "-_.".contains(c)))?;
+    match term[end..].starts_with('=') && end > 0 {
+        true => Some((&term[..end], &term[end + 1..])),
+        false => None,
+    }
+}
+
+/// Evaluates the SPF policy of the envelope sender, or of the HELO name for bounces (RFC 7208)
+pub async fn check(resolver: &Resolver, envelope: &Envelope) -> SpfResult {
+    let helo = envelope.helo.trim_end_matches('.').to_ascii_lowercase();
+    let (scope, local, domain) = match envelope.mail_from.rsplit_once('@') {
+        Some((local, domain)) if !domain.is_empty() => {
+            let local = match local.is_empty() {
+                true => "postmaster",
+                false => local,
+            };
+            (SpfScope::MailFrom, local.to_string(), domain.trim_end_matches('.').to_ascii_lowercase())
+        }
+        _ => (SpfScope::Helo, "postmaster".to_string(), helo.clone()),
+    };
+
+    let result = match envelope.client_ip {
+        Some(ip) => {
+            // Clients on a dual stack socket show up as mapped addresses
+            let ip = match ip {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+                ip => ip,
+            };
+            let mut check = Check {
+                resolver,
+                ip,
+                local,
+                sender_domain: domain.clone(),
+                helo,
+                lookups: 0,
+                void_lookups: 0,
+            };
+            check.check_host(domain.clone()).await
+        }
+        None => Verdict::None,
+    };
+    SpfResult { result, scope, domain }
+}
+
+/// State of one evaluation, the lookup limits span every included record
+struct Check<'a> {
+    resolver: &'a Resolver,
+    ip: IpAddr,
+    local: String,
+    sender_domain: String,
+    helo: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Check<'_> {
+    /// The check_host() function of RFC 7208 section 4, boxed since include and redirect recurse
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Verdict> {
+        Box::pin(async move {
+            match self.evaluate(&domain).await {
+                Ok(result) | Err(result) => result,
+            }
+        })
+    }
+
+    async fn evaluate(&mut self, domain: &str) -> Result<Verdict, Verdict> {
+        if !is_domain(domain) {
+            return Ok(Verdict::None);
+        }
+        let Some(record) = self.record(domain).await? else {
+            return Ok(Verdict::None);
+        };
+
+        let mut redirect = None;
+        let mut directives = Vec::new();
+        for term in record.split_ascii_whitespace().skip(1) {
+            match modifier(term) {
+                Some((name, value)) if name.eq_ignore_ascii_case("redirect") => {
+                    if redirect.replace(value).is_some() {
+                        return Err(Verdict::PermError);
+                    }
+                }
+                // exp= only explains failures and unknown modifiers are ignored
+                Some(_) => {}
+                None => directives.push(term),
+            }
+        }
+
+        for term in directives {
+            let (qualifier, mechanism) = match term.as_bytes()[0] {
+                b'+' => (Verdict::Pass, &term[1..]),
+                b'-' => (Verdict::Fail, &term[1..]),
+                b'~' => (Verdict::SoftFail, &term[1..]),
+                b'?' => (Verdict::Neutral, &term[1..]),
+                _ => (Verdict::Pass, term),
+            };
+            if self.matches(mechanism, domain).await? {
+                return Ok(qualifier);
+            }
+        }
+
+        if let Some(target) = redirect {
+            self.count_lookup()?;
+            let target = self.expand(target, domain)?;
+            return match self.check_host(target).await {
+                Verdict::None => Err(Verdict::PermError),
+                result => Ok(result),
+            };
+        }
+        Ok(Verdict::Neutral)
+    }
+
+    /// The single SPF record of a domain, more than one is an error
+    async fn record(&self, domain: &str) -> Result<Option<String>, Verdict> {
+        let records = self.resolver.txt(domain).await.map_err(|_| Verdict::TempError)?;
+        let mut records = records.into_iter().filter(|record| {
+            let version = record.split_ascii_whitespace().next().unwrap_or_default();
+            version.eq_ignore_ascii_case("v=spf1")
+        });
+        match (records.next(), records.next()) {
+            (record, None) => Ok(record),
+            _ => Err(Verdict::PermError),
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Verdict> {
+        self.lookups += 1;
+        match self.lookups > MAX_LOOKUPS {
+            true => Err(Verdict::PermError),
+            false => Ok(()),
+        }
+    }
+
+    fn count_void(&mut self) -> Result<(), Verdict> {
+        self.void_lookups += 1;
+        match self.void_lookups > MAX_VOID_LOOKUPS {
+            true => Err(Verdict::PermError),
+            false => Ok(()),
+        }
+    }
+
+    async fn matches(&mut self, mechanism: &str, domain: &str) -> Result<bool, Verdict> {
+        let end = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, rest) = mechanism.split_at(end);
+        match name.to_ascii_lowercase().as_str() {
+            "all" if rest.is_empty() => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = rest.strip_prefix(':').ok_or(Verdict::PermError)?;
+                let target = self.expand(target, domain)?;
+                match self.check_host(target).await {
+                    Verdict::Pass => Ok(true),
+                    Verdict::Fail | Verdict::SoftFail | Verdict::Neutral => Ok(false),
+                    Verdict::TempError => Err(Verdict::TempError),
+                    _ => Err(Verdict::PermError),
+                }
+            }
+            "a" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = self.target(rest, domain)?;
+                let addresses = self.addresses(&target).await?;
+                Ok(self.any_matches(&addresses, v4, v6))
+            }
+            "mx" => {
+                self.count_lookup()?;
+                let (target, v4, v6) = self.target(rest, domain)?;
+                let hosts = self.exchangers(&target).await?;
+                if hosts.len() > MAX_MX_HOSTS {
+                    return Err(Verdict::PermError);
+                }
+                for host in hosts {
+                    let addresses = self.addresses(&host).await?;
+                    if self.any_matches(&addresses, v4, v6) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            // RFC 7208 section 5.5 discourages ptr, it counts against the limit but never matches
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            "ip4" => {
+                let network = rest.strip_prefix(':').ok_or(Verdict::PermError)?;
+                let (address, bits) = match network.split_once('/') {
+                    Some((address, bits)) => (address, prefix(bits, 32)?),
+                    None => (network, 32),
+                };
+                let address = address.parse::<Ipv4Addr>().map_err(|_| Verdict::PermError)?;
+                Ok(in_network(self.ip, address.into(), bits))
+            }
+            "ip6" => {
+                let network = rest.strip_prefix(':').ok_or(Verdict::PermError)?;
+                let (address, bits) = match network.split_once('/') {
+                    Some((address, bits)) => (address, prefix(bits, 128)?),
+                    None => (network, 128),
+                };
+                let address = address.parse::<Ipv6Addr>().map_err(|_| Verdict::PermError)?;
+                Ok(in_network(self.ip, address.into(), bits))
+            }
+            "exists" => {
+                self.count_lookup()?;
+                let target = rest.strip_prefix(':').ok_or(Verdict::PermError)?;
+                let target = self.expand(target, domain)?;
+                let records = match self.resolver.query(&target, RecordType::A).await {
+                    Ok(records) => records,
+                    Err(DnsError::NotFound(_)) => Vec::new(),
+                    Err(_) => return Err(Verdict::TempError),
+                };
+                if records.is_empty() {
+                    self.count_void()?;
+                }
+                Ok(!records.is_empty())
+            }
+            _ => Err(Verdict::PermError),
+        }
+    }
+
+    fn any_matches(&self, addresses: &[IpAddr], v4: u8, v6: u8) -> bool {
+        addresses.iter().any(|&address| {
+            let bits = match address {
+                IpAddr::V4(_) => v4,
+                IpAddr::V6(_) => v6,
+            };
+            in_network(self.ip, address, bits)
+        })
+    }
+
+    /// Parses `[:domain][/prefix4][//prefix6]` of the a and mx mechanisms
+    fn target(&self, rest: &str, domain: &str) -> Result<(String, u8, u8), Verdict> {
+        let (rest, v6) = match rest.split_once("//") {
+            Some((rest, bits)) => (rest, prefix(bits, 128)?),
+            None => (rest, 128),
+        };
+        let (rest, v4) = match rest.rsplit_once('/') {
+            Some((rest, bits)) => (rest, prefix(bits, 32)?),
+            None => (rest, 32),
+        };
+        let target = match rest.strip_prefix(':') {
+            Some(spec) => self.expand(spec, domain)?,
+            None if rest.is_empty() => domain.to_string(),
+            None => return Err(Verdict::PermError),
+        };
+        Ok((target, v4, v6))
+    }
+
+    /// Addresses of a name in the family of the client, the only ones that could match
+    async fn addresses(&mut self, name: &str) -> Result<Vec<IpAddr>, Verdict> {
+        let kind = match self.ip {
+            IpAddr::V4(_) => RecordType::A,
+            IpAddr::V6(_) => RecordType::Aaaa,
+        };
+        let records = match self.resolver.query(name, kind).await {
+            Ok(records) => records,
+            Err(DnsError::NotFound(_)) => Vec::new(),
+            Err(_) => return Err(Verdict::TempError),
+        };
+        if records.is_empty() {
+            self.count_void()?;
+        }
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Address(ip) => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// MX hosts of a name, without the implicit MX mail delivery falls back to
+    async fn exchangers(&mut self, name: &str) -> Result<Vec<String>, Verdict> {
+        let records = match self.resolver.query(name, RecordType::Mx).await {
+            Ok(records) => records,
+            Err(DnsError::NotFound(_)) => Vec::new(),
+            Err(_) => return Err(Verdict::TempError),
+        };
+        if records.is_empty() {
+            self.count_void()?;
+        }
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Mx { exchange, .. } if !exchange.is_empty() => Some(exchange),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Expands the macros of a domain-spec (RFC 7208 section 7)
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Verdict> {
+        let mut expanded = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some('_') => expanded.push(' '),
+                Some('-') => expanded.push_str("%20"),
+                Some('{') => {
+                    let body = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                    expanded.push_str(&self.macro_value(&body, domain)?);
+                }
+                _ => return Err(Verdict::PermError),
+            }
+        }
+
+        // Overlong names lose labels from the left until they fit
+        while expanded.len() > 253 {
+            match expanded.split_once('.') {
+                Some((_, rest)) => expanded = rest.to_string(),
+                None => return Err(Verdict::PermError),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Value of a single `%{...}` macro with its transformers applied
+    fn macro_value(&self, body: &str, domain: &str) -> Result<String, Verdict> {
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or(Verdict::PermError)?.to_ascii_lowercase();
+        let value = match letter {
+            's' => format!("{}@{}", self.local, self.sender_domain),
+            'l' => self.local.clone(),
+            'o' => self.sender_domain.clone(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.clone(),
+            _ => return Err(Verdict::PermError),
+        };
+
+        let transformers = chars.as_str();
+        let digits = transformers
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(transformers.len());
+        let keep = match &transformers[..digits] {
+            "" => None,
+            count => match count.parse::<usize>() {
+                Ok(0) | Err(_) => return Err(Verdict::PermError),
+                Ok(count) => Some(count),
+            },
+        };
+        let rest = &transformers[digits..];
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(Verdict::PermError);
+        }
+        let delimiters = match delimiters {
+            "" => ".",
+            delimiters => delimiters,
+        };
+
+        let mut parts = value
+            .split(|c| delimiters.contains(c))
+            .collect::<Vec<_>>();
+        if reverse {
+            parts.reverse();
+        }
+        if let Some(keep) = keep {
+            parts = parts.split_off(parts.len().saturating_sub(keep));
+        }
+        Ok(parts.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Fixtures;
+
+    const ZONES: &str = r#"
+["example.com"]
+txt = ["v=spf1 ip4:192.0.2.0/24 a:mail.example.com mx -all"]
+mx = ["10 mx.example.com"]
+
+["mail.example.com"]
+a = ["198.51.100.7"]
+
+["mx.example.com"]
+a = ["203.0.113.5"]
+aaaa = ["2001:db8::25"]
+
+["soft.example"]
+txt = ["v=spf1 include:example.com ~all"]
+
+["forward.example"]
+txt = ["v=spf1 redirect=example.com"]
+
+["macro.example"]
+txt = ["v=spf1 exists:%{ir}.%{l}._spf.%{d} -all"]
+
+["7.100.51.198.ann._spf.macro.example"]
+a = ["127.0.0.2"]
+
+["twice.example"]
+txt = ["v=spf1 -all", "v=spf1 +all"]
+
+["loop.example"]
+txt = ["v=spf1 include:loop.example -all"]
+
+["voids.example"]
+txt = ["v=spf1 a:none1.example a:none2.example a:none3.example -all"]
+
+["broken.example"]
+servfail = true
+"#;
+
+    async fn spf(ip: &str, mail_from: &str) -> SpfResult {
+        let resolver = Resolver::new(Fixtures::parse(ZONES).unwrap());
+        let envelope = Envelope {
+            client_ip: Some(ip.parse().unwrap()),
+            helo: "client.example.net".to_string(),
+            mail_from: mail_from.to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+        };
+        check(&resolver, &envelope).await
+    }
+
+    async fn verdict(ip: &str, mail_from: &str) -> Verdict {
+        spf(ip, mail_from).await.result
+    }
+
+    #[tokio::test]
+    async fn matches_addresses_and_hosts() {
+        assert_eq!(verdict("192.0.2.44", "ann@example.com").await, Verdict::Pass);
+        assert_eq!(verdict("198.51.100.7", "ann@example.com").await, Verdict::Pass);
+        assert_eq!(verdict("203.0.113.5", "ann@example.com").await, Verdict::Pass);
+        assert_eq!(verdict("2001:db8::25", "ann@example.com").await, Verdict::Pass);
+        assert_eq!(verdict("::ffff:192.0.2.44", "ann@example.com").await, Verdict::Pass);
+        assert_eq!(verdict("203.0.113.6", "ann@example.com").await, Verdict::Fail);
+    }
+
+    #[tokio::test]
+    async fn follows_include_and_redirect() {
+        assert_eq!(verdict("192.0.2.1", "ann@soft.example").await, Verdict::Pass);
+        assert_eq!(verdict("10.0.0.1", "ann@soft.example").await, Verdict::SoftFail);
+        assert_eq!(verdict("192.0.2.1", "ann@forward.example").await, Verdict::Pass);
+        assert_eq!(verdict("10.0.0.1", "ann@forward.example").await, Verdict::Fail);
+    }
+
+    #[tokio::test]
+    async fn expands_macros() {
+        assert_eq!(verdict("198.51.100.7", "ann@macro.example").await, Verdict::Pass);
+        assert_eq!(verdict("198.51.100.7", "bob@macro.example").await, Verdict::Fail);
+    }
+
+    #[tokio::test]
+    async fn checks_helo_for_null_senders() {
+        let result = spf("192.0.2.1", "").await;
+        assert_eq!(result.scope, SpfScope::Helo);
+        assert_eq!(result.domain, "client.example.net");
+        assert_eq!(result.result, Verdict::None);
+    }
+
+    #[tokio::test]
+    async fn reports_errors() {
+        assert_eq!(verdict("192.0.2.1", "ann@unknown.example").await, Verdict::None);
+        assert_eq!(verdict("192.0.2.1", "ann@twice.example").await, Verdict::PermError);
+        assert_eq!(verdict("192.0.2.1", "ann@loop.example").await, Verdict::PermError);
+        assert_eq!(verdict("192.0.2.1", "ann@voids.example").await, Verdict::PermError);
+        assert_eq!(verdict("192.0.2.1", "ann@broken.example").await, Verdict::TempError);
+    }
+}
@@ -0,0 +1,328 @@
+mod signature;
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::http::header::ContentType;
+use awc::Client;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use tokio::sync::Notify;
+
+use crate::{
+    db::{DatabaseError, Store},
+    message::{Envelope, Header, Message, Summary},
+    time,
+};
+use signature::SIGNATURE_HEADER;
+
+/// Attempts before a delivery is moved to the dead letters
+const MAX_ATTEMPTS: u32 = 12;
+/// Delay before the first retry, doubled on every further attempt
+const INITIAL_BACKOFF: i64 = 10;
+/// Upper bound for the delay between two attempts
+const MAX_BACKOFF: i64 = 6 * 60 * 60;
+/// How often the queue is checked when no new message arrives
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of the POST sent for every new message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Payload {
+    pub id: String,
+    pub envelope: Envelope,
+    pub headers: Vec<Header>,
+    pub summary: Summary,
+}
+
+/// A payload waiting to be delivered, or given up on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub payload: Payload,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+}
+
+/// Persistent delivery queue for new message notifications
+#[derive(Clone)]
+pub struct Webhook {
+    url: Option<String>,
+    secret: Option<Arc<[u8]>>,
+    queue: Store<Delivery>,
+    dead_letters: Store<Delivery>,
+    wake: Arc<Notify>,
+}
+
+/// Delay before the given attempt, growing exponentially
+fn backoff(attempts: u32) -> i64 {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(MAX_BACKOFF)
+}
+
+impl Webhook {
+    /// Opens the queues, without a url nothing is ever queued.
+    /// With a secret every request is signed with HMAC-SHA256.
+    pub fn open(database: &Db, url: Option<String>, secret: Option<String>) -> Result<Self, DatabaseError> {
+        if url.is_some() && secret.is_none() {
+            log::warn!("No WEBHOOK_SECRET configured, webhook payloads will not be signed");
+        }
+        Ok(Webhook {
+            url,
+            secret: secret.map(|secret| secret.into_bytes().into()),
+            queue: Store::open(database, "webhook_queue")?,
+            dead_letters: Store::open(database, "webhook_dead_letters")?,
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Queues a notification for a newly stored message
+    pub async fn enqueue(&self, id: &str, message: &Message) -> Result<(), DatabaseError> {
+        if self.url.is_none() {
+            return Ok(());
+        }
+        let delivery = Delivery {
+            payload: Payload {
+                id: id.to_string(),
+                envelope: message.envelope.clone(),
+                headers: message.headers.clone(),
+                summary: message.summary(),
+            },
+            attempts: 0,
+            next_attempt: time::now(),
+            last_error: None,
+        };
+        self.queue.set(id, &delivery).await?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub async fn pending(&self) -> Result<Vec<(String, Delivery)>, DatabaseError> {
+        self.queue.list().await
+    }
+
+    pub async fn dead_letters(&self) -> Result<Vec<(String, Delivery)>, DatabaseError> {
+        self.dead_letters.list().await
+    }
+
+    /// Moves a dead letter back into the queue with a fresh set of attempts
+    pub async fn retry(&self, id: &str) -> Result<(), DatabaseError> {
+        let mut delivery = self.dead_letters.get(id).await?;
+        delivery.attempts = 0;
+        delivery.next_attempt = time::now();
+        self.queue.set(id, &delivery).await?;
+        self.dead_letters.delete(id).await?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub async fn discard(&self, id: &str) -> Result<(), DatabaseError> {
+        self.dead_letters.delete(id).await
+    }
+
+    /// Delivers queued notifications until the process exits.
+    /// The HTTP client is not `Send`, so this has to run on the actix runtime.
+    pub async fn run(self) {
+        let Some(url) = self.url.clone() else {
+            log::info!("No WEBHOOK_URL configured, webhooks are disabled");
+            return;
+        };
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).finish();
+
+        loop {
+            if let Err(error) = self.deliver_due(&client, &url).await {
+                log::error!("Webhook Queue Error: {}", error);
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Attempts every delivery whose retry time has come
+    async fn deliver_due(&self, client: &Client, url: &str) -> Result<(), DatabaseError> {
+        for (id, mut delivery) in self.queue.list().await? {
+            if delivery.next_attempt > time::now() {
+                continue;
+            }
+
+            let body = serde_json::to_vec(&delivery.payload).map_err(|error| {
+                log::error!("Webhook Serialization Error: {}", error);
+                DatabaseError::Serialize
+            })?;
+            let mut request = client.post(url).insert_header(ContentType::json());
+            // Signed per attempt, so the timestamp tells receivers how fresh the request is
+            if let Some(secret) = &self.secret {
+                request = request.insert_header((
+                    SIGNATURE_HEADER,
+                    signature::sign(secret, time::now(), &body),
+                ));
+            }
+
+            let error = match request.send_body(body).await {
+                Ok(response) if response.status().is_success() => {
+                    log::info!("Delivered webhook for message {}", id);
+                    self.queue.delete(&id).await?;
+                    continue;
+                }
+                Ok(response) => format!("Receiver responded with {}", response.status()),
+                Err(error) => error.to_string(),
+            };
+
+            delivery.attempts += 1;
+            delivery.last_error = Some(error);
+            if delivery.attempts >= MAX_ATTEMPTS {
+                log::error!("Giving up webhook for message {} after {} attempts", id, delivery.attempts);
+                self.dead_letters.set(&id, &delivery).await?;
+                self.queue.delete(&id).await?;
+            } else {
+                delivery.next_attempt = time::now() + backoff(delivery.attempts);
+                log::warn!(
+                    "Webhook for message {} failed, retrying in {}s: {}",
+                    id,
+                    delivery.next_attempt - time::now(),
+                    delivery.last_error.as_deref().unwrap_or_default()
+                );
+                self.queue.set(&id, &delivery).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Answers every request with the given status, passing the raw requests on
+    async fn receiver(status: &'static str) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).into_owned();
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(str::to_string))
+                        .and_then(|length| length.trim().parse::<usize>().ok())
+                        .unwrap_or_default();
+                    if read == 0 || body.len() >= length {
+                        break;
+                    }
+                }
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                let _ = requests.send(String::from_utf8_lossy(&request).into_owned());
+            }
+        });
+        (url, received)
+    }
+
+    /// The HTTP client needs the process wide TLS provider the server installs on startup
+    fn client() -> Client {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        Client::default()
+    }
+
+    fn message() -> Message {
+        let envelope = Envelope {
+            client_ip: None,
+            helo: String::new(),
+            mail_from: "ann@example.org".to_string(),
+            rcpt_to: vec!["bob@example.com".to_string()],
+        };
+        Message::parse(envelope, b"Subject: Invoice\r\n\r\nSee attached\r\n").0
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_a_limit() {
+        assert_eq!(backoff(1), 10);
+        assert_eq!(backoff(2), 20);
+        assert_eq!(backoff(3), 40);
+        assert_eq!(backoff(12), 20480);
+        assert_eq!(backoff(13), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[actix_web::test]
+    async fn queues_nothing_without_a_url() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let webhook = Webhook::open(&database, None, None).unwrap();
+        webhook.enqueue("00000000000000000001", &message()).await.unwrap();
+        assert!(webhook.pending().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn delivers_signed_payloads() {
+        let (url, mut received) = receiver("204 No Content").await;
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let webhook = Webhook::open(&database, Some(url.clone()), Some("whsec_test".to_string())).unwrap();
+        webhook.enqueue("00000000000000000001", &message()).await.unwrap();
+
+        webhook.deliver_due(&client(), &url).await.unwrap();
+        assert!(webhook.pending().await.unwrap().is_empty());
+
+        let request = received.recv().await.unwrap();
+        assert!(request.starts_with("POST /hook "));
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let payload: Payload = serde_json::from_str(body).unwrap();
+        assert_eq!(payload.id, "00000000000000000001");
+        assert_eq!(payload.envelope.mail_from, "ann@example.org");
+        let header = head
+            .lines()
+            .find_map(|line| line.strip_prefix("x-feathermail-signature: "))
+            .unwrap();
+        let timestamp = header.strip_prefix("t=").unwrap().split(',').next().unwrap();
+        assert_eq!(header, signature::sign(b"whsec_test", timestamp.parse().unwrap(), body.as_bytes()));
+    }
+
+    #[actix_web::test]
+    async fn retries_failures_then_gives_up() {
+        let (url, _received) = receiver("503 Service Unavailable").await;
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let webhook = Webhook::open(&database, Some(url.clone()), None).unwrap();
+        let client = client();
+        webhook.enqueue("00000000000000000001", &message()).await.unwrap();
+
+        let before = time::now();
+        webhook.deliver_due(&client, &url).await.unwrap();
+        let (_, delivery) = webhook.pending().await.unwrap().remove(0);
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.next_attempt >= before + INITIAL_BACKOFF);
+        assert!(delivery.last_error.unwrap().contains("503"));
+
+        // Not due yet, so nothing is attempted
+        webhook.deliver_due(&client, &url).await.unwrap();
+        assert_eq!(webhook.pending().await.unwrap()[0].1.attempts, 1);
+
+        let mut delivery = webhook.queue.get("00000000000000000001").await.unwrap();
+        delivery.attempts = MAX_ATTEMPTS - 1;
+        delivery.next_attempt = time::now();
+        webhook.queue.set("00000000000000000001", &delivery).await.unwrap();
+        webhook.deliver_due(&client, &url).await.unwrap();
+        assert!(webhook.pending().await.unwrap().is_empty());
+        let dead_letters = webhook.dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].1.attempts, MAX_ATTEMPTS);
+
+        webhook.retry("00000000000000000001").await.unwrap();
+        assert!(webhook.dead_letters().await.unwrap().is_empty());
+        assert_eq!(webhook.pending().await.unwrap()[0].1.attempts, 0);
+    }
+}